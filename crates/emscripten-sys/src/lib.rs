@@ -395,3 +395,6 @@ extern "C" {
         unpackedHeaders: *mut *mut ::std::os::raw::c_char,
     );
 }
+extern "C" {
+    pub fn emscripten_sleep(ms: ::std::os::raw::c_uint);
+}
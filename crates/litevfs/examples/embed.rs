@@ -0,0 +1,42 @@
+//! Embeds LiteVFS into a Rust application using rusqlite, without loading the
+//! SQLite extension.
+//!
+//! ```sh
+//! $ LITEFS_CLOUD_TOKEN=<your token> LITEFS_CLOUD_CLUSTER=<cluster> cargo run --example embed -- db1
+//! ```
+
+use rusqlite::{Connection, OpenFlags};
+use std::env;
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let db = env::args().nth(1).unwrap_or("db1".to_string());
+
+    let mut builder = litevfs::Builder::new()
+        .token(&env::var("LITEFS_CLOUD_TOKEN")?)
+        .cache_dir(env::temp_dir().join("litevfs-embed"));
+    if let Ok(cluster) = env::var("LITEFS_CLOUD_CLUSTER") {
+        builder = builder.cluster(&cluster);
+    }
+    let vfs = builder.build()?;
+
+    // SQLite takes the VFS over, keep a handle to shut it down with
+    let shutdown = vfs.shutdown_handle();
+    // rusqlite links SQLite in, so the VFS is registered as a linked extension
+    litevfs::sqlite_vfs::LinkedExtension::build().register("litevfs", vfs, false)?;
+
+    let conn = Connection::open_with_flags_and_vfs(
+        &db,
+        OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+        "litevfs",
+    )?;
+    let tables: Vec<String> = conn
+        .prepare("select name from sqlite_schema where type = 'table'")?
+        .query_map([], |row| row.get(0))?
+        .collect::<Result<_, _>>()?;
+    println!("{}: {}", db, tables.join(", "));
+
+    drop(conn);
+    shutdown.shutdown(std::time::Duration::from_secs(5))?;
+
+    Ok(())
+}
@@ -0,0 +1,459 @@
+use crate::{
+    http,
+    leaser::{
+        DEFAULT_LEASE_DURATION, DEFAULT_LEASE_REFRESH_FRACTION, MIN_LEASE_DURATION,
+        MIN_LEASE_REFRESH_PERIOD,
+    },
+    lfsc,
+    pager::{
+        CacheDurability, PagerBackend, DEFAULT_CACHE_PROBATION, DEFAULT_CACHE_PROTECTED,
+        DEFAULT_FETCH_PARALLELISM,
+    },
+    syncer::DEFAULT_SYNC_PERIOD,
+    vfs::{parse_duration_or_secs, LiteVfs},
+};
+use rand::distributions::{Alphanumeric, DistString};
+use std::{collections::HashMap, env, fmt, fs, io, path::PathBuf, process, time};
+
+/// Builds a [LiteVfs] for applications that embed LiteVFS instead of loading it as
+/// a SQLite extension. The VFS still needs to be registered with SQLite, e.g. with
+/// [sqlite_vfs::LinkedExtension], see `examples/embed.rs`.
+///
+/// The settings that have a builder method are not read from the environment, the
+/// rest of them, like `LITEVFS_SYNC_PERIOD`, still are, and [Builder::build] fails if
+/// any of them is invalid.
+#[derive(Default)]
+pub struct Builder {
+    cache_dir: Option<PathBuf>,
+    token: Option<String>,
+    cluster: Option<String>,
+    host: Option<String>,
+    ca_cert: Option<PathBuf>,
+    fingerprints: Vec<String>,
+    read_only: bool,
+    lease_duration: Option<time::Duration>,
+    lease_refresh_fraction: Option<f64>,
+    lease_idle_timeout: Option<time::Duration>,
+    temp_in_memory: bool,
+    conditional_fetches: bool,
+
+    // Set by the extension entry points from the environment.
+    client: Option<lfsc::Client>,
+    clusters: HashMap<String, lfsc::Client>,
+    pager_backend: PagerBackend,
+}
+
+impl Builder {
+    pub fn new() -> Builder {
+        Builder::default()
+    }
+
+    /// Sets the cache directory for databases. Defaults to a random directory under `/tmp`.
+    pub fn cache_dir<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.cache_dir = Some(path.into());
+        self
+    }
+
+    /// Sets the LiteFS Cloud token. Mandatory.
+    pub fn token(mut self, token: &str) -> Self {
+        self.token = Some(token.to_string());
+        self
+    }
+
+    /// Sets the LiteFS Cloud cluster. Optional for cluster-scoped tokens.
+    pub fn cluster(mut self, cluster: &str) -> Self {
+        self.cluster = Some(cluster.to_string());
+        self
+    }
+
+    /// Sets the LiteFS Cloud host, which may include a base path. Defaults to
+    /// `https://litefs.fly.io`.
+    pub fn host(mut self, host: &str) -> Self {
+        self.host = Some(host.to_string());
+        self
+    }
+
+    /// Verifies LiteFS Cloud certificates against the root certificates in the PEM
+    /// file instead of the default ones.
+    pub fn ca_cert<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.ca_cert = Some(path.into());
+        self
+    }
+
+    /// Requires one of the certificates presented by LiteFS Cloud to match the
+    /// hex-encoded SHA-256 fingerprint. May be called multiple times.
+    pub fn pin_certificate(mut self, fingerprint: &str) -> Self {
+        self.fingerprints.push(fingerprint.to_string());
+        self
+    }
+
+    /// Never modifies databases or acquires write leases.
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Sets the duration of write leases. Defaults to 1s.
+    pub fn lease_duration(mut self, duration: time::Duration) -> Self {
+        self.lease_duration = Some(duration);
+        self
+    }
+
+    /// Sets the fraction of the lease duration after which write leases are refreshed,
+    /// between 0 and 1. Defaults to a third. Leases aren't refreshed more often than
+    /// every 100ms.
+    pub fn lease_refresh_fraction(mut self, fraction: f64) -> Self {
+        self.lease_refresh_fraction = Some(fraction);
+        self
+    }
+
+    /// Releases write leases that haven't been used for the given duration.
+    pub fn lease_idle_timeout(mut self, timeout: time::Duration) -> Self {
+        self.lease_idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Keeps temporary databases in memory instead of the cache directory.
+    pub fn temp_in_memory(mut self, temp_in_memory: bool) -> Self {
+        self.temp_in_memory = temp_in_memory;
+        self
+    }
+
+    /// Revalidates the cached pages with LiteFS Cloud after a full resync, instead of
+    /// downloading them again. Requires LiteFS Cloud to support ETags of the pages.
+    pub fn conditional_fetches(mut self, enabled: bool) -> Self {
+        self.conditional_fetches = enabled;
+        self
+    }
+
+    /// Uses an already configured LFSC client instead of the token, cluster and host.
+    pub(crate) fn client(mut self, client: lfsc::Client) -> Self {
+        self.client = Some(client);
+        self
+    }
+
+    /// Adds an additional cluster to serve databases from.
+    pub(crate) fn add_cluster(mut self, name: &str, client: lfsc::Client) -> Self {
+        self.clusters.insert(name.to_string(), client);
+        self
+    }
+
+    pub(crate) fn pager_backend(mut self, backend: PagerBackend) -> Self {
+        self.pager_backend = backend;
+        self
+    }
+
+    /// Builds the VFS. Fails if the settings are invalid or the cache directory
+    /// can't be created.
+    pub fn build(self) -> io::Result<LiteVfs> {
+        let lease_duration = self.lease_duration.unwrap_or(DEFAULT_LEASE_DURATION);
+        if lease_duration < MIN_LEASE_DURATION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "lease duration must be at least {}",
+                    humantime::format_duration(MIN_LEASE_DURATION)
+                ),
+            ));
+        }
+
+        let lease_refresh_fraction = self
+            .lease_refresh_fraction
+            .unwrap_or(DEFAULT_LEASE_REFRESH_FRACTION);
+        if !(lease_refresh_fraction > 0.0 && lease_refresh_fraction < 1.0) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "lease refresh fraction must be between 0 and 1",
+            ));
+        }
+        if lease_duration.mul_f64(lease_refresh_fraction) < MIN_LEASE_REFRESH_PERIOD {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "leases must be refreshed at most every {}",
+                    humantime::format_duration(MIN_LEASE_REFRESH_PERIOD)
+                ),
+            ));
+        }
+
+        let settings = EnvSettings::from_env()?;
+
+        let client = match self.client {
+            Some(client) => client,
+            None => {
+                let token = self.token.ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidInput, "LiteFS Cloud token is not set")
+                })?;
+                let mut builder = lfsc::Client::builder()
+                    .token(&token)
+                    .conditional_fetches(self.conditional_fetches);
+                if let Some(cluster) = self.cluster {
+                    builder = builder.cluster(&cluster);
+                }
+                if let Some(host) = self.host {
+                    builder = builder.host(
+                        &host
+                            .parse()
+                            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?,
+                    );
+                }
+
+                if let Some(path) = self.ca_cert {
+                    builder = builder.ca_cert(http::CaCert::from_file(path)?);
+                }
+                for fingerprint in &self.fingerprints {
+                    builder = builder.pin_certificate(http::Fingerprint::new(fingerprint)?);
+                }
+
+                let mut client = builder.build();
+                client.init_cluster_id();
+                client
+            }
+        };
+
+        let cache_dir = self.cache_dir.unwrap_or_else(|| {
+            PathBuf::from(format!(
+                "/tmp/litevfs-{}-{}",
+                process::id(),
+                Alphanumeric.sample_string(&mut rand::thread_rng(), 8)
+            ))
+        });
+        fs::create_dir_all(&cache_dir)?;
+
+        Ok(LiteVfs::new(
+            cache_dir,
+            client,
+            self.pager_backend,
+            self.read_only,
+            lease_duration,
+            lease_refresh_fraction,
+            self.lease_idle_timeout,
+            settings,
+            self.temp_in_memory,
+            self.clusters,
+        ))
+    }
+}
+
+/// Settings without a builder method, read from the environment by [Builder::build].
+#[derive(Debug, Clone)]
+pub(crate) struct EnvSettings {
+    /// `LITEVFS_SYNC_PERIOD`, either a number of seconds or a human readable duration.
+    pub(crate) sync_period: time::Duration,
+    /// `LITEVFS_CACHE_PROBATION` and `LITEVFS_CACHE_PROTECTED`, capacities of the LRU segments.
+    pub(crate) cache_probation: usize,
+    pub(crate) cache_protected: usize,
+    /// `LITEVFS_FETCH_PARALLELISM`, the number of parallel requests of a large page fetch.
+    pub(crate) fetch_parallelism: usize,
+    /// `LITEVFS_CACHE_DURABILITY`, either `fast` or `safe`.
+    pub(crate) cache_durability: CacheDurability,
+    /// `LITEVFS_CACHE_COMPRESSION`, compresses the newly cached pages.
+    pub(crate) cache_compression: bool,
+    /// `LITEVFS_JOURNAL_IN_MEMORY`, keeps rollback journals in memory.
+    pub(crate) journal_in_memory: bool,
+    /// `LITEVFS_OFFLINE_WRITES`, queues transactions while LFSC is unreachable.
+    pub(crate) offline_writes: bool,
+}
+
+impl Default for EnvSettings {
+    fn default() -> Self {
+        EnvSettings {
+            sync_period: DEFAULT_SYNC_PERIOD,
+            cache_probation: DEFAULT_CACHE_PROBATION,
+            cache_protected: DEFAULT_CACHE_PROTECTED,
+            fetch_parallelism: DEFAULT_FETCH_PARALLELISM,
+            cache_durability: CacheDurability::default(),
+            cache_compression: false,
+            journal_in_memory: false,
+            offline_writes: false,
+        }
+    }
+}
+
+impl EnvSettings {
+    /// Reads the settings from the environment. Fails if any of them is invalid.
+    pub(crate) fn from_env() -> io::Result<EnvSettings> {
+        EnvSettings::from_vars(&|name| env::var(name).ok())
+    }
+
+    // Same as `from_env`, but the variables are looked up with `var`.
+    fn from_vars(var: &dyn Fn(&str) -> Option<String>) -> io::Result<EnvSettings> {
+        let default = EnvSettings::default();
+        let settings = EnvSettings {
+            sync_period: parse_var(var, "LITEVFS_SYNC_PERIOD", parse_duration_or_secs)?
+                .unwrap_or(default.sync_period),
+            cache_probation: parse_var(var, "LITEVFS_CACHE_PROBATION", parse_positive)?
+                .unwrap_or(default.cache_probation),
+            cache_protected: parse_var(var, "LITEVFS_CACHE_PROTECTED", parse_positive)?
+                .unwrap_or(default.cache_protected),
+            fetch_parallelism: parse_var(var, "LITEVFS_FETCH_PARALLELISM", parse_positive)?
+                .unwrap_or(default.fetch_parallelism),
+            cache_durability: parse_var(var, "LITEVFS_CACHE_DURABILITY", str::parse)?
+                .unwrap_or(default.cache_durability),
+            cache_compression: parse_var(var, "LITEVFS_CACHE_COMPRESSION", parse_bool)?
+                .unwrap_or(default.cache_compression),
+            journal_in_memory: parse_var(var, "LITEVFS_JOURNAL_IN_MEMORY", parse_bool)?
+                .unwrap_or(default.journal_in_memory),
+            offline_writes: parse_var(var, "LITEVFS_OFFLINE_WRITES", parse_bool)?
+                .unwrap_or(default.offline_writes),
+        };
+
+        if cfg!(target_os = "emscripten") && settings.cache_compression {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "cache compression is not supported in browsers",
+            ));
+        }
+        if cfg!(target_os = "emscripten") && settings.offline_writes {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "offline writes are not supported in browsers",
+            ));
+        }
+
+        Ok(settings)
+    }
+}
+
+/// Returns the value of the boolean environment variable `name`, `false` if it's not set.
+/// Fails if the value is invalid.
+pub(crate) fn env_bool(name: &str) -> io::Result<bool> {
+    Ok(parse_var(&|name| env::var(name).ok(), name, parse_bool)?.unwrap_or(false))
+}
+
+// Parses the value of the environment variable `name` looked up with `var`. Returns
+// `None` if the variable is not set.
+fn parse_var<T, E: fmt::Display>(
+    var: &dyn Fn(&str) -> Option<String>,
+    name: &str,
+    parse: impl FnOnce(&str) -> Result<T, E>,
+) -> io::Result<Option<T>> {
+    let Some(val) = var(name) else {
+        return Ok(None);
+    };
+
+    parse(&val).map(Some).map_err(|err| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid {} value: {}: {}", name, val, err),
+        )
+    })
+}
+
+fn parse_bool(val: &str) -> Result<bool, &'static str> {
+    match val {
+        "1" | "true" => Ok(true),
+        "0" | "false" => Ok(false),
+        _ => Err("expected true or false"),
+    }
+}
+
+fn parse_positive(val: &str) -> Result<usize, &'static str> {
+    match val.parse() {
+        Ok(n) if n > 0 => Ok(n),
+        _ => Err("expected a positive integer"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Builder, EnvSettings};
+    use std::{collections::HashMap, io, time::Duration};
+
+    #[test]
+    fn env_settings() {
+        let from_vars = |vars: &[(&str, &str)]| {
+            let vars = vars
+                .iter()
+                .map(|&(name, val)| (name.to_string(), val.to_string()))
+                .collect::<HashMap<_, _>>();
+            EnvSettings::from_vars(&|name| vars.get(name).cloned())
+        };
+
+        let settings = from_vars(&[]).expect("defaults");
+        let default = EnvSettings::default();
+        assert_eq!(default.sync_period, settings.sync_period);
+        assert_eq!(default.fetch_parallelism, settings.fetch_parallelism);
+        assert!(!settings.journal_in_memory);
+
+        let settings = from_vars(&[
+            ("LITEVFS_SYNC_PERIOD", "250ms"),
+            ("LITEVFS_CACHE_PROBATION", "10"),
+            ("LITEVFS_FETCH_PARALLELISM", "2"),
+            ("LITEVFS_JOURNAL_IN_MEMORY", "1"),
+        ])
+        .expect("settings");
+        assert_eq!(Duration::from_millis(250), settings.sync_period);
+        assert_eq!(10, settings.cache_probation);
+        assert_eq!(2, settings.fetch_parallelism);
+        assert!(settings.journal_in_memory);
+        assert_eq!(
+            Duration::from_secs(5),
+            from_vars(&[("LITEVFS_SYNC_PERIOD", "5")])
+                .expect("settings")
+                .sync_period
+        );
+
+        for (name, val) in [
+            ("LITEVFS_SYNC_PERIOD", "invalid"),
+            ("LITEVFS_CACHE_PROTECTED", "0"),
+            ("LITEVFS_FETCH_PARALLELISM", "-1"),
+            ("LITEVFS_CACHE_DURABILITY", "paranoid"),
+            ("LITEVFS_CACHE_COMPRESSION", "yes"),
+            ("LITEVFS_OFFLINE_WRITES", "2"),
+        ] {
+            let err = from_vars(&[(name, val)]).expect_err(name);
+            assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+            assert!(err.to_string().contains(name));
+        }
+    }
+
+    #[test]
+    fn invalid_settings() {
+        let err = Builder::new().build().err().expect("build without token");
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+
+        let err = Builder::new()
+            .token("token")
+            .lease_duration(std::time::Duration::from_millis(100))
+            .build()
+            .err()
+            .expect("build with short lease");
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+
+        // Refreshed every 10ms
+        let err = Builder::new()
+            .token("token")
+            .lease_refresh_fraction(0.01)
+            .build()
+            .err()
+            .expect("build with short refresh period");
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+
+        for fraction in [0.0, 1.0, -0.5, f64::NAN] {
+            let err = Builder::new()
+                .token("token")
+                .lease_refresh_fraction(fraction)
+                .build()
+                .err()
+                .expect("build with invalid refresh fraction");
+            assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+        }
+
+        let err = Builder::new()
+            .token("token")
+            .host("not a url")
+            .build()
+            .err()
+            .expect("build with invalid host");
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+
+        let err = Builder::new()
+            .token("token")
+            .ca_cert("/nonexistent/ca.pem")
+            .build()
+            .err()
+            .expect("build with missing CA file");
+        assert!(err.to_string().contains("/nonexistent/ca.pem"));
+    }
+}
@@ -1,33 +1,86 @@
 use crate::{
+    builder::EnvSettings,
     leaser::Leaser,
     lfsc,
     locks::{ConnLock, VfsLock},
-    pager::{PageRef, PageSource, Pager},
+    metrics::metrics,
+    pager::{remove_old_files, PageRef, PageSource, Pager},
     sqlite,
     syncer::{Changes, Syncer},
-    IterLogger, OptionLogger,
+    wal::WalIndexState,
+    IterLogger, OptionLogger, LITEVFS_IOERR_POS_MISMATCH,
 };
-use litetx as ltx;
-use sqlite_vfs::OpenAccess;
+#[cfg(not(target_os = "emscripten"))]
+use crate::{pager::Page, Worker};
+use bytesize::ByteSize;
+use litetx::{self as ltx, PageChecksum};
+use rand::seq::SliceRandom;
+use sqlite_vfs::{CodeError, OpenAccess};
 use std::{
-    collections::{BTreeMap, BTreeSet, HashMap},
+    collections::{hash_map::DefaultHasher, BTreeMap, BTreeSet, HashMap, VecDeque},
     fs,
+    hash::{Hash, Hasher},
     io::{self, Read, Seek, SeekFrom},
     ops,
-    path::PathBuf,
-    sync::{Arc, Mutex, RwLock},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering},
+        Arc, Condvar, Mutex, RwLock,
+    },
     time,
 };
 
 const DEFAULT_MAX_PREFETCH_PAGES: usize = 32;
 pub(crate) const MAX_MAX_PREFETCH_PAGES: usize = 128;
+// Number of prefetched pages that haven't been read yet kept track of for the prefetch
+// stats. Pages that are never read, e.g. evicted ones, would accumulate otherwise.
+const MAX_PREFETCHED_PAGES: usize = 4096;
+// Number of consecutive increasing page reads after which the reads are considered
+// a sequential scan, and the number of pages to read ahead during one.
+const SEQUENTIAL_READS: usize = 3;
+const READ_AHEAD_PAGES: u32 = 16;
+// Number of independently locked parts of the open databases map.
+const DATABASE_SHARDS: usize = 16;
+// How often closed databases are dropped from memory and orphaned files are removed.
+#[cfg(not(target_os = "emscripten"))]
+const PRUNE_PERIOD: time::Duration = time::Duration::from_secs(60);
+// Delays between the attempts to upload the queued transactions.
+#[cfg(not(target_os = "emscripten"))]
+const UPLOAD_RETRY_MIN_DELAY: time::Duration = time::Duration::from_secs(1);
+#[cfg(not(target_os = "emscripten"))]
+const UPLOAD_RETRY_MAX_DELAY: time::Duration = time::Duration::from_secs(30);
+// Sizes of the LTX header, page frame header and trailer, used to estimate the
+// size of a transaction before encoding it.
+const LTX_HEADER_SIZE: u64 = 100;
+const LTX_PAGE_HEADER_SIZE: u64 = 4;
+const LTX_TRAILER_SIZE: u64 = 16;
+// Size of the transactions held by `litevfs_combine_writes` that closes the window
+// early if none is given. Merged transactions are built in memory.
+pub(crate) const DEFAULT_COMBINE_MAX_BYTES: u64 = 4 * 1024 * 1024;
+// Number of pages requested from LFSC at once by `verify_cache`.
+const VERIFY_BATCH_PAGES: usize = 64;
+// Number of the last position changes remembered to tell if a page has changed since
+// a pinned position.
+const MAX_POS_CHANGES: usize = 64;
+// Number of the pinned positions page 1 is kept for.
+const MAX_PINNED_PAGE1: usize = 8;
+// How often the background upload checks if the write-combining window has closed.
+const COMBINE_POLL_PERIOD: time::Duration = time::Duration::from_millis(50);
+
+type Databases = HashMap<String, Arc<RwLock<Database>>>;
 
 pub(crate) struct DatabaseManager {
     pager: Arc<Pager>,
-    databases: HashMap<String, Arc<RwLock<Database>>>,
+    // Open databases, sharded by name so that different databases can be
+    // opened and looked up concurrently. Shared with the background pruning.
+    databases: Arc<[RwLock<Databases>; DATABASE_SHARDS]>,
     client: Arc<lfsc::Client>,
     leaser: Arc<Leaser>,
     syncer: Arc<Syncer>,
+    read_only: bool,
+    offline_writes: bool,
+    journal_in_memory: bool,
+    background: Arc<Background>,
 }
 
 impl DatabaseManager {
@@ -36,40 +89,99 @@ impl DatabaseManager {
         client: Arc<lfsc::Client>,
         leaser: Arc<Leaser>,
         syncer: Arc<Syncer>,
+        read_only: bool,
+        settings: &EnvSettings,
     ) -> DatabaseManager {
-        DatabaseManager {
+        let manager = DatabaseManager {
             pager,
-            databases: HashMap::new(),
+            databases: Default::default(),
             client,
             leaser,
             syncer,
-        }
+            read_only,
+            offline_writes: settings.offline_writes,
+            journal_in_memory: settings.journal_in_memory,
+            background: Arc::new(Background::default()),
+        };
+        manager.prune_in_background();
+
+        manager
+    }
+
+    // Periodically drops the idle databases and removes the orphaned files, until
+    // shutdown or the manager is dropped.
+    #[cfg(not(target_os = "emscripten"))]
+    fn prune_in_background(&self) {
+        let databases = Arc::downgrade(&self.databases);
+        let pager = Arc::clone(&self.pager);
+        let background = Arc::clone(&self.background);
+
+        self.background.spawn(move || {
+            while background.sleep(PRUNE_PERIOD) {
+                let Some(databases) = databases.upgrade() else {
+                    return;
+                };
+                DatabaseManager::prune_idle(&databases[..], &pager);
+                DatabaseManager::remove_orphans(&databases[..]);
+            }
+        });
+    }
+
+    #[cfg(target_os = "emscripten")]
+    fn prune_in_background(&self) {}
+
+    /// Returns the background threads of the databases, to be stopped on shutdown.
+    pub(crate) fn background(&self) -> Arc<Background> {
+        Arc::clone(&self.background)
     }
 
     pub(crate) fn get_database(
-        &mut self,
+        &self,
         dbname: &str,
         access: OpenAccess,
     ) -> io::Result<Arc<RwLock<Database>>> {
-        let db = if let Some(db) = self.get_database_local_in_mem(dbname, access)? {
-            db
-        } else if let Some(db) = self.get_database_local_on_disk(dbname, access)? {
-            self.databases.insert(dbname.into(), Arc::clone(&db));
-            db
-        } else if let Some(db) = self.get_database_remote(dbname, access)? {
-            self.databases.insert(dbname.into(), Arc::clone(&db));
-            db
-        } else {
+        // PermissionDenied makes SQLite retry opening the database as read-only
+        if self.read_only && access != OpenAccess::Read {
             return Err(io::Error::new(
-                io::ErrorKind::NotFound,
-                "database not found",
+                io::ErrorKind::PermissionDenied,
+                "LiteVFS is in read-only mode",
             ));
+        }
+
+        let shard = self.shard(dbname);
+        let cached =
+            DatabaseManager::get_database_local_in_mem(&shard.read().unwrap(), dbname, access)?;
+        let db = if let Some(db) = cached {
+            db
+        } else {
+            // Hold the shard lock while loading the database, so that concurrent
+            // opens of the same database end up with the same instance.
+            let mut databases = shard.write().unwrap();
+            if let Some(db) =
+                DatabaseManager::get_database_local_in_mem(&databases, dbname, access)?
+            {
+                db
+            } else if let Some(db) = self.get_database_local_on_disk(dbname, access)? {
+                databases.insert(dbname.into(), Arc::clone(&db));
+                // Transactions queued before a restart
+                Database::upload_in_background(&db);
+                db
+            } else if let Some(db) = self.get_database_remote(dbname, access)? {
+                databases.insert(dbname.into(), Arc::clone(&db));
+                db
+            } else {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "database not found",
+                ));
+            }
         };
 
         if access != OpenAccess::Read {
+            // PermissionDenied makes SQLite retry opening the database as read-only
             if db.read().unwrap().wal {
                 return Err(io::Error::new(
-                    io::ErrorKind::Unsupported,
+                    io::ErrorKind::PermissionDenied,
                     "DB in WAL mode can't be opened for RW",
                 ));
             }
@@ -84,12 +196,71 @@ impl DatabaseManager {
         Ok(db)
     }
 
+    /// Drops the databases nobody uses from memory, so that a process touching many
+    /// databases doesn't accumulate them. Databases are dropped along with everything
+    /// the pager keeps about them, so only once all of their pages are evicted from
+    /// the cache. Returns the number of dropped databases.
+    fn prune_idle(databases: &[RwLock<Databases>], pager: &Pager) -> usize {
+        let mut pruned = 0;
+        for shard in databases {
+            let mut databases = shard.write().unwrap();
+            databases.retain(|name, db| {
+                // Nobody but the manager can get a new reference while the shard is locked
+                if Arc::strong_count(db) > 1 {
+                    return true;
+                }
+                let idle = match db.try_read() {
+                    Ok(db) => db.dirty_pages.is_empty() && db.pending.is_empty(),
+                    Err(_) => false,
+                };
+                if !idle {
+                    return true;
+                }
+
+                // The pages are still cached, and would refer to a forgotten name
+                if !pager.forget_db(name) {
+                    return true;
+                }
+
+                log::debug!("[manager] prune_idle: name = {}", name);
+                pruned += 1;
+
+                false
+            });
+        }
+
+        pruned
+    }
+
+    /// Removes the files left over from crashes and interrupted writes of the open
+    /// databases. Databases busy with a write are skipped, as their files may be in use.
+    /// Returns the number of removed files.
+    fn remove_orphans(databases: &[RwLock<Databases>]) -> usize {
+        let mut removed = 0;
+        for shard in databases {
+            let databases = shard.read().unwrap();
+            for db in databases.values() {
+                let Ok(db) = db.try_read() else {
+                    continue;
+                };
+                match db.remove_orphans() {
+                    Ok(n) => removed += n,
+                    Err(err) => {
+                        log::warn!("[manager] remove_orphans: name = {}: {}", db.name, err)
+                    }
+                }
+            }
+        }
+
+        removed
+    }
+
     fn get_database_local_in_mem(
-        &self,
+        databases: &Databases,
         dbname: &str,
         access: OpenAccess,
     ) -> io::Result<Option<Arc<RwLock<Database>>>> {
-        let db = self.databases.get(dbname);
+        let db = databases.get(dbname);
 
         if db.is_some() && access == OpenAccess::CreateNew {
             return Err(io::Error::new(
@@ -127,14 +298,9 @@ impl DatabaseManager {
             pos
         );
 
-        Ok(Some(Arc::new(RwLock::new(Database::new(
-            dbname,
-            Some(pos),
-            Arc::clone(&self.pager),
-            Arc::clone(&self.client),
-            Arc::clone(&self.leaser),
-            Arc::clone(&self.syncer),
-        )?))))
+        Ok(Some(Arc::new(RwLock::new(
+            self.new_database(dbname, Some(pos))?,
+        ))))
     }
 
     fn get_database_remote(
@@ -163,18 +329,32 @@ impl DatabaseManager {
             OptionLogger(&pos)
         );
 
-        Ok(Some(Arc::new(RwLock::new(Database::new(
+        Ok(Some(Arc::new(RwLock::new(self.new_database(dbname, pos)?))))
+    }
+
+    fn new_database(&self, dbname: &str, pos: Option<ltx::Pos>) -> io::Result<Database> {
+        let mut db = Database::new(
             dbname,
             pos,
             Arc::clone(&self.pager),
             Arc::clone(&self.client),
             Arc::clone(&self.leaser),
             Arc::clone(&self.syncer),
-        )?))))
+            self.read_only,
+            self.offline_writes,
+        )?;
+        db.journal_in_memory = self.journal_in_memory;
+        db.background = Arc::clone(&self.background);
+
+        Ok(db)
     }
 
     pub(crate) fn database_exists<S: AsRef<str>>(&self, dbname: S) -> io::Result<bool> {
-        if self.databases.contains_key(dbname.as_ref())
+        if self
+            .shard(dbname.as_ref())
+            .read()
+            .unwrap()
+            .contains_key(dbname.as_ref())
             || self.client.pos_map()?.contains_key(dbname.as_ref())
         {
             Ok(true)
@@ -182,6 +362,13 @@ impl DatabaseManager {
             Ok(false)
         }
     }
+
+    fn shard(&self, dbname: &str) -> &RwLock<Databases> {
+        let mut hasher = DefaultHasher::new();
+        dbname.hash(&mut hasher);
+
+        &self.databases[hasher.finish() as usize % DATABASE_SHARDS]
+    }
 }
 
 pub(crate) struct Database {
@@ -191,18 +378,69 @@ pub(crate) struct Database {
     pager: Arc<Pager>,
     leaser: Arc<Leaser>,
     syncer: Arc<Syncer>,
+    read_only: bool,
     ltx_path: PathBuf,
     pos_path: PathBuf,
+    pending_path: PathBuf,
+    // Exists while a transaction with the journal in memory has dirty pages in the cache.
+    uncommitted_path: PathBuf,
     pub(crate) journal_path: PathBuf,
-    pub(crate) page_size: Option<ltx::PageSize>,
+    // The journal is kept in `mem_journal` instead of at `journal_path`, `None`
+    // meaning that it doesn't exist.
+    pub(crate) journal_in_memory: bool,
+    pub(crate) mem_journal: Option<Vec<u8>>,
+    // Forgotten on a full sync, and derived again from page 1 on the next read.
+    pub(crate) page_size: Mutex<Option<ltx::PageSize>>,
+    reserved_bytes: AtomicU8,
     committed_db_size: Mutex<Option<ltx::PageNum>>,
+    // The header of page 1, so that header-only reads don't need the whole page,
+    // which may have to be fetched from LFSC.
+    header: Mutex<Option<Vec<u8>>>,
     current_db_size: Option<ltx::PageNum>,
     pub(crate) pos: Option<ltx::Pos>,
     dirty_pages: BTreeMap<ltx::PageNum, Option<ltx::Checksum>>,
     prefetch_pages: Mutex<BTreeSet<ltx::PageNum>>,
     pub(crate) prefetch_limit: usize,
+    prefetch_enabled: bool,
+    pub(crate) max_tx_bytes: Option<u64>,
+    prefetched_pages: Mutex<BTreeSet<ltx::PageNum>>,
+    recent_reads: Mutex<VecDeque<ltx::PageNum>>,
+    prefetch_stats: PrefetchStats,
+    cache_task: Mutex<Option<Arc<CacheTask>>>,
+    eager_refetch: bool,
+    refetch_pages: Mutex<BTreeSet<ltx::PageNum>>,
+    refetch_running: Arc<AtomicBool>,
+    // Committed transactions that haven't been uploaded to LFSC yet, in TXID order.
+    // `pos` stays at the last uploaded transaction until they are.
+    offline_writes: bool,
+    pending: Vec<PendingTx>,
+    upload_running: Arc<AtomicBool>,
+    // Committed transactions are held in `pending` while the window is open and
+    // uploaded as a single merged LTX file once it closes.
+    combine_writes: Option<CombineWrites>,
+    combine_since: Option<time::Instant>,
+    combine_bytes: u64,
     wal: bool,
+    // Never locked while holding the database lock, see [WalIndexState].
+    wal_index: Arc<Mutex<WalIndexState>>,
     auto_vacuum: bool,
+    background: Arc<Background>,
+    // The connection that has acquired the current lease, along with the lease ID,
+    // see `release_owned_lease`.
+    lease_owner: Mutex<Option<(u64, String)>>,
+    // The last position changes, oldest first, so that the pages that haven't changed
+    // since a pinned position are read from the cache.
+    pos_changes: VecDeque<PosChange>,
+    // Page 1 as of the last pinned positions, oldest first. It's read by every transaction,
+    // but almost always changes, so it can't be read from the cache.
+    pinned_page1: Mutex<VecDeque<(ltx::Pos, Vec<u8>)>>,
+}
+
+// The pages changed by moving from one position to another, `None` if they are unknown.
+struct PosChange {
+    from: ltx::Pos,
+    to: ltx::Pos,
+    pages: Option<BTreeSet<ltx::PageNum>>,
 }
 
 impl Database {
@@ -213,37 +451,75 @@ impl Database {
         client: Arc<lfsc::Client>,
         leaser: Arc<Leaser>,
         syncer: Arc<Syncer>,
+        read_only: bool,
+        offline_writes: bool,
     ) -> io::Result<Database> {
         let ltx_path = pager.db_path(name).join("ltx");
         let pos_path = pager.db_path(name).join("pos");
+        let pending_path = pager.db_path(name).join("pending");
         let journal_path = pager.db_path(name).join("journal");
+        let uncommitted_path = pager.db_path(name).join("uncommitted");
 
         pager.prepare_db(name)?;
+        // A transaction with the journal in memory has been interrupted by a crash, so there
+        // is no hot journal to roll its pages back with. Drop them along with the rest.
+        if uncommitted_path.try_exists()? {
+            log::warn!(
+                "[database] db = {}, dropping the cache after an interrupted transaction",
+                name
+            );
+            pager.clear(name)?;
+            fs::remove_file(&uncommitted_path)?;
+        }
         fs::create_dir_all(&ltx_path)?;
+        fs::create_dir_all(&pending_path)?;
+        // LTX files of transactions interrupted by a crash
+        remove_old_files(&ltx_path, pager.orphan_age())?;
+        let pending = Database::load_pending(&pending_path)?;
+        if !pending.is_empty() {
+            log::info!(
+                "[database] found transactions pending upload: db = {}, pos = {}, pending = {}",
+                name,
+                OptionLogger(&pos),
+                pending.len()
+            );
+        }
 
-        let (wal, auto_vacuum, page_size, commit) =
+        let (wal, auto_vacuum, page_size, reserved_bytes, commit, header) =
             match pager.get_page(name, pos, ltx::PageNum::ONE, None) {
                 Ok(page) => (
                     Database::parse_wal(page.as_ref()),
                     Database::parse_autovacuum(page.as_ref())?,
                     Some(Database::parse_page_size_database(page.as_ref())?),
+                    Database::parse_reserved_bytes(page.as_ref()),
                     Some(Database::parse_commit_database(
                         page.as_ref(),
                         sqlite::COMMIT_RANGE,
                     )?),
+                    Some(page.as_ref()[..sqlite::HEADER_SIZE].to_vec()),
                 ),
                 Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => {
-                    (false, false, None, None)
+                    (false, false, None, 0, None, None)
                 }
                 Err(err) => return Err(err),
             };
 
         if wal {
-            log::warn!("[database] db = {}, database in WAL mode", name);
+            log::warn!(
+                "[database] db = {}, database in WAL mode, opening read-only",
+                name
+            );
         }
         if auto_vacuum {
             log::warn!("[database] db = {}, database with auto vacuum", name);
         }
+        if reserved_bytes > 0 {
+            log::info!(
+                "[database] db = {}, database with {} reserved bytes per page",
+                name,
+                reserved_bytes
+            );
+        }
 
         Ok(Database {
             lock: VfsLock::new(),
@@ -252,18 +528,45 @@ impl Database {
             pager,
             leaser,
             syncer,
+            read_only,
             ltx_path,
             pos_path,
+            pending_path,
+            uncommitted_path,
             journal_path,
-            page_size,
+            journal_in_memory: false,
+            mem_journal: None,
+            page_size: Mutex::new(page_size),
+            reserved_bytes: AtomicU8::new(reserved_bytes),
             committed_db_size: Mutex::new(commit),
+            header: Mutex::new(header),
             current_db_size: None,
             pos,
             dirty_pages: BTreeMap::new(),
             prefetch_pages: Mutex::new(BTreeSet::new()),
             prefetch_limit: DEFAULT_MAX_PREFETCH_PAGES,
+            prefetch_enabled: true,
+            max_tx_bytes: None,
+            prefetched_pages: Mutex::new(BTreeSet::new()),
+            recent_reads: Mutex::new(VecDeque::with_capacity(SEQUENTIAL_READS)),
+            prefetch_stats: PrefetchStats::default(),
+            cache_task: Mutex::new(None),
+            eager_refetch: false,
+            refetch_pages: Mutex::new(BTreeSet::new()),
+            refetch_running: Arc::new(AtomicBool::new(false)),
+            offline_writes,
+            pending,
+            upload_running: Arc::new(AtomicBool::new(false)),
+            combine_writes: None,
+            combine_since: None,
+            combine_bytes: 0,
             wal,
+            wal_index: WalIndexState::new(),
             auto_vacuum,
+            background: Arc::default(),
+            lease_owner: Mutex::new(None),
+            pos_changes: VecDeque::new(),
+            pinned_page1: Mutex::new(VecDeque::new()),
         })
     }
 
@@ -294,7 +597,11 @@ impl Database {
             n => n as u32,
         };
 
-        ltx::PageSize::new(page_size).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        parse_page_size(page_size)
+    }
+
+    fn parse_reserved_bytes(page1: &[u8]) -> u8 {
+        page1[sqlite::RESERVED_BYTES_OFFSET]
     }
 
     fn parse_commit_database(page1: &[u8], loc: ops::Range<usize>) -> io::Result<ltx::PageNum> {
@@ -314,18 +621,39 @@ impl Database {
                 .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
         );
 
-        ltx::PageSize::new(page_size).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        parse_page_size(page_size)
     }
 
     pub(crate) fn conn_lock(&self) -> ConnLock {
         self.lock.conn_lock()
     }
 
+    pub(crate) fn is_wal(&self) -> bool {
+        self.wal
+    }
+
+    pub(crate) fn wal_index(&self) -> Arc<Mutex<WalIndexState>> {
+        Arc::clone(&self.wal_index)
+    }
+
     pub(crate) fn page_size(&self) -> io::Result<ltx::PageSize> {
         self.page_size
+            .lock()
+            .unwrap()
             .ok_or(io::Error::new(io::ErrorKind::Other, "page size unknown"))
     }
 
+    fn ensure_writable(&self) -> io::Result<()> {
+        if self.read_only {
+            return Err(io::Error::new(
+                io::ErrorKind::ReadOnlyFilesystem,
+                "LiteVFS is in read-only mode",
+            ));
+        }
+
+        Ok(())
+    }
+
     fn ensure_aligned(&self, buf: &[u8], offset: u64) -> io::Result<()> {
         let page_size = self.page_size()?.into_inner() as usize;
 
@@ -365,12 +693,212 @@ impl Database {
         Ok(self.page_size()?.into_inner() as u64 * commit.into_inner() as u64)
     }
 
+    /// Reads the database as of `pos`, which may be behind the current position. The
+    /// cache is used for the pages that haven't changed since `pos`, the others are
+    /// fetched from LFSC without caching them.
+    pub(crate) fn read_at_pos(
+        &self,
+        buf: &mut [u8],
+        offset: u64,
+        local_only: bool,
+        pos: ltx::Pos,
+        cancel: Option<&AtomicBool>,
+    ) -> io::Result<PageSource> {
+        if self.is_at_pos(pos) {
+            return self.read_at(buf, offset, local_only, cancel);
+        }
+
+        let (number, page_offset) = if offset <= sqlite::HEADER_SIZE as u64 {
+            (ltx::PageNum::ONE, offset as usize)
+        } else {
+            self.ensure_aligned(buf, offset)?;
+            (self.page_num_for(offset)?, 0)
+        };
+        let copy = |buf: &mut [u8], page: &[u8]| -> io::Result<()> {
+            let data = page
+                .get(page_offset..page_offset + buf.len())
+                .ok_or(io::ErrorKind::UnexpectedEof)?;
+            buf.copy_from_slice(data);
+            Ok(())
+        };
+
+        // The page is the same at the current position, whether it's cached or not
+        if self.is_unchanged_since(pos, number) {
+            return self.pager.get_page_slice(
+                &self.name,
+                self.pos,
+                number,
+                buf,
+                page_offset as u64,
+                local_only,
+                None,
+                cancel,
+            );
+        }
+
+        if number == ltx::PageNum::ONE {
+            let (page, source) = self.page1_at_pos(pos, local_only, cancel)?;
+            copy(buf, &page)?;
+
+            return Ok(source);
+        }
+        if local_only {
+            return Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                "local_only page not found in cache",
+            ));
+        }
+
+        let page = self
+            .pager
+            .get_page_uncached(&self.name, pos, number, cancel)?;
+        copy(buf, page.as_ref())?;
+
+        Ok(PageSource::Remote)
+    }
+
+    /// Returns the size of the database as of `pos`.
+    pub(crate) fn size_at_pos(&self, pos: ltx::Pos, local_only: bool) -> io::Result<u64> {
+        if self.is_at_pos(pos) || self.is_unchanged_since(pos, ltx::PageNum::ONE) {
+            return self.size();
+        }
+
+        let (page, _) = self.page1_at_pos(pos, local_only, None)?;
+        let commit = Database::parse_commit_database(&page, sqlite::COMMIT_RANGE)?;
+
+        Ok(page.len() as u64 * commit.into_inner() as u64)
+    }
+
+    /// Keeps page 1 as of `pos` if it's cached, so that the database can be read as of
+    /// `pos` without LFSC once the position moves on.
+    pub(crate) fn pin_pos(&self, pos: ltx::Pos) {
+        if !self.is_at_pos(pos) {
+            return;
+        }
+
+        // Without a position only the cached page is read, otherwise it's fetched
+        // from LFSC on the first read
+        match self
+            .pager
+            .get_page(&self.name, None, ltx::PageNum::ONE, None)
+        {
+            Ok(page) => self.remember_page1(pos, page.as_ref().to_vec()),
+            Err(err) => log::debug!(
+                "[database] pin_pos: db = {}, pos = {}, page 1 not kept: {}",
+                self.name,
+                pos,
+                err
+            ),
+        }
+    }
+
+    // Whether the cache holds the database exactly as of `pos`.
+    fn is_at_pos(&self, pos: ltx::Pos) -> bool {
+        self.pos == Some(pos) && self.dirty_pages.is_empty() && self.pending.is_empty()
+    }
+
+    // Whether the page `pgno` is known to be the same as of `pos` and now, and the cache
+    // doesn't hold a newer local version of it.
+    fn is_unchanged_since(&self, pos: ltx::Pos, pgno: ltx::PageNum) -> bool {
+        !self.dirty_pages.contains_key(&pgno)
+            && !self
+                .pending
+                .iter()
+                .any(|tx| tx.pages.contains(&pgno.into_inner()))
+            && self.changed_since(pos, pgno) == Some(false)
+    }
+
+    // Returns whether the page `pgno` has changed since `pos`, or `None` if it's unknown,
+    // e.g. because `pos` is older than the remembered position changes.
+    fn changed_since(&self, pos: ltx::Pos, pgno: ltx::PageNum) -> Option<bool> {
+        if self.pos == Some(pos) {
+            return Some(false);
+        }
+
+        let start = self
+            .pos_changes
+            .iter()
+            .rposition(|change| change.from == pos)?;
+        let (mut at, mut changed) = (pos, false);
+        for change in self.pos_changes.range(start..) {
+            if change.from != at {
+                return None;
+            }
+            changed |= change.pages.as_ref()?.contains(&pgno);
+            at = change.to;
+        }
+
+        (self.pos == Some(at)).then_some(changed)
+    }
+
+    // Remembers the pages changed by moving from the current position to `pos`.
+    fn record_pos_change(&mut self, pos: Option<ltx::Pos>, pages: Option<BTreeSet<ltx::PageNum>>) {
+        match (self.pos, pos) {
+            (Some(from), Some(to)) if from == to => return,
+            (Some(from), Some(to)) => self.pos_changes.push_back(PosChange { from, to, pages }),
+            // There is nothing to chain the changes with
+            _ => self.pos_changes.clear(),
+        }
+        if self.pos_changes.len() > MAX_POS_CHANGES {
+            self.pos_changes.pop_front();
+        }
+    }
+
+    // Returns page 1 as of `pos`, fetching it from LFSC only the first time.
+    fn page1_at_pos(
+        &self,
+        pos: ltx::Pos,
+        local_only: bool,
+        cancel: Option<&AtomicBool>,
+    ) -> io::Result<(Vec<u8>, PageSource)> {
+        if let Some((_, page)) = self
+            .pinned_page1
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(p, _)| *p == pos)
+        {
+            return Ok((page.clone(), PageSource::Local));
+        }
+        if local_only {
+            return Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                "local_only page not found in cache",
+            ));
+        }
+
+        let page = self
+            .pager
+            .get_page_uncached(&self.name, pos, ltx::PageNum::ONE, cancel)?
+            .as_ref()
+            .to_vec();
+        self.remember_page1(pos, page.clone());
+
+        Ok((page, PageSource::Remote))
+    }
+
+    fn remember_page1(&self, pos: ltx::Pos, page: Vec<u8>) {
+        let mut pinned = self.pinned_page1.lock().unwrap();
+        pinned.retain(|(p, _)| *p != pos);
+        pinned.push_back((pos, page));
+        if pinned.len() > MAX_PINNED_PAGE1 {
+            pinned.pop_front();
+        }
+    }
+
+    /// Reads the database at the current position. Setting `cancel` abandons the pending
+    /// retries of the LFSC request, if the page has to be fetched.
     pub(crate) fn read_at(
         &self,
         buf: &mut [u8],
         offset: u64,
         local_only: bool,
+        cancel: Option<&AtomicBool>,
     ) -> io::Result<PageSource> {
+        if offset < sqlite::HEADER_SIZE as u64 && self.page_size().is_err() {
+            self.refresh_page_size(local_only)?;
+        }
+
         let (number, page_offset) = if offset <= sqlite::HEADER_SIZE as u64 {
             (ltx::PageNum::ONE, offset)
         } else {
@@ -378,6 +906,30 @@ impl Database {
             (self.page_num_for(offset)?, 0)
         };
 
+        if number == ltx::PageNum::ONE && page_offset as usize + buf.len() <= sqlite::HEADER_SIZE {
+            if let Some(header) = self.header.lock().unwrap().as_deref() {
+                let page_offset = page_offset as usize;
+                buf.copy_from_slice(&header[page_offset..page_offset + buf.len()]);
+                self.update_committed_size(buf, offset)?;
+
+                return Ok(PageSource::Local);
+            }
+        }
+
+        if self.prefetched_pages.lock().unwrap().remove(&number) {
+            self.prefetch_stats.used.fetch_add(1, Ordering::Relaxed);
+        }
+
+        self.restore_pending_page(number)?;
+
+        // Header reads don't say anything about the access pattern
+        let can_prefetch = self.prefetch_enabled && self.can_prefetch(buf);
+        let sequential = can_prefetch && self.is_sequential(number);
+        let prefetch = if self.prefetch_enabled {
+            self.prefetch_pages(number, sequential && !local_only)
+        } else {
+            None
+        };
         let source = self.pager.get_page_slice(
             &self.name,
             self.pos,
@@ -385,40 +937,66 @@ impl Database {
             buf,
             page_offset,
             local_only,
-            self.prefetch_pages(number),
+            prefetch.clone(),
+            cancel,
         )?;
 
-        if self.can_prefetch(buf) {
+        if let (PageSource::Remote, Some(pgnos)) = (&source, prefetch) {
+            self.prefetch_stats
+                .prefetched
+                .fetch_add(pgnos.len() as u64, Ordering::Relaxed);
+            self.remember_prefetched(pgnos);
+        }
+
+        if can_prefetch {
             let mut prefetch = self.prefetch_pages.lock().unwrap();
-            if let Some(candidates) = sqlite::prefetch_candidates(buf, number).map(|t| {
+            if let Some(candidates) = sqlite::prefetch_candidates(
+                buf,
+                number,
+                self.reserved_bytes.load(Ordering::Relaxed),
+            )
+            .map(|t| {
                 t.into_iter()
                     .filter(|&pgno| !self.pager.has_page(&self.name, pgno).unwrap_or(false))
+                    .take(self.prefetch_limit)
                     .collect()
             }) {
                 *prefetch = candidates;
             }
         }
 
-        let offset = offset as usize;
-        if offset <= sqlite::WRITE_VERSION_OFFSET
-            && offset + buf.len() >= sqlite::READ_VERSION_OFFSET
-            && self.wal
-        {
-            buf[sqlite::WRITE_VERSION_OFFSET - offset] = u8::to_be(1);
-            buf[sqlite::READ_VERSION_OFFSET - offset] = u8::to_be(1);
-        };
+        if number == ltx::PageNum::ONE && page_offset == 0 && buf.len() >= sqlite::HEADER_SIZE {
+            *self.header.lock().unwrap() = Some(buf[..sqlite::HEADER_SIZE].to_vec());
+        }
+        self.update_committed_size(buf, offset)?;
+
+        Ok(source)
+    }
+
+    // Keeps track of the prefetched pages, so that their first reads are counted as used
+    // prefetches. Forgetting some of them only makes the stats undercount those.
+    fn remember_prefetched(&self, pgnos: Vec<ltx::PageNum>) {
+        let mut prefetched = self.prefetched_pages.lock().unwrap();
+        prefetched.extend(pgnos);
+        while prefetched.len() > MAX_PREFETCHED_PAGES {
+            prefetched.pop_first();
+        }
+    }
 
-        if offset <= sqlite::COMMIT_RANGE.start
-            && offset + buf.len() >= sqlite::COMMIT_RANGE.end
+    fn update_committed_size(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+        // Offsets of large databases don't fit into usize on 32-bit targets
+        if offset <= sqlite::COMMIT_RANGE.start as u64
+            && offset + buf.len() as u64 >= sqlite::COMMIT_RANGE.end as u64
             && !self.dirty_pages.contains_key(&ltx::PageNum::ONE)
         {
+            let offset = offset as usize;
             *self.committed_db_size.lock().unwrap() = Some(Database::parse_commit_database(
                 buf,
                 sqlite::COMMIT_RANGE.start - offset..sqlite::COMMIT_RANGE.end - offset,
             )?);
         }
 
-        Ok(source)
+        Ok(())
     }
 
     fn can_prefetch(&self, buf: &[u8]) -> bool {
@@ -432,10 +1010,16 @@ impl Database {
     }
 
     pub(crate) fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<()> {
+        self.ensure_writable()?;
+
         if offset == 0 && buf.len() >= sqlite::HEADER_SIZE {
-            if self.page_size().is_err() {
-                self.page_size = Some(Database::parse_page_size_database(buf)?);
+            let mut page_size = self.page_size.lock().unwrap();
+            if page_size.is_none() {
+                *page_size = Some(Database::parse_page_size_database(buf)?);
             }
+            drop(page_size);
+            self.reserved_bytes
+                .store(Database::parse_reserved_bytes(buf), Ordering::Relaxed);
             self.current_db_size =
                 Some(Database::parse_commit_database(buf, sqlite::COMMIT_RANGE)?);
         }
@@ -451,17 +1035,38 @@ impl Database {
         self.ensure_aligned(buf, offset)?;
         let page_num = self.page_num_for(offset)?;
 
-        let orig_checksum = match *self.committed_db_size.lock().unwrap() {
+        self.write_page(page_num, buf)
+    }
+
+    fn write_page(&mut self, page_num: ltx::PageNum, buf: &[u8]) -> io::Result<()> {
+        self.restore_pending_page(page_num)?;
+
+        let orig = match *self.committed_db_size.lock().unwrap() {
             Some(dbsize) if page_num > dbsize => None,
             _ => match self.pager.get_page(&self.name, self.pos, page_num, None) {
-                Ok(page) => Some(page.checksum()),
+                Ok(page) => Some(page),
                 Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => None,
                 Err(err) => return Err(err),
             },
         };
 
+        // SQLite often rewrites hot pages without changing them. The cached page is
+        // either the committed one or an already dirty one, nothing to do in both cases.
+        if orig.as_ref().map_or(false, |orig| orig.as_ref() == buf) {
+            return Ok(());
+        }
+        let orig_checksum = orig.map(|orig| orig.checksum());
+
+        // Nothing on disk would tell that the cached pages are uncommitted after a crash
+        if self.journal_in_memory && self.dirty_pages.is_empty() {
+            fs::write(&self.uncommitted_path, [])?;
+        }
+
         let page = PageRef::new(page_num, buf);
-        self.pager.put_page(&self.name, page)?;
+        self.pager.put_uncommitted_page(&self.name, page)?;
+        if page_num == ltx::PageNum::ONE && buf.len() >= sqlite::HEADER_SIZE {
+            *self.header.lock().unwrap() = Some(buf[..sqlite::HEADER_SIZE].to_vec());
+        }
 
         if page_num == ltx::PageNum::lock_page(self.page_size()?) {
             return Ok(());
@@ -484,27 +1089,93 @@ impl Database {
             ));
         }
 
-        self.pager
-            .truncate(&self.name, ltx::PageNum::new((size / page_size) as u32)?)
+        let pgno = ltx::PageNum::new((size / page_size) as u32)?;
+
+        // Remember the original checksums of the pages being removed, they need
+        // to be excluded from the database checksum on commit.
+        let committed_db_size = *self.committed_db_size.lock().unwrap();
+        if let Some(dbsize) = committed_db_size {
+            let lock_page = ltx::PageNum::lock_page(self.page_size()?);
+            let mut remote = Vec::new();
+            for n in pgno.into_inner() + 1..=dbsize.into_inner() {
+                let page_num = ltx::PageNum::new(n)?;
+                if page_num == lock_page || self.dirty_pages.contains_key(&page_num) {
+                    continue;
+                }
+                self.restore_pending_page(page_num)?;
+
+                if self.pager.has_page(&self.name, page_num)? {
+                    let page = self.pager.get_page(&self.name, self.pos, page_num, None)?;
+                    self.dirty_pages.insert(page_num, Some(page.checksum()));
+                } else {
+                    remote.push(page_num);
+                }
+            }
+
+            // The others are fetched at once, and not cached as they are about to be removed
+            let mut checksums = HashMap::new();
+            if let Some(pos) = self.pos.filter(|_| !remote.is_empty()) {
+                for page in self
+                    .pager
+                    .get_pages_uncached(&self.name, pos, &remote, None)?
+                {
+                    checksums.insert(page.number(), page.checksum());
+                }
+            }
+            for page_num in remote {
+                self.dirty_pages
+                    .insert(page_num, checksums.remove(&page_num));
+            }
+        }
+
+        self.pager.truncate(&self.name, pgno)
     }
 
     fn is_journal_header_valid(&self) -> io::Result<bool> {
         const VALID_JOURNAL_HDR: [u8; 8] = [0xd9, 0xd5, 0x05, 0xf9, 0x20, 0xa1, 0x63, 0xd7];
         let mut hdr: [u8; 8] = [0; 8];
 
-        fs::File::open(&self.journal_path)?.read_exact(&mut hdr)?;
+        if self.journal_in_memory {
+            let journal = self
+                .mem_journal
+                .as_deref()
+                .ok_or(io::Error::from(io::ErrorKind::NotFound))?;
+            hdr.copy_from_slice(
+                journal
+                    .get(..8)
+                    .ok_or(io::Error::from(io::ErrorKind::UnexpectedEof))?,
+            );
+        } else {
+            fs::File::open(&self.journal_path)?.read_exact(&mut hdr)?;
+        }
 
         Ok(hdr == VALID_JOURNAL_HDR)
     }
 
+    // Learns the page size from the journal header, and commits the transaction when SQLite
+    // zeroes the header. Must be called before the write, as the commit checks the old header.
+    pub(crate) fn before_journal_write(&mut self, buf: &[u8], offset: u64) -> io::Result<()> {
+        const JOURNAL_HDR_SIZE: usize = 28;
+
+        if offset == 0 && buf.len() >= JOURNAL_HDR_SIZE && self.page_size().is_err() {
+            *self.page_size.lock().unwrap() = Some(Database::parse_page_size_journal(buf)?);
+        };
+        if offset == 0 && buf.len() == JOURNAL_HDR_SIZE && buf.iter().all(|&b| b == 0) {
+            self.commit_journal()?;
+        };
+
+        Ok(())
+    }
+
     pub(crate) fn commit_journal(&mut self) -> io::Result<()> {
+        self.ensure_writable()?;
+
         if !self.is_journal_header_valid()? {
             log::info!("[database] rollback: db = {}", self.name);
-            self.dirty_pages.clear();
-            return Ok(());
+            return self.clear_dirty_pages();
         };
 
-        let txid = if let Some(pos) = self.pos {
+        let txid = if let Some(pos) = self.head_pos() {
             pos.txid + 1
         } else {
             ltx::TXID::ONE
@@ -535,8 +1206,11 @@ impl Database {
                 for &page_num in self.dirty_pages.keys() {
                     self.pager.del_page(&self.name, page_num)?;
                 }
+                if self.dirty_pages.contains_key(&ltx::PageNum::ONE) {
+                    self.header.lock().unwrap().take();
+                }
                 self.current_db_size.take();
-                self.dirty_pages.clear();
+                self.clear_dirty_pages()?;
 
                 return Err(err);
             }
@@ -544,41 +1218,59 @@ impl Database {
 
         *self.committed_db_size.lock().unwrap() = self.current_db_size;
         self.current_db_size.take();
-        self.dirty_pages.clear();
+        let committed = self.dirty_pages.keys().copied().collect();
+        self.clear_dirty_pages()?;
 
-        self.pos = Some(pos);
-        self.syncer.set_pos(&self.name, self.pos);
+        if let Some(pos) = pos {
+            self.record_pos_change(Some(pos), Some(committed));
+            self.pos = Some(pos);
+            self.syncer.set_pos(&self.name, self.pos);
+        }
 
         Ok(())
     }
 
-    fn commit_journal_inner(&mut self, txid: ltx::TXID) -> io::Result<ltx::Pos> {
-        if self.current_db_size < *self.committed_db_size.lock().unwrap() {
-            log::warn!(
-                "[database] commit_journal: db = {}: VACUUM is not supported by LiteVFS",
-                self.name
-            );
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                "vacuum is not supported by LiteVFS",
-            ));
+    // Forgets the dirty pages once the transaction is either committed or rolled back,
+    // making them evictable again.
+    fn clear_dirty_pages(&mut self) -> io::Result<()> {
+        let dirty_pages = std::mem::take(&mut self.dirty_pages);
+        self.pager
+            .commit_pages(&self.name, dirty_pages.into_keys())?;
+        match fs::remove_file(&self.uncommitted_path) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+            _ => Ok(()),
         }
+    }
 
+    // Returns the new position, or `None` if the transaction has been queued for upload.
+    fn commit_journal_inner(&mut self, txid: ltx::TXID) -> io::Result<Option<ltx::Pos>> {
         let commit = self.current_db_size.ok_or(io::Error::new(
             io::ErrorKind::Other,
             "database size unknown",
         ))?;
+
+        // Fail before spending the time and bandwidth on a transaction LFSC may reject
+        if let Some(max_tx_bytes) = self.max_tx_bytes {
+            let size = self.estimated_tx_size(commit)?;
+            if size > max_tx_bytes {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "transaction of {} pages ({}) exceeds litevfs_max_tx_bytes ({})",
+                        self.dirty_pages.range(..=commit).count(),
+                        ByteSize::b(size).to_string_as(true),
+                        ByteSize::b(max_tx_bytes).to_string_as(true),
+                    ),
+                ));
+            }
+        }
+
         let lease = self.leaser.get_lease(&self.name)?;
 
-        let ltx_path = self.ltx_path.join(format!("{0}-{0}.ltx", txid));
-        let mut file = fs::OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .open(&ltx_path)?;
+        let size_hint = self.dirty_pages.len() as u64 * self.page_size()?.into_inner() as u64;
+        let mut buf = LtxBuffer::new(self.ltx_path.join(format!("{0}-{0}.ltx", txid)), size_hint)?;
         let mut enc = ltx::Encoder::new(
-            &file,
+            &mut buf,
             &ltx::Header {
                 flags: ltx::HeaderFlags::empty(),
                 page_size: self.page_size()?,
@@ -586,21 +1278,24 @@ impl Database {
                 min_txid: txid,
                 max_txid: txid,
                 timestamp: time::SystemTime::now(),
-                pre_apply_checksum: self.pos.map(|p| p.post_apply_checksum),
+                pre_apply_checksum: self.head_pos().map(|p| p.post_apply_checksum),
             },
         )?;
 
         let mut checksum = self
-            .pos
+            .head_pos()
             .map(|p| p.post_apply_checksum.into_inner())
             .unwrap_or(0);
         let mut pages = Vec::with_capacity(self.dirty_pages.len());
-        for (&page_num, &prev_checksum) in self.dirty_pages.iter().filter(|&(&n, _)| n <= commit) {
+        for (&page_num, &prev_checksum) in self.dirty_pages.iter() {
+            // Pages past the commit have been removed, e.g. by VACUUM
+            if page_num > commit {
+                checksum = update_checksum(checksum, prev_checksum, None);
+                continue;
+            }
+
             let page = self.pager.get_page(&self.name, self.pos, page_num, None)?;
-            if let Some(prev_checksum) = prev_checksum {
-                checksum ^= prev_checksum.into_inner();
-            };
-            checksum ^= page.checksum().into_inner();
+            checksum = update_checksum(checksum, prev_checksum, Some(page.checksum()));
             enc.encode_page(page_num, page.as_ref())?;
             pages.push(page_num);
         }
@@ -608,27 +1303,652 @@ impl Database {
         let checksum = ltx::Checksum::new(checksum);
         enc.finish(checksum)?;
 
-        // rewind the file and send it to LFSC
-        file.seek(SeekFrom::Start(0))?;
-        self.client
-            .write_tx(&self.name, &file, file.metadata()?.len(), &lease)?;
-        fs::remove_file(&ltx_path)?;
-
         let pos = ltx::Pos {
             txid,
             post_apply_checksum: checksum,
         };
 
+        // Transactions are uploaded in order, so queue the new one behind the pending ones.
+        // Combined writes are queued as well and uploaded together later.
+        if !self.pending.is_empty() || self.combine_writes.is_some() {
+            self.queue_tx(&mut buf, pos, &pages)?;
+            buf.remove()?;
+            return Ok(None);
+        }
+
+        // rewind the buffer and send it to LFSC
+        buf.seek(SeekFrom::Start(0))?;
+        let len = buf.len()?;
+        match self.client.write_tx(&self.name, &mut buf, len, &lease) {
+            Ok(()) => (),
+            Err(lfsc::Error::Transport(_) | lfsc::Error::RateLimited(_)) if self.offline_writes => {
+                self.queue_tx(&mut buf, pos, &pages)?;
+                buf.remove()?;
+                return Ok(None);
+            }
+            Err(err) => return Err(err.into()),
+        };
+        buf.remove()?;
+
         self.commit_pos(pos)?;
 
-        Ok(pos)
+        Ok(Some(pos))
     }
 
-    fn commit_pos(&mut self, pos: ltx::Pos) -> io::Result<()> {
-        let file = fs::OpenOptions::new()
-            .write(true)
-            .create(true)
-            .truncate(true)
+    // Upper bound of the encoded size of the current transaction, the pages may
+    // end up compressed.
+    fn estimated_tx_size(&self, commit: ltx::PageNum) -> io::Result<u64> {
+        let pages = self.dirty_pages.range(..=commit).count() as u64;
+        let page_size = self.page_size()?.into_inner() as u64;
+
+        Ok(LTX_HEADER_SIZE + pages * (LTX_PAGE_HEADER_SIZE + page_size) + LTX_TRAILER_SIZE)
+    }
+
+    // The position of the last locally committed transaction, uploaded or not.
+    fn head_pos(&self) -> Option<ltx::Pos> {
+        self.pending.last().map(|tx| tx.pos).or(self.pos)
+    }
+
+    /// Returns the write-combining settings, `None` if every transaction is uploaded
+    /// as soon as it's committed.
+    pub(crate) fn combine_writes(&self) -> Option<CombineWrites> {
+        self.combine_writes
+    }
+
+    /// Enables or disables write combining. Transactions that are already held are
+    /// uploaded once the new window closes, or right away if combining is disabled.
+    pub(crate) fn set_combine_writes(&mut self, combine: Option<CombineWrites>) {
+        self.combine_writes = combine;
+        if combine.is_none() {
+            self.close_combine_window();
+        }
+    }
+
+    /// Makes the background upload send the held transactions without waiting for
+    /// the write-combining window to close.
+    pub(crate) fn close_combine_window(&mut self) {
+        self.combine_since = None;
+        self.combine_bytes = 0;
+    }
+
+    // Returns how long the held transactions should wait before they are uploaded,
+    // or `None` if they should be uploaded now.
+    fn combine_wait(&self) -> Option<time::Duration> {
+        let combine = self.combine_writes?;
+        let since = self.combine_since?;
+        if self.combine_bytes >= combine.max_bytes {
+            return None;
+        }
+
+        combine
+            .window
+            .checked_sub(since.elapsed())
+            .filter(|wait| !wait.is_zero())
+    }
+
+    // Returns the oldest pending transactions, and their LTX files, that fit into
+    // `litevfs_combine_writes`' size window together. Always includes the first one.
+    fn pending_to_merge(&self) -> io::Result<Vec<(ltx::Pos, PathBuf)>> {
+        let max_bytes = self
+            .combine_writes
+            .map_or(u64::MAX, |combine| combine.max_bytes);
+
+        let mut txs = Vec::new();
+        let mut size = 0;
+        for tx in &self.pending {
+            let path = self.pending_tx_path(tx.pos.txid, "ltx");
+            size += fs::metadata(&path)?.len();
+            if !txs.is_empty() && size > max_bytes {
+                break;
+            }
+            txs.push((tx.pos, path));
+        }
+
+        Ok(txs)
+    }
+
+    // Merges the given pending transactions into a single LTX file with the latest
+    // version of every page. Returns the position of the last merged transaction
+    // and the path of the file. Doesn't need the database lock: the transactions
+    // are streamed from their files, holding a single page of each in memory.
+    fn merge_pending(
+        name: &str,
+        pending_path: &Path,
+        txs: &[(ltx::Pos, PathBuf)],
+    ) -> io::Result<(ltx::Pos, PathBuf)> {
+        let (first, last) = match (txs.first(), txs.last()) {
+            (Some(first), Some(last)) => (first.0, last.0),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "no transactions pending upload",
+                ))
+            }
+        };
+
+        // The current page of every transaction, oldest first
+        let mut sources = Vec::with_capacity(txs.len());
+        let mut merged: Option<ltx::Header> = None;
+        for (_, path) in txs {
+            let file = fs::File::open(path)?;
+            let (mut dec, header) = ltx::Decoder::new(io::BufReader::new(file))?;
+            let mut data = vec![0; header.page_size.into_inner() as usize];
+            let number = dec.decode_page(&mut data)?;
+            sources.push((dec, data, number));
+
+            merged = Some(match merged {
+                Some(merged) => ltx::Header {
+                    commit: header.commit,
+                    max_txid: header.max_txid,
+                    timestamp: header.timestamp,
+                    ..merged
+                },
+                None => header,
+            });
+        }
+        let header = merged.expect("at least one pending transaction");
+
+        let path = pending_path.join(format!("{}-{}.ltx", first.txid, last.txid));
+        let mut file = io::BufWriter::new(fs::File::create(&path)?);
+        let mut enc = ltx::Encoder::new(&mut file, &header)?;
+        // LTX pages are sorted, so the files are merged page by page, with the
+        // newest transaction winning
+        let mut count = 0;
+        while let Some(number) = sources.iter().filter_map(|source| source.2).min() {
+            // Pages past the last commit have been truncated by a later transaction
+            if number <= header.commit {
+                let newest = sources
+                    .iter()
+                    .rposition(|source| source.2 == Some(number))
+                    .expect("page is present");
+                enc.encode_page(number, &sources[newest].1)?;
+                count += 1;
+            }
+            for (dec, data, current) in &mut sources {
+                if *current == Some(number) {
+                    *current = dec.decode_page(data)?;
+                }
+            }
+        }
+        enc.finish(last.post_apply_checksum)?;
+        file.into_inner()?.sync_all()?;
+
+        log::debug!(
+            "[database] merge_pending: db = {}, pos = {}, transactions = {}, pages = {}",
+            name,
+            last,
+            txs.len(),
+            count
+        );
+
+        Ok((last, path))
+    }
+
+    fn load_pending(pending_path: &Path) -> io::Result<Vec<PendingTx>> {
+        let mut pending = Vec::new();
+        for entry in fs::read_dir(pending_path)? {
+            let path = entry?.path();
+            // Merged transactions are rebuilt from the queued ones before every upload
+            if path.extension().map_or(false, |ext| ext == "ltx")
+                && path
+                    .file_stem()
+                    .map_or(false, |stem| stem.to_string_lossy().contains('-'))
+            {
+                fs::remove_file(&path)?;
+                continue;
+            }
+            if path.extension().map_or(true, |ext| ext != "json") {
+                continue;
+            }
+
+            // The metadata is written after the LTX file, so a crash in between
+            // leaves an LTX file without it, which is safe to ignore.
+            let tx: PendingTx = serde_json::from_slice(&fs::read(&path)?)?;
+            if !path.with_extension("ltx").try_exists()? {
+                log::warn!(
+                    "[database] load_pending: path = {}: LTX file is missing",
+                    path.display()
+                );
+                fs::remove_file(&path)?;
+                continue;
+            }
+            pending.push(tx);
+        }
+        pending.sort_by_key(|tx| tx.pos.txid);
+
+        Ok(pending)
+    }
+
+    /// Removes the temporary page and LTX files older than the pager's orphan age.
+    /// Returns the number of removed files.
+    pub(crate) fn remove_orphans(&self) -> io::Result<usize> {
+        Ok(self.pager.remove_orphans(&self.name)?
+            + remove_old_files(&self.ltx_path, self.pager.orphan_age())?)
+    }
+
+    fn pending_tx_path(&self, txid: ltx::TXID, ext: &str) -> PathBuf {
+        self.pending_path.join(format!("{}.{}", txid, ext))
+    }
+
+    fn queue_tx(
+        &mut self,
+        buf: &mut LtxBuffer,
+        pos: ltx::Pos,
+        pages: &[ltx::PageNum],
+    ) -> io::Result<()> {
+        let mut file = fs::File::create(self.pending_tx_path(pos.txid, "ltx"))?;
+        buf.seek(SeekFrom::Start(0))?;
+        io::copy(buf, &mut file)?;
+        file.sync_all()?;
+
+        let tx = PendingTx {
+            pos,
+            pages: pages.iter().map(|pgno| pgno.into_inner()).collect(),
+        };
+        let file = fs::File::create(self.pending_tx_path(pos.txid, "json"))?;
+        serde_json::to_writer(&file, &tx)?;
+        file.sync_all()?;
+
+        let level = if self.combine_writes.is_some() {
+            log::Level::Debug
+        } else {
+            log::Level::Warn
+        };
+        log::log!(
+            level,
+            "[database] queued transaction for upload: db = {}, pos = {}, pending = {}",
+            self.name,
+            pos,
+            self.pending.len() + 1
+        );
+        self.pending.push(tx);
+        if self.combine_writes.is_some() {
+            self.combine_since.get_or_insert_with(time::Instant::now);
+            self.combine_bytes += buf.len()?;
+        }
+
+        Ok(())
+    }
+
+    // Pages of the pending transactions can't be fetched from LFSC, so they are
+    // restored from the queued LTX files if they have been evicted from the cache.
+    fn restore_pending_page(&self, pgno: ltx::PageNum) -> io::Result<()> {
+        let tx = match self
+            .pending
+            .iter()
+            .rev()
+            .find(|tx| tx.pages.contains(&pgno.into_inner()))
+        {
+            Some(tx) => tx,
+            None => return Ok(()),
+        };
+        if self.pager.has_page(&self.name, pgno)? {
+            return Ok(());
+        }
+
+        let file = fs::File::open(self.pending_tx_path(tx.pos.txid, "ltx"))?;
+        let (mut dec, header) = ltx::Decoder::new(io::BufReader::new(file))?;
+        let mut data = vec![0; header.page_size.into_inner() as usize];
+        while let Some(number) = dec.decode_page(&mut data)? {
+            if number == pgno {
+                log::debug!(
+                    "[database] restore_pending_page: db = {}, pgno = {}, txid = {}",
+                    self.name,
+                    pgno,
+                    tx.pos.txid
+                );
+                return self.pager.put_page(&self.name, PageRef::new(pgno, &data));
+            }
+        }
+
+        Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "page not found in the pending transaction",
+        ))
+    }
+
+    // Handles the result of uploading the pending transactions from `first` to `pos`,
+    // merged or not. Returns `false` if the upload should be retried later. Must be
+    // given the result of `resolve_upload`, which can't run under the database lock.
+    fn complete_upload(
+        &mut self,
+        first: ltx::Pos,
+        pos: ltx::Pos,
+        ret: lfsc::Result<()>,
+    ) -> io::Result<bool> {
+        // The queue has been discarded while the upload was in flight
+        if self.pending.first().map(|tx| tx.pos) != Some(first)
+            || !self.pending.iter().any(|tx| tx.pos == pos)
+        {
+            return Ok(true);
+        }
+
+        match ret {
+            Ok(()) => (),
+            Err(
+                lfsc::Error::Transport(_)
+                | lfsc::Error::RateLimited(_)
+                | lfsc::Error::Body(_)
+                | lfsc::Error::Cancelled,
+            ) => return Ok(false),
+            Err(lfsc::Error::Lfsc(ref err)) if err.http_code >= 500 => return Ok(false),
+            // Only LFSC being at a different position means the queue can never be applied
+            Err(err @ lfsc::Error::PosMismatch(_)) => {
+                self.discard_pending()?;
+                return Err(err.into());
+            }
+            // The queue is kept on disk and uploaded by the next commit or open
+            Err(err) => return Err(err.into()),
+        };
+
+        let uploaded = self
+            .pending
+            .iter()
+            .position(|tx| tx.pos == pos)
+            .map_or(0, |idx| idx + 1);
+        let mut changed = BTreeSet::new();
+        for tx in self.pending.drain(..uploaded) {
+            changed.extend(
+                tx.pages
+                    .iter()
+                    .filter_map(|&pgno| ltx::PageNum::new(pgno).ok()),
+            );
+            _ = fs::remove_file(self.pending_tx_path(tx.pos.txid, "json"));
+            _ = fs::remove_file(self.pending_tx_path(tx.pos.txid, "ltx"));
+        }
+
+        // Transactions committed during the upload start a new window
+        self.close_combine_window();
+        if self.combine_writes.is_some() && !self.pending.is_empty() {
+            self.combine_since = Some(time::Instant::now());
+            self.combine_bytes = self
+                .pending
+                .iter()
+                .map(|tx| fs::metadata(self.pending_tx_path(tx.pos.txid, "ltx")).map(|m| m.len()))
+                .sum::<io::Result<u64>>()?;
+        }
+
+        self.commit_pos(pos)?;
+        self.record_pos_change(Some(pos), Some(changed));
+        self.pos = Some(pos);
+        self.syncer.set_pos(&self.name, self.pos);
+        log::info!(
+            "[database] uploaded pending transaction: db = {}, pos = {}, transactions = {}, pending = {}",
+            self.name,
+            pos,
+            uploaded,
+            self.pending.len()
+        );
+
+        Ok(true)
+    }
+
+    // Drops the transactions that can't be uploaded anymore, so that their pages
+    // are refetched from LFSC.
+    fn discard_pending(&mut self) -> io::Result<()> {
+        log::error!(
+            "[database] discarding transactions pending upload: db = {}, pos = {}, pending = {}",
+            self.name,
+            OptionLogger(&self.pos),
+            self.pending.len()
+        );
+
+        for tx in std::mem::take(&mut self.pending) {
+            for pgno in tx.pages {
+                self.pager.del_page(&self.name, ltx::PageNum::new(pgno)?)?;
+            }
+            _ = fs::remove_file(self.pending_tx_path(tx.pos.txid, "json"));
+            _ = fs::remove_file(self.pending_tx_path(tx.pos.txid, "ltx"));
+        }
+        self.committed_db_size.lock().unwrap().take();
+        self.header.lock().unwrap().take();
+        self.close_combine_window();
+
+        Ok(())
+    }
+
+    /// Starts uploading the pending transactions in a background thread, retrying
+    /// with a backoff while LFSC is unreachable. Does nothing if there are no such
+    /// transactions or the upload is already running.
+    #[cfg(not(target_os = "emscripten"))]
+    pub(crate) fn upload_in_background(database: &Arc<RwLock<Database>>) {
+        let (running, name, leaser, syncer, pos, background) = {
+            let db = database.read().unwrap();
+            if db.pending.is_empty()
+                || db.background.is_stopped()
+                || db.upload_running.swap(true, Ordering::AcqRel)
+            {
+                return;
+            }
+
+            (
+                Arc::clone(&db.upload_running),
+                db.name.clone(),
+                Arc::clone(&db.leaser),
+                Arc::clone(&db.syncer),
+                db.pos,
+                Arc::clone(&db.background),
+            )
+        };
+        // The upload updates the syncer with the new positions, so keep the database
+        // registered even if all the connections are closed before the queue drains.
+        syncer.open_conn(&name, pos);
+
+        background.spawn({
+            let database = Arc::clone(database);
+            let background = Arc::clone(&background);
+
+            move || {
+                let acquired = Database::upload_pending(&database, &running, &background);
+                syncer.close_conn(&name);
+
+                // The lease has been acquired just for the upload, don't keep it
+                // unless somebody else has reacquired it since then.
+                if let Some(id) = acquired {
+                    if matches!(leaser.lease_info(&name), Ok(Some(lease)) if lease.id == id) {
+                        if let Err(err) = leaser.release_lease(&name) {
+                            log::warn!("[database] upload_in_background: db = {}: {}", name, err);
+                            metrics().record_error("release_lease", Some(&name), &err);
+                        }
+                    }
+                }
+            }
+        });
+    }
+
+    // Uploads the pending transactions until the queue is empty, the upload fails
+    // for good or the VFS is shut down. Returns the ID of the lease acquired for
+    // the upload, if any.
+    #[cfg(not(target_os = "emscripten"))]
+    fn upload_pending(
+        database: &Arc<RwLock<Database>>,
+        running: &AtomicBool,
+        background: &Background,
+    ) -> Option<String> {
+        let mut acquired = None;
+        let mut delay = UPLOAD_RETRY_MIN_DELAY;
+        background.sleep(delay);
+
+        loop {
+            // The upload itself happens without the database lock, so that
+            // the database stays readable while LFSC is unreachable.
+            let (first, pos, ret) = {
+                let db = database.read().unwrap();
+                let first = match db.pending.first() {
+                    // The queue stays on disk and is uploaded once the database is reopened
+                    Some(_) if background.is_stopped() => {
+                        running.store(false, Ordering::Release);
+                        return acquired;
+                    }
+                    Some(tx) => tx.pos,
+                    None => {
+                        // Cleared with the database lock held, so the transactions
+                        // queued concurrently are picked up by either this or a new worker.
+                        running.store(false, Ordering::Release);
+                        return acquired;
+                    }
+                };
+                if let Some(wait) = db.combine_wait() {
+                    drop(db);
+                    background.sleep(wait.min(COMBINE_POLL_PERIOD));
+                    continue;
+                }
+
+                let txs = if db.combine_writes.is_some() && db.pending.len() > 1 {
+                    match db.pending_to_merge() {
+                        Ok(txs) => txs,
+                        Err(err) => {
+                            log::warn!(
+                                "[database] upload_in_background: db = {}, pos = {}: can't list transactions: {}",
+                                db.name,
+                                first,
+                                err
+                            );
+                            vec![]
+                        }
+                    }
+                } else {
+                    vec![]
+                };
+                let pending_path = db.pending_path.clone();
+                let single = (first, db.pending_tx_path(first.txid, "ltx"), false);
+                let (name, base, client, leaser) = (
+                    db.name.clone(),
+                    db.pos,
+                    Arc::clone(&db.client),
+                    Arc::clone(&db.leaser),
+                );
+                drop(db);
+
+                let (pos, path, merged) = if txs.len() > 1 {
+                    match Database::merge_pending(&name, &pending_path, &txs) {
+                        Ok((pos, path)) => (pos, path, true),
+                        Err(err) => {
+                            log::warn!(
+                                "[database] upload_in_background: db = {}, pos = {}: can't merge transactions: {}",
+                                name,
+                                first,
+                                err
+                            );
+                            single
+                        }
+                    }
+                } else {
+                    single
+                };
+
+                // The lease might have been lost while LFSC was unreachable. Retrying
+                // wouldn't help if somebody else holds it or the leaser is shut down, so
+                // stop here and leave the queue for the next commit or open to upload.
+                if leaser.get_lease(&name).is_err() {
+                    if let Err(err) = leaser.acquire_lease(&name) {
+                        if merged {
+                            _ = fs::remove_file(&path);
+                        }
+                        let db = database.read().unwrap();
+                        running.store(false, Ordering::Release);
+                        log::error!(
+                            "[database] upload_in_background: db = {}, pos = {}: can't acquire lease: {}",
+                            db.name,
+                            pos,
+                            err
+                        );
+                        metrics().record_error("upload", Some(&db.name), &err);
+
+                        return acquired;
+                    }
+                    acquired = leaser
+                        .lease_info(&name)
+                        .ok()
+                        .flatten()
+                        .map(|lease| lease.id);
+                }
+
+                let ret = Database::upload_tx(&client, &leaser, &name, &path);
+                if merged {
+                    _ = fs::remove_file(&path);
+                }
+                let ret = Database::resolve_upload(&client, &name, base, pos, ret);
+
+                (first, pos, ret)
+            };
+
+            let mut db = database.write().unwrap();
+            match db.complete_upload(first, pos, ret) {
+                Ok(true) => delay = UPLOAD_RETRY_MIN_DELAY,
+                Ok(false) => {
+                    drop(db);
+                    background.sleep(delay);
+                    delay = (delay * 2).min(UPLOAD_RETRY_MAX_DELAY);
+                }
+                Err(err) => {
+                    running.store(false, Ordering::Release);
+                    log::error!(
+                        "[database] upload_in_background: db = {}, pos = {}: {}",
+                        db.name,
+                        pos,
+                        err
+                    );
+                    metrics().record_error("upload", Some(&db.name), &err);
+
+                    return acquired;
+                }
+            }
+        }
+    }
+
+    // Checks if a rejected upload has actually been applied before the connection
+    // broke, in which case LFSC is already at `pos` and the upload is done.
+    #[cfg(not(target_os = "emscripten"))]
+    fn resolve_upload(
+        client: &lfsc::Client,
+        db: &str,
+        base: Option<ltx::Pos>,
+        pos: ltx::Pos,
+        ret: lfsc::Result<()>,
+    ) -> lfsc::Result<()> {
+        let err = match ret {
+            Err(err @ lfsc::Error::PosMismatch(_)) => err,
+            ret => return ret,
+        };
+
+        match client.sync_db(db, base) {
+            Ok(changes) if changes.pos() == Some(pos) => {
+                log::info!(
+                    "[database] resolve_upload: db = {}, pos = {}, already applied: {}",
+                    db,
+                    pos,
+                    err
+                );
+                Ok(())
+            }
+            Ok(_) => Err(err),
+            // Not known yet, retry later
+            Err(err) => Err(err),
+        }
+    }
+
+    #[cfg(target_os = "emscripten")]
+    pub(crate) fn upload_in_background(_database: &Arc<RwLock<Database>>) {}
+
+    #[cfg(not(target_os = "emscripten"))]
+    fn upload_tx(
+        client: &lfsc::Client,
+        leaser: &Leaser,
+        db: &str,
+        path: &Path,
+    ) -> lfsc::Result<()> {
+        let lease = leaser.get_lease(db)?;
+        let file = fs::File::open(path)?;
+        let len = file.metadata()?.len();
+        client.write_tx(db, io::BufReader::new(file), len, &lease)
+    }
+
+    fn commit_pos(&mut self, pos: ltx::Pos) -> io::Result<()> {
+        let file = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
             .open(&self.pos_path)?;
         serde_json::to_writer(&file, &pos)?;
         file.sync_all()?;
@@ -641,11 +1961,60 @@ impl Database {
     }
 
     pub(crate) fn sync(&mut self, force: bool, deep: bool) -> io::Result<()> {
+        // Whoever forces a sync wants to see the latest state, which includes the
+        // transactions held by write combining.
+        if force {
+            self.close_combine_window();
+        }
+
+        // A sync that is already in progress brings the same changes, so there is no
+        // need to send another request to LFSC, unless it fails. Deep syncs always
+        // send their own request.
         if force {
-            self.syncer.sync_one(&self.name, deep)?;
+            let waited = if deep {
+                None
+            } else {
+                self.syncer.wait_for_sync(&self.name)
+            };
+            if !matches!(waited, Some(Ok(()))) {
+                self.syncer.sync_one(&self.name, deep)?;
+            }
         }
 
-        let pos = match self.syncer.get_changes(&self.name, self.pos)? {
+        let mut changes = self.syncer.get_changes(&self.name, self.pos)?;
+        // One of the pending transactions has landed while its upload is in flight.
+        // The cache already has the latest pages, so there is nothing to drop.
+        let uploaded = self.upload_running.load(Ordering::Acquire)
+            && changes
+                .0
+                .map_or(false, |pos| self.pending.iter().any(|tx| tx.pos == pos));
+        if uploaded {
+            changes.1.take();
+        }
+        // Somebody else has written to the database, the pending transactions
+        // are based on a stale state and can't be uploaded anymore.
+        if changes.1.is_some() && !self.pending.is_empty() {
+            let err = io::Error::new(
+                io::ErrorKind::Other,
+                "database changed while transactions were pending upload",
+            );
+            metrics().record_error("upload", Some(&self.name), &err);
+            self.discard_pending()?;
+        }
+
+        let changed = match &changes.1 {
+            // The pending transactions might have landed
+            None => Some(
+                self.pending
+                    .iter()
+                    .flat_map(|tx| &tx.pages)
+                    .filter_map(|&pgno| ltx::PageNum::new(pgno).ok())
+                    .collect(),
+            ),
+            Some(Changes::All) => None,
+            Some(Changes::Pages(pgnos)) => Some(pgnos.clone()),
+        };
+        let pos = match changes {
             // No changes
             (pos, None) => {
                 log::debug!(
@@ -665,7 +2034,14 @@ impl Database {
                     OptionLogger(&self.pos),
                     OptionLogger(&pos)
                 );
-                match self.pager.clear(&self.name) {
+                // Pages that haven't changed can be revalidated instead of downloaded again
+                match self.pager.keep_stale(&self.name).and_then(|mut pgnos| {
+                    // Packed pages are copied, so they are cleared as well
+                    pgnos.extend(self.pager.clear(&self.name)?);
+                    pgnos.sort_unstable();
+                    pgnos.dedup();
+                    Ok(pgnos)
+                }) {
                     Err(err) => {
                         self.syncer.put_changes(&self.name, Changes::All);
                         return Err(err);
@@ -675,7 +2051,10 @@ impl Database {
                             pgnos.into_iter().take(self.prefetch_limit).collect();
                     }
                 };
+                self.prefetched_pages.lock().unwrap().clear();
                 self.committed_db_size.lock().unwrap().take();
+                self.header.lock().unwrap().take();
+                self.page_size.lock().unwrap().take();
 
                 pos
             }
@@ -691,20 +2070,31 @@ impl Database {
                 );
 
                 let mut prefetch = self.prefetch_pages.lock().unwrap();
+                let mut prefetched = self.prefetched_pages.lock().unwrap();
+                let mut refetch = self.refetch_pages.lock().unwrap();
                 prefetch.clear();
                 for pgno in &pgnos {
+                    prefetched.remove(pgno);
+                    // Only the pages that were cached are worth refetching, pages that
+                    // have been evicted to free up space are not.
                     match self.pager.del_page(&self.name, *pgno) {
                         Err(err) => {
                             self.syncer.put_changes(&self.name, Changes::Pages(pgnos));
                             return Err(err);
                         }
-                        Ok(true) if prefetch.len() < self.prefetch_limit => {
-                            prefetch.insert(*pgno);
+                        Ok(true) => {
+                            if prefetch.len() < self.prefetch_limit {
+                                prefetch.insert(*pgno);
+                            }
+                            if self.eager_refetch {
+                                refetch.insert(*pgno);
+                            }
                         }
-                        _ => (),
+                        Ok(false) => (),
                     }
                     if *pgno == ltx::PageNum::ONE {
                         self.committed_db_size.lock().unwrap().take();
+                        self.header.lock().unwrap().take();
                     };
                 }
 
@@ -716,17 +2106,201 @@ impl Database {
             self.commit_pos(pos)?;
         }
 
+        self.record_pos_change(pos, changed);
         self.pos = pos;
 
         Ok(())
     }
 
+    // The page size is forgotten on a full sync, as the database may have been recreated
+    // with a different one, so derive it again from page 1, instead of aligning reads to
+    // the old one. Does nothing if page 1 doesn't exist yet or isn't cached and only the
+    // cache may be used.
+    fn refresh_page_size(&self, local_only: bool) -> io::Result<()> {
+        self.restore_pending_page(ltx::PageNum::ONE)?;
+        if local_only && !self.pager.has_page(&self.name, ltx::PageNum::ONE)? {
+            return Ok(());
+        }
+        let page = match self
+            .pager
+            .get_page(&self.name, self.pos, ltx::PageNum::ONE, None)
+        {
+            Ok(page) => page,
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(err) => return Err(err),
+        };
+
+        let page_size = Database::parse_page_size_database(page.as_ref())?;
+        log::debug!(
+            "[database] refresh_page_size: db = {}, pos = {}, page size = {}",
+            self.name,
+            OptionLogger(&self.pos),
+            page_size.into_inner()
+        );
+        self.reserved_bytes.store(
+            Database::parse_reserved_bytes(page.as_ref()),
+            Ordering::Relaxed,
+        );
+        *self.committed_db_size.lock().unwrap() = Some(Database::parse_commit_database(
+            page.as_ref(),
+            sqlite::COMMIT_RANGE,
+        )?);
+        *self.header.lock().unwrap() = Some(page.as_ref()[..sqlite::HEADER_SIZE].to_vec());
+        *self.page_size.lock().unwrap() = Some(page_size);
+
+        Ok(())
+    }
+
+    /// Removes all the locally cached pages of the database and forces a resync,
+    /// so that the next read refetches them from LFSC. Returns the number of dropped pages.
+    pub(crate) fn drop_cache(&mut self) -> io::Result<usize> {
+        if !self.dirty_pages.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "database has uncommitted changes",
+            ));
+        }
+        if !self.pending.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "database has transactions pending upload",
+            ));
+        }
+
+        let pgnos = self.pager.clear(&self.name)?;
+        log::info!(
+            "[database] dropped cache, db = {}, pages = {}",
+            self.name,
+            pgnos.len()
+        );
+
+        self.prefetch_pages.lock().unwrap().clear();
+        self.prefetched_pages.lock().unwrap().clear();
+        self.refetch_pages.lock().unwrap().clear();
+        self.committed_db_size.lock().unwrap().take();
+        self.header.lock().unwrap().take();
+        self.sync(true, true)?;
+
+        // Refresh the DB header, so that the database size is known
+        let mut header = [0; sqlite::HEADER_SIZE];
+        match self.read_at(&mut header, 0, false, None) {
+            Err(err) if err.kind() != io::ErrorKind::UnexpectedEof => return Err(err),
+            _ => (),
+        };
+
+        Ok(pgnos.len())
+    }
+
+    /// Compares the locally cached pages, or a random `sample` of them, with the pages
+    /// LFSC has at the current position, and evicts the ones that don't match, so that
+    /// they are refetched on the next read. Returns the number of mismatched pages.
+    ///
+    /// Every checked page is downloaded from LFSC, so checking the whole cache costs
+    /// as much as fetching the whole database.
+    pub(crate) fn verify_cache(
+        database: &RwLock<Database>,
+        sample: Option<usize>,
+    ) -> io::Result<usize> {
+        let (name, pager, pos, pgnos) = {
+            let db = database.read().unwrap();
+            if !db.dirty_pages.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "database has uncommitted changes",
+                ));
+            }
+            let pos = match db.pos {
+                Some(pos) => pos,
+                None => return Ok(0),
+            };
+
+            // Pages of the transactions pending upload are newer than the ones LFSC has
+            let pending = db
+                .pending
+                .iter()
+                .flat_map(|tx| tx.pages.iter().copied())
+                .collect::<BTreeSet<_>>();
+            let mut pgnos = db
+                .pager
+                .cached_pages(&db.name)?
+                .into_iter()
+                .filter(|pgno| !pending.contains(&pgno.into_inner()))
+                .collect::<Vec<_>>();
+            if let Some(sample) = sample {
+                pgnos.shuffle(&mut rand::thread_rng());
+                pgnos.truncate(sample);
+            }
+            pgnos.sort();
+
+            (db.name.clone(), Arc::clone(&db.pager), pos, pgnos)
+        };
+
+        let mut mismatches = 0;
+        for chunk in pgnos.chunks(VERIFY_BATCH_PAGES) {
+            // The database isn't locked while the pages are fetched
+            let remote = pager
+                .get_pages_uncached(&name, pos, chunk, None)?
+                .into_iter()
+                .map(|page| (page.number(), page.checksum()))
+                .collect::<HashMap<_, _>>();
+
+            let db = database.read().unwrap();
+            if db.pos != Some(pos) || !db.dirty_pages.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::Interrupted,
+                    "database has changed during the verification",
+                ));
+            }
+            for &pgno in chunk {
+                // Evicted pages are skipped, without a position only the cached ones are read
+                if !pager.has_page(&name, pgno)? {
+                    continue;
+                }
+                let local = match pager.get_page(&name, None, pgno, None) {
+                    Ok(page) => page.checksum(),
+                    // Evicted in the meantime
+                    Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => continue,
+                    Err(err) => return Err(err),
+                };
+                let remote = remote.get(&pgno).copied();
+                if remote == Some(local) {
+                    continue;
+                }
+
+                log::warn!(
+                    "[database] verify_cache: db = {}, pos = {}, pgno = {}, local checksum = {:016x}, remote checksum = {}",
+                    name,
+                    pos,
+                    pgno,
+                    local.into_inner(),
+                    remote.map_or("<none>".to_string(), |c| format!("{:016x}", c.into_inner()))
+                );
+                pager.del_page(&name, pgno)?;
+                if pgno == ltx::PageNum::ONE {
+                    db.header.lock().unwrap().take();
+                    db.committed_db_size.lock().unwrap().take();
+                }
+                mismatches += 1;
+            }
+        }
+
+        log::info!(
+            "[database] verify_cache: db = {}, pos = {}, pages = {}, mismatches = {}",
+            name,
+            pos,
+            pgnos.len(),
+            mismatches
+        );
+
+        Ok(mismatches)
+    }
+
     pub(crate) fn cache(&mut self) -> io::Result<()> {
         self.sync(true, true)?;
 
         // Make sure we have up-to-date view of the DB header
         let mut header = [0; sqlite::HEADER_SIZE];
-        self.read_at(&mut header, 0, false)?;
+        self.read_at(&mut header, 0, false, None)?;
 
         let dbsize = self
             .committed_db_size
@@ -743,15 +2317,17 @@ impl Database {
             OptionLogger(&self.pos),
             dbsize
         );
+        // The lock page is never written by SQLite, so LFSC doesn't have it
+        let lock_page = ltx::PageNum::lock_page(self.page_size()?);
         let mut pgnos = Vec::with_capacity(MAX_MAX_PREFETCH_PAGES);
         for pgno in 1..=dbsize.into_inner() {
             let pgno = ltx::PageNum::new(pgno).unwrap();
 
-            if self.pager.has_page(&self.name, pgno)? {
+            if pgno == lock_page || self.pager.has_page(&self.name, pgno)? {
                 continue;
             }
 
-            if pgno == dbsize || pgnos.len() == MAX_MAX_PREFETCH_PAGES {
+            if pgnos.len() == MAX_MAX_PREFETCH_PAGES {
                 self.pager
                     .get_page(&self.name, self.pos, pgno, Some(&pgnos))?;
                 pgnos.clear();
@@ -759,26 +2335,2231 @@ impl Database {
                 pgnos.push(pgno);
             }
         }
+        if let Some((&last, rest)) = pgnos.split_last() {
+            self.pager
+                .get_page(&self.name, self.pos, last, Some(rest))?;
+        }
 
         Ok(())
     }
 
-    fn prefetch_pages(&self, pgno: ltx::PageNum) -> Option<Vec<ltx::PageNum>> {
-        let prefetch = self.prefetch_pages.lock().unwrap();
-        let pgnos = if prefetch.contains(&pgno) {
-            Some(prefetch.iter().filter(|&&no| no != pgno).copied().collect())
-        } else {
-            None
-        };
+    /// Imports the pages of an existing copy of the database at `path` into the local cache,
+    /// so that they don't need to be fetched from LFSC. The copy must be at the current
+    /// database position, pages that are already cached are skipped. Returns the number
+    /// of imported pages.
+    pub(crate) fn import_file(&mut self, path: &Path) -> io::Result<usize> {
+        if !self.dirty_pages.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "database has uncommitted changes",
+            ));
+        }
+        let pos = self.pos.ok_or(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "database position unknown",
+        ))?;
+
+        let mut file = io::BufReader::new(fs::File::open(path)?);
+        let mut header = [0; sqlite::HEADER_SIZE];
+        file.read_exact(&mut header)?;
+
+        let page_size = Database::parse_page_size_database(&header)?;
+        if self
+            .page_size
+            .lock()
+            .unwrap()
+            .map_or(false, |ps| ps != page_size)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("page size mismatch: {}", page_size.into_inner()),
+            ));
+        }
+        let commit = Database::parse_commit_database(&header, sqlite::COMMIT_RANGE)?;
+        if self
+            .committed_db_size
+            .lock()
+            .unwrap()
+            .map_or(false, |size| size != commit)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("database size mismatch: {}", commit),
+            ));
+        }
+
+        // Make sure the whole file is at our position before importing anything
+        let lock_page = ltx::PageNum::lock_page(page_size);
+        let mut buf = vec![0; page_size.into_inner() as usize];
+        let mut checksum = 0;
+        file.seek(SeekFrom::Start(0))?;
+        for pgno in 1..=commit.into_inner() {
+            let pgno = ltx::PageNum::new(pgno)?;
+            file.read_exact(&mut buf)?;
+            if pgno != lock_page {
+                checksum = update_checksum(checksum, None, Some(buf.page_checksum(pgno)));
+            }
+        }
+        if ltx::Checksum::new(checksum) != pos.post_apply_checksum {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("file doesn't match database position {}", pos),
+            ));
+        }
+
+        log::info!(
+            "[database] importing, db = {}, pos = {}, size = {}, path = {}",
+            self.name,
+            pos,
+            commit,
+            path.display()
+        );
+        let mut imported = 0;
+        file.seek(SeekFrom::Start(0))?;
+        for pgno in 1..=commit.into_inner() {
+            let pgno = ltx::PageNum::new(pgno)?;
+            file.read_exact(&mut buf)?;
+            if pgno == lock_page || self.pager.has_page(&self.name, pgno)? {
+                continue;
+            }
+
+            self.pager.put_page(&self.name, PageRef::new(pgno, &buf))?;
+            imported += 1;
+        }
+
+        self.page_size.lock().unwrap().get_or_insert(page_size);
+        self.committed_db_size.lock().unwrap().get_or_insert(commit);
+        self.header.lock().unwrap().get_or_insert(header.to_vec());
+
+        Ok(imported)
+    }
+
+    /// Starts caching all the database pages in the background, returning right away.
+    /// Unlike [Database::cache], this doesn't sync the database first, the pages are
+    /// fetched at the database position current at the time of each fetch.
+    #[cfg(not(target_os = "emscripten"))]
+    pub(crate) fn cache_in_background(
+        database: &Arc<RwLock<Database>>,
+    ) -> io::Result<Arc<CacheTask>> {
+        let (task, background) = {
+            let db = database.read().unwrap();
+            let mut cache_task = db.cache_task.lock().unwrap();
+            if let Some(task) = cache_task.as_ref() {
+                if !task.done.load(Ordering::Acquire) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::AlreadyExists,
+                        "database is already being cached",
+                    ));
+                }
+            }
+
+            let task = Arc::new(CacheTask::default());
+            *cache_task = Some(Arc::clone(&task));
+
+            log::info!("[database] caching in background, db = {}", db.name);
+            (task, Arc::clone(&db.background))
+        };
+
+        background.spawn({
+            let database = Arc::clone(database);
+            let task = Arc::clone(&task);
+            let background = Arc::clone(&background);
+
+            move || {
+                let mut pgno = 1;
+                let ret = loop {
+                    if background.is_stopped() {
+                        task.cancel();
+                    }
+                    if task.cancelled.load(Ordering::Acquire) {
+                        break Ok(());
+                    }
+
+                    match Database::cache_batch(&database, pgno, &task) {
+                        Ok(Some(next)) => pgno = next,
+                        Ok(None) => break Ok(()),
+                        // The pending retries of the batch have been abandoned
+                        Err(err)
+                            if err.kind() == io::ErrorKind::Interrupted
+                                && task.cancelled.load(Ordering::Acquire) =>
+                        {
+                            break Ok(())
+                        }
+                        Err(err) => break Err(err),
+                    }
+                };
+
+                let name = database.read().unwrap().name.clone();
+                match ret {
+                    Ok(()) => log::info!(
+                        "[database] cache_in_background: db = {}, cached = {}, cancelled = {}",
+                        name,
+                        task.cached.load(Ordering::Relaxed),
+                        task.cancelled.load(Ordering::Relaxed)
+                    ),
+                    Err(err) => {
+                        log::warn!("[database] cache_in_background: db = {}: {}", name, err);
+                        *task.error.lock().unwrap() = Some(err.to_string());
+                    }
+                };
+                task.done.store(true, Ordering::Release);
+            }
+        });
+
+        Ok(task)
+    }
+
+    #[cfg(target_os = "emscripten")]
+    pub(crate) fn cache_in_background(
+        _database: &Arc<RwLock<Database>>,
+    ) -> io::Result<Arc<CacheTask>> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "background caching is not supported",
+        ))
+    }
+
+    /// Returns the progress of the last background caching, if any.
+    pub(crate) fn cache_progress(&self) -> Option<CacheProgress> {
+        self.cache_task
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|task| task.progress())
+    }
+
+    // Fetches the next batch of missing pages starting from `pgno`, returning the page
+    // number to continue from, or `None` when all the pages have been cached. The pages
+    // are fetched without holding the database lock, so that syncs and writes aren't
+    // blocked by LFSC, see `cache_fetched`.
+    #[cfg(not(target_os = "emscripten"))]
+    fn cache_batch(
+        database: &RwLock<Database>,
+        pgno: u32,
+        task: &CacheTask,
+    ) -> io::Result<Option<u32>> {
+        let (name, pager, pos, pgnos, pgno, dbsize) = {
+            let db = database.read().unwrap();
+            let dbsize = match *db.committed_db_size.lock().unwrap() {
+                Some(dbsize) => dbsize.into_inner(),
+                None => return Ok(None),
+            };
+            task.total.store(dbsize as u64, Ordering::Relaxed);
+
+            let lock_page = ltx::PageNum::lock_page(db.page_size()?);
+            let mut pgno = pgno;
+            let mut pgnos = Vec::with_capacity(MAX_MAX_PREFETCH_PAGES);
+            while pgno <= dbsize && pgnos.len() < MAX_MAX_PREFETCH_PAGES {
+                let page = ltx::PageNum::new(pgno).unwrap();
+                if page != lock_page && !db.pager.has_page(&db.name, page)? {
+                    pgnos.push(page);
+                }
+                pgno += 1;
+            }
+
+            (
+                db.name.clone(),
+                Arc::clone(&db.pager),
+                db.pos,
+                pgnos,
+                pgno,
+                dbsize,
+            )
+        };
+
+        if let (Some(pos), false) = (pos, pgnos.is_empty()) {
+            let pages = pager.get_pages_uncached(&name, pos, &pgnos, Some(&task.cancelled))?;
+            database.read().unwrap().cache_fetched(pos, pages)?;
+        }
+        task.cached.store(pgno as u64 - 1, Ordering::Relaxed);
+
+        Ok(if pgno <= dbsize { Some(pgno) } else { None })
+    }
+
+    // Caches the pages fetched at `pos` without the database lock held, unless the
+    // database has been synced to another position in the meantime, as they may be
+    // stale then. Pages cached or changed by a transaction meanwhile are kept.
+    // Returns the number of cached pages.
+    #[cfg(not(target_os = "emscripten"))]
+    fn cache_fetched(&self, pos: ltx::Pos, pages: Vec<Page>) -> io::Result<usize> {
+        if self.pos != Some(pos) {
+            log::debug!(
+                "[database] cache_fetched: db = {}, pos = {}, fetched_pos = {}, dropping {} pages",
+                self.name,
+                OptionLogger(&self.pos),
+                pos,
+                pages.len()
+            );
+            return Ok(0);
+        }
+
+        let mut cached = 0;
+        for page in pages {
+            if self.dirty_pages.contains_key(&page.number())
+                || self.pager.has_page(&self.name, page.number())?
+            {
+                continue;
+            }
+            self.pager
+                .put_page(&self.name, PageRef::new(page.number(), page.as_ref()))?;
+            cached += 1;
+        }
+
+        Ok(cached)
+    }
+
+    pub(crate) fn prefetch_enabled(&self) -> bool {
+        self.prefetch_enabled
+    }
+
+    /// Enables or disables prefetching and read-ahead of pages on reads.
+    pub(crate) fn set_prefetch_enabled(&mut self, prefetch_enabled: bool) {
+        if !prefetch_enabled {
+            self.prefetch_pages.lock().unwrap().clear();
+            self.recent_reads.lock().unwrap().clear();
+        }
+        self.prefetch_enabled = prefetch_enabled;
+    }
+
+    pub(crate) fn eager_refetch(&self) -> bool {
+        self.eager_refetch
+    }
+
+    /// Enables or disables refetching of the cached pages changed by syncs.
+    pub(crate) fn set_eager_refetch(&mut self, eager_refetch: bool) {
+        if !eager_refetch {
+            self.refetch_pages.lock().unwrap().clear();
+        }
+        self.eager_refetch = eager_refetch;
+    }
+
+    /// Starts refetching the pages that were cached but have been changed by the
+    /// last syncs, so that the next reads don't have to go to LFSC. Does nothing if
+    /// there are no such pages or the refetch is already running.
+    #[cfg(not(target_os = "emscripten"))]
+    pub(crate) fn refetch_in_background(database: &Arc<RwLock<Database>>) {
+        let (running, background) = {
+            let db = database.read().unwrap();
+            if db.refetch_pages.lock().unwrap().is_empty()
+                || db.background.is_stopped()
+                || db.refetch_running.swap(true, Ordering::AcqRel)
+            {
+                return;
+            }
+
+            (Arc::clone(&db.refetch_running), Arc::clone(&db.background))
+        };
+
+        background.spawn({
+            let database = Arc::clone(database);
+            let background = Arc::clone(&background);
+
+            move || {
+                let mut refetched = 0;
+                loop {
+                    // The changed pages are simply fetched on demand after a restart
+                    let ret = if background.is_stopped() {
+                        Err(io::Error::new(
+                            io::ErrorKind::Interrupted,
+                            "VFS is shut down",
+                        ))
+                    } else {
+                        Database::refetch_batch(&database)
+                    };
+
+                    let db = database.read().unwrap();
+                    match ret {
+                        // A sync may have added pages since the batch was taken
+                        Ok(0) if !db.refetch_pages.lock().unwrap().is_empty() => (),
+                        Ok(0) => {
+                            // Cleared with the database lock held, so the pages added by
+                            // a concurrent sync are picked up by either this or a new worker.
+                            running.store(false, Ordering::Release);
+                            log::debug!(
+                                "[database] refetch_in_background: db = {}, refetched = {}",
+                                db.name,
+                                refetched
+                            );
+                            break;
+                        }
+                        Ok(n) => refetched += n,
+                        Err(err) => {
+                            db.refetch_pages.lock().unwrap().clear();
+                            running.store(false, Ordering::Release);
+                            log::warn!(
+                                "[database] refetch_in_background: db = {}: {}",
+                                db.name,
+                                err
+                            );
+                            break;
+                        }
+                    }
+                }
+            }
+        });
+    }
+
+    #[cfg(target_os = "emscripten")]
+    pub(crate) fn refetch_in_background(_database: &Arc<RwLock<Database>>) {}
+
+    // Fetches the next batch of changed pages, returning the number of pages taken
+    // from the refetch set. Just like with `cache_batch`, the pages are fetched without
+    // holding the database lock and are dropped if the database is synced meanwhile.
+    #[cfg(not(target_os = "emscripten"))]
+    fn refetch_batch(database: &RwLock<Database>) -> io::Result<usize> {
+        let (name, pager, pos, pgnos, taken) = {
+            let db = database.read().unwrap();
+            let batch = {
+                let mut refetch = db.refetch_pages.lock().unwrap();
+                let batch: Vec<_> = refetch
+                    .iter()
+                    .take(MAX_MAX_PREFETCH_PAGES)
+                    .copied()
+                    .collect();
+                for pgno in &batch {
+                    refetch.remove(pgno);
+                }
+
+                batch
+            };
+
+            let dbsize = db
+                .committed_db_size
+                .lock()
+                .unwrap()
+                .map(|size| size.into_inner());
+            let mut pgnos = Vec::with_capacity(batch.len());
+            for &pgno in &batch {
+                if dbsize.map_or(true, |size| pgno.into_inner() <= size)
+                    && !db.pager.has_page(&db.name, pgno)?
+                {
+                    pgnos.push(pgno);
+                }
+            }
+
+            (
+                db.name.clone(),
+                Arc::clone(&db.pager),
+                db.pos,
+                pgnos,
+                batch.len(),
+            )
+        };
+
+        if let (Some(pos), false) = (pos, pgnos.is_empty()) {
+            let pages = pager.get_pages_uncached(&name, pos, &pgnos, None)?;
+            database.read().unwrap().cache_fetched(pos, pages)?;
+        }
+
+        Ok(taken)
+    }
 
-        pgnos
+    fn prefetch_pages(&self, pgno: ltx::PageNum, read_ahead: bool) -> Option<Vec<ltx::PageNum>> {
+        let mut pgnos: Vec<_> = {
+            let prefetch = self.prefetch_pages.lock().unwrap();
+            if prefetch.contains(&pgno) {
+                prefetch.iter().filter(|&&no| no != pgno).copied().collect()
+            } else {
+                Vec::new()
+            }
+        };
+
+        // Fill the rest of the prefetch budget with the pages following a sequential scan
+        if read_ahead {
+            for no in self.read_ahead_pages(pgno) {
+                if pgnos.len() >= self.prefetch_limit {
+                    break;
+                }
+                if !pgnos.contains(&no) {
+                    pgnos.push(no);
+                }
+            }
+        }
+
+        if pgnos.is_empty() {
+            None
+        } else {
+            Some(pgnos)
+        }
+    }
+
+    // Records the read of `pgno` and checks if the last reads have been increasing,
+    // as is the case with full table scans.
+    fn is_sequential(&self, pgno: ltx::PageNum) -> bool {
+        let mut recent = self.recent_reads.lock().unwrap();
+        if recent.back().map_or(false, |&last| last >= pgno) {
+            recent.clear();
+        }
+        if recent.len() == SEQUENTIAL_READS {
+            recent.pop_front();
+        }
+        recent.push_back(pgno);
+
+        recent.len() == SEQUENTIAL_READS
+    }
+
+    // Returns the missing pages following `pgno` within the database size.
+    fn read_ahead_pages(&self, pgno: ltx::PageNum) -> Vec<ltx::PageNum> {
+        let dbsize = match *self.committed_db_size.lock().unwrap() {
+            Some(dbsize) => dbsize.into_inner(),
+            None => return Vec::new(),
+        };
+        let lock_page = self.page_size().ok().map(ltx::PageNum::lock_page);
+
+        (pgno.into_inner() + 1..=dbsize.min(pgno.into_inner().saturating_add(READ_AHEAD_PAGES)))
+            .filter_map(|no| ltx::PageNum::new(no).ok())
+            .filter(|&no| Some(no) != lock_page)
+            .filter(|&no| !self.pager.has_page(&self.name, no).unwrap_or(false))
+            .collect()
+    }
+
+    /// Syncs the database with LFSC after the position it's been reading at is gone.
+    /// This is only safe before the transaction has read or written anything.
+    pub(crate) fn resync_after_pos_mismatch(&mut self) -> io::Result<()> {
+        if !self.dirty_pages.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "can't resync a database with uncommitted changes",
+            ));
+        }
+
+        log::info!(
+            "[database] resync_after_pos_mismatch: db = {}, pos = {}",
+            self.name,
+            OptionLogger(&self.pos)
+        );
+        self.sync(true, false)
+    }
+
+    /// Checks that all the local changes have been accepted by LFSC: there are no
+    /// uncommitted pages or LTX files left to upload, and LFSC is at the same position.
+    /// The database isn't locked while LFSC is asked for its position.
+    pub(crate) fn flush_status(database: &RwLock<Database>) -> io::Result<FlushStatus> {
+        let (name, client, local_pos, dirty_pages, pending_ltx_files) = {
+            let db = database.read().unwrap();
+            let pending_ltx_files = fs::read_dir(&db.ltx_path)?
+                .filter_map(|entry| entry.ok())
+                .filter(|entry| entry.path().extension().map_or(false, |ext| ext == "ltx"))
+                .count()
+                + db.pending.len();
+
+            (
+                db.name.clone(),
+                Arc::clone(&db.client),
+                db.pos,
+                db.dirty_pages.len(),
+                pending_ltx_files,
+            )
+        };
+        let remote_pos = client.sync_db(&name, local_pos)?.pos();
+
+        let status = FlushStatus {
+            synced: pending_ltx_files == 0 && dirty_pages == 0 && remote_pos == local_pos,
+            dirty_pages,
+            pending_ltx_files,
+            local_pos,
+            remote_pos,
+        };
+        if !status.synced {
+            log::info!(
+                "[database] flush_status: db = {}, pos = {}, remote_pos = {}, dirty_pages = {}, pending_ltx_files = {}",
+                name,
+                OptionLogger(&status.local_pos),
+                OptionLogger(&status.remote_pos),
+                status.dirty_pages,
+                status.pending_ltx_files,
+            );
+        }
+
+        Ok(status)
+    }
+
+    /// Checks the database header for features LiteVFS doesn't support. Unlike opening
+    /// the database, this reports all of them instead of failing on the first one.
+    pub(crate) fn check(&self) -> io::Result<CompatReport> {
+        let issues = match self
+            .pager
+            .get_page(&self.name, self.pos, ltx::PageNum::ONE, None)
+        {
+            Ok(page1) => check_header(page1.as_ref()),
+            // Empty databases can be anything
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => Vec::new(),
+            Err(err) => return Err(err),
+        };
+
+        Ok(CompatReport {
+            readable: issues.iter().all(|issue| issue.blocks != Blocks::Read),
+            writable: issues.iter().all(|issue| issue.blocks == Blocks::Warning),
+            issues,
+        })
+    }
+
+    /// Returns the number of pages modified by the current write transaction.
+    pub(crate) fn dirty_page_count(&self) -> usize {
+        self.dirty_pages.len()
+    }
+
+    /// Returns the numbers of pages modified by the current write transaction.
+    pub(crate) fn dirty_page_numbers(&self) -> Vec<ltx::PageNum> {
+        self.dirty_pages.keys().copied().collect()
+    }
+
+    /// Returns the logical size of the database and how much of it is cached locally.
+    pub(crate) fn size_stats(&self) -> io::Result<SizeStats> {
+        let cached_pages = self.pager.page_count(&self.name)?;
+        let page_size = self.page_size().map_or(0, |ps| ps.into_inner() as u64);
+
+        Ok(SizeStats {
+            logical_bytes: self.size()?,
+            cached_pages,
+            cached_bytes: cached_pages as u64 * page_size,
+        })
+    }
+
+    /// Returns a snapshot of the prefetch statistics.
+    pub(crate) fn prefetch_stats(&self) -> PrefetchStatsSnapshot {
+        self.prefetch_stats.snapshot()
+    }
+
+    /// Resets the prefetch statistics counters to zero.
+    pub(crate) fn reset_prefetch_stats(&self) {
+        self.prefetch_stats.reset()
+    }
+
+    /// Returns the ID of the LFSC instance the database's requests are pinned to.
+    pub(crate) fn lfsc_instance(&self) -> Option<String> {
+        self.client.instance_id()
+    }
+
+    /// Lets the next LFSC request go to a different instance. The client is shared
+    /// by all the databases of the cluster, so this affects all of them.
+    pub(crate) fn reset_lfsc_instance(&self) {
+        log::info!(
+            "[database] reset_lfsc_instance: db = {}, instance = {}",
+            self.name,
+            OptionLogger(&self.client.instance_id())
+        );
+        self.client.reset_instance_id()
+    }
+
+    /// Acquires the lease on behalf of the connection `owner`, which releases it
+    /// with [Database::release_owned_lease] once closed.
+    pub(crate) fn acquire_lease(&self, owner: u64) -> io::Result<()> {
+        self.leaser.acquire_lease(&self.name)?;
+        *self.lease_owner.lock().unwrap() = self
+            .leaser
+            .lease_info(&self.name)?
+            .map(|lease| (owner, lease.id));
+
+        Ok(())
     }
 
-    pub(crate) fn acquire_lease(&self) -> io::Result<()> {
-        self.leaser.acquire_lease(&self.name)
+    pub(crate) fn lease_info(&self) -> io::Result<Option<lfsc::Lease>> {
+        self.leaser.lease_info(&self.name)
     }
 
     pub(crate) fn release_lease(&self) -> io::Result<()> {
+        self.lease_owner.lock().unwrap().take();
         self.leaser.release_lease(&self.name)
     }
+
+    /// Releases the lease acquired by the connection `owner`, unless it has been
+    /// released, or released and reacquired, by somebody else since then.
+    /// Returns the released lease, if any.
+    pub(crate) fn release_owned_lease(&self, owner: u64) -> io::Result<Option<lfsc::Lease>> {
+        let id = {
+            let mut lease_owner = self.lease_owner.lock().unwrap();
+            match lease_owner.as_ref() {
+                Some((o, _)) if *o == owner => lease_owner.take().map(|(_, id)| id),
+                _ => None,
+            }
+        };
+        let id = match id {
+            Some(id) => id,
+            None => return Ok(None),
+        };
+
+        // The leaser might have dropped the lease for being idle, and the uploader
+        // might have acquired a new one since then
+        match self.leaser.lease_info(&self.name)? {
+            Some(lease) if lease.id == id => {
+                self.leaser.release_lease(&self.name)?;
+                Ok(Some(lease))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Releases the lease `id` of the database, even if it's been acquired by another
+    /// connection or instance. LFSC isn't contacted while holding the database lock.
+    pub(crate) fn force_release_lease(database: &RwLock<Database>, id: &str) -> io::Result<()> {
+        let (name, leaser) = {
+            let db = database.read().unwrap();
+
+            (db.name.clone(), Arc::clone(&db.leaser))
+        };
+
+        leaser.force_release_lease(&name, id)?;
+
+        // Whoever has acquired the lease doesn't own it anymore
+        let db = database.read().unwrap();
+        let mut lease_owner = db.lease_owner.lock().unwrap();
+        if matches!(lease_owner.as_ref(), Some((_, owned)) if owned == id) {
+            lease_owner.take();
+        }
+
+        Ok(())
+    }
+}
+
+/// Logical and locally cached size of a [Database].
+#[derive(Debug, serde::Serialize)]
+pub(crate) struct SizeStats {
+    pub(crate) logical_bytes: u64,
+    pub(crate) cached_pages: usize,
+    pub(crate) cached_bytes: u64,
+}
+
+/// Prefetch effectiveness counters of a [Database].
+#[derive(Default)]
+struct PrefetchStats {
+    prefetched: AtomicU64,
+    used: AtomicU64,
+}
+
+impl PrefetchStats {
+    fn snapshot(&self) -> PrefetchStatsSnapshot {
+        let prefetched = self.prefetched.load(Ordering::Relaxed);
+        let used = self.used.load(Ordering::Relaxed);
+
+        PrefetchStatsSnapshot {
+            prefetched,
+            used,
+            hit_ratio: if prefetched > 0 {
+                used as f64 / prefetched as f64
+            } else {
+                0.0
+            },
+        }
+    }
+
+    fn reset(&self) {
+        self.prefetched.store(0, Ordering::Relaxed);
+        self.used.store(0, Ordering::Relaxed);
+    }
+}
+
+// Encoded LTX files up to this size are kept in memory instead of being written to disk.
+const MAX_IN_MEMORY_LTX_SIZE: u64 = 8 * 1024 * 1024;
+
+// Holds an encoded LTX file until it's uploaded to LFSC. Small transactions are kept
+// in memory, larger ones are spilled to disk. Either way the buffer can be rewound
+// and replayed if the upload is retried.
+enum LtxBuffer {
+    Memory(io::Cursor<Vec<u8>>),
+    File(fs::File, PathBuf),
+}
+
+impl LtxBuffer {
+    fn new(path: PathBuf, size_hint: u64) -> io::Result<LtxBuffer> {
+        if size_hint <= MAX_IN_MEMORY_LTX_SIZE {
+            return Ok(LtxBuffer::Memory(io::Cursor::new(Vec::with_capacity(
+                size_hint as usize,
+            ))));
+        }
+
+        let file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)?;
+
+        Ok(LtxBuffer::File(file, path))
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        match self {
+            LtxBuffer::Memory(cursor) => Ok(cursor.get_ref().len() as u64),
+            LtxBuffer::File(file, _) => Ok(file.metadata()?.len()),
+        }
+    }
+
+    fn remove(self) -> io::Result<()> {
+        match self {
+            LtxBuffer::Memory(_) => Ok(()),
+            LtxBuffer::File(_, path) => fs::remove_file(path),
+        }
+    }
+}
+
+impl io::Read for LtxBuffer {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            LtxBuffer::Memory(cursor) => cursor.read(buf),
+            LtxBuffer::File(file, _) => file.read(buf),
+        }
+    }
+}
+
+impl io::Write for LtxBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            LtxBuffer::Memory(cursor) => cursor.write(buf),
+            LtxBuffer::File(file, _) => file.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            LtxBuffer::Memory(cursor) => cursor.flush(),
+            LtxBuffer::File(file, _) => file.flush(),
+        }
+    }
+}
+
+impl io::Seek for LtxBuffer {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match self {
+            LtxBuffer::Memory(cursor) => cursor.seek(pos),
+            LtxBuffer::File(file, _) => file.seek(pos),
+        }
+    }
+}
+
+/// The background uploads, caching and refetches of the databases of a cluster.
+#[derive(Default)]
+pub(crate) struct Background {
+    stopped: Mutex<bool>,
+    cvar: Condvar,
+    #[cfg(not(target_os = "emscripten"))]
+    workers: Mutex<Vec<Worker>>,
+}
+
+impl Background {
+    #[cfg(not(target_os = "emscripten"))]
+    fn spawn<F: FnOnce() + Send + 'static>(&self, f: F) {
+        let mut workers = self.workers.lock().unwrap();
+        workers.retain(|worker| !worker.is_finished());
+        workers.push(Worker::spawn(f));
+    }
+
+    pub(crate) fn is_stopped(&self) -> bool {
+        *self.stopped.lock().unwrap()
+    }
+
+    // Sleeps for `duration`, waking up early on shutdown. Returns `false` if stopped.
+    #[cfg(not(target_os = "emscripten"))]
+    fn sleep(&self, duration: time::Duration) -> bool {
+        let stopped = self.stopped.lock().unwrap();
+        let (stopped, _) = self
+            .cvar
+            .wait_timeout_while(stopped, duration, |stopped| !*stopped)
+            .unwrap();
+
+        !*stopped
+    }
+
+    /// Stops the background threads after their current step and waits for at most
+    /// `timeout` for them to exit. Transactions that haven't been uploaded yet stay
+    /// queued on disk until the database is opened again.
+    pub(crate) fn shutdown(&self, timeout: time::Duration) -> io::Result<()> {
+        *self.stopped.lock().unwrap() = true;
+        self.cvar.notify_all();
+
+        #[cfg(not(target_os = "emscripten"))]
+        {
+            let deadline = time::Instant::now() + timeout;
+            let workers = std::mem::take(&mut *self.workers.lock().unwrap());
+            let mut stopped = true;
+            for worker in workers {
+                stopped &= worker.join(deadline.saturating_duration_since(time::Instant::now()));
+            }
+            if !stopped {
+                log::warn!("[database] shutdown: background threads didn't stop in time");
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "background threads didn't stop in time",
+                ));
+            }
+        }
+        #[cfg(target_os = "emscripten")]
+        let _ = timeout;
+
+        Ok(())
+    }
+}
+
+/// A background caching of a [Database], see [Database::cache_in_background].
+#[derive(Default)]
+pub(crate) struct CacheTask {
+    cached: AtomicU64,
+    total: AtomicU64,
+    done: AtomicBool,
+    cancelled: AtomicBool,
+    error: Mutex<Option<String>>,
+}
+
+impl CacheTask {
+    /// Stops the caching, abandoning the pending retries of the batch being fetched.
+    pub(crate) fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Release);
+    }
+
+    fn progress(&self) -> CacheProgress {
+        CacheProgress {
+            cached: self.cached.load(Ordering::Relaxed),
+            total: self.total.load(Ordering::Relaxed),
+            done: self.done.load(Ordering::Acquire),
+            cancelled: self.cancelled.load(Ordering::Acquire),
+            error: self.error.lock().unwrap().clone(),
+        }
+    }
+}
+
+/// A point-in-time progress of a [CacheTask].
+#[derive(Debug, serde::Serialize)]
+pub(crate) struct CacheProgress {
+    pub(crate) cached: u64,
+    pub(crate) total: u64,
+    pub(crate) done: bool,
+    pub(crate) cancelled: bool,
+    pub(crate) error: Option<String>,
+}
+
+/// Result of checking a [Database] for features LiteVFS doesn't support.
+#[derive(Debug, serde::Serialize)]
+pub(crate) struct CompatReport {
+    /// The database can be opened at all.
+    pub(crate) readable: bool,
+    /// The database can be modified through LiteVFS.
+    pub(crate) writable: bool,
+    pub(crate) issues: Vec<CompatIssue>,
+}
+
+/// A single database feature reported by [Database::check].
+#[derive(Debug, PartialEq, serde::Serialize)]
+pub(crate) struct CompatIssue {
+    pub(crate) feature: &'static str,
+    pub(crate) blocks: Blocks,
+    pub(crate) description: String,
+}
+
+/// What a [CompatIssue] prevents LiteVFS from doing with the database.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Blocks {
+    /// The database can't be opened.
+    Read,
+    /// The database can't be modified.
+    Write,
+    /// The database works, but some LiteVFS features are limited.
+    Warning,
+}
+
+impl CompatIssue {
+    fn new(feature: &'static str, blocks: Blocks, description: impl Into<String>) -> Self {
+        CompatIssue {
+            feature,
+            blocks,
+            description: description.into(),
+        }
+    }
+}
+
+/// Write-combining settings of a [Database]: committed transactions are held locally
+/// for up to `window`, or until they add up to `max_bytes`, and then uploaded to LFSC
+/// as a single transaction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct CombineWrites {
+    pub(crate) window: time::Duration,
+    pub(crate) max_bytes: u64,
+}
+
+// A committed transaction waiting to be uploaded to LFSC. Stored next to its
+// LTX file, so that the queue survives restarts.
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+struct PendingTx {
+    pos: ltx::Pos,
+    pages: Vec<u32>,
+}
+
+/// Whether the local state of a [Database] is fully synced to LFSC.
+#[derive(Debug, serde::Serialize)]
+pub(crate) struct FlushStatus {
+    pub(crate) synced: bool,
+    pub(crate) dirty_pages: usize,
+    pub(crate) pending_ltx_files: usize,
+    pub(crate) local_pos: Option<ltx::Pos>,
+    pub(crate) remote_pos: Option<ltx::Pos>,
+}
+
+/// A point-in-time copy of [Database] prefetch statistics.
+#[derive(Debug, serde::Serialize)]
+pub(crate) struct PrefetchStatsSnapshot {
+    pub(crate) prefetched: u64,
+    pub(crate) used: u64,
+    pub(crate) hit_ratio: f64,
+}
+
+/// Returns `true` if the error means LFSC no longer has the requested position.
+pub(crate) fn is_pos_mismatch(err: &io::Error) -> bool {
+    err.get_ref()
+        .and_then(|e| e.downcast_ref::<CodeError>())
+        .map_or(false, |code| code.code() == LITEVFS_IOERR_POS_MISMATCH)
+}
+
+// Returns the features of the database with the given header that LiteVFS
+// doesn't support, or supports only partially.
+fn check_header(page1: &[u8]) -> Vec<CompatIssue> {
+    const MAGIC: &[u8] = b"SQLite format 3\0";
+
+    if page1.len() < sqlite::HEADER_SIZE || !page1.starts_with(MAGIC) {
+        return vec![CompatIssue::new(
+            "header",
+            Blocks::Read,
+            "not an SQLite database",
+        )];
+    }
+
+    let mut issues = Vec::new();
+    if let Err(err) = Database::parse_page_size_database(page1) {
+        issues.push(CompatIssue::new("page_size", Blocks::Read, err.to_string()));
+    }
+    if Database::parse_wal(page1) {
+        issues.push(CompatIssue::new(
+            "journal_mode",
+            Blocks::Write,
+            "the database is in WAL mode, switch it to journal_mode=delete to modify it",
+        ));
+    }
+    if Database::parse_autovacuum(page1).unwrap_or(false) {
+        issues.push(CompatIssue::new(
+            "auto_vacuum",
+            Blocks::Write,
+            "auto_vacuum is enabled, disable it and run VACUUM to modify the database",
+        ));
+    }
+    if u32::from_be_bytes(page1[64..68].try_into().unwrap()) > 0 {
+        issues.push(CompatIssue::new(
+            "incremental_vacuum",
+            Blocks::Write,
+            "incremental vacuum is enabled, the database can only shrink via VACUUM",
+        ));
+    }
+    let reserved = Database::parse_reserved_bytes(page1);
+    if reserved > 0 {
+        issues.push(CompatIssue::new(
+            "reserved_bytes",
+            Blocks::Warning,
+            format!(
+                "{} bytes are reserved at the end of each page, extensions using them must be loaded by all clients",
+                reserved
+            ),
+        ));
+    }
+
+    issues
+}
+
+// Validates that the page size is a power of two between 512 and 65536.
+fn parse_page_size(page_size: u32) -> io::Result<ltx::PageSize> {
+    if !(512..=65536).contains(&page_size) || !page_size.is_power_of_two() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("invalid page size: {}", page_size),
+        ));
+    }
+
+    ltx::PageSize::new(page_size).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+// Updates the database checksum with a page changing from `prev` to `next` checksum.
+// `None` means that the page didn't exist before or has been removed.
+fn update_checksum(checksum: u64, prev: Option<ltx::Checksum>, next: Option<ltx::Checksum>) -> u64 {
+    let mut checksum = checksum;
+    if let Some(prev) = prev {
+        checksum ^= prev.into_inner();
+    }
+    if let Some(next) = next {
+        checksum ^= next.into_inner();
+    }
+
+    checksum
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        update_checksum, Blocks, Database, DatabaseManager, LtxBuffer, MAX_IN_MEMORY_LTX_SIZE,
+    };
+    use crate::{
+        builder::EnvSettings,
+        leaser::{Leaser, DEFAULT_LEASE_DURATION, DEFAULT_LEASE_REFRESH_FRACTION},
+        lfsc,
+        pager::{PageRef, PageSource, Pager, PagerBackend},
+        sqlite,
+        syncer::{Changes, Syncer, DEFAULT_SYNC_JITTER, DEFAULT_SYNC_PERIOD},
+        testutil::TempDir,
+    };
+    use litetx::{self as ltx, PageChecksum};
+    use std::{
+        collections::BTreeSet,
+        fs, io,
+        path::Path,
+        sync::{atomic::Ordering, Arc, RwLock},
+    };
+
+    fn pgno(n: u32) -> ltx::PageNum {
+        ltx::PageNum::new(n).unwrap()
+    }
+
+    // Returns page 1 of a database of two 4096 bytes pages.
+    fn page1() -> Vec<u8> {
+        let mut page1 = vec![0; 4096];
+        page1[16..18].copy_from_slice(&4096u16.to_be_bytes());
+        page1[28..32].copy_from_slice(&2u32.to_be_bytes());
+
+        page1
+    }
+
+    // Opens a database with the given pages already cached.
+    fn open_database(root: &Path, pos: Option<ltx::Pos>, pages: &[&[u8]]) -> Database {
+        open_database_with(root, lfsc::Client::builder().build(), pos, pages)
+    }
+
+    // Same as `open_database`, talking to LFSC with the `client`.
+    fn open_database_with(
+        root: &Path,
+        client: lfsc::Client,
+        pos: Option<ltx::Pos>,
+        pages: &[&[u8]],
+    ) -> Database {
+        let client = Arc::new(client);
+        let pager = Arc::new(Pager::new(
+            root,
+            Arc::clone(&client),
+            PagerBackend::Files,
+            &EnvSettings::default(),
+        ));
+        pager.prepare_db("db").expect("prepare_db");
+        for (n, page) in pages.iter().enumerate() {
+            pager
+                .put_page("db", PageRef::new(pgno(n as u32 + 1), page))
+                .expect("put_page");
+        }
+
+        Database::new(
+            "db",
+            pos,
+            pager,
+            Arc::clone(&client),
+            Leaser::new(
+                Arc::clone(&client),
+                DEFAULT_LEASE_DURATION,
+                DEFAULT_LEASE_REFRESH_FRACTION,
+                None,
+                false,
+            ),
+            Syncer::new(client, DEFAULT_SYNC_PERIOD, DEFAULT_SYNC_JITTER),
+            false,
+            false,
+        )
+        .expect("database")
+    }
+
+    #[test]
+    fn prune_idle() {
+        let root = TempDir::new("prune");
+        let db = open_database(&root, None, &[&page1(), &[2; 4096]]);
+        let manager = DatabaseManager::new(
+            Arc::clone(&db.pager),
+            Arc::clone(&db.client),
+            Arc::clone(&db.leaser),
+            Arc::clone(&db.syncer),
+            false,
+            &EnvSettings::default(),
+        );
+        let db = Arc::new(RwLock::new(db));
+        manager.databases[0]
+            .write()
+            .unwrap()
+            .insert("db".into(), Arc::clone(&db));
+
+        let prune_idle = || DatabaseManager::prune_idle(&manager.databases[..], &manager.pager);
+
+        // Open databases are kept
+        assert_eq!(0, prune_idle());
+        drop(db);
+
+        // So are the databases with cached pages
+        assert_eq!(0, prune_idle());
+        assert_eq!(1, manager.databases[0].read().unwrap().len());
+
+        // Until the cache evicts them
+        manager.pager.reclaim(u64::MAX).expect("reclaim");
+        assert_eq!(1, prune_idle());
+        assert!(manager.databases[0].read().unwrap().is_empty());
+    }
+
+    #[test]
+    fn sequential_read_ahead() {
+        let root = TempDir::new("read-ahead");
+        let mut page1 = page1();
+        page1[28..32].copy_from_slice(&100u32.to_be_bytes());
+        let db = open_database(&root, None, &[&page1, &[2; 4096], &[3; 4096]]);
+
+        // Pages 2 and 3 are cached, 4 is the first page to read ahead
+        let mut buf = vec![0; 4096];
+        db.read_at(&mut buf, 0, true, None).expect("read page 1");
+        db.read_at(&mut buf, 4096, true, None).expect("read page 2");
+        assert!(db.is_sequential(pgno(3)));
+        assert_eq!(
+            Some((4..=19).map(pgno).collect::<Vec<_>>()),
+            db.prefetch_pages(pgno(3), true)
+        );
+
+        // Going backwards is not a sequential scan
+        assert!(!db.is_sequential(pgno(2)));
+    }
+
+    #[test]
+    fn header_cache() {
+        let root = TempDir::new("header");
+        let mut page1 = page1();
+        page1[28..32].copy_from_slice(&2u32.to_be_bytes());
+        let db = open_database(&root, None, &[&page1, &[2; 4096]]);
+
+        // Page 1 is evicted, header reads are still served without fetching it
+        db.pager.del_page("db", pgno(1)).expect("del_page");
+        let mut header = [0; sqlite::HEADER_SIZE];
+        assert!(matches!(
+            db.read_at(&mut header, 0, true, None).expect("read header"),
+            PageSource::Local
+        ));
+        assert_eq!(page1[..sqlite::HEADER_SIZE], header);
+        let mut size = [0; 4];
+        db.read_at(&mut size, 28, true, None).expect("read size");
+        assert_eq!(2u32.to_be_bytes(), size);
+        assert_eq!(Some(pgno(2)), *db.committed_db_size.lock().unwrap());
+
+        // The rest of the page still needs the page itself
+        let mut buf = vec![0; 4096];
+        db.read_at(&mut buf, 0, true, None)
+            .expect_err("read page 1");
+    }
+
+    #[test]
+    fn max_tx_bytes() {
+        let root = TempDir::new("max-tx");
+        let mut db = open_database(&root, None, &[&page1(), &[2; 4096], &[3; 4096]]);
+        db.current_db_size = Some(pgno(3));
+        db.dirty_pages.insert(pgno(2), None);
+        db.dirty_pages.insert(pgno(3), None);
+
+        // Rejected before the lease is even looked up, let alone LFSC contacted
+        db.max_tx_bytes = Some(2 * 4096);
+        let err = db.commit_journal_inner(ltx::TXID::ONE).expect_err("commit");
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+        assert!(err.to_string().contains("litevfs_max_tx_bytes"), "{}", err);
+
+        db.max_tx_bytes = Some(3 * 4096);
+        let err = db.commit_journal_inner(ltx::TXID::ONE).expect_err("commit");
+        assert_eq!(io::ErrorKind::PermissionDenied, err.kind());
+    }
+
+    #[test]
+    fn prefetch_disabled() {
+        let root = TempDir::new("no-prefetch");
+        let mut page1 = page1();
+        page1[28..32].copy_from_slice(&100u32.to_be_bytes());
+        let mut db = open_database(&root, None, &[&page1, &[2; 4096], &[3; 4096]]);
+        db.set_prefetch_enabled(false);
+
+        // Neither the access pattern nor the read pages are looked at
+        let mut buf = vec![0; 4096];
+        for offset in [0, 4096, 8192] {
+            db.read_at(&mut buf, offset, true, None).expect("read page");
+        }
+        assert!(db.recent_reads.lock().unwrap().is_empty());
+        assert!(db.prefetch_pages.lock().unwrap().is_empty());
+
+        db.set_prefetch_enabled(true);
+        db.read_at(&mut buf, 0, true, None).expect("read page 1");
+        assert_eq!(1, db.recent_reads.lock().unwrap().len());
+    }
+
+    #[test]
+    fn page_size_64k() {
+        let root = TempDir::new("64k");
+        // Page size 65536 is stored as 1, the database spans past the lock page
+        let mut page1 = vec![0; 65536];
+        page1[16..18].copy_from_slice(&1u16.to_be_bytes());
+        page1[28..32].copy_from_slice(&16390u32.to_be_bytes());
+        let mut db = open_database(&root, None, &[&page1]);
+
+        let page_size = db.page_size().expect("page_size");
+        assert_eq!(65536, page_size.into_inner());
+        let lock_page = ltx::PageNum::lock_page(page_size);
+        assert_eq!(pgno(16385), lock_page);
+        let lock_offset = 1073741824;
+        assert_eq!(
+            lock_page,
+            db.page_num_for(lock_offset).expect("page_num_for")
+        );
+        assert_eq!(
+            pgno(16386),
+            db.page_num_for(lock_offset + 65536).expect("page_num_for")
+        );
+
+        // Header reads pick up the database size
+        let mut header = [0; sqlite::HEADER_SIZE];
+        db.read_at(&mut header, 0, false, None).expect("read_at");
+        assert_eq!(Some(pgno(16390)), *db.committed_db_size.lock().unwrap());
+        assert_eq!(16390 * 65536, db.size().expect("size"));
+
+        // Pages on both sides of the lock page round-trip, the lock page isn't dirty
+        for (n, fill) in [(16384, 4), (16385, 5), (16386, 6)] {
+            db.write_page(pgno(n), &[fill; 65536]).expect("write_page");
+        }
+        assert_eq!(vec![pgno(16384), pgno(16386)], db.dirty_page_numbers());
+        for (offset, fill) in [(lock_offset - 65536, 4), (lock_offset + 65536, 6)] {
+            let mut buf = vec![0; 65536];
+            db.read_at(&mut buf, offset, true, None).expect("read_at");
+            assert_eq!(vec![fill; 65536], buf);
+        }
+
+        // Read-ahead never asks LFSC for the lock page
+        assert!(!db.read_ahead_pages(pgno(16380)).contains(&lock_page));
+    }
+
+    #[test]
+    fn check_header() {
+        let features = |page1: &[u8]| {
+            super::check_header(page1)
+                .into_iter()
+                .map(|issue| (issue.feature, issue.blocks))
+                .collect::<Vec<_>>()
+        };
+
+        let mut page1 = page1();
+        page1[..16].copy_from_slice(b"SQLite format 3\0");
+        assert!(features(&page1).is_empty());
+
+        page1[sqlite::WRITE_VERSION_OFFSET] = 2;
+        page1[sqlite::RESERVED_BYTES_OFFSET] = 8;
+        page1[52..56].copy_from_slice(&3u32.to_be_bytes());
+        page1[64..68].copy_from_slice(&1u32.to_be_bytes());
+        assert_eq!(
+            vec![
+                ("journal_mode", Blocks::Write),
+                ("auto_vacuum", Blocks::Write),
+                ("incremental_vacuum", Blocks::Write),
+                ("reserved_bytes", Blocks::Warning),
+            ],
+            features(&page1)
+        );
+
+        assert_eq!(vec![("header", Blocks::Read)], features(&[0; 4096]));
+        assert_eq!(
+            serde_json::json!("warning"),
+            serde_json::to_value(Blocks::Warning).expect("json")
+        );
+    }
+
+    #[test]
+    fn open_databases_concurrently() {
+        use super::DatabaseManager;
+        use sqlite_vfs::OpenAccess;
+
+        let root = TempDir::new("manager");
+        let client = Arc::new(lfsc::Client::builder().build());
+        let pager = Arc::new(Pager::new(
+            &root,
+            Arc::clone(&client),
+            PagerBackend::Files,
+            &EnvSettings::default(),
+        ));
+        let pos = ltx::Pos {
+            txid: ltx::TXID::ONE,
+            post_apply_checksum: ltx::Checksum::new(0),
+        };
+        let names = (0..64).map(|n| format!("db{}", n)).collect::<Vec<_>>();
+        for name in &names {
+            pager.prepare_db(name).expect("prepare_db");
+            pager
+                .put_page(name, PageRef::new(pgno(1), &page1()))
+                .expect("put_page");
+            fs::write(
+                pager.db_path(name).join("pos"),
+                serde_json::to_vec(&pos).unwrap(),
+            )
+            .expect("write pos");
+        }
+
+        let manager = DatabaseManager::new(
+            Arc::clone(&pager),
+            Arc::clone(&client),
+            Leaser::new(
+                Arc::clone(&client),
+                DEFAULT_LEASE_DURATION,
+                DEFAULT_LEASE_REFRESH_FRACTION,
+                None,
+                false,
+            ),
+            Syncer::new(client, DEFAULT_SYNC_PERIOD, DEFAULT_SYNC_JITTER),
+            false,
+            &EnvSettings::default(),
+        );
+        let opened = std::thread::scope(|s| {
+            let threads = (0..8)
+                .map(|_| {
+                    s.spawn(|| {
+                        names
+                            .iter()
+                            .map(|name| {
+                                manager
+                                    .get_database(name, OpenAccess::Read)
+                                    .expect("get_database")
+                            })
+                            .collect::<Vec<_>>()
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            threads
+                .into_iter()
+                .map(|thread| thread.join().unwrap())
+                .collect::<Vec<_>>()
+        });
+
+        // All the threads got the same instances
+        for dbs in &opened[1..] {
+            for (db, first) in dbs.iter().zip(&opened[0]) {
+                assert!(Arc::ptr_eq(db, first));
+            }
+        }
+    }
+
+    #[test]
+    fn write_unchanged_page() {
+        let root = TempDir::new("unchanged");
+        let mut db = open_database(&root, None, &[&page1(), &[2; 4096]]);
+
+        db.write_page(pgno(2), &[2; 4096]).expect("write");
+        assert_eq!(0, db.dirty_page_count());
+
+        db.write_page(pgno(2), &[3; 4096]).expect("write");
+        db.write_page(pgno(2), &[3; 4096]).expect("write");
+        assert_eq!(
+            Some(&Some(vec![2; 4096].page_checksum(pgno(2)))),
+            db.dirty_pages.get(&pgno(2))
+        );
+        assert_eq!(1, db.dirty_page_count());
+    }
+
+    #[test]
+    fn import_file() {
+        let root = TempDir::new("import");
+        let path = root.join("import.db");
+        let page1 = page1();
+        let checksum = update_checksum(
+            page1.page_checksum(pgno(1)).into_inner(),
+            None,
+            Some(vec![2; 4096].page_checksum(pgno(2))),
+        );
+        let pos = ltx::Pos {
+            txid: ltx::TXID::ONE,
+            post_apply_checksum: ltx::Checksum::new(checksum),
+        };
+
+        // Only page 1 is cached
+        let mut db = open_database(&root, Some(pos), &[&page1]);
+
+        fs::write(&path, [page1.clone(), vec![3; 4096]].concat()).expect("write");
+        assert_eq!(
+            io::ErrorKind::InvalidData,
+            db.import_file(&path).expect_err("import").kind()
+        );
+        assert!(!db.pager.has_page("db", pgno(2)).expect("has_page"));
+
+        fs::write(&path, [page1, vec![2; 4096]].concat()).expect("write");
+        assert_eq!(1, db.import_file(&path).expect("import"));
+        assert!(db.pager.has_page("db", pgno(2)).expect("has_page"));
+    }
+
+    // Returns the position after the transaction `txid` of the test transactions.
+    fn tx_pos(txid: u64) -> ltx::Pos {
+        ltx::Pos {
+            txid: ltx::TXID::new(txid).unwrap(),
+            post_apply_checksum: ltx::Checksum::new(0x8000_0000_0000_0000 | txid),
+        }
+    }
+
+    // Encodes the transaction `txid` that sets the given pages to the fill bytes.
+    fn encode_tx(txid: u64, commit: u32, pages: &[(u32, u8)]) -> LtxBuffer {
+        let mut data = Vec::new();
+        let mut enc = ltx::Encoder::new(
+            &mut data,
+            &ltx::Header {
+                flags: ltx::HeaderFlags::empty(),
+                page_size: ltx::PageSize::new(4096).unwrap(),
+                commit: pgno(commit),
+                min_txid: tx_pos(txid).txid,
+                max_txid: tx_pos(txid).txid,
+                timestamp: std::time::SystemTime::now(),
+                pre_apply_checksum: Some(tx_pos(txid - 1).post_apply_checksum),
+            },
+        )
+        .expect("encoder");
+        for &(n, fill) in pages {
+            enc.encode_page(pgno(n), &[fill; 4096])
+                .expect("encode_page");
+        }
+        enc.finish(tx_pos(txid).post_apply_checksum)
+            .expect("finish");
+
+        LtxBuffer::Memory(io::Cursor::new(data))
+    }
+
+    #[test]
+    fn pending_transactions() {
+        let root = TempDir::new("pending");
+        let (pos, next) = (tx_pos(1), tx_pos(2));
+
+        let mut db = open_database(&root, Some(pos), &[&page1(), &[5; 4096]]);
+        db.queue_tx(&mut encode_tx(2, 2, &[(2, 5)]), next, &[pgno(2)])
+            .expect("queue_tx");
+        assert_eq!(Some(next), db.head_pos());
+        drop(db);
+
+        // The queue survives reopening, and evicted pages are restored from it
+        let mut db = open_database(&root, Some(pos), &[&page1()]);
+        db.syncer.open_conn("db", db.pos);
+        assert_eq!(Some(next), db.head_pos());
+        db.pager.del_page("db", pgno(2)).expect("del_page");
+        let mut buf = vec![0; 4096];
+        db.read_at(&mut buf, 4096, true, None).expect("read_at");
+        assert_eq!(vec![5; 4096], buf);
+
+        let err = lfsc::Error::Transport("offline".into());
+        assert!(!db
+            .complete_upload(next, next, Err(err))
+            .expect("complete_upload"));
+        assert_eq!(Some(pos), db.pos);
+
+        // Server errors are retried, other rejections keep the queue for later
+        let lfsc_err = |http_code| {
+            lfsc::Error::Lfsc(lfsc::LfscError {
+                http_code,
+                code: "error".into(),
+                error: "error".into(),
+            })
+        };
+        assert!(!db
+            .complete_upload(next, next, Err(lfsc_err(503)))
+            .expect("complete_upload"));
+        db.complete_upload(next, next, Err(lfsc_err(401)))
+            .expect_err("complete_upload");
+        assert_eq!(Some(next), db.head_pos());
+
+        assert!(db
+            .complete_upload(next, next, Ok(()))
+            .expect("complete_upload"));
+        assert_eq!(Some(next), db.pos);
+        assert!(db.pending.is_empty());
+        assert_eq!(0, fs::read_dir(&db.pending_path).expect("read_dir").count());
+
+        // Only a position mismatch discards the queue
+        let last = tx_pos(3);
+        db.queue_tx(&mut encode_tx(3, 2, &[(2, 6)]), last, &[pgno(2)])
+            .expect("queue_tx");
+        db.complete_upload(last, last, Err(lfsc::Error::PosMismatch(tx_pos(4))))
+            .expect_err("complete_upload");
+        assert!(db.pending.is_empty());
+        assert_eq!(Some(next), db.pos);
+    }
+
+    #[test]
+    fn sync_during_upload() {
+        let root = TempDir::new("sync-upload");
+        let mut db = open_database(&root, Some(tx_pos(1)), &[&page1(), &[7; 4096]]);
+        db.syncer.open_conn("db", db.pos);
+        db.queue_tx(&mut encode_tx(2, 2, &[(2, 6)]), tx_pos(2), &[pgno(2)])
+            .expect("queue_tx");
+        db.queue_tx(&mut encode_tx(3, 2, &[(2, 7)]), tx_pos(3), &[pgno(2)])
+            .expect("queue_tx");
+
+        // LFSC reports the first transaction before its upload completes
+        db.upload_running.store(true, Ordering::Release);
+        db.syncer.set_pos("db", Some(tx_pos(2)));
+        db.syncer
+            .put_changes("db", Changes::Pages(BTreeSet::from([pgno(2)])));
+        db.sync(false, false).expect("sync");
+        assert_eq!(Some(tx_pos(2)), db.pos);
+        assert_eq!(Some(tx_pos(3)), db.head_pos());
+        assert!(db.pager.has_page("db", pgno(2)).expect("has_page"));
+
+        // A transaction of somebody else invalidates the queue
+        let other = ltx::Pos {
+            txid: tx_pos(3).txid,
+            post_apply_checksum: ltx::Checksum::new(0x8000_0000_0000_00ff),
+        };
+        db.syncer.set_pos("db", Some(other));
+        db.syncer
+            .put_changes("db", Changes::Pages(BTreeSet::from([pgno(2)])));
+        db.sync(false, false).expect("sync");
+        assert_eq!(Some(other), db.pos);
+        assert!(db.pending.is_empty());
+        assert!(!db.pager.has_page("db", pgno(2)).expect("has_page"));
+    }
+
+    #[test]
+    #[cfg(not(target_os = "emscripten"))]
+    fn read_at_pos() {
+        use crate::{
+            pager::PageSource,
+            testutil::{mock_lfsc, Response},
+        };
+
+        let mut page1 = page1();
+        page1[28..32].copy_from_slice(&3u32.to_be_bytes());
+
+        // Only page 1 as of the first transaction is expected to be fetched
+        let (client, server) = mock_lfsc(Some(1), {
+            let page1 = page1.clone();
+            move |request| {
+                assert_eq!(Some(tx_pos(1).to_string()), request.query("pos"));
+                Response::pages([(1, &page1[..])])
+            }
+        });
+
+        let root = TempDir::new("read-at-pos");
+        let mut db = open_database_with(
+            &root,
+            client,
+            Some(tx_pos(1)),
+            &[&page1, &[2; 4096], &[3; 4096]],
+        );
+        db.syncer.open_conn("db", db.pos);
+
+        // Somebody else changes page 3
+        db.syncer.set_pos("db", Some(tx_pos(2)));
+        db.syncer
+            .put_changes("db", Changes::Pages(BTreeSet::from([pgno(1), pgno(3)])));
+        db.sync(false, false).expect("sync");
+        assert_eq!(Some(tx_pos(2)), db.pos);
+
+        // Page 2 hasn't changed since, so it's read from the cache
+        let mut buf = vec![0; 4096];
+        let source = db
+            .read_at_pos(&mut buf, 4096, true, tx_pos(1), None)
+            .expect("read_at_pos");
+        assert!(matches!(source, PageSource::Local));
+        assert_eq!(vec![2; 4096], buf);
+
+        // Page 1 is fetched once and kept for the position
+        for expected in [PageSource::Remote, PageSource::Local] {
+            let source = db
+                .read_at_pos(&mut buf, 0, false, tx_pos(1), None)
+                .expect("read_at_pos");
+            assert_eq!(
+                std::mem::discriminant(&expected),
+                std::mem::discriminant(&source)
+            );
+            assert_eq!(page1, buf);
+        }
+        assert_eq!(
+            3 * 4096,
+            db.size_at_pos(tx_pos(1), true).expect("size_at_pos")
+        );
+
+        // Page 3 has changed and isn't fetched by local-only reads
+        assert_eq!(
+            io::ErrorKind::WouldBlock,
+            db.read_at_pos(&mut buf, 2 * 4096, true, tx_pos(1), None)
+                .expect_err("read_at_pos")
+                .kind()
+        );
+        assert_eq!(
+            vec![vec![1]],
+            server
+                .join()
+                .unwrap()
+                .iter()
+                .map(|r| r.pgnos())
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn verify_cache() {
+        let root = TempDir::new("verify");
+        let db = open_database(&root, None, &[&page1(), &[2; 4096]]);
+
+        // Nothing to compare with before the database is uploaded
+        let database = RwLock::new(db);
+        assert_eq!(
+            0,
+            Database::verify_cache(&database, None).expect("verify_cache")
+        );
+
+        database.write().unwrap().dirty_pages.insert(pgno(2), None);
+        let err = Database::verify_cache(&database, Some(1)).expect_err("verify_cache");
+        assert_eq!(io::ErrorKind::Other, err.kind());
+    }
+
+    #[test]
+    fn combine_writes() {
+        use super::CombineWrites;
+
+        let root = TempDir::new("combine");
+        let mut db = open_database(&root, Some(tx_pos(1)), &[&page1(), &[5; 4096]]);
+        db.syncer.open_conn("db", db.pos);
+        db.set_combine_writes(Some(CombineWrites {
+            window: std::time::Duration::from_secs(3600),
+            max_bytes: 1024 * 1024,
+        }));
+        db.queue_tx(&mut encode_tx(2, 2, &[(2, 5)]), tx_pos(2), &[pgno(2)])
+            .expect("queue_tx");
+        db.queue_tx(
+            &mut encode_tx(3, 3, &[(2, 6), (3, 7)]),
+            tx_pos(3),
+            &[pgno(2), pgno(3)],
+        )
+        .expect("queue_tx");
+        assert!(db.combine_wait().is_some());
+
+        // The merged transaction has the latest version of every page
+        let txs = db.pending_to_merge().expect("pending_to_merge");
+        assert_eq!(2, txs.len());
+        let (last, path) =
+            Database::merge_pending(&db.name, &db.pending_path, &txs).expect("merge_pending");
+        assert_eq!(tx_pos(3), last);
+        let file = fs::File::open(&path).expect("open");
+        let (mut dec, header) = ltx::Decoder::new(io::BufReader::new(file)).expect("decoder");
+        assert_eq!(tx_pos(2).txid, header.min_txid);
+        assert_eq!(tx_pos(3).txid, header.max_txid);
+        assert_eq!(pgno(3), header.commit);
+        assert_eq!(
+            Some(tx_pos(1).post_apply_checksum),
+            header.pre_apply_checksum
+        );
+        let mut data = vec![0; 4096];
+        let mut pages = Vec::new();
+        while let Some(number) = dec.decode_page(&mut data).expect("decode_page") {
+            pages.push((number, data[0]));
+        }
+        assert_eq!(vec![(pgno(2), 6), (pgno(3), 7)], pages);
+        fs::remove_file(path).expect("remove");
+
+        // A full window is uploaded without waiting, merging only what fits into it
+        db.combine_writes.as_mut().unwrap().max_bytes = 0;
+        assert!(db.combine_wait().is_none());
+        assert_eq!(
+            vec![tx_pos(2)],
+            db.pending_to_merge()
+                .expect("pending_to_merge")
+                .into_iter()
+                .map(|(pos, _)| pos)
+                .collect::<Vec<_>>()
+        );
+
+        assert!(db
+            .complete_upload(tx_pos(2), tx_pos(3), Ok(()))
+            .expect("complete_upload"));
+        assert_eq!(Some(tx_pos(3)), db.pos);
+        assert!(db.pending.is_empty());
+        assert_eq!(0, fs::read_dir(&db.pending_path).expect("read_dir").count());
+    }
+
+    #[test]
+    fn checksum_after_shrink() {
+        let checksums = (1..=3)
+            .map(|n| vec![n as u8; 4096].page_checksum(ltx::PageNum::new(n).unwrap()))
+            .collect::<Vec<_>>();
+
+        let full = checksums.iter().fold(0, |checksum, &page| {
+            update_checksum(checksum, None, Some(page))
+        });
+        let shrunk = update_checksum(full, Some(checksums[2]), None);
+
+        assert_eq!(
+            checksums[0].into_inner() ^ checksums[1].into_inner(),
+            shrunk
+        );
+    }
+
+    #[test]
+    fn parse_page_size() {
+        let database = |page_size: u16| {
+            let mut page1 = vec![0; 100];
+            page1[16..18].copy_from_slice(&page_size.to_be_bytes());
+            Database::parse_page_size_database(&page1)
+        };
+        let journal = |page_size: u32| {
+            let mut hdr = vec![0; 28];
+            hdr[24..28].copy_from_slice(&page_size.to_be_bytes());
+            Database::parse_page_size_journal(&hdr)
+        };
+
+        assert_eq!(512, database(512).expect("512").into_inner());
+        assert_eq!(32768, database(32768).expect("32768").into_inner());
+        assert_eq!(65536, database(1).expect("1").into_inner());
+        assert!(database(1000).is_err());
+        assert!(database(256).is_err());
+
+        assert_eq!(512, journal(512).expect("512").into_inner());
+        assert_eq!(65536, journal(65536).expect("65536").into_inner());
+        assert!(journal(1000).is_err());
+        assert!(journal(131072).is_err());
+
+        let err = journal(1000).unwrap_err();
+        assert_eq!(std::io::ErrorKind::InvalidData, err.kind());
+        assert_eq!("invalid page size: 1000", err.to_string());
+    }
+
+    #[test]
+    fn ltx_buffer() {
+        use std::{
+            fs,
+            io::{Read, Seek, SeekFrom, Write},
+        };
+
+        let dir = TempDir::new("ltx");
+        fs::create_dir_all(&dir).expect("create dir");
+        let data = vec![0xaa; 4 * 1024 * 1024];
+
+        // A multi-MB transaction under the limit never touches the disk
+        let mut buf = LtxBuffer::new(dir.join("small.ltx"), data.len() as u64).expect("new");
+        buf.write_all(&data).expect("write");
+        assert_eq!(0, fs::read_dir(&dir).expect("read dir").count());
+
+        // Larger ones are spilled to disk
+        let mut file =
+            LtxBuffer::new(dir.join("large.ltx"), MAX_IN_MEMORY_LTX_SIZE + 1).expect("new");
+        file.write_all(&data).expect("write");
+        assert_eq!(1, fs::read_dir(&dir).expect("read dir").count());
+
+        // Both are replayable
+        for buf in [&mut buf, &mut file] {
+            assert_eq!(data.len() as u64, buf.len().expect("len"));
+            for _ in 0..2 {
+                let mut read = Vec::new();
+                buf.seek(SeekFrom::Start(0)).expect("seek");
+                buf.read_to_end(&mut read).expect("read");
+                assert_eq!(data, read);
+            }
+        }
+
+        buf.remove().expect("remove");
+        file.remove().expect("remove");
+        assert_eq!(0, fs::read_dir(&dir).expect("read dir").count());
+    }
+
+    #[test]
+    fn mem_journal() {
+        let root = TempDir::new("mem-journal");
+        let mut db = open_database(&root, None, &[&page1(), &[2; 4096]]);
+        db.journal_in_memory = true;
+        db.page_size = Mutex::new(None);
+
+        let mut hdr = vec![0; 28];
+        hdr[..8].copy_from_slice(&[0xd9, 0xd5, 0x05, 0xf9, 0x20, 0xa1, 0x63, 0xd7]);
+        hdr[24..28].copy_from_slice(&4096u32.to_be_bytes());
+
+        // No journal yet
+        assert_eq!(
+            io::ErrorKind::NotFound,
+            db.before_journal_write(&[0; 28], 0).unwrap_err().kind()
+        );
+
+        // Writing the header learns the page size, but doesn't commit
+        db.mem_journal = Some(Vec::new());
+        db.before_journal_write(&hdr, 0).expect("header");
+        assert_eq!(4096, db.page_size().expect("page size").into_inner());
+        db.mem_journal = Some(hdr);
+        db.dirty_pages.insert(pgno(2), None);
+        db.current_db_size = Some(pgno(2));
+
+        // Zeroing the header of a hot journal commits the transaction, which fails as
+        // there is no lease, dropping the dirty pages.
+        assert_eq!(
+            io::ErrorKind::PermissionDenied,
+            db.before_journal_write(&[0; 28], 0).unwrap_err().kind()
+        );
+        assert!(db.dirty_pages.is_empty());
+
+        // Zeroing an already invalid header is a rollback
+        db.mem_journal = Some(vec![0; 28]);
+        db.dirty_pages.insert(pgno(2), None);
+        db.before_journal_write(&[0; 28], 0).expect("rollback");
+        assert!(db.dirty_pages.is_empty());
+    }
+
+    #[test]
+    fn prefetched_pages_cap() {
+        use super::MAX_PREFETCHED_PAGES;
+
+        let root = TempDir::new("prefetched-cap");
+        let db = open_database(&root, None, &[&page1()]);
+        let pages = MAX_PREFETCHED_PAGES as u32 + 10;
+        db.remember_prefetched((1..=pages).map(pgno).collect());
+
+        let prefetched = db.prefetched_pages.lock().unwrap();
+        assert_eq!(MAX_PREFETCHED_PAGES, prefetched.len());
+        assert!(prefetched.contains(&pgno(pages)));
+        drop(prefetched);
+    }
+
+    #[test]
+    fn reclaim_during_write() {
+        let root = TempDir::new("reclaim-write");
+        let mut db = open_database(&root, None, &[&page1(), &[2; 4096]]);
+        db.journal_in_memory = true;
+        db.write_page(pgno(2), &[3; 4096]).expect("write_page");
+
+        // Uncommitted pages can't be refetched, so only the committed ones are evicted
+        assert_eq!(1, db.pager.reclaim(u64::MAX).expect("reclaim"));
+        assert!(!db.pager.has_page("db", pgno(1)).expect("has_page"));
+        assert!(db.pager.has_page("db", pgno(2)).expect("has_page"));
+
+        // Until the transaction is over
+        db.mem_journal = Some(vec![0; 28]);
+        db.commit_journal().expect("rollback");
+        assert_eq!(1, db.pager.reclaim(u64::MAX).expect("reclaim"));
+        assert!(!db.pager.has_page("db", pgno(2)).expect("has_page"));
+    }
+
+    #[test]
+    fn mem_journal_interrupted() {
+        let root = TempDir::new("mem-journal-crash");
+        let mut db = open_database(&root, None, &[&page1(), &[2; 4096]]);
+        db.journal_in_memory = true;
+
+        // Finished transactions leave nothing behind
+        db.write_page(pgno(2), &[3; 4096]).expect("write_page");
+        assert!(db.uncommitted_path.exists());
+        db.mem_journal = Some(vec![0; 28]);
+        db.commit_journal().expect("rollback");
+        assert!(!db.uncommitted_path.exists());
+
+        // The process crashes in the middle of a transaction
+        db.write_page(pgno(2), &[4; 4096]).expect("write_page");
+        let pager = Arc::clone(&db.pager);
+        drop(db);
+        assert!(pager.has_page("db", pgno(2)).expect("has_page"));
+
+        let db = open_database(&root, None, &[]);
+        assert!(!db.uncommitted_path.exists());
+        assert!(!db.pager.has_page("db", pgno(2)).expect("has_page"));
+    }
+
+    #[test]
+    #[cfg(not(target_os = "emscripten"))]
+    fn page_size_change() {
+        use crate::testutil::{mock_lfsc, Response};
+
+        // The database has been recreated upstream with 8192 bytes pages
+        let mut new_page1 = vec![0; 8192];
+        new_page1[16..18].copy_from_slice(&8192u16.to_be_bytes());
+        new_page1[28..32].copy_from_slice(&1u32.to_be_bytes());
+        let (client, _) = mock_lfsc(None, {
+            let new_page1 = new_page1.clone();
+            move |request| {
+                if request.pgnos().contains(&1) {
+                    Response::pages([(1, &new_page1[..])])
+                } else {
+                    Response::new(404)
+                }
+            }
+        });
+
+        let root = TempDir::new("page-size");
+        let mut db = open_database_with(&root, client, None, &[&page1()]);
+        assert_eq!(4096, db.page_size().expect("page size").into_inner());
+
+        let pos = ltx::Pos {
+            txid: ltx::TXID::new(2).unwrap(),
+            post_apply_checksum: ltx::Checksum::new(0x8000_0000_0000_0002),
+        };
+        db.syncer.open_conn("db", None);
+        db.syncer.set_pos("db", Some(pos));
+        db.syncer.put_changes("db", Changes::All);
+        db.sync(false, false).expect("sync");
+
+        // The sync itself doesn't fetch anything, the page size is derived again
+        // from the next read of page 1
+        assert_eq!(Some(pos), db.pos);
+        assert!(db.page_size().is_err());
+        let mut buf = vec![0; 16];
+        db.read_at(&mut buf, 24, false, None).expect("read_at");
+        assert_eq!(new_page1[24..40], buf[..]);
+        assert_eq!(8192, db.page_size().expect("page size").into_inner());
+        assert_eq!(8192, db.size().expect("size"));
+        let mut buf = vec![0; 8192];
+        db.read_at(&mut buf, 0, true, None).expect("read_at");
+        assert_eq!(new_page1, buf);
+    }
+
+    #[test]
+    #[cfg(not(target_os = "emscripten"))]
+    fn cache_in_background() {
+        use crate::{
+            pager::Page,
+            testutil::{mock_lfsc, serve_pages},
+        };
+        use std::{thread, time::Duration};
+
+        let (client, server) = mock_lfsc(Some(1), serve_pages(4096));
+
+        let root = TempDir::new("cache-background");
+        let mut page1 = page1();
+        page1[28..32].copy_from_slice(&4u32.to_be_bytes());
+        let db = open_database_with(&root, client, Some(tx_pos(1)), &[&page1]);
+
+        // Pages fetched before a sync may be stale
+        let stale = vec![Page::new(pgno(2), vec![9; 4096])];
+        assert_eq!(
+            0,
+            db.cache_fetched(tx_pos(2), stale).expect("cache_fetched")
+        );
+        assert!(!db.pager.has_page("db", pgno(2)).expect("has_page"));
+
+        let database = Arc::new(RwLock::new(db));
+        let task = Database::cache_in_background(&database).expect("cache_in_background");
+        while !task.progress().done {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let progress = task.progress();
+        assert_eq!(
+            (4, 4, None),
+            (progress.cached, progress.total, progress.error)
+        );
+        let db = database.read().unwrap();
+        for n in 2..=4 {
+            let page = db
+                .pager
+                .get_page("db", db.pos, pgno(n), None)
+                .expect("get_page");
+            assert_eq!(&[n as u8; 4096][..], page.as_ref());
+        }
+        assert_eq!(
+            vec![vec![2, 3, 4]],
+            server
+                .join()
+                .unwrap()
+                .iter()
+                .map(|r| r.pgnos())
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    #[cfg(not(target_os = "emscripten"))]
+    fn cache_in_background_cancelled() {
+        use crate::testutil::{serve, Response};
+        use std::{
+            thread,
+            time::{Duration, Instant},
+        };
+
+        // Asks to retry the first request later than the test is willing to wait
+        let (host, server) = serve(Some(1), |_| Response::new(429).header("Retry-After", "4"));
+        let client = lfsc::Client::builder()
+            .host(&host.parse().unwrap())
+            .retries(3)
+            .build();
+
+        let root = TempDir::new("cache-background-cancelled");
+        let mut page1 = page1();
+        page1[28..32].copy_from_slice(&4u32.to_be_bytes());
+        let db = open_database_with(&root, client, Some(tx_pos(1)), &[&page1]);
+
+        let database = Arc::new(RwLock::new(db));
+        let task = Database::cache_in_background(&database).expect("cache_in_background");
+        server.join().unwrap();
+
+        // The pending retry is abandoned right away
+        let cancelled = Instant::now();
+        task.cancel();
+        while !task.progress().done {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(cancelled.elapsed() < Duration::from_secs(2));
+
+        let progress = task.progress();
+        assert_eq!(
+            (0, true, None),
+            (progress.cached, progress.cancelled, progress.error)
+        );
+    }
+
+    #[test]
+    #[cfg(not(target_os = "emscripten"))]
+    fn refetch_in_background() {
+        use crate::testutil::{mock_lfsc, serve_pages};
+        use std::{thread, time::Duration};
+
+        let (client, server) = mock_lfsc(Some(1), serve_pages(4096));
+
+        // Page 2 was changed by a sync, page 5 is past the end of the database
+        let root = TempDir::new("refetch-background");
+        let mut page1 = page1();
+        page1[28..32].copy_from_slice(&4u32.to_be_bytes());
+        let db = open_database_with(&root, client, Some(tx_pos(1)), &[&page1]);
+        db.refetch_pages.lock().unwrap().extend([pgno(2), pgno(5)]);
+
+        let database = Arc::new(RwLock::new(db));
+        Database::refetch_in_background(&database);
+        while database
+            .read()
+            .unwrap()
+            .refetch_running
+            .load(Ordering::Acquire)
+        {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let db = database.read().unwrap();
+        assert!(db.refetch_pages.lock().unwrap().is_empty());
+        assert!(db.pager.has_page("db", pgno(2)).expect("has_page"));
+        assert!(!db.pager.has_page("db", pgno(3)).expect("has_page"));
+        assert!(!db.pager.has_page("db", pgno(5)).expect("has_page"));
+        assert_eq!(
+            vec![vec![2]],
+            server
+                .join()
+                .unwrap()
+                .iter()
+                .map(|r| r.pgnos())
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    #[cfg(not(target_os = "emscripten"))]
+    fn flush_status() {
+        use crate::testutil::{mock_lfsc, Response};
+
+        // LFSC is at the first transaction
+        let (client, _) = mock_lfsc(None, |_| {
+            Response::json(r#"{"pos":{"txid":"1","postApplyChecksum":"8000000000000001"}}"#)
+        });
+
+        let root = TempDir::new("flush-status");
+        let db = open_database_with(&root, client, Some(tx_pos(1)), &[&page1(), &[2; 4096]]);
+        db.syncer.open_conn("db", db.pos);
+        let database = RwLock::new(db);
+
+        let status = Database::flush_status(&database).expect("flush_status");
+        assert!(status.synced);
+        assert_eq!(Some(tx_pos(1)), status.remote_pos);
+
+        // The next one hasn't been uploaded yet
+        database
+            .write()
+            .unwrap()
+            .queue_tx(&mut encode_tx(2, 2, &[(2, 5)]), tx_pos(2), &[pgno(2)])
+            .expect("queue_tx");
+        let status = Database::flush_status(&database).expect("flush_status");
+        assert!(!status.synced);
+        assert_eq!(1, status.pending_ltx_files);
+        assert_eq!(Some(tx_pos(1)), status.remote_pos);
+    }
+
+    #[test]
+    #[cfg(not(target_os = "emscripten"))]
+    fn lease_owner() {
+        use crate::testutil::{mock_lfsc, Response};
+
+        // Every acquired lease gets a new ID, refreshes keep it
+        let mut acquired = 0;
+        let (client, _) = mock_lfsc(None, move |request| {
+            if request.line.starts_with("DELETE") {
+                return Response::new(200);
+            }
+            let id = request.query("id").unwrap_or_else(|| {
+                acquired += 1;
+                format!("lease{}", acquired)
+            });
+            Response::json(&format!(
+                r#"{{"id":"{}","expires_at":"2100-01-01T00:00:00Z"}}"#,
+                id
+            ))
+        });
+
+        let root = TempDir::new("lease-owner");
+        let db = open_database_with(&root, client, None, &[&page1(), &[2; 4096]]);
+        let database = RwLock::new(db);
+        let lease_id = || {
+            database
+                .read()
+                .unwrap()
+                .lease_info()
+                .expect("lease_info")
+                .map(|lease| lease.id)
+        };
+
+        // Only the connection that has acquired the lease releases it
+        database.read().unwrap().acquire_lease(1).expect("acquire");
+        let id = lease_id().expect("lease");
+        let released = |owner| {
+            database
+                .read()
+                .unwrap()
+                .release_owned_lease(owner)
+                .expect("release")
+                .map(|lease| lease.id)
+        };
+        assert_eq!(None, released(2));
+        assert_eq!(Some(id), released(1));
+        assert_eq!(None, lease_id());
+
+        // A force-released lease isn't owned anymore, so a lease acquired
+        // by somebody else later isn't released by the former owner
+        database.read().unwrap().acquire_lease(1).expect("acquire");
+        let id = lease_id().expect("lease");
+        Database::force_release_lease(&database, &id).expect("force release");
+        assert_eq!(None, lease_id());
+        database
+            .read()
+            .unwrap()
+            .leaser
+            .acquire_lease("db")
+            .expect("acquire");
+        let other = lease_id().expect("lease");
+        assert_ne!(id, other);
+        assert_eq!(None, released(1));
+        assert_eq!(Some(other), lease_id());
+    }
+
+    #[test]
+    #[cfg(not(target_os = "emscripten"))]
+    fn verify_cache_mismatch() {
+        use crate::testutil::{mock_lfsc, Response};
+
+        // Page 2 has changed upstream
+        let (client, _) = mock_lfsc(None, |request| {
+            let pages = request
+                .pgnos()
+                .into_iter()
+                .map(|pgno| (pgno, if pgno == 1 { page1() } else { vec![3; 4096] }))
+                .collect::<Vec<_>>();
+            Response::pages(pages.iter().map(|(pgno, data)| (*pgno, &data[..])))
+        });
+
+        let root = TempDir::new("verify-mismatch");
+        let db = open_database_with(&root, client, Some(tx_pos(1)), &[&page1(), &[2; 4096]]);
+        let database = RwLock::new(db);
+        assert_eq!(
+            1,
+            Database::verify_cache(&database, None).expect("verify_cache")
+        );
+
+        let db = database.read().unwrap();
+        assert!(db.pager.has_page("db", pgno(1)).expect("has_page"));
+        assert!(!db.pager.has_page("db", pgno(2)).expect("has_page"));
+    }
 }
@@ -1,7 +1,19 @@
-use crate::{lfsc, vfs::LiteVfs};
-use rand::distributions::{Alphanumeric, DistString};
+use crate::{
+    builder::{env_bool, Builder},
+    lfsc,
+    metrics::metrics,
+    vfs::LiteVfs,
+};
 use sqlite_vfs::{ffi, RegisterError};
-use std::{env, fs, process};
+use std::{
+    env,
+    ffi::{c_char, c_int},
+    fs, ptr,
+};
+
+#[cfg(not(target_os = "emscripten"))]
+static API: std::sync::atomic::AtomicPtr<ffi::sqlite3_api_routines> =
+    std::sync::atomic::AtomicPtr::new(ptr::null_mut());
 
 fn init_logger() {
     let target = if let Ok(filename) = env::var("LITEVFS_LOG_FILE") {
@@ -18,54 +30,97 @@ fn init_logger() {
         .ok();
 }
 
-fn prepare() -> Result<(lfsc::Client, String), Box<dyn std::error::Error + 'static>> {
-    let client = lfsc::Client::from_env()?;
+const DEFAULT_VFS_NAME: &str = "litevfs";
+
+// Returns the name to register the VFS under and the VFS configured from the environment.
+fn prepare() -> Result<(String, LiteVfs), Box<dyn std::error::Error + 'static>> {
+    let vfs_name = env::var("LITEVFS_VFS_NAME").unwrap_or(DEFAULT_VFS_NAME.to_string());
+    if vfs_name.is_empty() {
+        return Err("LITEVFS_VFS_NAME must not be empty".into());
+    }
+
+    let mut builder = Builder::new().client(lfsc::Client::from_env()?);
+
+    if let Ok(names) = env::var("LITEVFS_CLUSTERS") {
+        for name in names
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+        {
+            if name.contains(':') {
+                return Err(format!("invalid LITEVFS_CLUSTERS cluster name: {}", name).into());
+            }
+            builder = builder.add_cluster(name, lfsc::Client::from_env_cluster(Some(name))?);
+        }
+    }
+
+    if let Ok(cache_dir) = env::var("LITEVFS_CACHE_DIR") {
+        builder = builder.cache_dir(cache_dir);
+    }
+
+    if let Ok(backend) = env::var("LITEVFS_PAGER_BACKEND") {
+        builder = builder.pager_backend(backend.parse()?);
+    }
+
+    if let Ok(val) = env::var("LITEVFS_LEASE_DURATION") {
+        builder = builder.lease_duration(humantime::parse_duration(&val)?);
+    }
+
+    if let Ok(val) = env::var("LITEVFS_LEASE_REFRESH_FRACTION") {
+        builder = builder.lease_refresh_fraction(
+            val.parse()
+                .map_err(|_| format!("invalid LITEVFS_LEASE_REFRESH_FRACTION value: {}", val))?,
+        );
+    }
+
+    if let Ok(val) = env::var("LITEVFS_LEASE_IDLE_TIMEOUT") {
+        builder = builder.lease_idle_timeout(humantime::parse_duration(&val)?);
+    }
 
-    let cache_dir = env::var("LITEVFS_CACHE_DIR").unwrap_or(format!(
-        "/tmp/litevfs-{}-{}",
-        process::id(),
-        Alphanumeric.sample_string(&mut rand::thread_rng(), 8)
-    ));
-    fs::create_dir_all(&cache_dir)?;
+    let vfs = builder
+        .read_only(env_bool("LITEVFS_READ_ONLY")?)
+        .temp_in_memory(env_bool("LITEVFS_TEMP_IN_MEMORY")?)
+        .build()?;
 
-    Ok((client, cache_dir))
+    Ok((vfs_name, vfs))
 }
 
 #[no_mangle]
 #[cfg(not(target_os = "emscripten"))]
 #[allow(non_snake_case)]
 pub extern "C" fn sqlite3_litevfs_init(
-    _db: *mut ffi::sqlite3,
+    db: *mut ffi::sqlite3,
     pzErrMsg: *mut *mut std::ffi::c_char,
     pApi: *mut ffi::sqlite3_api_routines,
 ) -> std::ffi::c_int {
-    litevfs_init(pzErrMsg, pApi, false)
+    litevfs_init(db, pzErrMsg, pApi, false)
 }
 
 #[no_mangle]
 #[cfg(not(target_os = "emscripten"))]
 #[allow(non_snake_case)]
 pub extern "C" fn sqlite3_litevfs_init_default_vfs(
-    _db: *mut ffi::sqlite3,
+    db: *mut ffi::sqlite3,
     pzErrMsg: *mut *mut std::ffi::c_char,
     pApi: *mut ffi::sqlite3_api_routines,
 ) -> std::ffi::c_int {
-    litevfs_init(pzErrMsg, pApi, true)
+    litevfs_init(db, pzErrMsg, pApi, true)
 }
 
 #[cfg(not(target_os = "emscripten"))]
 #[allow(non_snake_case)]
 fn litevfs_init(
+    db: *mut ffi::sqlite3,
     pzErrMsg: *mut *mut std::ffi::c_char,
     pApi: *mut ffi::sqlite3_api_routines,
     as_default: bool,
 ) -> std::ffi::c_int {
-    use std::{ffi::CString, ptr};
+    use std::ffi::CString;
 
     init_logger();
 
     log::info!("registering LiteVFS");
-    let (client, cache_dir) = match prepare() {
+    let (vfs_name, vfs) = match prepare() {
         Ok(ret) => ret,
         Err(err) if !pzErrMsg.is_null() => {
             let msg = CString::new(err.to_string()).unwrap();
@@ -87,18 +142,37 @@ fn litevfs_init(
         }
     };
 
-    let code = match unsafe { sqlite_vfs::DynamicExtension::build(pApi) }.register(
-        "litevfs",
-        LiteVfs::new(cache_dir, client),
-        as_default,
-    ) {
+    let code = match unsafe { sqlite_vfs::DynamicExtension::build(pApi) }
+        .register(&vfs_name, vfs, as_default)
+    {
         Ok(_) => ffi::SQLITE_OK_LOAD_PERMANENTLY,
         Err(RegisterError::Nul(_)) => ffi::SQLITE_ERROR,
         Err(RegisterError::Register(code)) => code,
     };
-    log::debug!("register(litevfs) -> {}", code);
+    log::debug!("register({}) -> {}", vfs_name, code);
+    if code != ffi::SQLITE_OK_LOAD_PERMANENTLY {
+        return code;
+    }
 
-    code
+    // Register the functions for all future connections as well as the one
+    // that has loaded the extension.
+    API.store(pApi, std::sync::atomic::Ordering::Release);
+    let code = unsafe {
+        match (*pApi).auto_extension.unwrap()(Some(std::mem::transmute::<
+            RegisterFunctions,
+            unsafe extern "C" fn(),
+        >(register_functions)))
+        {
+            ffi::SQLITE_OK => register_functions(db, pzErrMsg, pApi),
+            code => code,
+        }
+    };
+    log::debug!("register_functions() -> {}", code);
+    if code != ffi::SQLITE_OK {
+        return code;
+    }
+
+    ffi::SQLITE_OK_LOAD_PERMANENTLY
 }
 
 #[no_mangle]
@@ -107,7 +181,7 @@ pub extern "C" fn sqlite3_wasm_extra_init(_unused: *const std::ffi::c_char) -> s
     init_logger();
 
     log::info!("registering LiteVFS");
-    let (client, cache_dir) = match prepare() {
+    let (vfs_name, vfs) = match prepare() {
         Ok(ret) => ret,
         Err(err) => {
             log::warn!("{}", err);
@@ -115,16 +189,144 @@ pub extern "C" fn sqlite3_wasm_extra_init(_unused: *const std::ffi::c_char) -> s
         }
     };
 
-    let code = match sqlite_vfs::LinkedExtension::build().register(
-        "litevfs",
-        LiteVfs::new(cache_dir, client),
-        true,
-    ) {
+    let code = match sqlite_vfs::LinkedExtension::build().register(&vfs_name, vfs, true) {
         Ok(_) => ffi::SQLITE_OK,
         Err(RegisterError::Nul(_)) => ffi::SQLITE_ERROR,
         Err(RegisterError::Register(code)) => code,
     };
-    log::debug!("register(litevfs) -> {}", code);
+    log::debug!("register({}) -> {}", vfs_name, code);
+    if code != ffi::SQLITE_OK {
+        return code;
+    }
+
+    let code = unsafe {
+        ffi::sqlite3_auto_extension(Some(std::mem::transmute::<
+            RegisterFunctions,
+            unsafe extern "C" fn(),
+        >(register_functions)))
+    };
+    log::debug!("register_functions() -> {}", code);
 
     code
 }
+
+type RegisterFunctions = unsafe extern "C" fn(
+    *mut ffi::sqlite3,
+    *mut *mut c_char,
+    *mut ffi::sqlite3_api_routines,
+) -> c_int;
+
+// Registers LiteVFS SQL functions on a database connection.
+unsafe extern "C" fn register_functions(
+    db: *mut ffi::sqlite3,
+    _err_msg: *mut *mut c_char,
+    _api: *mut ffi::sqlite3_api_routines,
+) -> c_int {
+    match create_function(
+        db,
+        b"litevfs_version\0",
+        FUNCTION_FLAGS | ffi::SQLITE_DETERMINISTIC,
+        litevfs_version,
+    ) {
+        ffi::SQLITE_OK => {
+            create_function(db, b"litevfs_metrics\0", FUNCTION_FLAGS, litevfs_metrics)
+        }
+        code => code,
+    }
+}
+
+// Returns the version of LiteVFS.
+unsafe extern "C" fn litevfs_version(
+    ctx: *mut ffi::sqlite3_context,
+    _argc: c_int,
+    _argv: *mut *mut ffi::sqlite3_value,
+) {
+    const VERSION: &str = concat!(env!("CARGO_PKG_VERSION"), "\0");
+
+    // A NULL destructor is SQLITE_STATIC, the string is never freed
+    result_text(ctx, VERSION.as_ptr() as *const c_char, None);
+}
+
+// Returns LiteVFS metrics in Prometheus text exposition format.
+unsafe extern "C" fn litevfs_metrics(
+    ctx: *mut ffi::sqlite3_context,
+    _argc: c_int,
+    _argv: *mut *mut ffi::sqlite3_value,
+) {
+    let metrics = std::ffi::CString::new(metrics().render()).unwrap_or_default();
+
+    // SQLITE_TRANSIENT makes SQLite copy the string before we free it
+    result_text(
+        ctx,
+        metrics.as_ptr(),
+        Some(std::mem::transmute::<
+            isize,
+            unsafe extern "C" fn(*mut std::ffi::c_void),
+        >(-1)),
+    );
+}
+
+type ScalarFunction =
+    unsafe extern "C" fn(*mut ffi::sqlite3_context, c_int, *mut *mut ffi::sqlite3_value);
+
+const FUNCTION_FLAGS: c_int = ffi::SQLITE_UTF8 | ffi::SQLITE_INNOCUOUS;
+
+#[cfg(not(target_os = "emscripten"))]
+unsafe fn create_function(
+    db: *mut ffi::sqlite3,
+    name: &[u8],
+    flags: c_int,
+    func: ScalarFunction,
+) -> c_int {
+    let api = API.load(std::sync::atomic::Ordering::Acquire);
+    (*api).create_function_v2.unwrap()(
+        db,
+        name.as_ptr() as *const c_char,
+        0,
+        flags,
+        ptr::null_mut(),
+        Some(func),
+        None,
+        None,
+        None,
+    )
+}
+
+#[cfg(not(target_os = "emscripten"))]
+unsafe fn result_text(
+    ctx: *mut ffi::sqlite3_context,
+    text: *const c_char,
+    destructor: Option<unsafe extern "C" fn(*mut std::ffi::c_void)>,
+) {
+    let api = API.load(std::sync::atomic::Ordering::Acquire);
+    (*api).result_text.unwrap()(ctx, text, -1, destructor)
+}
+
+#[cfg(target_os = "emscripten")]
+unsafe fn create_function(
+    db: *mut ffi::sqlite3,
+    name: &[u8],
+    flags: c_int,
+    func: ScalarFunction,
+) -> c_int {
+    ffi::sqlite3_create_function_v2(
+        db,
+        name.as_ptr() as *const c_char,
+        0,
+        flags,
+        ptr::null_mut(),
+        Some(func),
+        None,
+        None,
+        None,
+    )
+}
+
+#[cfg(target_os = "emscripten")]
+unsafe fn result_text(
+    ctx: *mut ffi::sqlite3_context,
+    text: *const c_char,
+    destructor: Option<unsafe extern "C" fn(*mut std::ffi::c_void)>,
+) {
+    ffi::sqlite3_result_text(ctx, text, -1, destructor)
+}
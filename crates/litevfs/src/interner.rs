@@ -0,0 +1,77 @@
+use std::collections::HashMap;
+
+/// A symbol standing for an interned string.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) struct Symbol(u64);
+
+/// A string interner that, unlike most of them, can forget strings. Symbols are never
+/// reused, so a symbol of a removed string that is still held somewhere resolves to
+/// nothing instead of to an unrelated string interned later.
+#[derive(Default)]
+pub(crate) struct Interner {
+    symbols: HashMap<String, Symbol>,
+    strings: HashMap<Symbol, String>,
+    next: u64,
+}
+
+impl Interner {
+    pub(crate) fn new() -> Interner {
+        Interner::default()
+    }
+
+    pub(crate) fn get_or_intern(&mut self, s: &str) -> Symbol {
+        if let Some(&sym) = self.symbols.get(s) {
+            return sym;
+        }
+
+        let sym = Symbol(self.next);
+        self.next += 1;
+        self.symbols.insert(s.to_string(), sym);
+        self.strings.insert(sym, s.to_string());
+
+        sym
+    }
+
+    pub(crate) fn get(&self, s: &str) -> Option<Symbol> {
+        self.symbols.get(s).copied()
+    }
+
+    pub(crate) fn resolve(&self, sym: Symbol) -> Option<&str> {
+        self.strings.get(&sym).map(String::as_str)
+    }
+
+    /// Forgets the string, returning its symbol if it's been interned.
+    pub(crate) fn remove(&mut self, s: &str) -> Option<Symbol> {
+        let sym = self.symbols.remove(s)?;
+        self.strings.remove(&sym);
+
+        Some(sym)
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.symbols.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Interner;
+
+    #[test]
+    fn remove() {
+        let mut interner = Interner::new();
+        let db1 = interner.get_or_intern("db1");
+        assert_eq!(db1, interner.get_or_intern("db1"));
+        assert_eq!(Some("db1"), interner.resolve(db1));
+
+        assert_eq!(Some(db1), interner.remove("db1"));
+        assert_eq!(0, interner.len());
+        assert_eq!(None, interner.resolve(db1));
+        assert_eq!(None, interner.get("db1"));
+
+        // Symbols of removed strings are never handed out again
+        let db2 = interner.get_or_intern("db2");
+        assert_ne!(db1, db2);
+        assert_ne!(db1, interner.get_or_intern("db1"));
+    }
+}
@@ -1,47 +1,140 @@
+/// Default duration of write leases.
+pub(crate) const DEFAULT_LEASE_DURATION: std::time::Duration = std::time::Duration::from_secs(1);
+/// Minimum duration of write leases. Leases are refreshed every third of their
+/// duration by default, so shorter leases are likely to expire between refreshes.
+pub(crate) const MIN_LEASE_DURATION: std::time::Duration = std::time::Duration::from_millis(500);
+/// Default fraction of the lease duration after which leases are refreshed.
+pub(crate) const DEFAULT_LEASE_REFRESH_FRACTION: f64 = 1.0 / 3.0;
+/// Minimum period of lease refreshes, so that a tiny refresh fraction doesn't make
+/// the refresher flood LFSC with requests.
+pub(crate) const MIN_LEASE_REFRESH_PERIOD: std::time::Duration =
+    std::time::Duration::from_millis(100);
+
 #[cfg(not(target_os = "emscripten"))]
 pub(crate) use native::Leaser;
 
 #[cfg(target_os = "emscripten")]
 pub(crate) use emscripten::Leaser;
 
+// A lease held by this instance along with the last time it's been used.
+struct HeldLease {
+    lease: crate::lfsc::Lease,
+    used_at: std::time::Instant,
+}
+
+impl HeldLease {
+    fn new(lease: crate::lfsc::Lease) -> HeldLease {
+        HeldLease {
+            lease,
+            used_at: std::time::Instant::now(),
+        }
+    }
+
+    fn is_idle(&self, idle_timeout: Option<std::time::Duration>) -> bool {
+        idle_timeout.map_or(false, |timeout| self.used_at.elapsed() >= timeout)
+    }
+}
+
+/// A lease held by this process, as returned by `Leaser::leases`.
+#[derive(Debug, serde::Serialize)]
+pub(crate) struct LeaseInfo {
+    /// The cluster of the database, `None` for the default one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) cluster: Option<String>,
+    pub(crate) db: String,
+    #[serde(flatten)]
+    pub(crate) lease: crate::lfsc::Lease,
+}
+
+// Returns the held leases ordered by the database name.
+fn lease_infos(leases: &std::collections::HashMap<String, HeldLease>) -> Vec<LeaseInfo> {
+    let mut infos = leases
+        .iter()
+        .map(|(db, held)| LeaseInfo {
+            cluster: None,
+            db: db.clone(),
+            lease: held.lease.clone(),
+        })
+        .collect::<Vec<_>>();
+    infos.sort_by(|a, b| a.db.cmp(&b.db));
+
+    infos
+}
+
+fn read_only_error() -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::ReadOnlyFilesystem,
+        "LiteVFS is in read-only mode",
+    )
+}
+
 #[cfg(not(target_os = "emscripten"))]
 mod native {
-    use crate::lfsc;
+    use super::{lease_infos, read_only_error, HeldLease, LeaseInfo};
+    use crate::{lfsc, metrics::metrics, Worker};
     use std::{
         collections::HashMap,
         io,
         sync::{Arc, Mutex},
-        thread,
     };
 
+    enum Message {
+        // The leases have changed, recalculate the next refresh.
+        Notify,
+        Shutdown,
+    }
+
     pub(crate) struct Leaser {
         client: Arc<lfsc::Client>,
-        leases: Mutex<HashMap<String, lfsc::Lease>>,
+        leases: Mutex<HashMap<String, HeldLease>>,
 
         duration: std::time::Duration,
-        notifier: crossbeam_channel::Sender<()>,
+        refresh_fraction: f64,
+        idle_timeout: Option<std::time::Duration>,
+        read_only: bool,
+        notifier: crossbeam_channel::Sender<Message>,
+        // The lease refresher, `None` once the leaser is shut down.
+        worker: Mutex<Option<Worker>>,
     }
 
     impl Leaser {
-        pub(crate) fn new(client: Arc<lfsc::Client>, duration: std::time::Duration) -> Arc<Leaser> {
+        pub(crate) fn new(
+            client: Arc<lfsc::Client>,
+            duration: std::time::Duration,
+            refresh_fraction: f64,
+            idle_timeout: Option<std::time::Duration>,
+            read_only: bool,
+        ) -> Arc<Leaser> {
             let (tx, rx) = crossbeam_channel::unbounded();
             let leaser = Arc::new(Leaser {
                 client,
                 leases: Mutex::new(HashMap::new()),
                 duration,
+                refresh_fraction,
+                idle_timeout,
+                read_only,
                 notifier: tx,
+                worker: Mutex::new(None),
             });
 
-            thread::spawn({
+            *leaser.worker.lock().unwrap() = Some(Worker::spawn({
                 let leaser = Arc::clone(&leaser);
 
                 move || leaser.run(rx)
-            });
+            }));
 
             leaser
         }
 
         pub(crate) fn acquire_lease(&self, db: &str) -> io::Result<()> {
+            if self.read_only {
+                return Err(read_only_error());
+            }
+            // Nobody would refresh the lease
+            if self.worker.lock().unwrap().is_none() {
+                return Err(io::Error::new(io::ErrorKind::Other, "leaser is shut down"));
+            }
+
             let lease = match self
                 .client
                 .acquire_lease(db, lfsc::LeaseOp::Acquire(self.duration))
@@ -56,15 +149,18 @@ mod native {
                 }
             };
 
-            self.leases.lock().unwrap().insert(db.into(), lease);
+            self.leases
+                .lock()
+                .unwrap()
+                .insert(db.into(), HeldLease::new(lease));
             self.notify();
 
             Ok(())
         }
 
         pub(crate) fn release_lease(&self, db: &str) -> io::Result<()> {
-            if let Some(lease) = self.leases.lock().unwrap().remove(db) {
-                match self.client.release_lease(db, lease) {
+            if let Some(held) = self.leases.lock().unwrap().remove(db) {
+                match self.client.release_lease(db, &held.lease.id) {
                     Ok(()) => {
                         log::debug!("[leaser] release_lease: db = {}", db);
                     }
@@ -79,24 +175,109 @@ mod native {
             Ok(())
         }
 
+        /// Returns the lease currently held for the `db`, if any.
+        pub(crate) fn lease_info(&self, db: &str) -> io::Result<Option<lfsc::Lease>> {
+            Ok(self
+                .leases
+                .lock()
+                .unwrap()
+                .get(db)
+                .map(|held| held.lease.clone()))
+        }
+
+        /// Returns all the leases held by this process.
+        pub(crate) fn leases(&self) -> Vec<LeaseInfo> {
+            lease_infos(&self.leases.lock().unwrap())
+        }
+
+        /// Releases the lease `id` of the `db`, even if it hasn't been acquired by this
+        /// process, e.g. a lease left behind by a crashed instance.
+        pub(crate) fn force_release_lease(&self, db: &str, id: &str) -> io::Result<()> {
+            if self.read_only {
+                return Err(read_only_error());
+            }
+
+            log::warn!("[leaser] force_release_lease: db = {}, lease = {}", db, id);
+            self.client.release_lease(db, id)?;
+
+            let mut leases = self.leases.lock().unwrap();
+            if leases.get(db).map_or(false, |held| held.lease.id == id) {
+                leases.remove(db);
+            }
+            drop(leases);
+            self.notify();
+
+            Ok(())
+        }
+
         pub(crate) fn get_lease(&self, db: &str) -> io::Result<String> {
+            if self.read_only {
+                return Err(read_only_error());
+            }
+
             self.leases
                 .lock()
                 .unwrap()
-                .get(db)
-                .map(|lease| lease.id.clone())
+                .get_mut(db)
+                .map(|held| {
+                    held.used_at = std::time::Instant::now();
+                    held.lease.id.clone()
+                })
                 .ok_or_else(|| io::Error::new(io::ErrorKind::PermissionDenied, "lease not found"))
         }
 
+        /// Stops refreshing the leases and releases all of them, so that other instances
+        /// don't have to wait for them to expire. Waits for at most `timeout` for the
+        /// refresher to stop.
+        pub(crate) fn shutdown(&self, timeout: std::time::Duration) -> io::Result<()> {
+            let worker = self.worker.lock().unwrap().take();
+            let stopped = match worker {
+                Some(worker) => {
+                    _ = self.notifier.send(Message::Shutdown);
+                    worker.join(timeout)
+                }
+                None => true,
+            };
+            if !stopped {
+                log::warn!("[leaser] shutdown: lease refresher didn't stop in time");
+            }
+
+            let dbs = self
+                .leases
+                .lock()
+                .unwrap()
+                .keys()
+                .cloned()
+                .collect::<Vec<_>>();
+            let mut ret = Ok(());
+            for db in dbs {
+                if let Err(err) = self.release_lease(&db) {
+                    metrics().record_error("release_lease", Some(&db), &err);
+                    ret = ret.and(Err(err));
+                }
+            }
+            ret?;
+
+            if !stopped {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "lease refresher didn't stop in time",
+                ));
+            }
+
+            Ok(())
+        }
+
         fn notify(&self) {
-            self.notifier.send(()).unwrap();
+            // The refresher is gone after a shutdown, there is nobody to notify
+            _ = self.notifier.send(Message::Notify);
         }
 
-        fn run(&self, rx: crossbeam_channel::Receiver<()>) {
+        fn run(&self, rx: crossbeam_channel::Receiver<Message>) {
             use crossbeam_channel::{after, select};
             use time::OffsetDateTime;
 
-            let min_period = self.duration / 3;
+            let min_period = self.duration.mul_f64(self.refresh_fraction);
 
             loop {
                 // TODO: we probably not gonna have a lot of leases, but might need to optimize later
@@ -104,15 +285,17 @@ mod native {
                     let leases = self.leases.lock().unwrap();
                     leases
                         .iter()
-                        .min_by_key(|(_, lease)| lease.expires_at)
-                        .map(|(db, lease)| (db.clone(), lease.clone()))
+                        .min_by_key(|(_, held)| held.lease.expires_at)
+                        .map(|(db, held)| (db.clone(), held.lease.clone()))
                 };
                 let (db, lease) = if let Some((db, lease)) = first {
                     (db, lease)
                 } else {
                     // No active leases, wait to get notified
-                    rx.recv().unwrap();
-                    continue;
+                    match rx.recv() {
+                        Ok(Message::Notify) => continue,
+                        Ok(Message::Shutdown) | Err(_) => return,
+                    }
                 };
 
                 let until_expires = lease.expires_at - OffsetDateTime::now_utc();
@@ -124,11 +307,29 @@ mod native {
                     };
 
                 select! {
-                recv(rx) -> _ => continue,
+                recv(rx) -> msg => match msg {
+                    Ok(Message::Notify) => continue,
+                    Ok(Message::Shutdown) | Err(_) => return,
+                },
                 recv(after(wait_for)) -> _ => {
-                    // Check if we are still holding the lease
-                    if !self.leases.lock().unwrap().contains_key(&db) {
-                        continue
+                    // Check if we are still holding the lease and if it's been used recently
+                    {
+                        let mut leases = self.leases.lock().unwrap();
+                        match leases.get(&db) {
+                            Some(held) if held.is_idle(self.idle_timeout) => {
+                                leases.remove(&db);
+                                drop(leases);
+
+                                log::info!("[leaser] releasing idle lease: db = {}, lease = {}", db, lease);
+                                if let Err(err) = self.client.release_lease(&db, &lease.id) {
+                                    log::warn!("[leaser] failed to release idle lease: db = {}: {}", db, err);
+                                    metrics().record_error("release_lease", Some(&db), &err);
+                                }
+                                continue
+                            },
+                            Some(_) => (),
+                            None => continue,
+                        }
                     }
 
                     // This can potentially reacquire a released lease, but since it won't be
@@ -136,14 +337,15 @@ mod native {
                     log::debug!("[leaser] refreshing lease: db = {}, lease = {}", db, lease);
                     match self.client.acquire_lease(&db, lfsc::LeaseOp::Refresh(&lease.id, self.duration)) {
                         Ok(lease) => {
-                            self.leases.lock().unwrap().entry(db).and_modify(|old_lease| *old_lease = lease);
+                            self.leases.lock().unwrap().entry(db).and_modify(|held| held.lease = lease);
                         },
                         Err(err) => {
                             log::warn!("[leaser] failed to refresh lease: db = {}, lease = {}: {}", db, lease, err);
+                            metrics().record_error("refresh_lease", Some(&db), &err);
                             // It's possible a new one has been acquired
                             let mut leases = self.leases.lock().unwrap();
                             match leases.get(&db) {
-                                Some(l) if l.id == lease.id => {
+                                Some(held) if held.lease.id == lease.id => {
                                     leases.remove(&db);
                                 },
                                 _ => (),
@@ -159,38 +361,293 @@ mod native {
 
 #[cfg(target_os = "emscripten")]
 mod emscripten {
+    use super::{lease_infos, read_only_error, HeldLease, LeaseInfo};
     use crate::lfsc;
-    use std::{io, sync::Arc};
+    use std::{
+        collections::HashMap,
+        io,
+        sync::{Arc, Mutex},
+    };
 
-    pub(crate) struct Leaser;
+    /// There are no background threads under Emscripten, so instead of refreshing
+    /// leases periodically, they are refreshed lazily when they are used and the
+    /// refresh fraction of their duration has passed.
+    pub(crate) struct Leaser {
+        client: Arc<lfsc::Client>,
+        leases: Mutex<HashMap<String, HeldLease>>,
+
+        duration: std::time::Duration,
+        refresh_fraction: f64,
+        idle_timeout: Option<std::time::Duration>,
+        read_only: bool,
+    }
 
     impl Leaser {
         pub(crate) fn new(
-            _client: Arc<lfsc::Client>,
-            _duration: std::time::Duration,
+            client: Arc<lfsc::Client>,
+            duration: std::time::Duration,
+            refresh_fraction: f64,
+            idle_timeout: Option<std::time::Duration>,
+            read_only: bool,
         ) -> Arc<Leaser> {
-            Arc::new(Leaser)
+            Arc::new(Leaser {
+                client,
+                leases: Mutex::new(HashMap::new()),
+                duration,
+                refresh_fraction,
+                idle_timeout,
+                read_only,
+            })
+        }
+
+        pub(crate) fn acquire_lease(&self, db: &str) -> io::Result<()> {
+            if self.read_only {
+                return Err(read_only_error());
+            }
+
+            let lease = match self
+                .client
+                .acquire_lease(db, lfsc::LeaseOp::Acquire(self.duration))
+            {
+                Ok(lease) => {
+                    log::debug!("[leaser] acquire_lease: db = {}: {}", db, lease);
+                    lease
+                }
+                Err(err) => {
+                    log::warn!("[leaser] acquire_lease: db = {}: {}", db, err);
+                    return Err(err.into());
+                }
+            };
+
+            self.leases
+                .lock()
+                .unwrap()
+                .insert(db.into(), HeldLease::new(lease));
+
+            Ok(())
         }
 
-        pub(crate) fn acquire_lease(&self, _db: &str) -> io::Result<()> {
-            Err(io::Error::new(
-                io::ErrorKind::Unsupported,
-                "lease management is not supported",
-            ))
+        pub(crate) fn release_lease(&self, db: &str) -> io::Result<()> {
+            if let Some(held) = self.leases.lock().unwrap().remove(db) {
+                match self.client.release_lease(db, &held.lease.id) {
+                    Ok(()) => {
+                        log::debug!("[leaser] release_lease: db = {}", db);
+                    }
+                    Err(err) => {
+                        log::warn!("[leaser] release_lease: db = {}: {}", db, err);
+                        return Err(err.into());
+                    }
+                };
+            }
+
+            Ok(())
         }
 
-        pub(crate) fn release_lease(&self, _db: &str) -> io::Result<()> {
-            Err(io::Error::new(
-                io::ErrorKind::Unsupported,
-                "lease management is not supported",
-            ))
+        /// Returns the lease currently held for the `db`, if any.
+        pub(crate) fn lease_info(&self, db: &str) -> io::Result<Option<lfsc::Lease>> {
+            Ok(self
+                .leases
+                .lock()
+                .unwrap()
+                .get(db)
+                .map(|held| held.lease.clone()))
         }
 
-        pub(crate) fn get_lease(&self, _db: &str) -> io::Result<String> {
-            Err(io::Error::new(
-                io::ErrorKind::Unsupported,
-                "lease management is not supported",
-            ))
+        /// Returns all the leases held by this process.
+        pub(crate) fn leases(&self) -> Vec<LeaseInfo> {
+            lease_infos(&self.leases.lock().unwrap())
         }
+
+        /// Releases the lease `id` of the `db`, even if it hasn't been acquired by this
+        /// process, e.g. a lease left behind by a crashed instance.
+        pub(crate) fn force_release_lease(&self, db: &str, id: &str) -> io::Result<()> {
+            if self.read_only {
+                return Err(read_only_error());
+            }
+
+            log::warn!("[leaser] force_release_lease: db = {}, lease = {}", db, id);
+            self.client.release_lease(db, id)?;
+
+            let mut leases = self.leases.lock().unwrap();
+            if leases.get(db).map_or(false, |held| held.lease.id == id) {
+                leases.remove(db);
+            }
+
+            Ok(())
+        }
+
+        /// Releases all the held leases, so that other instances don't have to wait for
+        /// them to expire.
+        pub(crate) fn shutdown(&self, _timeout: std::time::Duration) -> io::Result<()> {
+            let dbs = self
+                .leases
+                .lock()
+                .unwrap()
+                .keys()
+                .cloned()
+                .collect::<Vec<_>>();
+            let mut ret = Ok(());
+            for db in dbs {
+                ret = ret.and(self.release_lease(&db));
+            }
+
+            ret
+        }
+
+        pub(crate) fn get_lease(&self, db: &str) -> io::Result<String> {
+            if self.read_only {
+                return Err(read_only_error());
+            }
+
+            let mut leases = self.leases.lock().unwrap();
+            let held = leases.get_mut(db).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::PermissionDenied, "lease not found")
+            })?;
+
+            if held.is_idle(self.idle_timeout) {
+                let held = leases.remove(db).unwrap();
+                drop(leases);
+
+                log::info!(
+                    "[leaser] releasing idle lease: db = {}, lease = {}",
+                    db,
+                    held.lease
+                );
+                if let Err(err) = self.client.release_lease(db, &held.lease.id) {
+                    log::warn!(
+                        "[leaser] failed to release idle lease: db = {}: {}",
+                        db,
+                        err
+                    );
+                }
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "lease not found",
+                ));
+            }
+            held.used_at = std::time::Instant::now();
+            let lease = held.lease.clone();
+
+            let until_expires = lease.expires_at - time::OffsetDateTime::now_utc();
+            if !until_expires.is_negative()
+                && until_expires.unsigned_abs()
+                    > self.duration - self.duration.mul_f64(self.refresh_fraction)
+            {
+                return Ok(lease.id);
+            }
+
+            log::debug!("[leaser] refreshing lease: db = {}, lease = {}", db, lease);
+            match self
+                .client
+                .acquire_lease(db, lfsc::LeaseOp::Refresh(&lease.id, self.duration))
+            {
+                Ok(lease) => {
+                    let id = lease.id.clone();
+                    leases.insert(db.into(), HeldLease::new(lease));
+                    Ok(id)
+                }
+                Err(err) => {
+                    log::warn!(
+                        "[leaser] failed to refresh lease: db = {}, lease = {}: {}",
+                        db,
+                        lease,
+                        err
+                    );
+                    leases.remove(db);
+                    Err(err.into())
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Leaser;
+    use crate::testutil::{mock_lfsc, Response};
+    use std::{
+        sync::{Arc, Mutex},
+        time::{Duration, Instant},
+    };
+
+    #[test]
+    #[cfg(not(target_os = "emscripten"))]
+    fn refresh_fraction() {
+        // Records when the lease is acquired, refreshed and released
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let (client, server) = mock_lfsc(Some(3), {
+            let requests = Arc::clone(&requests);
+
+            move |request| {
+                requests
+                    .lock()
+                    .unwrap()
+                    .push((Instant::now(), request.query("id")));
+                if request.line.starts_with("DELETE") {
+                    return Response::new(200);
+                }
+                Response::json(r#"{"id":"lease1","expires_at":"2100-01-01T00:00:00Z"}"#)
+            }
+        });
+
+        let leaser = Leaser::new(Arc::new(client), Duration::from_secs(1), 0.6, None, false);
+        leaser.acquire_lease("db").expect("acquire_lease");
+        while requests.lock().unwrap().len() < 2 {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        leaser.shutdown(Duration::from_secs(1)).expect("shutdown");
+        server.join().unwrap();
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(
+            vec![None, Some("lease1"), Some("lease1")],
+            requests
+                .iter()
+                .map(|(_, id)| id.as_deref())
+                .collect::<Vec<_>>()
+        );
+        // Refreshed after 600ms rather than a third of the duration
+        let refreshed_after = requests[1].0 - requests[0].0;
+        assert!(
+            refreshed_after >= Duration::from_millis(550),
+            "{:?}",
+            refreshed_after
+        );
+    }
+
+    #[test]
+    #[cfg(not(target_os = "emscripten"))]
+    fn idle_timeout() {
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let (client, server) = mock_lfsc(Some(2), {
+            let requests = Arc::clone(&requests);
+
+            move |request| {
+                requests.lock().unwrap().push(request.query("id"));
+                if request.line.starts_with("DELETE") {
+                    return Response::new(200);
+                }
+                Response::json(r#"{"id":"lease1","expires_at":"2100-01-01T00:00:00Z"}"#)
+            }
+        });
+
+        // The lease is released at the first refresh, as it hasn't been used since
+        // it's been acquired
+        let leaser = Leaser::new(
+            Arc::new(client),
+            Duration::from_secs(1),
+            0.2,
+            Some(Duration::from_millis(100)),
+            false,
+        );
+        leaser.acquire_lease("db").expect("acquire_lease");
+        server.join().unwrap();
+
+        assert_eq!(
+            vec![None, Some("lease1".to_string())],
+            *requests.lock().unwrap()
+        );
+        assert!(leaser.lease_info("db").expect("lease_info").is_none());
+        leaser.shutdown(Duration::from_secs(1)).expect("shutdown");
     }
 }
@@ -1,6 +1,38 @@
-use crate::{http, IterLogger, OptionLogger, PositionsLogger};
+use crate::{
+    http, metrics::metrics, IterLogger, OptionLogger, PositionsLogger, LITEVFS_IOERR_AUTH,
+    LITEVFS_IOERR_CONFLICT, LITEVFS_IOERR_RATE_LIMITED, LITEVFS_IOERR_VALIDATION,
+};
 use litetx as ltx;
-use std::{collections::HashMap, env, fmt, io, sync};
+use sqlite_vfs::CodeError;
+use std::{
+    collections::HashMap,
+    env, fmt, io,
+    sync::{
+        self,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread, time,
+};
+
+// Timeout of a single attempt of a cancellable request, so that a cancelled
+// request doesn't wait on a stuck connection for long.
+const CANCELLABLE_ATTEMPT_TIMEOUT: time::Duration = time::Duration::from_secs(10);
+// How often the cancellation flag is checked while waiting between retries.
+const CANCEL_CHECK_PERIOD: time::Duration = time::Duration::from_millis(50);
+// Content type of the binary page format, which can be parsed as the pages arrive,
+// unlike the JSON one. See `read_page_frames`.
+pub(crate) const PAGES_CONTENT_TYPE: &str = "application/vnd.litefs.pages";
+// The maximum SQLite page size.
+const MAX_PAGE_FRAME_SIZE: usize = 65536;
+
+// Headers set by the client itself, custom headers with these names are ignored.
+const MANAGED_HEADERS: &[&str] = &[
+    "Accept-Encoding",
+    "Authorization",
+    "Content-Encoding",
+    "fly-force-instance-id",
+    "Litefs-Cluster-Id",
+];
 
 /// All possible errors returned by the LFSC client.
 #[derive(thiserror::Error, Debug)]
@@ -11,10 +43,14 @@ pub(crate) enum Error {
     PosMismatch(ltx::Pos),
     #[error("LFSC: {0}")]
     Lfsc(LfscError),
+    #[error("rate limited by LFSC")]
+    RateLimited(Option<time::Duration>),
     #[error("body: {0}")]
     Body(#[from] io::Error),
     #[error("environment: {0}")]
     Env(String),
+    #[error("request cancelled")]
+    Cancelled,
 }
 
 impl From<Error> for io::Error {
@@ -23,10 +59,27 @@ impl From<Error> for io::Error {
             Error::Transport(e) => io::Error::new(io::ErrorKind::Other, e),
             Error::PosMismatch(_) => io::Error::new(io::ErrorKind::InvalidData, e),
             Error::Lfsc(e) if e.http_code == 404 => io::Error::new(io::ErrorKind::NotFound, e),
-            Error::Lfsc(e) if e.http_code == 409 => io::Error::new(io::ErrorKind::AlreadyExists, e),
-            Error::Lfsc(e) => io::Error::new(io::ErrorKind::Other, e),
+            Error::Lfsc(e) => match e.http_code {
+                401 | 403 => {
+                    io::Error::new(io::ErrorKind::Other, CodeError::new(LITEVFS_IOERR_AUTH))
+                }
+                400 | 422 => io::Error::new(
+                    io::ErrorKind::Other,
+                    CodeError::new(LITEVFS_IOERR_VALIDATION),
+                ),
+                409 => io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    CodeError::new(LITEVFS_IOERR_CONFLICT),
+                ),
+                _ => io::Error::new(io::ErrorKind::Other, e),
+            },
+            Error::RateLimited(_) => io::Error::new(
+                io::ErrorKind::Other,
+                CodeError::new(LITEVFS_IOERR_RATE_LIMITED),
+            ),
             Error::Body(e) => e,
             Error::Env(s) => io::Error::new(io::ErrorKind::Other, s),
+            Error::Cancelled => io::Error::new(io::ErrorKind::Interrupted, e),
         }
     }
 }
@@ -37,7 +90,7 @@ impl From<serde_json::Error> for Error {
     }
 }
 
-type Result<T> = std::result::Result<T, Error>;
+pub(crate) type Result<T> = std::result::Result<T, Error>;
 
 #[derive(thiserror::Error, Debug)]
 pub(crate) struct LfscError {
@@ -82,6 +135,38 @@ pub(crate) struct Client {
     cluster: Option<String>,
     cluster_id: Option<String>,
     instance_id: sync::RwLock<Option<String>>,
+    headers: Vec<(String, String)>,
+    endpoints: Endpoints,
+    gzip_uploads: bool,
+    conditional_fetches: bool,
+    retry: RetryPolicy,
+}
+
+/// Paths of the LFSC endpoints, relative to the base path of the host. Can be
+/// overridden for gateways that expose the API under different routes.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Endpoints {
+    pub(crate) pos: String,
+    pub(crate) tx: String,
+    pub(crate) page: String,
+    pub(crate) db_sync: String,
+    pub(crate) sync: String,
+    pub(crate) lease: String,
+    pub(crate) info: String,
+}
+
+impl Default for Endpoints {
+    fn default() -> Self {
+        Endpoints {
+            pos: "/pos".into(),
+            tx: "/db/tx".into(),
+            page: "/db/page".into(),
+            db_sync: "/db/sync".into(),
+            sync: "/sync".into(),
+            lease: "/lease".into(),
+            info: "/info".into(),
+        }
+    }
 }
 
 /// A single database page fetched from LFSC.
@@ -92,6 +177,9 @@ pub(crate) struct Page {
     data: Vec<u8>,
     #[serde(rename = "pgno")]
     number: ltx::PageNum,
+    // Identifies the page at the position, only if conditional fetches are supported.
+    #[serde(default)]
+    etag: Option<String>,
 }
 
 impl Page {
@@ -100,6 +188,11 @@ impl Page {
         self.number
     }
 
+    /// Get the ETag of the page, if LFSC sent one.
+    pub(crate) fn etag(&self) -> Option<&str> {
+        self.etag.as_deref()
+    }
+
     /// Consume the page and return the underlying buffer.
     pub(crate) fn into_inner(self) -> Vec<u8> {
         self.data
@@ -160,7 +253,7 @@ impl<'a> fmt::Display for LeaseOp<'a> {
     }
 }
 
-#[derive(Debug, Clone, serde::Deserialize, PartialEq)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, PartialEq)]
 pub(crate) struct Lease {
     pub(crate) id: String,
     #[serde(with = "time::serde::rfc3339")]
@@ -182,13 +275,30 @@ impl Client {
     }
 
     pub(crate) fn from_env() -> Result<Client> {
-        let builder = Client::builder().token(
-            &env::var("LITEFS_CLOUD_TOKEN")
-                .map_err(|_| Error::Env("LITEFS_CLOUD_TOKEN env var is not set".into()))?,
-        );
-        let builder = match env::var("LITEFS_CLOUD_CLUSTER") {
-            Ok(cluster) => builder.cluster(&cluster),
-            Err(_) => builder,
+        Client::from_env_cluster(None)
+    }
+
+    /// Same as [Client::from_env], but for the given `cluster` instead of `LITEFS_CLOUD_CLUSTER`.
+    /// The token is taken from `LITEFS_CLOUD_TOKEN_<CLUSTER>` if set, e.g. `LITEFS_CLOUD_TOKEN_MY_CLUSTER`
+    /// for `my-cluster`, all the other settings are shared.
+    pub(crate) fn from_env_cluster(cluster: Option<&str>) -> Result<Client> {
+        let token = cluster
+            .and_then(|cluster| {
+                let suffix = cluster
+                    .to_uppercase()
+                    .replace(|c: char| !c.is_ascii_alphanumeric(), "_");
+                env::var(format!("LITEFS_CLOUD_TOKEN_{}", suffix)).ok()
+            })
+            .or_else(|| env::var("LITEFS_CLOUD_TOKEN").ok())
+            .ok_or_else(|| Error::Env("LITEFS_CLOUD_TOKEN env var is not set".into()))?;
+        let builder = Client::builder().token(&token);
+        let cluster = match cluster {
+            Some(cluster) => Some(cluster.to_string()),
+            None => env::var("LITEFS_CLOUD_CLUSTER").ok(),
+        };
+        let builder = match cluster {
+            Some(cluster) => builder.cluster(&cluster),
+            None => builder,
         };
         let builder = match env::var("LITEFS_CLOUD_HOST") {
             Ok(host) => builder.host(
@@ -198,16 +308,75 @@ impl Client {
             ),
             Err(_) => builder,
         };
+        let builder = match env::var("LITEFS_CLOUD_TIMEOUT") {
+            Ok(timeout) => builder.timeout(
+                humantime::parse_duration(&timeout)
+                    .map_err(|e| Error::Env(format!("LITEFS_CLOUD_TIMEOUT: {}", e)))?,
+            ),
+            Err(_) => builder,
+        };
+        let builder = match env::var("LITEFS_CLOUD_PROXY") {
+            Ok(proxy) => builder.proxy(
+                http::Proxy::new(&proxy)
+                    .map_err(|e| Error::Env(format!("LITEFS_CLOUD_PROXY: {}", e)))?,
+            ),
+            Err(_) => builder,
+        };
+        let builder = match env::var("LITEFS_CLOUD_CA_CERT") {
+            Ok(path) => builder.ca_cert(
+                http::CaCert::from_file(path)
+                    .map_err(|e| Error::Env(format!("LITEFS_CLOUD_CA_CERT: {}", e)))?,
+            ),
+            Err(_) => builder,
+        };
+        let mut builder = builder;
+        if let Ok(fingerprints) = env::var("LITEFS_CLOUD_CERT_FINGERPRINTS") {
+            for fingerprint in fingerprints.split(',').filter(|fp| !fp.trim().is_empty()) {
+                builder =
+                    builder.pin_certificate(http::Fingerprint::new(fingerprint).map_err(|e| {
+                        Error::Env(format!("LITEFS_CLOUD_CERT_FINGERPRINTS: {}", e))
+                    })?);
+            }
+        }
+        let builder = match env::var("LITEFS_CLOUD_HEADERS") {
+            Ok(headers) => parse_headers(&headers)?
+                .into_iter()
+                .fold(builder, |builder, (name, value)| {
+                    builder.header(name, value)
+                }),
+            Err(_) => builder,
+        };
+        let builder = match env::var("LITEFS_CLOUD_GZIP_UPLOADS").as_deref() {
+            Ok("true") | Ok("1") => builder.gzip_uploads(true),
+            Ok("false") | Ok("0") | Err(_) => builder,
+            Ok(val) => {
+                return Err(Error::Env(format!(
+                    "LITEFS_CLOUD_GZIP_UPLOADS: invalid value: {}",
+                    val
+                )))
+            }
+        };
+        let builder = match env::var("LITEFS_CLOUD_CONDITIONAL_FETCHES").as_deref() {
+            Ok("true") | Ok("1") => builder.conditional_fetches(true),
+            Ok("false") | Ok("0") | Err(_) => builder,
+            Ok(val) => {
+                return Err(Error::Env(format!(
+                    "LITEFS_CLOUD_CONDITIONAL_FETCHES: invalid value: {}",
+                    val
+                )))
+            }
+        };
+        let builder = match env::var("LITEFS_CLOUD_RETRIES") {
+            Ok(retries) => builder.retries(
+                retries
+                    .parse()
+                    .map_err(|e: std::num::ParseIntError| Error::Env(e.to_string()))?,
+            ),
+            Err(_) => builder,
+        };
 
         let mut client = builder.build();
-
-        // let info = client.info()?;
-        // client.set_cluster_id(if let Some(cluster_id) = info.cluster_id {
-        //     cluster_id
-        // } else {
-        //     Client::generate_cluster_id()
-        // });
-        client.set_cluster_id(Client::generate_cluster_id());
+        client.init_cluster_id();
 
         log::info!(
             "[lfsc] from_env: host = {}, cluster = {}, cluster_id = {}",
@@ -219,10 +388,40 @@ impl Client {
         Ok(client)
     }
 
+    /// Sets the cluster ID to the one reported by LFSC, or to a generated one if
+    /// LFSC doesn't know it.
+    pub(crate) fn init_cluster_id(&mut self) {
+        let cluster_id = match self.info() {
+            Ok(Info {
+                cluster_id: Some(cluster_id),
+            }) => cluster_id,
+            Ok(_) => Client::generate_cluster_id(),
+            Err(err) => {
+                log::warn!(
+                    "[lfsc] init_cluster_id: failed to get cluster info, generating cluster ID: {}",
+                    err
+                );
+                Client::generate_cluster_id()
+            }
+        };
+        self.set_cluster_id(cluster_id);
+    }
+
     pub(crate) fn set_cluster_id(&mut self, id: String) {
         self.cluster_id = Some(id)
     }
 
+    /// Returns the ID of the LFSC instance the requests are currently pinned to.
+    pub(crate) fn instance_id(&self) -> Option<String> {
+        self.instance_id.read().unwrap().clone()
+    }
+
+    /// Stops pinning the requests to the current LFSC instance, so that the next
+    /// request may be routed to a different one.
+    pub(crate) fn reset_instance_id(&self) {
+        self.instance_id.write().unwrap().take();
+    }
+
     pub(crate) fn generate_cluster_id() -> String {
         use rand::Rng;
 
@@ -247,7 +446,7 @@ impl Client {
     pub(crate) fn write_tx(
         &self,
         db: &str,
-        ltx: impl io::Read,
+        ltx: impl io::Read + io::Seek,
         ltx_len: u64,
         lease: &str,
     ) -> Result<()> {
@@ -258,7 +457,11 @@ impl Client {
             ltx_len
         );
 
-        match self.write_tx_inner(db, ltx, ltx_len, lease) {
+        let started = time::Instant::now();
+        let ret = self.write_tx_inner(db, ltx, ltx_len, lease);
+        metrics().record_write_tx(started.elapsed());
+
+        match ret {
             Err(err) => {
                 log::error!(
                     "[lfsc] write_tx: db = {}, lease = {}, ltx_len = {}: {}",
@@ -273,12 +476,34 @@ impl Client {
         }
     }
 
+    /// Fetches the pages at `pos`. Pending retries are abandoned as soon as `cancel`
+    /// is set.
     pub(crate) fn get_pages(
         &self,
         db: &str,
         pos: ltx::Pos,
         pgnos: &[ltx::PageNum],
+        cancel: Option<&AtomicBool>,
     ) -> Result<Vec<Page>> {
+        let mut pages = Vec::with_capacity(pgnos.len());
+        self.stream_pages(db, pos, pgnos, cancel, &mut |page| {
+            pages.push(page);
+            Ok(())
+        })?;
+
+        Ok(pages)
+    }
+
+    /// Same as [Client::get_pages], but passes the pages to `on_page` as they arrive,
+    /// if LFSC supports the binary page format, instead of buffering all of them.
+    pub(crate) fn stream_pages(
+        &self,
+        db: &str,
+        pos: ltx::Pos,
+        pgnos: &[ltx::PageNum],
+        cancel: Option<&AtomicBool>,
+        on_page: &mut dyn FnMut(Page) -> io::Result<()>,
+    ) -> Result<()> {
         log::debug!(
             "[lfsc] get_pages: db = {}, pos = {}, pgnos = {}",
             db,
@@ -286,7 +511,11 @@ impl Client {
             IterLogger(pgnos)
         );
 
-        match self.get_pages_inner(db, pos, pgnos) {
+        let started = time::Instant::now();
+        let ret = self.get_pages_inner(db, pos, pgnos, None, cancel, on_page);
+        metrics().record_get_pages(started.elapsed());
+
+        match ret {
             Err(err) => {
                 log::error!(
                     "[lfsc] get_pages: db = {}, pos = {}, pgnos = {}: {}",
@@ -297,11 +526,64 @@ impl Client {
                 );
                 Err(err)
             }
-            x => x,
+            Ok(_) => Ok(()),
         }
     }
 
-    #[allow(dead_code)]
+    /// Fetches the page at `pos`, unless it still matches the `etag` of a previously
+    /// fetched copy, in which case `None` is returned and the copy can be kept.
+    /// Pending retries are abandoned as soon as `cancel` is set.
+    pub(crate) fn get_page_if_none_match(
+        &self,
+        db: &str,
+        pos: ltx::Pos,
+        pgno: ltx::PageNum,
+        etag: &str,
+        cancel: Option<&AtomicBool>,
+    ) -> Result<Option<Page>> {
+        log::debug!(
+            "[lfsc] get_page_if_none_match: db = {}, pos = {}, pgno = {}, etag = {}",
+            db,
+            pos,
+            pgno,
+            etag
+        );
+
+        let mut page = None;
+        let started = time::Instant::now();
+        let ret = self.get_pages_inner(db, pos, &[pgno], Some(etag), cancel, &mut |p| {
+            page = Some(p);
+            Ok(())
+        });
+        metrics().record_get_pages(started.elapsed());
+
+        match ret {
+            Err(err) => {
+                log::error!(
+                    "[lfsc] get_page_if_none_match: db = {}, pos = {}, pgno = {}, etag = {}: {}",
+                    db,
+                    pos,
+                    pgno,
+                    etag,
+                    err
+                );
+                Err(err)
+            }
+            Ok(false) => Ok(None),
+            Ok(true) => page.map(Some).ok_or_else(|| {
+                Error::Body(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("page {} is missing from the response", pgno),
+                ))
+            }),
+        }
+    }
+
+    /// Returns true if page requests may be conditional.
+    pub(crate) fn conditional_fetches(&self) -> bool {
+        self.conditional_fetches
+    }
+
     pub(crate) fn info(&self) -> Result<Info> {
         log::debug!("[lfsc] info");
 
@@ -346,17 +628,12 @@ impl Client {
     }
 
     #[allow(dead_code)]
-    pub(crate) fn release_lease(&self, db: &str, lease: Lease) -> Result<()> {
-        log::debug!("[lfsc] release_lease: db = {}, lease = {}", db, lease.id);
+    pub(crate) fn release_lease(&self, db: &str, id: &str) -> Result<()> {
+        log::debug!("[lfsc] release_lease: db = {}, lease = {}", db, id);
 
-        match self.release_lease_inner(db, &lease) {
+        match self.release_lease_inner(db, id) {
             Err(err) => {
-                log::error!(
-                    "[lfsc] release_lease: db = {}, lease = {}: {}",
-                    db,
-                    lease.id,
-                    err
-                );
+                log::error!("[lfsc] release_lease: db = {}, lease = {}: {}", db, id, err);
                 Err(err)
             }
             x => x,
@@ -383,8 +660,7 @@ impl Client {
     }
 
     fn pos_map_inner(&self) -> Result<HashMap<String, Option<ltx::Pos>>> {
-        let mut u = self.host.clone();
-        u.set_path("/pos");
+        let u = self.endpoint(&self.endpoints.pos);
 
         #[derive(serde::Deserialize)]
         #[serde(transparent)]
@@ -400,39 +676,67 @@ impl Client {
     fn write_tx_inner(
         &self,
         db: &str,
-        ltx: impl io::Read,
+        mut ltx: impl io::Read + io::Seek,
         ltx_len: u64,
         lease: &str,
     ) -> Result<()> {
-        let mut u = self.host.clone();
-        u.set_path("/db/tx");
+        let mut u = self.endpoint(&self.endpoints.tx);
         u.query_pairs_mut().append_pair("db", db);
 
-        let req = self
-            .make_request("POST", u)
-            .set("Content-Length", &ltx_len.to_string())
-            .set("Lfsc-Lease-Id", lease);
-        let resp = self.process_response(req.send(ltx))?;
+        // Compressed while being sent, so the LTX file is never held in memory. The
+        // compressed size isn't known up front, so the body is sent chunked.
+        #[cfg(not(target_os = "emscripten"))]
+        if self.gzip_uploads {
+            return self.retry.run(self.retry.writes, || {
+                // rewind the LTX file in case this is a retry
+                ltx.rewind()?;
+
+                let req = self
+                    .make_request("POST", u.clone())
+                    .set("Content-Encoding", "gzip")
+                    .set("Lfsc-Lease-Id", lease);
+                let body = flate2::read::GzEncoder::new(&mut ltx, flate2::Compression::default());
+                let resp = self.process_response(time::Instant::now(), req.send(body))?;
+
+                io::copy(&mut resp.into_reader(), &mut io::sink()).ok();
+
+                Ok(())
+            });
+        }
+
+        self.retry.run(self.retry.writes, || {
+            // rewind the LTX file in case this is a retry
+            ltx.rewind()?;
+
+            let req = self
+                .make_request("POST", u.clone())
+                .set("Content-Length", &ltx_len.to_string())
+                .set("Lfsc-Lease-Id", lease);
+            let resp = self.process_response(time::Instant::now(), req.send(&mut ltx))?;
 
-        // consume the body (and ignore any errors) to reuse the connection
-        io::copy(&mut resp.into_reader(), &mut io::sink()).ok();
+            // consume the body (and ignore any errors) to reuse the connection
+            io::copy(&mut resp.into_reader(), &mut io::sink()).ok();
 
-        Ok(())
+            Ok(())
+        })
     }
 
+    // Returns false if LFSC responded that the page still matches `if_none_match`.
     fn get_pages_inner(
         &self,
         db: &str,
         pos: ltx::Pos,
         pgnos: &[ltx::PageNum],
-    ) -> Result<Vec<Page>> {
+        if_none_match: Option<&str>,
+        cancel: Option<&AtomicBool>,
+        on_page: &mut dyn FnMut(Page) -> io::Result<()>,
+    ) -> Result<bool> {
         #[derive(serde::Deserialize)]
         struct GetPageResponse {
             pages: Vec<Page>,
         }
 
-        let mut u = self.host.clone();
-        u.set_path("/db/page");
+        let mut u = self.endpoint(&self.endpoints.page);
         u.query_pairs_mut()
             .append_pair("db", db)
             .append_pair("pos", &pos.to_string())
@@ -445,20 +749,59 @@ impl Client {
                     .join(","),
             );
 
-        Ok(self.call::<GetPageResponse>("GET", u)?.pages)
+        // Errors while reading the body are never retried, so `on_page` doesn't get
+        // the same pages twice.
+        self.retry.run_cancellable(true, cancel, || {
+            let mut req = self.make_request("GET", u.clone()).set(
+                "Accept",
+                &format!("{}, application/json", PAGES_CONTENT_TYPE),
+            );
+            if cancel.is_some() {
+                req = req.timeout(CANCELLABLE_ATTEMPT_TIMEOUT);
+            }
+            if let Some(etag) = if_none_match {
+                req = req.set("If-None-Match", etag);
+            }
+            let resp = self.process_response(time::Instant::now(), req.call())?;
+            if resp.status() == 304 {
+                return Ok(false);
+            }
+
+            // The ETag of a single page response identifies the page
+            let etag = match pgnos {
+                [_] => resp.header("ETag").map(String::from),
+                _ => None,
+            };
+            let on_page = &mut |mut page: Page| {
+                if page.etag.is_none() {
+                    page.etag = etag.clone();
+                }
+                on_page(page)
+            };
+
+            let binary = resp.header("Content-Type").map_or(false, |ct| {
+                ct.split(';').next().unwrap_or("").trim() == PAGES_CONTENT_TYPE
+            });
+            if binary {
+                read_page_frames(resp.into_reader(), cancel, on_page)?;
+            } else {
+                for page in resp.into_json::<GetPageResponse>()?.pages {
+                    on_page(page)?;
+                }
+            }
+
+            Ok(true)
+        })
     }
 
-    #[allow(dead_code)]
     fn info_inner(&self) -> Result<Info> {
-        let mut u = self.host.clone();
-        u.set_path("/info");
+        let u = self.endpoint(&self.endpoints.info);
 
         self.call("GET", u)
     }
 
     fn sync_db_inner(&self, db: &str, pos: Option<ltx::Pos>) -> Result<Changes> {
-        let mut u = self.host.clone();
-        u.set_path("/db/sync");
+        let mut u = self.endpoint(&self.endpoints.db_sync);
         u.query_pairs_mut().append_pair("db", db);
         if let Some(pos) = pos {
             u.query_pairs_mut().append_pair("pos", &pos.to_string());
@@ -469,8 +812,7 @@ impl Client {
 
     #[allow(dead_code)]
     fn acquire_lease_inner(&self, db: &str, op: &LeaseOp) -> Result<Lease> {
-        let mut u = self.host.clone();
-        u.set_path("/lease");
+        let mut u = self.endpoint(&self.endpoints.lease);
         u.query_pairs_mut().append_pair("db", db);
         match op {
             LeaseOp::Acquire(duration) => u
@@ -487,27 +829,27 @@ impl Client {
     }
 
     #[allow(dead_code)]
-    fn release_lease_inner(&self, db: &str, lease: &Lease) -> Result<()> {
-        let mut u = self.host.clone();
-        u.set_path("/lease");
+    fn release_lease_inner(&self, db: &str, id: &str) -> Result<()> {
+        let mut u = self.endpoint(&self.endpoints.lease);
         u.query_pairs_mut()
             .append_pair("db", db)
-            .append_pair("id", &lease.id);
+            .append_pair("id", id);
 
-        let req = self.make_request("DELETE", u);
-        let resp = self.process_response(req.call())?;
-        // consume the body (and ignore any errors) to reuse the connection
-        io::copy(&mut resp.into_reader(), &mut io::sink()).ok();
+        self.retry.run(is_idempotent("DELETE"), || {
+            let req = self.make_request("DELETE", u.clone());
+            let resp = self.process_response(time::Instant::now(), req.call())?;
+            // consume the body (and ignore any errors) to reuse the connection
+            io::copy(&mut resp.into_reader(), &mut io::sink()).ok();
 
-        Ok(())
+            Ok(())
+        })
     }
 
     fn sync_inner(
         &self,
         positions: &HashMap<String, Option<ltx::Pos>>,
     ) -> Result<HashMap<String, Changes>> {
-        let mut u = self.host.clone();
-        u.set_path("/sync");
+        let u = self.endpoint(&self.endpoints.sync);
 
         #[derive(serde::Serialize)]
         #[serde(transparent)]
@@ -515,7 +857,7 @@ impl Client {
 
         #[derive(serde::Serialize)]
         struct SyncRequest<'a> {
-            positions: HashMap<&'a str, Helper>,
+            positions: &'a HashMap<&'a str, Helper>,
         }
 
         #[derive(serde::Deserialize)]
@@ -528,9 +870,18 @@ impl Client {
             .map(|(k, &v)| (k.as_str(), Helper(v)))
             .collect();
 
-        let req = self.make_request("POST", u);
-        let resp = self.process_response(req.send_json(SyncRequest { positions }))?;
-        let resp = resp.into_json::<SyncResponse>()?;
+        // Sync doesn't modify any state on LFSC, so it's safe to retry
+        let resp = self.retry.run(true, || {
+            let req = self.make_request("POST", u.clone());
+            let resp = self.process_response(
+                time::Instant::now(),
+                req.send_json(SyncRequest {
+                    positions: &positions,
+                }),
+            )?;
+
+            Ok(resp.into_json::<SyncResponse>()?)
+        })?;
 
         Ok(resp
             .changes
@@ -543,10 +894,21 @@ impl Client {
     where
         R: serde::de::DeserializeOwned,
     {
-        let req = self.make_request(method, u);
-        let resp = self.process_response(req.call())?;
+        self.retry.run(is_idempotent(method), || {
+            let req = self.make_request(method, u.clone());
+            let resp = self.process_response(time::Instant::now(), req.call())?;
+
+            Ok(resp.into_json()?)
+        })
+    }
+
+    // Returns the URL of the endpoint at `path`, relative to the base path of the host.
+    fn endpoint(&self, path: &str) -> url::Url {
+        let mut u = self.host.clone();
+        let base = self.host.path().trim_end_matches('/');
+        u.set_path(&format!("{}{}", base, path));
 
-        Ok(resp.into_json()?)
+        u
     }
 
     fn make_request(&self, method: &str, mut u: url::Url) -> http::Request {
@@ -555,6 +917,16 @@ impl Client {
         }
 
         let mut req = self.client.request(method, &u);
+        for (name, value) in &self.headers {
+            if !MANAGED_HEADERS
+                .iter()
+                .any(|managed| managed.eq_ignore_ascii_case(name))
+            {
+                req = req.set(name, value);
+            }
+        }
+
+        req = req.set("Accept-Encoding", "gzip, deflate");
         if let Some(ref token) = self.token {
             req = req.set("Authorization", token);
         }
@@ -570,8 +942,11 @@ impl Client {
 
     fn process_response(
         &self,
+        started: time::Instant,
         resp: std::result::Result<http::Response, http::Error>,
     ) -> Result<http::Response> {
+        metrics().record_lfsc_request(started.elapsed());
+
         match resp {
             Ok(resp) => {
                 let mut instance_id = self.instance_id.write().unwrap();
@@ -582,6 +957,9 @@ impl Client {
                 Ok(resp)
             }
             Err(http::Error::Transport(err)) => Err(Error::Transport(err)),
+            Err(http::Error::Status(429, body)) => Err(Error::RateLimited(
+                body.header("Retry-After").and_then(parse_retry_after),
+            )),
             Err(http::Error::Status(code, body)) => {
                 let repr: LfscErrorRepr = body.into_json()?;
                 match repr.pos {
@@ -597,19 +975,237 @@ impl Client {
     }
 }
 
-#[allow(dead_code)]
-#[derive(serde::Deserialize)]
+// Reads pages in the binary format: a sequence of frames, each one being a big-endian
+// u32 page number, a big-endian u32 data length and the page data.
+fn read_page_frames(
+    reader: impl io::Read,
+    cancel: Option<&AtomicBool>,
+    on_page: &mut dyn FnMut(Page) -> io::Result<()>,
+) -> Result<()> {
+    use io::Read;
+
+    let invalid = |msg: &str| Error::Body(io::Error::new(io::ErrorKind::InvalidData, msg));
+
+    let mut reader = io::BufReader::new(reader);
+    let mut hdr = [0; 8];
+    loop {
+        if cancel.map_or(false, |cancel| cancel.load(Ordering::Acquire)) {
+            return Err(Error::Cancelled);
+        }
+
+        // The response ends at a frame boundary
+        let n = reader.read(&mut hdr[..1])?;
+        if n == 0 {
+            return Ok(());
+        }
+        reader.read_exact(&mut hdr[1..])?;
+
+        let number = u32::from_be_bytes(hdr[..4].try_into().unwrap());
+        let len = u32::from_be_bytes(hdr[4..].try_into().unwrap()) as usize;
+        if len > MAX_PAGE_FRAME_SIZE {
+            return Err(invalid("page frame is too large"));
+        }
+        let number = ltx::PageNum::new(number).map_err(|_| invalid("invalid page number"))?;
+
+        let mut data = vec![0; len];
+        reader.read_exact(&mut data)?;
+        on_page(Page {
+            data,
+            number,
+            etag: None,
+        })?;
+    }
+}
+
+#[derive(Debug, PartialEq, serde::Deserialize)]
 pub(crate) struct Info {
     #[serde(rename = "clusterID")]
     pub(crate) cluster_id: Option<String>,
 }
 
+// Parses the Retry-After header, which is either a number of seconds or an HTTP date.
+fn parse_retry_after(val: &str) -> Option<time::Duration> {
+    use ::time::{macros::format_description, OffsetDateTime, PrimitiveDateTime};
+
+    let val = val.trim();
+    if let Ok(secs) = val.parse() {
+        return Some(time::Duration::from_secs(secs));
+    }
+
+    let at = PrimitiveDateTime::parse(
+        val,
+        format_description!(
+            "[weekday repr:short], [day] [month repr:short] [year] [hour]:[minute]:[second] GMT"
+        ),
+    )
+    .ok()?
+    .assume_utc();
+
+    // A date in the past means the request can be retried right away
+    Some(
+        (at - OffsetDateTime::now_utc())
+            .try_into()
+            .unwrap_or_default(),
+    )
+}
+
+// Parses a comma-separated list of `name=value` headers.
+fn parse_headers(val: &str) -> Result<Vec<(&str, &str)>> {
+    val.split(',')
+        .map(str::trim)
+        .filter(|header| !header.is_empty())
+        .map(|header| match header.split_once('=') {
+            Some((name, value)) if !name.trim().is_empty() => Ok((name.trim(), value.trim())),
+            _ => Err(Error::Env(format!(
+                "LITEFS_CLOUD_HEADERS: invalid header: {}",
+                header
+            ))),
+        })
+        .collect()
+}
+
+#[cfg(not(target_os = "emscripten"))]
+fn is_idempotent(method: &str) -> bool {
+    matches!(method, "GET" | "HEAD" | "PUT" | "DELETE")
+}
+
+/// Retry policy for transport level errors.
+#[derive(Debug, Clone, Copy)]
+struct RetryPolicy {
+    retries: u32,
+    base_delay: time::Duration,
+    writes: bool,
+}
+
+impl RetryPolicy {
+    const DEFAULT_RETRIES: u32 = 3;
+    const DEFAULT_BASE_DELAY: time::Duration = time::Duration::from_millis(100);
+    /// Total time an operation may wait for LFSC to stop rate limiting it. Once the
+    /// next wait would exceed it, the operation fails with [Error::RateLimited] instead.
+    const RETRY_AFTER_BUDGET: time::Duration = time::Duration::from_secs(5);
+
+    /// Runs `f`, retrying it with exponential backoff on transport errors. Non-idempotent
+    /// operations are only retried when rate limited, as LFSC hasn't processed them.
+    fn run<T, F>(&self, idempotent: bool, f: F) -> Result<T>
+    where
+        F: FnMut() -> Result<T>,
+    {
+        self.run_cancellable(idempotent, None, f)
+    }
+
+    /// Same as [RetryPolicy::run], but gives up with [Error::Cancelled] as soon as
+    /// `cancel` is set, instead of waiting for the next attempt.
+    fn run_cancellable<T, F>(
+        &self,
+        idempotent: bool,
+        cancel: Option<&AtomicBool>,
+        mut f: F,
+    ) -> Result<T>
+    where
+        F: FnMut() -> Result<T>,
+    {
+        let retries = if idempotent { self.retries } else { 0 };
+        let cancelled = || cancel.map_or(false, |cancel| cancel.load(Ordering::Acquire));
+
+        let mut attempt = 0;
+        let mut rate_limited = time::Duration::ZERO;
+        loop {
+            if cancelled() {
+                return Err(Error::Cancelled);
+            }
+
+            match f() {
+                Err(Error::RateLimited(retry_after)) if attempt < self.retries => {
+                    let delay = retry_after.unwrap_or_else(|| self.backoff(attempt));
+                    if delay > RetryPolicy::RETRY_AFTER_BUDGET.saturating_sub(rate_limited) {
+                        log::warn!(
+                            "[lfsc] rate limited, retrying in {}ms exceeds the budget, giving up",
+                            delay.as_millis(),
+                        );
+                        return Err(Error::RateLimited(retry_after));
+                    }
+                    log::warn!(
+                        "[lfsc] rate limited, retrying in {}ms ({}/{})",
+                        delay.as_millis(),
+                        attempt + 1,
+                        self.retries,
+                    );
+
+                    RetryPolicy::sleep(delay, cancel);
+                    rate_limited += delay;
+                    attempt += 1;
+                }
+                Err(Error::Transport(err)) if attempt < retries => {
+                    let delay = self.backoff(attempt);
+                    log::warn!(
+                        "[lfsc] transport error, retrying in {}ms ({}/{}): {}",
+                        delay.as_millis(),
+                        attempt + 1,
+                        retries,
+                        err
+                    );
+
+                    RetryPolicy::sleep(delay, cancel);
+                    attempt += 1;
+                }
+                x => return x,
+            }
+        }
+    }
+
+    // Sleeps for `delay`, waking up early if `cancel` is set.
+    fn sleep(delay: time::Duration, cancel: Option<&AtomicBool>) {
+        let cancel = match cancel {
+            Some(cancel) => cancel,
+            None => return thread::sleep(delay),
+        };
+
+        let deadline = time::Instant::now() + delay;
+        while !cancel.load(Ordering::Acquire) {
+            let now = time::Instant::now();
+            if now >= deadline {
+                break;
+            }
+            thread::sleep((deadline - now).min(CANCEL_CHECK_PERIOD));
+        }
+    }
+
+    fn backoff(&self, attempt: u32) -> time::Duration {
+        use rand::Rng;
+
+        let delay = self.base_delay.saturating_mul(1 << attempt.min(16));
+        let jitter = rand::thread_rng().gen_range(0..=delay.as_millis() as u64 / 2);
+
+        delay + time::Duration::from_millis(jitter)
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            retries: RetryPolicy::DEFAULT_RETRIES,
+            base_delay: RetryPolicy::DEFAULT_BASE_DELAY,
+            writes: false,
+        }
+    }
+}
+
 /// A LiteFS Cloud client builder.
 #[derive(Default)]
 pub(crate) struct ClientBuilder {
     host: Option<url::Url>,
     token: Option<String>,
     cluster: Option<String>,
+    timeout: Option<time::Duration>,
+    proxy: Option<http::Proxy>,
+    ca_cert: Option<http::CaCert>,
+    fingerprints: Vec<http::Fingerprint>,
+    max_idle_connections_per_host: Option<usize>,
+    headers: Vec<(String, String)>,
+    endpoints: Endpoints,
+    gzip_uploads: bool,
+    conditional_fetches: bool,
+    retry: RetryPolicy,
 }
 
 impl ClientBuilder {
@@ -628,9 +1224,97 @@ impl ClientBuilder {
         self
     }
 
+    /// Sets the connect and read timeouts of LFSC requests.
+    pub(crate) fn timeout(mut self, timeout: time::Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Sends all LFSC requests through the given proxy. By default, the proxy
+    /// is taken from the standard `HTTPS_PROXY`/`HTTP_PROXY`/`ALL_PROXY` env vars.
+    pub(crate) fn proxy(mut self, proxy: http::Proxy) -> Self {
+        self.proxy = Some(proxy);
+        self
+    }
+
+    /// Verifies LFSC certificates against the given root certificates instead of
+    /// the default ones.
+    pub(crate) fn ca_cert(mut self, ca_cert: http::CaCert) -> Self {
+        self.ca_cert = Some(ca_cert);
+        self
+    }
+
+    /// Requires one of the certificates presented by LFSC to match the fingerprint.
+    /// Can be called multiple times to allow any of several certificates, e.g. while
+    /// rotating them.
+    pub(crate) fn pin_certificate(mut self, fingerprint: http::Fingerprint) -> Self {
+        self.fingerprints.push(fingerprint);
+        self
+    }
+
+    /// Adds a header sent with every LFSC request. Headers managed by the client,
+    /// like `Authorization`, can't be overridden.
+    pub(crate) fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Overrides the paths of the LFSC endpoints.
+    #[allow(dead_code)]
+    pub(crate) fn endpoints(mut self, endpoints: Endpoints) -> Self {
+        self.endpoints = endpoints;
+        self
+    }
+
+    /// Compresses uploaded LTX files with gzip. Not supported in browsers.
+    pub(crate) fn gzip_uploads(mut self, enabled: bool) -> Self {
+        self.gzip_uploads = enabled;
+        self
+    }
+
+    /// Revalidates pages kept across full resyncs with `If-None-Match`, instead of
+    /// downloading them again. Requires LFSC to send ETags of the pages.
+    pub(crate) fn conditional_fetches(mut self, enabled: bool) -> Self {
+        self.conditional_fetches = enabled;
+        self
+    }
+
+    /// Sets the maximum number of idle keep-alive connections to LFSC.
+    #[allow(dead_code)]
+    pub(crate) fn max_idle_connections_per_host(mut self, max_idle: usize) -> Self {
+        self.max_idle_connections_per_host = Some(max_idle);
+        self
+    }
+
+    /// Sets the number of retries on transport errors.
+    pub(crate) fn retries(mut self, retries: u32) -> Self {
+        self.retry.retries = retries;
+        self
+    }
+
+    /// Sets the initial delay between retries. The delay doubles with each attempt.
+    #[allow(dead_code)]
+    pub(crate) fn retry_delay(mut self, delay: time::Duration) -> Self {
+        self.retry.base_delay = delay;
+        self
+    }
+
+    /// Enables retries of non-idempotent operations, like writing transactions.
+    #[allow(dead_code)]
+    pub(crate) fn retry_writes(mut self, enabled: bool) -> Self {
+        self.retry.writes = enabled;
+        self
+    }
+
     pub(crate) fn build(self) -> Client {
         Client {
-            client: http::Client::new(),
+            client: http::Client::new(
+                self.timeout,
+                self.proxy,
+                self.max_idle_connections_per_host,
+                self.ca_cert,
+                self.fingerprints,
+            ),
             host: self
                 .host
                 .unwrap_or(url::Url::parse("https://litefs.fly.io").unwrap()),
@@ -638,6 +1322,11 @@ impl ClientBuilder {
             cluster: self.cluster,
             cluster_id: None,
             instance_id: sync::RwLock::new(None),
+            headers: self.headers,
+            endpoints: self.endpoints,
+            gzip_uploads: self.gzip_uploads && cfg!(not(target_os = "emscripten")),
+            conditional_fetches: self.conditional_fetches,
+            retry: self.retry,
         }
     }
 }
@@ -693,15 +1382,88 @@ mod option_pos {
 
 #[cfg(test)]
 mod tests {
-    use super::{Lease, Page};
+    use super::{Client, Error, Info, Lease, LfscError, Page, RetryPolicy};
     use litetx as ltx;
     use serde_test::{assert_de_tokens, Token};
+    use std::time;
+
+    #[test]
+    fn custom_headers() {
+        let client = Client::builder()
+            .token("token")
+            .header("X-Tenant-Id", "tenant")
+            .header("authorization", "other")
+            .build();
+        let req = client.make_request("GET", "https://litefs.fly.io/info".parse().unwrap());
+
+        assert_eq!(Some("tenant"), req.header("X-Tenant-Id"));
+        assert_eq!(Some("token"), req.header("Authorization"));
+
+        assert_eq!(
+            vec![("X-Tenant-Id", "tenant"), ("X-Trace", "a=b")],
+            super::parse_headers("X-Tenant-Id=tenant, X-Trace=a=b,").unwrap()
+        );
+        assert!(super::parse_headers("X-Tenant-Id").is_err());
+    }
+
+    #[test]
+    fn instance_id() {
+        let client = Client::builder().build();
+        assert_eq!(None, client.instance_id());
+
+        *client.instance_id.write().unwrap() = Some("instance1".into());
+        assert_eq!(Some("instance1".into()), client.instance_id());
+        let req = client.make_request("GET", "https://litefs.fly.io/info".parse().unwrap());
+        assert_eq!(Some("instance1"), req.header("fly-force-instance-id"));
+
+        client.reset_instance_id();
+        assert_eq!(None, client.instance_id());
+        let req = client.make_request("GET", "https://litefs.fly.io/info".parse().unwrap());
+        assert_eq!(None, req.header("fly-force-instance-id"));
+    }
+
+    #[test]
+    fn error_codes() {
+        use crate::{LITEVFS_IOERR_AUTH, LITEVFS_IOERR_CONFLICT, LITEVFS_IOERR_VALIDATION};
+        use sqlite_vfs::CodeError;
+        use std::io;
+
+        let code = |http_code| {
+            let err: io::Error = Error::Lfsc(LfscError {
+                http_code,
+                code: "ECODE".into(),
+                error: "error".into(),
+            })
+            .into();
+
+            (
+                err.kind(),
+                err.get_ref()
+                    .and_then(|e| e.downcast_ref::<CodeError>())
+                    .map(|code| code.code()),
+            )
+        };
+
+        assert_eq!((io::ErrorKind::Other, Some(LITEVFS_IOERR_AUTH)), code(401));
+        assert_eq!((io::ErrorKind::Other, Some(LITEVFS_IOERR_AUTH)), code(403));
+        assert_eq!(
+            (io::ErrorKind::Other, Some(LITEVFS_IOERR_VALIDATION)),
+            code(400)
+        );
+        assert_eq!(
+            (io::ErrorKind::AlreadyExists, Some(LITEVFS_IOERR_CONFLICT)),
+            code(409)
+        );
+        assert_eq!((io::ErrorKind::NotFound, None), code(404));
+        assert_eq!((io::ErrorKind::Other, None), code(500));
+    }
 
     #[test]
     fn page_de() {
         let page = Page {
             data: vec![1, 2, 3, 4, 5, 6],
             number: ltx::PageNum::new(123).unwrap(),
+            etag: None,
         };
 
         assert_de_tokens(
@@ -744,4 +1506,382 @@ mod tests {
             ],
         );
     }
+
+    #[test]
+    fn info_de() {
+        let info = Info {
+            cluster_id: Some("LFSC0123456789ABCDEF".into()),
+        };
+
+        assert_de_tokens(
+            &info,
+            &[
+                Token::Struct {
+                    name: "Info",
+                    len: 1,
+                },
+                Token::Str("clusterID"),
+                Token::Some,
+                Token::Str("LFSC0123456789ABCDEF"),
+                Token::StructEnd,
+            ],
+        );
+    }
+
+    #[test]
+    fn info_de_no_cluster_id() {
+        let info = Info { cluster_id: None };
+
+        assert_de_tokens(
+            &info,
+            &[
+                Token::Struct {
+                    name: "Info",
+                    len: 0,
+                },
+                Token::StructEnd,
+            ],
+        );
+    }
+
+    fn retry_policy(retries: u32) -> RetryPolicy {
+        RetryPolicy {
+            retries,
+            base_delay: time::Duration::from_millis(1),
+            writes: false,
+        }
+    }
+
+    #[test]
+    fn retry_transport_errors() {
+        let mut calls = 0;
+        let ret = retry_policy(3).run(true, || {
+            calls += 1;
+            if calls <= 2 {
+                Err(Error::Transport("connection reset".into()))
+            } else {
+                Ok(calls)
+            }
+        });
+
+        assert_eq!(3, ret.expect("retry"));
+    }
+
+    #[test]
+    fn retry_gives_up() {
+        let mut calls = 0;
+        let ret: super::Result<()> = retry_policy(2).run(true, || {
+            calls += 1;
+            Err(Error::Transport("connection reset".into()))
+        });
+
+        assert!(matches!(ret, Err(Error::Transport(_))));
+        assert_eq!(3, calls);
+    }
+
+    #[test]
+    fn retry_rate_limited() {
+        let mut calls = 0;
+        let ret = retry_policy(3).run(false, || {
+            calls += 1;
+            if calls < 3 {
+                Err(Error::RateLimited(Some(time::Duration::from_millis(1))))
+            } else {
+                Ok(calls)
+            }
+        });
+
+        assert_eq!(3, ret.expect("result"));
+    }
+
+    #[test]
+    fn retry_after_budget() {
+        // The second wait doesn't fit in what's left of the budget
+        let started = time::Instant::now();
+        let mut calls = 0;
+        let ret: super::Result<()> = retry_policy(3).run(false, || {
+            calls += 1;
+            let retry_after = if calls == 1 {
+                time::Duration::from_millis(1)
+            } else {
+                RetryPolicy::RETRY_AFTER_BUDGET
+            };
+            Err(Error::RateLimited(Some(retry_after)))
+        });
+
+        assert!(matches!(
+            ret,
+            Err(Error::RateLimited(Some(retry_after))) if retry_after == RetryPolicy::RETRY_AFTER_BUDGET
+        ));
+        assert_eq!(2, calls);
+        assert!(started.elapsed() < time::Duration::from_secs(1));
+    }
+
+    #[test]
+    fn retry_after() {
+        assert_eq!(
+            Some(time::Duration::from_secs(5)),
+            super::parse_retry_after(" 5 ")
+        );
+        assert_eq!(
+            Some(time::Duration::ZERO),
+            super::parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT")
+        );
+        assert_eq!(None, super::parse_retry_after("soon"));
+    }
+
+    #[test]
+    fn retry_cancelled() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        let policy = RetryPolicy {
+            base_delay: time::Duration::from_secs(10),
+            ..retry_policy(3)
+        };
+        let cancel = AtomicBool::new(false);
+        let started = time::Instant::now();
+        let mut calls = 0;
+        let ret: super::Result<()> = policy.run_cancellable(true, Some(&cancel), || {
+            calls += 1;
+            cancel.store(true, Ordering::Release);
+            Err(Error::Transport("connection reset".into()))
+        });
+
+        assert!(matches!(ret, Err(Error::Cancelled)));
+        assert_eq!(1, calls);
+        assert!(started.elapsed() < time::Duration::from_secs(1));
+    }
+
+    #[test]
+    fn retry_skips_non_idempotent() {
+        let mut calls = 0;
+        let ret: super::Result<()> = retry_policy(3).run(false, || {
+            calls += 1;
+            Err(Error::Transport("connection reset".into()))
+        });
+
+        assert!(ret.is_err());
+        assert_eq!(1, calls);
+    }
+
+    #[test]
+    fn page_frames() {
+        use super::read_page_frames;
+
+        let mut body = Vec::new();
+        for (pgno, fill) in [(1u32, 1u8), (3, 3)] {
+            body.extend_from_slice(&pgno.to_be_bytes());
+            body.extend_from_slice(&4096u32.to_be_bytes());
+            body.extend_from_slice(&[fill; 4096]);
+        }
+
+        let mut pages = Vec::new();
+        read_page_frames(&body[..], None, &mut |page| {
+            pages.push((page.number(), page.into_inner()));
+            Ok(())
+        })
+        .expect("read_page_frames");
+        assert_eq!(
+            vec![
+                (ltx::PageNum::new(1).unwrap(), vec![1; 4096]),
+                (ltx::PageNum::new(3).unwrap(), vec![3; 4096])
+            ],
+            pages
+        );
+
+        // Truncated frames and page number 0 are errors
+        let ret = read_page_frames(&body[..body.len() - 1], None, &mut |_| Ok(()));
+        assert!(matches!(ret, Err(Error::Body(_))));
+        let ret = read_page_frames(&[0; 8][..], None, &mut |_| Ok(()));
+        assert!(matches!(ret, Err(Error::Body(_))));
+    }
+
+    #[test]
+    #[cfg(not(target_os = "emscripten"))]
+    fn ca_cert() {
+        use super::http;
+        use std::{env::temp_dir, fs, io};
+
+        let path = temp_dir().join(format!("litevfs-ca-{}.pem", std::process::id()));
+        let err = http::CaCert::from_file(&path)
+            .err()
+            .expect("missing CA file");
+        assert_eq!(io::ErrorKind::NotFound, err.kind());
+        assert!(err.to_string().contains(&path.display().to_string()));
+
+        fs::write(&path, "not a certificate").expect("write");
+        let err = http::CaCert::from_file(&path)
+            .err()
+            .expect("invalid CA file");
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+        assert!(err.to_string().contains("no PEM certificates found"));
+        fs::remove_file(&path).expect("cleanup");
+
+        let fingerprint = "AB:".repeat(31) + "AB";
+        assert_eq!(
+            http::Fingerprint::new(&fingerprint).expect("fingerprint with colons"),
+            http::Fingerprint::new(&"ab".repeat(32)).expect("fingerprint")
+        );
+        let err = http::Fingerprint::new("abcd")
+            .err()
+            .expect("short fingerprint");
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+    }
+
+    #[test]
+    #[cfg(not(target_os = "emscripten"))]
+    fn proxy() {
+        use super::{http, Client};
+        use crate::testutil::{serve, Response};
+
+        let (proxy, server) = serve(Some(1), |_| Response::json(r#"{"clusterID":null}"#));
+        let client = Client::builder()
+            .host(&"http://lfsc.invalid".parse().unwrap())
+            .proxy(http::Proxy::new(&proxy).expect("proxy"))
+            .retries(0)
+            .build();
+
+        assert_eq!(Info { cluster_id: None }, client.info().expect("info"));
+        // The proxy may be asked to tunnel the connection first
+        assert!(server
+            .join()
+            .unwrap()
+            .iter()
+            .any(|request| request.line.contains("lfsc.invalid")));
+    }
+
+    #[test]
+    #[cfg(not(target_os = "emscripten"))]
+    fn gzip_uploads() {
+        use super::Client;
+        use crate::testutil::{serve, Response};
+        use std::io::{self, Read};
+
+        // A transaction updating rows of similar pages
+        let mut ltx = Vec::new();
+        let mut enc = ltx::Encoder::new(
+            &mut ltx,
+            &ltx::Header {
+                flags: ltx::HeaderFlags::empty(),
+                page_size: ltx::PageSize::new(4096).unwrap(),
+                commit: ltx::PageNum::new(16).unwrap(),
+                min_txid: ltx::TXID::ONE,
+                max_txid: ltx::TXID::ONE,
+                timestamp: time::SystemTime::UNIX_EPOCH,
+                pre_apply_checksum: None,
+            },
+        )
+        .expect("encoder");
+        for n in 1..=16u32 {
+            let page = (0..4096 / 32)
+                .flat_map(|row| {
+                    format!("{:08}|user-{:05}|active|{:04}\n", n, row, row % 7).into_bytes()
+                })
+                .take(4096)
+                .collect::<Vec<_>>();
+            enc.encode_page(ltx::PageNum::new(n).unwrap(), &page)
+                .expect("encode_page");
+        }
+        enc.finish(ltx::Checksum::new(0x8000_0000_0000_0001))
+            .expect("finish");
+
+        // The first attempt is rate limited, the retry compresses the file again
+        let mut calls = 0;
+        let (host, server) = serve(Some(2), move |_| {
+            calls += 1;
+            if calls == 1 {
+                Response::new(429).header("Retry-After", "0")
+            } else {
+                Response::new(200)
+            }
+        });
+        let client = Client::builder()
+            .host(&host.parse().unwrap())
+            .gzip_uploads(true)
+            .retries(1)
+            .build();
+
+        client
+            .write_tx("db", io::Cursor::new(&ltx), ltx.len() as u64, "lease")
+            .expect("write_tx");
+        let requests = server.join().unwrap();
+        assert_eq!(2, requests.len());
+        for request in &requests {
+            assert_eq!(Some("gzip"), request.header("content-encoding"));
+            assert_eq!(Some("chunked"), request.header("transfer-encoding"));
+
+            let mut decoded = Vec::new();
+            flate2::read::GzDecoder::new(&request.body[..])
+                .read_to_end(&mut decoded)
+                .expect("gunzip");
+            assert_eq!(ltx, decoded);
+        }
+        let body = &requests[1].body;
+        // Similar pages compress well
+        assert!(
+            body.len() * 4 < ltx.len(),
+            "ltx = {}, gzip = {}",
+            ltx.len(),
+            body.len()
+        );
+    }
+
+    #[test]
+    #[cfg(not(target_os = "emscripten"))]
+    fn connection_reuse() {
+        use super::Client;
+        use crate::testutil::{serve, Response};
+
+        let (host, server) = serve(Some(2), |_| {
+            Response::json(r#"{"clusterID":null}"#).keep_alive()
+        });
+        let client = Client::builder()
+            .host(&host.parse().unwrap())
+            .max_idle_connections_per_host(1)
+            .timeout(time::Duration::from_secs(1))
+            .retries(0)
+            .build();
+
+        // Requests are served one at a time, so requests on a new connection
+        // would time out while the server waits on the first one.
+        for _ in 0..2 {
+            assert_eq!(Info { cluster_id: None }, client.info().expect("info"));
+        }
+        assert!(server
+            .join()
+            .unwrap()
+            .iter()
+            .all(|request| request.conn == 0));
+    }
+
+    #[test]
+    fn endpoint() {
+        use super::{Client, Endpoints};
+
+        for (host, expected) in [
+            ("https://lfsc.example", "https://lfsc.example/db/page"),
+            ("https://lfsc.example/", "https://lfsc.example/db/page"),
+            ("https://gateway/litefs", "https://gateway/litefs/db/page"),
+            ("https://gateway/litefs/", "https://gateway/litefs/db/page"),
+        ] {
+            let client = Client::builder().host(&host.parse().unwrap()).build();
+            assert_eq!(expected, client.endpoint(&client.endpoints.page).as_str());
+        }
+
+        let client = Client::builder()
+            .host(&"https://gateway/litefs".parse().unwrap())
+            .endpoints(Endpoints {
+                page: "/v2/pages".into(),
+                ..Endpoints::default()
+            })
+            .build();
+        assert_eq!(
+            "https://gateway/litefs/v2/pages",
+            client.endpoint(&client.endpoints.page).as_str()
+        );
+        assert_eq!(
+            "https://gateway/litefs/db/tx",
+            client.endpoint(&client.endpoints.tx).as_str()
+        );
+    }
 }
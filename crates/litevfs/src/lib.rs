@@ -1,13 +1,24 @@
+mod builder;
 mod database;
 mod ext;
 mod http;
+mod interner;
 mod leaser;
 mod lfsc;
 mod locks;
+mod metrics;
+mod packfile;
 mod pager;
 mod sqlite;
 mod syncer;
+#[cfg(test)]
+mod testutil;
 mod vfs;
+mod wal;
+
+pub use builder::Builder;
+pub use sqlite_vfs;
+pub use vfs::{LiteVfs, ShutdownHandle};
 
 use litetx as ltx;
 use sqlite_vfs::ffi;
@@ -17,7 +28,29 @@ use std::{collections::HashMap, fmt};
 /// required state and LiteVFS can't recover from this in the middle of
 /// a transaction. 'POS' in hex, which is hopefully large enough to never
 /// collide with an upstream's error code.
-const LITEVFS_IOERR_POS_MISMATCH: i32 = ffi::SQLITE_IOERR | (0x504F53 << 8);
+///
+/// The transaction should be retried, it will read the database at the
+/// latest position.
+pub const LITEVFS_IOERR_POS_MISMATCH: i32 = ffi::SQLITE_IOERR | (0x504F53 << 8);
+
+/// Message of the [LITEVFS_IOERR_POS_MISMATCH] errors.
+const POS_MISMATCH_MESSAGE: &str = "transaction must be retried due to position mismatch";
+
+/// A custom SQLite error code to indicate that LFSC kept rate limiting
+/// the requests after all the retries. 'RL' in hex.
+const LITEVFS_IOERR_RATE_LIMITED: i32 = ffi::SQLITE_IOERR | (0x524C << 8);
+
+/// A custom SQLite error code to indicate that LFSC rejected the token
+/// (HTTP 401 or 403). 'AU' in hex.
+const LITEVFS_IOERR_AUTH: i32 = ffi::SQLITE_IOERR | (0x4155 << 8);
+
+/// A custom SQLite error code to indicate that LFSC rejected the request
+/// as invalid (HTTP 400 or 422). 'VA' in hex.
+const LITEVFS_IOERR_VALIDATION: i32 = ffi::SQLITE_IOERR | (0x5641 << 8);
+
+/// A custom SQLite error code to indicate a conflict with the state of LFSC,
+/// e.g. a lease held by another instance (HTTP 409). 'CF' in hex.
+const LITEVFS_IOERR_CONFLICT: i32 = ffi::SQLITE_IOERR | (0x4346 << 8);
 
 struct OptionLogger<'a, T>(&'a Option<T>);
 
@@ -70,3 +103,39 @@ impl<'a> fmt::Display for PositionsLogger<'a> {
         write!(f, "]")
     }
 }
+
+// A background thread that can be waited for with a timeout, unlike a plain `JoinHandle`.
+#[cfg(not(target_os = "emscripten"))]
+struct Worker {
+    handle: std::thread::JoinHandle<()>,
+    // Disconnected once the thread exits, even if it panics.
+    done: crossbeam_channel::Receiver<()>,
+}
+
+#[cfg(not(target_os = "emscripten"))]
+impl Worker {
+    fn spawn<F: FnOnce() + Send + 'static>(f: F) -> Worker {
+        let (tx, done) = crossbeam_channel::bounded::<()>(0);
+        let handle = std::thread::spawn(move || {
+            let _tx = tx;
+            f()
+        });
+
+        Worker { handle, done }
+    }
+
+    fn is_finished(&self) -> bool {
+        self.handle.is_finished()
+    }
+
+    // Waits for the thread to exit for at most `timeout`. Returns `false` if it's still running.
+    fn join(self, timeout: std::time::Duration) -> bool {
+        match self.done.recv_timeout(timeout) {
+            Err(crossbeam_channel::RecvTimeoutError::Disconnected) => {
+                _ = self.handle.join();
+                true
+            }
+            _ => false,
+        }
+    }
+}
@@ -1,9 +1,12 @@
-use std::sync::{Arc, Mutex};
+use std::{
+    sync::{Arc, Condvar, Mutex},
+    time,
+};
 
 use sqlite_vfs::LockKind;
 
 pub(crate) struct VfsLock {
-    inner: Arc<Mutex<InnerVfsLock>>,
+    inner: Arc<SharedVfsLock>,
 }
 
 // Lock implements in-memory SQLite lock shared between multiple connections.
@@ -11,7 +14,10 @@ pub(crate) struct VfsLock {
 impl VfsLock {
     pub(crate) fn new() -> Self {
         Self {
-            inner: Arc::new(Mutex::new(InnerVfsLock::new())),
+            inner: Arc::new(SharedVfsLock {
+                lock: Mutex::new(InnerVfsLock::new()),
+                cvar: Condvar::new(),
+            }),
         }
     }
 
@@ -21,15 +27,22 @@ impl VfsLock {
 
     #[cfg(test)]
     fn readers(&self) -> usize {
-        self.inner.lock().unwrap().readers()
+        self.inner.lock.lock().unwrap().readers()
     }
 
     #[cfg(test)]
     fn has_writer(&self) -> bool {
-        self.inner.lock().unwrap().has_writer()
+        self.inner.lock.lock().unwrap().has_writer()
     }
 }
 
+// The lock state together with a condition variable used to notify the
+// waiters about lock state changes.
+struct SharedVfsLock {
+    lock: Mutex<InnerVfsLock>,
+    cvar: Condvar,
+}
+
 struct InnerVfsLock {
     readers: usize,
     writer: Option<bool>,
@@ -122,12 +135,12 @@ impl InnerVfsLock {
 
 /// ConnLock tracks individial connection lock state.
 pub(crate) struct ConnLock {
-    vfs_lock: Arc<Mutex<InnerVfsLock>>,
+    vfs_lock: Arc<SharedVfsLock>,
     state: LockKind,
 }
 
 impl ConnLock {
-    fn new(vfs_lock: Arc<Mutex<InnerVfsLock>>) -> ConnLock {
+    fn new(vfs_lock: Arc<SharedVfsLock>) -> ConnLock {
         ConnLock {
             vfs_lock,
             state: LockKind::None,
@@ -135,8 +148,43 @@ impl ConnLock {
     }
 
     pub(crate) fn acquire(&mut self, to: LockKind) -> bool {
-        self.state = self.vfs_lock.lock().unwrap().transition(self.state, to);
-        self.state == to
+        let vfs_lock = Arc::clone(&self.vfs_lock);
+        let mut inner = vfs_lock.lock.lock().unwrap();
+        self.transition(&mut inner, to)
+    }
+
+    /// Same as [ConnLock::acquire], but blocks for up to `timeout` waiting for other
+    /// connections to release their locks.
+    pub(crate) fn acquire_timeout(&mut self, to: LockKind, timeout: time::Duration) -> bool {
+        let deadline = time::Instant::now() + timeout;
+
+        let vfs_lock = Arc::clone(&self.vfs_lock);
+        let mut inner = vfs_lock.lock.lock().unwrap();
+        loop {
+            if self.transition(&mut inner, to) {
+                return true;
+            }
+
+            let remaining = deadline.saturating_duration_since(time::Instant::now());
+            if remaining.is_zero() {
+                return false;
+            }
+
+            inner = vfs_lock.cvar.wait_timeout(inner, remaining).unwrap().0;
+        }
+    }
+
+    /// Blocks for up to `timeout` until there are no connections holding a write lock.
+    /// Returns `false` on timeout.
+    pub(crate) fn wait_no_writer(&self, timeout: time::Duration) -> bool {
+        let inner = self.vfs_lock.lock.lock().unwrap();
+        let (_inner, res) = self
+            .vfs_lock
+            .cvar
+            .wait_timeout_while(inner, timeout, |inner| inner.has_writer())
+            .unwrap();
+
+        !res.timed_out()
     }
 
     pub(crate) fn state(&self) -> LockKind {
@@ -144,7 +192,17 @@ impl ConnLock {
     }
 
     pub(crate) fn reserved(&self) -> bool {
-        self.state >= LockKind::Shared || self.vfs_lock.lock().unwrap().has_writer()
+        self.state >= LockKind::Shared || self.vfs_lock.lock.lock().unwrap().has_writer()
+    }
+
+    fn transition(&mut self, inner: &mut InnerVfsLock, to: LockKind) -> bool {
+        let from = self.state;
+        self.state = inner.transition(from, to);
+        if self.state != from {
+            self.vfs_lock.cvar.notify_all();
+        }
+
+        self.state == to
     }
 }
 
@@ -158,6 +216,7 @@ impl Drop for ConnLock {
 mod tests {
     use super::VfsLock;
     use sqlite_vfs::LockKind;
+    use std::{thread, time};
 
     #[test]
     fn muiltiple_readers() {
@@ -327,4 +386,46 @@ mod tests {
         }
         assert!(!vfs_lock.has_writer());
     }
+
+    #[test]
+    fn acquire_timeout_wakes_on_release() {
+        let vfs_lock = VfsLock::new();
+
+        let mut conn1_lock = vfs_lock.conn_lock();
+        let mut conn2_lock = vfs_lock.conn_lock();
+
+        assert!(conn1_lock.acquire(LockKind::Shared));
+        assert!(conn1_lock.acquire(LockKind::Exclusive));
+
+        let waiter = thread::spawn(move || {
+            let now = time::Instant::now();
+            let acquired =
+                conn2_lock.acquire_timeout(LockKind::Shared, time::Duration::from_secs(5));
+
+            (acquired, now.elapsed(), conn2_lock)
+        });
+
+        thread::sleep(time::Duration::from_millis(50));
+        assert!(conn1_lock.acquire(LockKind::None));
+
+        let (acquired, elapsed, conn2_lock) = waiter.join().unwrap();
+        assert!(acquired);
+        assert!(elapsed < time::Duration::from_secs(1));
+        assert_eq!(LockKind::Shared, conn2_lock.state());
+        assert_eq!(1, vfs_lock.readers());
+    }
+
+    #[test]
+    fn acquire_timeout_expires() {
+        let vfs_lock = VfsLock::new();
+
+        let mut conn1_lock = vfs_lock.conn_lock();
+        let mut conn2_lock = vfs_lock.conn_lock();
+
+        assert!(conn1_lock.acquire(LockKind::Shared));
+        assert!(conn1_lock.acquire(LockKind::Exclusive));
+
+        assert!(!conn2_lock.acquire_timeout(LockKind::Shared, time::Duration::from_millis(10)));
+        assert_eq!(LockKind::None, conn2_lock.state());
+    }
 }
@@ -0,0 +1,450 @@
+use std::{
+    collections::VecDeque,
+    fmt::{self, Write},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, OnceLock,
+    },
+    time,
+};
+
+// Upper bounds of LFSC request latency histogram buckets, in seconds.
+const LATENCY_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+// Number of the most recent errors kept around.
+const MAX_RECENT_ERRORS: usize = 32;
+
+/// Returns process-wide LiteVFS metrics.
+pub(crate) fn metrics() -> &'static Metrics {
+    static METRICS: OnceLock<Metrics> = OnceLock::new();
+
+    METRICS.get_or_init(Metrics::default)
+}
+
+/// [Metrics] collects process-wide counters and gauges. Their updates are plain
+/// atomic operations, so recording is cheap enough for the hot path. Recent errors
+/// are kept behind a mutex, they are only recorded when something goes wrong.
+#[derive(Default)]
+pub(crate) struct Metrics {
+    local_page_hits: AtomicU64,
+    remote_page_fetches: AtomicU64,
+    remote_pages: AtomicU64,
+    lfsc_request_duration: Histogram,
+    get_pages_duration: Histogram,
+    write_tx_duration: Histogram,
+    queries: AtomicU64,
+    queries_over_budget: AtomicU64,
+    cached_pages: AtomicU64,
+    available_space: AtomicU64,
+    recent_errors: Mutex<VecDeque<RecentError>>,
+}
+
+/// Latency percentiles of LFSC operations, as returned by [Metrics::lfsc_latency].
+#[derive(Debug, serde::Serialize)]
+pub(crate) struct LfscLatency {
+    pub(crate) get_pages: LatencyPercentiles,
+    pub(crate) write_tx: LatencyPercentiles,
+}
+
+/// Latency percentiles of a single operation. The values are the upper bounds of the
+/// histogram buckets the percentiles fall into, `None` if nothing has been recorded.
+#[derive(Debug, serde::Serialize)]
+pub(crate) struct LatencyPercentiles {
+    pub(crate) count: u64,
+    pub(crate) p50: Option<String>,
+    pub(crate) p95: Option<String>,
+    pub(crate) p99: Option<String>,
+}
+
+/// Query counters, as returned by [Metrics::query_stats].
+#[derive(Debug, serde::Serialize)]
+pub(crate) struct QueryStats {
+    /// Number of read transactions started.
+    pub(crate) queries: u64,
+    /// Number of read transactions that exhausted `litevfs_max_reqs_per_query`
+    /// and had to continue with locally cached pages only.
+    pub(crate) over_budget: u64,
+}
+
+/// An error that happened in the background or was otherwise swallowed,
+/// as returned by [Metrics::recent_errors].
+#[derive(Debug, Clone, serde::Serialize)]
+pub(crate) struct RecentError {
+    #[serde(with = "::time::serde::rfc3339")]
+    pub(crate) timestamp: ::time::OffsetDateTime,
+    pub(crate) op: &'static str,
+    pub(crate) db: Option<String>,
+    pub(crate) error: String,
+}
+
+impl Metrics {
+    /// Records a page served from the local cache.
+    pub(crate) fn record_local_hit(&self) {
+        self.local_page_hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records a fetch of `pages` pages from LFSC.
+    pub(crate) fn record_remote_fetch(&self, pages: usize) {
+        self.remote_page_fetches.fetch_add(1, Ordering::Relaxed);
+        self.remote_pages.fetch_add(pages as u64, Ordering::Relaxed);
+    }
+
+    /// Returns the number of pages served from the local cache.
+    pub(crate) fn local_page_hits(&self) -> u64 {
+        self.local_page_hits.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of page fetch requests sent to LFSC.
+    pub(crate) fn remote_page_fetches(&self) -> u64 {
+        self.remote_page_fetches.load(Ordering::Relaxed)
+    }
+
+    /// Records the duration of a single LFSC request.
+    pub(crate) fn record_lfsc_request(&self, duration: time::Duration) {
+        self.lfsc_request_duration.observe(duration);
+    }
+
+    /// Records the duration of fetching pages from LFSC, including the retries.
+    pub(crate) fn record_get_pages(&self, duration: time::Duration) {
+        self.get_pages_duration.observe(duration);
+    }
+
+    /// Records the duration of uploading a transaction to LFSC, including the retries.
+    pub(crate) fn record_write_tx(&self, duration: time::Duration) {
+        self.write_tx_duration.observe(duration);
+    }
+
+    /// Returns p50/p95/p99 latencies of the LFSC operations since the last reset.
+    pub(crate) fn lfsc_latency(&self) -> LfscLatency {
+        LfscLatency {
+            get_pages: self.get_pages_duration.percentiles(),
+            write_tx: self.write_tx_duration.percentiles(),
+        }
+    }
+
+    /// Resets the latencies returned by [Metrics::lfsc_latency].
+    pub(crate) fn reset_lfsc_latency(&self) {
+        self.get_pages_duration.reset();
+        self.write_tx_duration.reset();
+    }
+
+    /// Records the start of a read transaction.
+    pub(crate) fn record_query(&self) {
+        self.queries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records a read transaction that ran out of its remote fetch budget.
+    pub(crate) fn record_query_over_budget(&self) {
+        self.queries_over_budget.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns the query counters since the last reset.
+    pub(crate) fn query_stats(&self) -> QueryStats {
+        QueryStats {
+            queries: self.queries.load(Ordering::Relaxed),
+            over_budget: self.queries_over_budget.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Resets the counters returned by [Metrics::query_stats].
+    pub(crate) fn reset_query_stats(&self) {
+        self.queries.store(0, Ordering::Relaxed);
+        self.queries_over_budget.store(0, Ordering::Relaxed);
+    }
+
+    /// Records the current number of cached pages and available FS space.
+    pub(crate) fn set_cache_usage(&self, pages: usize, available_space: u64) {
+        self.cached_pages.store(pages as u64, Ordering::Relaxed);
+        self.available_space
+            .store(available_space, Ordering::Relaxed);
+    }
+
+    /// Records an error of `op` that isn't reported to the application, dropping
+    /// the oldest one if there are too many.
+    pub(crate) fn record_error(&self, op: &'static str, db: Option<&str>, err: &dyn fmt::Display) {
+        let mut errors = self.recent_errors.lock().unwrap();
+        if errors.len() == MAX_RECENT_ERRORS {
+            errors.pop_front();
+        }
+        errors.push_back(RecentError {
+            timestamp: ::time::OffsetDateTime::now_utc(),
+            op,
+            db: db.map(Into::into),
+            error: err.to_string(),
+        });
+    }
+
+    /// Returns up to `n` most recent errors, oldest first.
+    pub(crate) fn recent_errors(&self, n: usize) -> Vec<RecentError> {
+        let errors = self.recent_errors.lock().unwrap();
+
+        errors
+            .iter()
+            .skip(errors.len().saturating_sub(n))
+            .cloned()
+            .collect()
+    }
+
+    /// Renders the metrics in Prometheus text exposition format.
+    pub(crate) fn render(&self) -> String {
+        let mut out = String::new();
+
+        let counters = [
+            (
+                "litevfs_local_page_hits_total",
+                "Number of pages served from the local cache.",
+                &self.local_page_hits,
+            ),
+            (
+                "litevfs_remote_page_fetches_total",
+                "Number of page fetch requests sent to LFSC.",
+                &self.remote_page_fetches,
+            ),
+            (
+                "litevfs_remote_pages_total",
+                "Number of pages fetched from LFSC, including prefetched ones.",
+                &self.remote_pages,
+            ),
+        ];
+        for (name, help, value) in counters {
+            write_metric(&mut out, name, help, "counter", value);
+        }
+
+        let gauges = [
+            (
+                "litevfs_cached_pages",
+                "Number of pages in the local cache.",
+                &self.cached_pages,
+            ),
+            (
+                "litevfs_available_space_bytes",
+                "Available space on the cache filesystem.",
+                &self.available_space,
+            ),
+        ];
+        for (name, help, value) in gauges {
+            write_metric(&mut out, name, help, "gauge", value);
+        }
+
+        self.lfsc_request_duration.render(
+            &mut out,
+            "litevfs_lfsc_request_duration_seconds",
+            "Duration of LFSC requests.",
+        );
+
+        out
+    }
+}
+
+fn write_metric(out: &mut String, name: &str, help: &str, kind: &str, value: &AtomicU64) {
+    _ = writeln!(out, "# HELP {} {}", name, help);
+    _ = writeln!(out, "# TYPE {} {}", name, kind);
+    _ = writeln!(out, "{} {}", name, value.load(Ordering::Relaxed));
+}
+
+// A histogram with fixed [LATENCY_BUCKETS] buckets.
+#[derive(Default)]
+struct Histogram {
+    // Non-cumulative bucket counts, the last one is +Inf.
+    buckets: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, duration: time::Duration) {
+        let secs = duration.as_secs_f64();
+        let idx = LATENCY_BUCKETS
+            .iter()
+            .position(|&le| secs <= le)
+            .unwrap_or(LATENCY_BUCKETS.len());
+
+        self.buckets[idx].fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn count(&self) -> u64 {
+        self.buckets.iter().map(|b| b.load(Ordering::Relaxed)).sum()
+    }
+
+    // Returns the upper bound of the bucket the `q` quantile falls into, or `None`
+    // if it is beyond the last one.
+    fn quantile(&self, q: f64) -> Option<f64> {
+        let rank = (self.count() as f64 * q).ceil().max(1.0) as u64;
+        let mut count = 0;
+        for (i, bucket) in self.buckets.iter().enumerate() {
+            count += bucket.load(Ordering::Relaxed);
+            if count >= rank {
+                return LATENCY_BUCKETS.get(i).copied();
+            }
+        }
+
+        None
+    }
+
+    fn percentiles(&self) -> LatencyPercentiles {
+        let count = self.count();
+        let percentile = |q| {
+            if count == 0 {
+                return None;
+            }
+
+            Some(match self.quantile(q) {
+                Some(le) => {
+                    humantime::format_duration(time::Duration::from_secs_f64(le)).to_string()
+                }
+                None => format!(
+                    "> {}",
+                    humantime::format_duration(time::Duration::from_secs_f64(
+                        LATENCY_BUCKETS[LATENCY_BUCKETS.len() - 1]
+                    ))
+                ),
+            })
+        };
+
+        LatencyPercentiles {
+            count,
+            p50: percentile(0.5),
+            p95: percentile(0.95),
+            p99: percentile(0.99),
+        }
+    }
+
+    fn reset(&self) {
+        for bucket in &self.buckets {
+            bucket.store(0, Ordering::Relaxed);
+        }
+        self.sum_micros.store(0, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        _ = writeln!(out, "# HELP {} {}", name, help);
+        _ = writeln!(out, "# TYPE {} histogram", name);
+
+        let mut count = 0;
+        for (i, bucket) in self.buckets.iter().enumerate() {
+            count += bucket.load(Ordering::Relaxed);
+            match LATENCY_BUCKETS.get(i) {
+                Some(le) => _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, le, count),
+                None => _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, count),
+            }
+        }
+
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+        _ = writeln!(out, "{}_sum {}", name, sum);
+        _ = writeln!(out, "{}_count {}", name, count);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Metrics;
+    use std::time::Duration;
+
+    #[test]
+    fn recent_errors() {
+        let metrics = Metrics::default();
+        for i in 0..super::MAX_RECENT_ERRORS + 2 {
+            metrics.record_error("sync", Some("db"), &i);
+        }
+
+        let errors = metrics.recent_errors(usize::MAX);
+        assert_eq!(super::MAX_RECENT_ERRORS, errors.len());
+        assert_eq!("2", errors[0].error);
+
+        let errors = metrics.recent_errors(2);
+        assert_eq!(
+            vec!["32", "33"],
+            errors.iter().map(|e| e.error.as_str()).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn query_stats() {
+        let metrics = Metrics::default();
+        for _ in 0..3 {
+            metrics.record_query();
+        }
+        metrics.record_query_over_budget();
+
+        let stats = metrics.query_stats();
+        assert_eq!(3, stats.queries);
+        assert_eq!(1, stats.over_budget);
+
+        metrics.reset_query_stats();
+        let stats = metrics.query_stats();
+        assert_eq!(0, stats.queries);
+        assert_eq!(0, stats.over_budget);
+    }
+
+    #[test]
+    fn lfsc_latency() {
+        let metrics = Metrics::default();
+        let latency = metrics.lfsc_latency();
+        assert_eq!(0, latency.get_pages.count);
+        assert_eq!(None, latency.get_pages.p50);
+
+        for _ in 0..90 {
+            metrics.record_get_pages(Duration::from_millis(20));
+        }
+        for _ in 0..9 {
+            metrics.record_get_pages(Duration::from_millis(200));
+        }
+        metrics.record_get_pages(Duration::from_secs(20));
+        metrics.record_write_tx(Duration::from_millis(80));
+
+        let latency = metrics.lfsc_latency();
+        assert_eq!(100, latency.get_pages.count);
+        assert_eq!(Some("25ms"), latency.get_pages.p50.as_deref());
+        assert_eq!(Some("250ms"), latency.get_pages.p95.as_deref());
+        assert_eq!(Some("250ms"), latency.get_pages.p99.as_deref());
+        assert_eq!(1, latency.write_tx.count);
+        assert_eq!(Some("100ms"), latency.write_tx.p99.as_deref());
+
+        metrics.record_get_pages(Duration::from_secs(20));
+        assert_eq!(
+            Some("> 10s"),
+            metrics.lfsc_latency().get_pages.p99.as_deref()
+        );
+
+        metrics.reset_lfsc_latency();
+        assert_eq!(0, metrics.lfsc_latency().get_pages.count);
+        assert_eq!(0, metrics.lfsc_latency().write_tx.count);
+    }
+
+    #[test]
+    fn render() {
+        let metrics = Metrics::default();
+        metrics.record_local_hit();
+        metrics.record_remote_fetch(3);
+        metrics.set_cache_usage(42, 1024);
+        metrics.record_lfsc_request(Duration::from_millis(20));
+        metrics.record_lfsc_request(Duration::from_secs(20));
+
+        let out = metrics.render();
+        for line in [
+            "# TYPE litevfs_local_page_hits_total counter",
+            "litevfs_local_page_hits_total 1",
+            "litevfs_remote_page_fetches_total 1",
+            "litevfs_remote_pages_total 3",
+            "# TYPE litevfs_cached_pages gauge",
+            "litevfs_cached_pages 42",
+            "litevfs_available_space_bytes 1024",
+            "# TYPE litevfs_lfsc_request_duration_seconds histogram",
+            "litevfs_lfsc_request_duration_seconds_bucket{le=\"0.01\"} 0",
+            "litevfs_lfsc_request_duration_seconds_bucket{le=\"0.025\"} 1",
+            "litevfs_lfsc_request_duration_seconds_bucket{le=\"10\"} 1",
+            "litevfs_lfsc_request_duration_seconds_bucket{le=\"+Inf\"} 2",
+            "litevfs_lfsc_request_duration_seconds_sum 20.02",
+            "litevfs_lfsc_request_duration_seconds_count 2",
+        ] {
+            assert!(
+                out.lines().any(|l| l == line),
+                "missing {:?} in:\n{}",
+                line,
+                out
+            );
+        }
+    }
+}
@@ -0,0 +1,314 @@
+use litetx as ltx;
+use read_write_at::{ReadAtMut, WriteAtMut};
+use std::{
+    collections::BTreeSet,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+// Index header: page size as big-endian u32.
+const INDEX_HEADER_SIZE: u64 = 4;
+
+const PAGE_PRESENT: u8 = 1;
+const PAGE_ABSENT: u8 = 0;
+
+/// [PackFile] stores all the cached pages of a single database in one file.
+///
+/// The data file is slotted by page number: page N is stored at offset
+/// `(N - 1) * page_size`, so the file is sparse when not all the pages are
+/// cached. A separate index file holds the page size followed by a presence
+/// byte per page. Page data is always written before the index is updated.
+pub(crate) struct PackFile {
+    inner: Mutex<InnerPackFile>,
+}
+
+struct InnerPackFile {
+    data: fs::File,
+    index: fs::File,
+    page_size: Option<u64>,
+    pages: BTreeSet<ltx::PageNum>,
+}
+
+impl PackFile {
+    pub(crate) const DATA_NAME: &'static str = "pages.pack";
+    pub(crate) const INDEX_NAME: &'static str = "pages.idx";
+
+    /// Opens (creating if needed) a pack file in the given `dir`.
+    pub(crate) fn open<P: AsRef<Path>>(dir: P) -> io::Result<PackFile> {
+        let open = |name| {
+            fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(dir.as_ref().join(name))
+        };
+
+        let data = open(PackFile::DATA_NAME)?;
+        let index = open(PackFile::INDEX_NAME)?;
+        let raw = fs::read(dir.as_ref().join(PackFile::INDEX_NAME))?;
+
+        let (page_size, pages) = if raw.len() >= INDEX_HEADER_SIZE as usize {
+            let page_size =
+                u32::from_be_bytes(raw[..INDEX_HEADER_SIZE as usize].try_into().unwrap());
+            let pages = raw[INDEX_HEADER_SIZE as usize..]
+                .iter()
+                .enumerate()
+                .filter(|(_, &b)| b == PAGE_PRESENT)
+                .filter_map(|(i, _)| ltx::PageNum::new(i as u32 + 1).ok())
+                .collect();
+
+            (Some(page_size as u64).filter(|&ps| ps > 0), pages)
+        } else {
+            (None, BTreeSet::new())
+        };
+
+        Ok(PackFile {
+            inner: Mutex::new(InnerPackFile {
+                data,
+                index,
+                page_size,
+                pages: if page_size.is_some() {
+                    pages
+                } else {
+                    BTreeSet::new()
+                },
+            }),
+        })
+    }
+
+    /// Returns the paths of the files backing a pack file in the given `dir`.
+    pub(crate) fn paths<P: AsRef<Path>>(dir: P) -> [PathBuf; 2] {
+        [
+            dir.as_ref().join(PackFile::DATA_NAME),
+            dir.as_ref().join(PackFile::INDEX_NAME),
+        ]
+    }
+
+    /// Returns the numbers of all the pages stored in the pack file.
+    pub(crate) fn pages(&self) -> Vec<ltx::PageNum> {
+        self.inner.lock().unwrap().pages.iter().copied().collect()
+    }
+
+    /// Checks if the page is stored in the pack file.
+    pub(crate) fn contains(&self, pgno: ltx::PageNum) -> bool {
+        self.inner.lock().unwrap().pages.contains(&pgno)
+    }
+
+    /// Returns the whole page. Returns [io::ErrorKind::NotFound] if the page is absent.
+    pub(crate) fn read(&self, pgno: ltx::PageNum) -> io::Result<Vec<u8>> {
+        let mut inner = self.inner.lock().unwrap();
+        let page_size = inner.page_offset(pgno)?.1;
+
+        let mut buf = vec![0; page_size as usize];
+        inner.read_at(pgno, &mut buf, 0)?;
+
+        Ok(buf)
+    }
+
+    /// Copies the page starting at `offset` to the provided buffer. Returns
+    /// [io::ErrorKind::NotFound] if the page is absent.
+    pub(crate) fn read_at(
+        &self,
+        pgno: ltx::PageNum,
+        buf: &mut [u8],
+        offset: u64,
+    ) -> io::Result<()> {
+        self.inner.lock().unwrap().read_at(pgno, buf, offset)
+    }
+
+    /// Stores the page. If the page size differs from the one of already stored
+    /// pages, all of them are removed first and their numbers are returned.
+    pub(crate) fn write(&self, pgno: ltx::PageNum, data: &[u8]) -> io::Result<Vec<ltx::PageNum>> {
+        self.write_inner(pgno, data, None)
+    }
+
+    /// Same as [PackFile::write], but calls `fsync` on the data file before the page
+    /// is marked as present in the index, and on the index afterwards.
+    pub(crate) fn write_synced(
+        &self,
+        pgno: ltx::PageNum,
+        data: &[u8],
+        fsync: fn(&fs::File) -> io::Result<()>,
+    ) -> io::Result<Vec<ltx::PageNum>> {
+        self.write_inner(pgno, data, Some(fsync))
+    }
+
+    fn write_inner(
+        &self,
+        pgno: ltx::PageNum,
+        data: &[u8],
+        fsync: Option<fn(&fs::File) -> io::Result<()>>,
+    ) -> io::Result<Vec<ltx::PageNum>> {
+        let mut inner = self.inner.lock().unwrap();
+
+        let mut removed = Vec::new();
+        let page_size = data.len() as u64;
+        if inner.page_size != Some(page_size) {
+            removed = inner.clear()?;
+            inner
+                .index
+                .write_all_at(&(page_size as u32).to_be_bytes(), 0)?;
+            inner.page_size = Some(page_size);
+        }
+
+        let (offset, _) = inner.page_offset(pgno)?;
+        inner.data.write_all_at(data, offset)?;
+        if let Some(fsync) = fsync {
+            fsync(&inner.data)?;
+        }
+        inner
+            .index
+            .write_all_at(&[PAGE_PRESENT], index_offset(pgno))?;
+        if let Some(fsync) = fsync {
+            fsync(&inner.index)?;
+        }
+        inner.pages.insert(pgno);
+
+        Ok(removed)
+    }
+
+    /// Removes the page, returning `false` if it has not been stored.
+    pub(crate) fn remove(&self, pgno: ltx::PageNum) -> io::Result<bool> {
+        let mut inner = self.inner.lock().unwrap();
+        if !inner.pages.contains(&pgno) {
+            return Ok(false);
+        }
+
+        let (offset, page_size) = inner.page_offset(pgno)?;
+        inner
+            .index
+            .write_all_at(&[PAGE_ABSENT], index_offset(pgno))?;
+        inner.pages.remove(&pgno);
+        punch_hole(&inner.data, offset, page_size)?;
+
+        Ok(true)
+    }
+
+    /// Removes all pages past the provided `pgno`, returning the removed page numbers.
+    pub(crate) fn truncate(&self, pgno: ltx::PageNum) -> io::Result<Vec<ltx::PageNum>> {
+        let mut inner = self.inner.lock().unwrap();
+        let page_size = if let Some(page_size) = inner.page_size {
+            page_size
+        } else {
+            return Ok(vec![]);
+        };
+
+        let mut removed = inner.pages.split_off(&pgno);
+        if removed.remove(&pgno) {
+            inner.pages.insert(pgno);
+        }
+        inner
+            .index
+            .set_len(INDEX_HEADER_SIZE + pgno.into_inner() as u64)?;
+        inner.data.set_len(page_size * pgno.into_inner() as u64)?;
+
+        Ok(removed.into_iter().collect())
+    }
+
+    /// Removes all pages, returning the removed page numbers.
+    pub(crate) fn clear(&self) -> io::Result<Vec<ltx::PageNum>> {
+        self.inner.lock().unwrap().clear()
+    }
+}
+
+impl InnerPackFile {
+    fn page_offset(&self, pgno: ltx::PageNum) -> io::Result<(u64, u64)> {
+        match self.page_size {
+            Some(page_size) => Ok(((pgno.into_inner() as u64 - 1) * page_size, page_size)),
+            None => Err(io::ErrorKind::NotFound.into()),
+        }
+    }
+
+    fn read_at(&mut self, pgno: ltx::PageNum, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        if !self.pages.contains(&pgno) {
+            return Err(io::ErrorKind::NotFound.into());
+        }
+
+        let (page_offset, _) = self.page_offset(pgno)?;
+        self.data.read_exact_at(buf, page_offset + offset)
+    }
+
+    fn clear(&mut self) -> io::Result<Vec<ltx::PageNum>> {
+        self.index.set_len(0)?;
+        self.data.set_len(0)?;
+        self.page_size = None;
+
+        Ok(std::mem::take(&mut self.pages).into_iter().collect())
+    }
+}
+
+fn index_offset(pgno: ltx::PageNum) -> u64 {
+    INDEX_HEADER_SIZE + pgno.into_inner() as u64 - 1
+}
+
+// Releases the disk space of a removed page, so that evicting pages from the
+// cache actually frees up space on the FS.
+#[cfg(target_os = "linux")]
+fn punch_hole(file: &fs::File, offset: u64, len: u64) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let ret = unsafe {
+        libc::fallocate(
+            file.as_raw_fd(),
+            libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+            offset as libc::off_t,
+            len as libc::off_t,
+        )
+    };
+    if ret != 0 {
+        let err = io::Error::last_os_error();
+        // Not all filesystems support punching holes, the space will be
+        // reused by the next page written to the same slot.
+        if err.raw_os_error() != Some(libc::EOPNOTSUPP) {
+            return Err(err);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn punch_hole(_file: &fs::File, _offset: u64, _len: u64) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PackFile;
+    use crate::testutil::TempDir;
+    use litetx as ltx;
+    use std::{fs, io};
+
+    #[test]
+    fn pack_file() {
+        let dir = TempDir::new("pack");
+        fs::create_dir_all(&dir).expect("create dir");
+        let pgno = |n| ltx::PageNum::new(n).unwrap();
+
+        let pack = PackFile::open(&dir).expect("open");
+        for n in 1..=4u8 {
+            pack.write(pgno(n as u32), &[n; 512]).expect("write");
+        }
+        assert!(pack.remove(pgno(2)).expect("remove"));
+        assert_eq!(vec![pgno(4)], pack.truncate(pgno(3)).expect("truncate"));
+
+        // Reopen to make sure the index is persisted
+        drop(pack);
+        let pack = PackFile::open(&dir).expect("open");
+        assert_eq!(vec![pgno(1), pgno(3)], pack.pages());
+        assert_eq!(vec![3; 512], pack.read(pgno(3)).expect("read"));
+        assert_eq!(
+            io::ErrorKind::NotFound,
+            pack.read(pgno(2)).expect_err("read").kind()
+        );
+
+        // Page size change drops all the pages
+        assert_eq!(
+            vec![pgno(1), pgno(3)],
+            pack.write(pgno(2), &[2; 1024]).expect("write")
+        );
+        assert_eq!(vec![pgno(2)], pack.pages());
+    }
+}
@@ -1,19 +1,53 @@
-use crate::{lfsc, IterLogger, OptionLogger, LITEVFS_IOERR_POS_MISMATCH};
+use crate::{
+    builder::EnvSettings,
+    interner::{Interner, Symbol},
+    lfsc,
+    metrics::metrics,
+    packfile::PackFile,
+    IterLogger, OptionLogger, LITEVFS_IOERR_POS_MISMATCH, POS_MISMATCH_MESSAGE,
+};
 use bytesize::ByteSize;
-use caches::{Cache, SegmentedCache};
+use caches::{Cache, RawLRU, SegmentedCache};
 use litetx::{self as ltx, PageChecksum};
 use read_write_at::ReadAtMut;
 use sqlite_vfs::CodeError;
 use std::{
-    ffi, fs,
+    collections::{HashMap, HashSet},
+    fs,
     io::{self, Read, Write},
     path::{Path, PathBuf},
+    str::FromStr,
     sync::{
-        atomic::{AtomicU64, AtomicUsize, Ordering},
-        Arc, Mutex,
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Condvar, Mutex,
     },
+    thread, time,
 };
-use string_interner::{DefaultSymbol, StringInterner};
+
+// How often the LRU cache ordering is persisted to disk.
+const LRU_SAVE_PERIOD: time::Duration = time::Duration::from_secs(60);
+
+// Number of pages kept in memory in front of the on-disk cache by default.
+const DEFAULT_MEM_CACHE_PAGES: usize = 32;
+// Remote page requests smaller than this are never split.
+const MIN_PARALLEL_FETCH_PAGES: usize = 32;
+// Page numbers are sent comma-separated in the URL, which proxies commonly limit to 8KiB.
+// A page number takes up to 13 bytes with the escaped comma, the rest is left for
+// the host, the database name and the position.
+const DEFAULT_MAX_PAGES_PER_REQUEST: usize = (8192 - 1024) / 13;
+// Every cached page is a file, so keep some inodes free for the other files as well.
+const DEFAULT_MIN_AVAILABLE_INODES: u64 = 1000;
+// Temporary files older than this are left over from crashes, as writes finish much sooner.
+pub(crate) const ORPHAN_AGE: time::Duration = time::Duration::from_secs(60 * 60);
+// Default capacities of the probationary and protected LRU segments.
+pub(crate) const DEFAULT_CACHE_PROBATION: usize = 6500;
+pub(crate) const DEFAULT_CACHE_PROTECTED: usize = 26000;
+
+// Emscripten builds don't support threads.
+#[cfg(not(target_os = "emscripten"))]
+pub(crate) const DEFAULT_FETCH_PARALLELISM: usize = 4;
+#[cfg(target_os = "emscripten")]
+pub(crate) const DEFAULT_FETCH_PARALLELISM: usize = 1;
 
 #[derive(PartialEq, Eq)]
 pub(crate) enum PageSource {
@@ -21,37 +55,156 @@ pub(crate) enum PageSource {
     Remote,
 }
 
+/// Defines how [Pager] stores the cached pages on the local FS.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum PagerBackend {
+    /// Each page is stored in its own file.
+    #[default]
+    Files,
+    /// All the pages of a database are stored in a single [PackFile].
+    Packed,
+}
+
+impl FromStr for PagerBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "files" => Ok(PagerBackend::Files),
+            "packed" => Ok(PagerBackend::Packed),
+            _ => Err(format!("unknown pager backend: {}", s)),
+        }
+    }
+}
+
+/// Defines how durably [Pager] writes the cached pages to the local FS.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum CacheDurability {
+    /// Pages are written without fsync. A crash may leave a partially written
+    /// page behind, which is fine as long as the storage is reliable.
+    #[default]
+    Fast,
+    /// Pages are fsynced before they are renamed into place, and so is the
+    /// pages directory afterwards. With the packed backend, the page data is
+    /// fsynced before the index marks it as present, and so is the index afterwards.
+    Safe,
+}
+
+impl FromStr for CacheDurability {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fast" => Ok(CacheDurability::Fast),
+            "safe" => Ok(CacheDurability::Safe),
+            _ => Err(format!("unknown cache durability: {}", s)),
+        }
+    }
+}
+
 /// [Pager] manages SQLite page data. It uses local filesystem to cache
 /// the pages and when the pages are absent in the cache, requests them from LFSC.
 pub(crate) struct Pager {
     root: PathBuf,
     client: Arc<lfsc::Client>,
-
-    interner: Mutex<StringInterner>,
-    lru: Mutex<SegmentedCache<PageCacheKey, ()>>,
+    backend: PagerBackend,
+
+    interner: Mutex<Interner>,
+    lru: Mutex<SegmentedCache<PageCacheKey, bool>>,
+    // Pages put into the LRU cache by database, with the `lru_clock` value of their last
+    // access, so that the pages of a database and the cache ordering can be looked up
+    // without draining the cache. May still list the pages the cache has dropped on its
+    // own. Always locked after `lru`.
+    lru_pages: Mutex<HashMap<Symbol, HashMap<ltx::PageNum, u64>>>,
+    lru_clock: AtomicU64,
+    lru_saved_at: Mutex<time::Instant>,
+    // Pages kept in `stale/` for revalidation, counted towards the cache limits.
+    stale: Mutex<HashSet<PageCacheKey>>,
+    mem_cache: Mutex<Option<RawLRU<PageCacheKey, Vec<u8>>>>,
+    inflight: Mutex<HashMap<InflightKey, Arc<InflightFetch>>>,
+    packs: Mutex<HashMap<Symbol, Arc<PackFile>>>,
+    bandwidth: Mutex<HashMap<Symbol, Bandwidth>>,
 
     min_available_space: AtomicU64,
+    min_available_inodes: AtomicU64,
     max_cached_pages: AtomicUsize,
+    // f64 bits of the maximum fraction of the FS the cache may use
+    max_cache_fraction: AtomicU64,
+    // Size of the most recently cached page, used to convert the fraction to pages
+    page_size_hint: AtomicU64,
+    max_pages_per_request: AtomicUsize,
+    fetch_parallelism: usize,
+    durability: CacheDurability,
+    // Compress the page files, only applies to the files backend.
+    compression: AtomicBool,
+    // Called to fsync files and directories, replaceable in tests.
+    fsync: fn(&fs::File) -> io::Result<()>,
+    // Age of temporary files after which they are removed, replaceable in tests.
+    orphan_age: time::Duration,
+
+    stats: PagerStats,
 }
 
 impl Pager {
-    pub(crate) fn new<P: AsRef<Path>>(path: P, client: Arc<lfsc::Client>) -> Pager {
-        Pager {
+    pub(crate) fn new<P: AsRef<Path>>(
+        path: P,
+        client: Arc<lfsc::Client>,
+        backend: PagerBackend,
+        settings: &EnvSettings,
+    ) -> Pager {
+        let pager = Pager {
             root: path.as_ref().to_path_buf(),
             client,
+            backend,
 
-            interner: Mutex::new(StringInterner::new()),
-            // The size is chosen from:
+            interner: Mutex::new(Interner::new()),
+            // The default size is chosen from:
             //  - 128Mb of space
             //  - 4k page size
-            // In reality is doesn't matter as we are gonna check available
-            // FS space anyway. But we need some predetermined size as
-            // the cache is not resizable.
-            lru: Mutex::new(SegmentedCache::new(6500, 26000).unwrap()),
+            // The actual limit on cached pages is enforced by `reclaim_space`, which
+            // checks the available FS space, but we need some predetermined size as
+            // the cache is not resizable. Pages dropped from the segments are no
+            // longer tracked for eviction, so the total should stay above the number
+            // of pages the disk limits allow. Newly cached pages go to the
+            // probationary segment and are promoted to the protected one on reuse.
+            lru: Mutex::new(
+                SegmentedCache::new(settings.cache_probation, settings.cache_protected).unwrap(),
+            ),
+            lru_pages: Mutex::new(HashMap::new()),
+            lru_clock: AtomicU64::new(0),
+            lru_saved_at: Mutex::new(time::Instant::now()),
+            stale: Mutex::new(HashSet::new()),
+            mem_cache: Mutex::new(RawLRU::new(DEFAULT_MEM_CACHE_PAGES).ok()),
+            inflight: Mutex::new(HashMap::new()),
+            packs: Mutex::new(HashMap::new()),
+            bandwidth: Mutex::new(HashMap::new()),
 
             min_available_space: AtomicU64::new(10 * 1024 * 1024),
+            min_available_inodes: AtomicU64::new(DEFAULT_MIN_AVAILABLE_INODES),
             max_cached_pages: AtomicUsize::new(0),
+            max_cache_fraction: AtomicU64::new(0f64.to_bits()),
+            page_size_hint: AtomicU64::new(0),
+            max_pages_per_request: AtomicUsize::new(DEFAULT_MAX_PAGES_PER_REQUEST),
+            fetch_parallelism: settings.fetch_parallelism,
+            durability: settings.cache_durability,
+            compression: AtomicBool::new(settings.cache_compression),
+            fsync: fs::File::sync_all,
+            orphan_age: ORPHAN_AGE,
+
+            stats: PagerStats::default(),
+        };
+        // Local hits and remote fetches of the pagers created before don't count
+        pager.stats.reset();
+
+        if let Err(err) = pager.restore_lru() {
+            log::warn!(
+                "[pager] new: root = {}, failed to restore LRU cache: {}",
+                pager.root.display(),
+                err
+            );
         }
+
+        pager
     }
 
     /// Returns a base path for the given `db`.
@@ -59,14 +212,56 @@ impl Pager {
         self.root.join(db)
     }
 
-    /// Prepares all the paths for the given `db`.
+    /// Prepares all the paths for the given `db`. Pages cached by a different
+    /// backend are removed, as they are not kept up to date.
     pub(crate) fn prepare_db(&self, db: &str) -> io::Result<()> {
-        fs::create_dir_all(self.pages_path(db))?;
-        fs::create_dir_all(self.tmp_path(db))?;
+        fs::create_dir_all(self.etags_path(db))?;
+        fs::create_dir_all(self.stale_path(db))?;
+
+        match self.backend {
+            PagerBackend::Files => {
+                fs::create_dir_all(self.pages_path(db))?;
+                fs::create_dir_all(self.tmp_path(db))?;
+                for path in PackFile::paths(self.db_path(db)) {
+                    remove_file(path)?;
+                }
+                self.remove_orphans(db)?;
+            }
+            PagerBackend::Packed => {
+                fs::create_dir_all(self.db_path(db))?;
+                for path in [self.pages_path(db), self.tmp_path(db)] {
+                    match fs::remove_dir_all(path) {
+                        Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+                        _ => (),
+                    }
+                }
+            }
+        }
 
         Ok(())
     }
 
+    /// Returns the age after which temporary files are considered left over from a crash.
+    pub(crate) fn orphan_age(&self) -> time::Duration {
+        self.orphan_age
+    }
+
+    /// Removes the temporary files of the `db` left over from interrupted page writes.
+    /// Returns the number of removed files.
+    pub(crate) fn remove_orphans(&self, db: &str) -> io::Result<usize> {
+        if self.backend != PagerBackend::Files {
+            return Ok(0);
+        }
+
+        match remove_old_files(&self.tmp_path(db), self.orphan_age) {
+            Err(err) => {
+                log::error!("[pager] remove_orphans: db = {}: {}", db, err);
+                Err(err)
+            }
+            x => x,
+        }
+    }
+
     /// Returns a `db` `page` at the given database `pos`.
     pub(crate) fn get_page(
         &self,
@@ -90,7 +285,6 @@ impl Pager {
         // Request the page either from local cache or from LFSC and convert
         // io::ErrorKind::NotFound errors to io::ErrorKind::UnexpectedEof, as
         // this is what local IO will return in case we read past the file.
-        // TODO: we may need to suppress duplicated calls to the same page here.
         let r = match self.get_page_inner(db, pos, pgno, prefetch) {
             Err(err) if err.kind() == io::ErrorKind::NotFound => {
                 Err(io::ErrorKind::UnexpectedEof.into())
@@ -119,7 +313,8 @@ impl Pager {
         }
     }
 
-    /// Copies the page starting at `offset` to the provided buffer.
+    /// Copies the page starting at `offset` to the provided buffer. Setting `cancel`
+    /// abandons the pending retries of the LFSC request, if any.
     #[allow(clippy::too_many_arguments)]
     pub(crate) fn get_page_slice(
         &self,
@@ -130,6 +325,7 @@ impl Pager {
         offset: u64,
         local_only: bool,
         prefetch: Option<Vec<ltx::PageNum>>,
+        cancel: Option<&AtomicBool>,
     ) -> io::Result<PageSource> {
         log::debug!(
             "[pager] get_page_slice: db = {}, pos = {}, pgno = {}, len = {}, offset = {}, local_only = {}, prefetch = {}",
@@ -145,7 +341,6 @@ impl Pager {
         // Request the page either from local cache or from LFSC and convert
         // io::ErrorKind::NotFound errors to io::ErrorKind::UnexpectedEof, as
         // this is what local IO will return in case we read past the file.
-        // TODO: we may need to suppress duplicated calls to the same page here.
         let r = match self.get_page_slice_inner(
             db,
             pos,
@@ -154,6 +349,7 @@ impl Pager {
             offset,
             local_only,
             prefetch.as_deref(),
+            cancel,
         ) {
             Err(err) if err.kind() == io::ErrorKind::NotFound => {
                 Err(io::ErrorKind::UnexpectedEof.into())
@@ -186,7 +382,7 @@ impl Pager {
     pub(crate) fn put_page(&self, db: &str, page: PageRef) -> io::Result<()> {
         log::debug!("[pager] put_page: db = {}, pgno = {}", db, page.number());
 
-        match self.put_page_inner(db, page) {
+        match self.put_page_inner(db, page, false) {
             Err(err) => {
                 log::error!(
                     "[pager] put_page: db = {}, pgno = {}: {}",
@@ -200,6 +396,116 @@ impl Pager {
         }
     }
 
+    /// Writes a page of a transaction that hasn't been committed yet to the cache. Unlike
+    /// [Pager::put_page], the page isn't evictable until [Pager::commit_pages] is called,
+    /// as there would be no way to get it back.
+    pub(crate) fn put_uncommitted_page(&self, db: &str, page: PageRef) -> io::Result<()> {
+        log::debug!(
+            "[pager] put_uncommitted_page: db = {}, pgno = {}",
+            db,
+            page.number()
+        );
+
+        // The committed version of the page may be evictable
+        let ret = self
+            .store_page(db, page)
+            .map(|()| self.untrack_page(db, page.number()));
+        match ret {
+            Err(err) => {
+                log::error!(
+                    "[pager] put_uncommitted_page: db = {}, pgno = {}: {}",
+                    db,
+                    page.number(),
+                    err,
+                );
+                Err(err)
+            }
+            x => x,
+        }
+    }
+
+    /// Makes the pages written with [Pager::put_uncommitted_page] evictable, once their
+    /// transaction is either committed or rolled back. Deleted pages are skipped.
+    pub(crate) fn commit_pages(
+        &self,
+        db: &str,
+        pgnos: impl IntoIterator<Item = ltx::PageNum>,
+    ) -> io::Result<()> {
+        for pgno in pgnos {
+            if self.has_page_inner(db, pgno)? {
+                self.track_page(db, pgno, false);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Fetches the page at the given `pos` from LFSC, bypassing the local cache. The
+    /// cache only holds pages at the current database position, so this is used to
+    /// read the database at older positions. Setting `cancel` abandons the pending
+    /// retries of the LFSC request.
+    pub(crate) fn get_page_uncached(
+        &self,
+        db: &str,
+        pos: ltx::Pos,
+        pgno: ltx::PageNum,
+        cancel: Option<&AtomicBool>,
+    ) -> io::Result<Page> {
+        self.get_pages_uncached(db, pos, &[pgno], cancel)?
+            .into_iter()
+            .find(|page| page.number() == pgno)
+            .ok_or(io::ErrorKind::NotFound.into())
+    }
+
+    /// Same as [Pager::get_page_uncached], but for several pages at once. Pages LFSC
+    /// doesn't have are missing from the result.
+    pub(crate) fn get_pages_uncached(
+        &self,
+        db: &str,
+        pos: ltx::Pos,
+        pgnos: &[ltx::PageNum],
+        cancel: Option<&AtomicBool>,
+    ) -> io::Result<Vec<Page>> {
+        log::debug!(
+            "[pager] get_pages_uncached: db = {}, pos = {}, pages = {}",
+            db,
+            pos,
+            pgnos.len()
+        );
+
+        let pages = Mutex::new(Vec::with_capacity(pgnos.len()));
+        let ret = self.get_pages_remote(db, pos, pgnos, cancel, &|page| {
+            pages
+                .lock()
+                .unwrap()
+                .push(Page::new(page.number(), page.into_inner()));
+            Ok(())
+        });
+        match ret {
+            Ok(()) => (),
+            Err(lfsc::Error::PosMismatch(x)) => {
+                log::warn!("[pager] get_pages_uncached: db = {}, pos mismatch error, requested = {}, got = {}",
+                    db, pos, x);
+                return Err(pos_mismatch_error());
+            }
+            Err(err) => {
+                log::error!(
+                    "[pager] get_pages_uncached: db = {}, pos = {}, pages = {}: {}",
+                    db,
+                    pos,
+                    pgnos.len(),
+                    err
+                );
+                return Err(err.into());
+            }
+        };
+
+        let pages = pages.into_inner().unwrap();
+        metrics().record_remote_fetch(pages.len());
+
+        Ok(pages)
+    }
+
     /// Deletes the page from the local cache. It's fine to attempt to delete an non-existing
     /// page.
     pub(crate) fn del_page(&self, db: &str, pgno: ltx::PageNum) -> io::Result<bool> {
@@ -251,6 +557,98 @@ impl Pager {
         }
     }
 
+    /// Returns the numbers of the pages of a database cached locally, in no particular order.
+    pub(crate) fn cached_pages(&self, db: &str) -> io::Result<Vec<ltx::PageNum>> {
+        match self.cached_pages_inner(db) {
+            Err(err) => {
+                log::error!("[pager] cached_pages: db = {}: {}", db, err);
+                Err(err)
+            }
+            x => x,
+        }
+    }
+
+    /// Returns the number of pages of a database cached locally.
+    pub(crate) fn page_count(&self, db: &str) -> io::Result<usize> {
+        match self.page_count_inner(db) {
+            Err(err) => {
+                log::error!("[pager] page_count: db = {}: {}", db, err);
+                Err(err)
+            }
+            x => x,
+        }
+    }
+
+    /// Moves the cached pages of the `db` that have an ETag out of the cache, so that they
+    /// can be revalidated with LFSC instead of downloaded again after the cache is cleared.
+    /// Returns the numbers of the kept pages, always empty unless conditional fetches are enabled.
+    pub(crate) fn keep_stale(&self, db: &str) -> io::Result<Vec<ltx::PageNum>> {
+        if !self.client.conditional_fetches() {
+            return Ok(Vec::new());
+        }
+
+        match self.keep_stale_inner(db) {
+            Err(err) => {
+                log::error!("[pager] keep_stale: db = {}: {}", db, err);
+                Err(err)
+            }
+            Ok(pgnos) => {
+                log::debug!("[pager] keep_stale: db = {}, pages = {}", db, pgnos.len());
+                Ok(pgnos)
+            }
+        }
+    }
+
+    /// Forgets everything the pager keeps in memory about the `db`, including its
+    /// interned name. Does nothing and returns `false` if any of its pages is still
+    /// tracked by the LRU cache or kept for revalidation, as they wouldn't be
+    /// reclaimable otherwise.
+    pub(crate) fn forget_db(&self, db: &str) -> bool {
+        // The LRU cache is locked before the interner, as elsewhere, and both are held
+        // until the name is removed, so that no page of the `db` is tracked in between.
+        let lru = self.lru.lock().unwrap();
+        let mut interner = self.interner.lock().unwrap();
+        let Some(dbsym) = interner.get(db) else {
+            return true;
+        };
+        if self.has_lru_pages(&lru, dbsym)
+            || self
+                .stale
+                .lock()
+                .unwrap()
+                .iter()
+                .any(|key| key.dbsym == dbsym)
+            || self
+                .inflight
+                .lock()
+                .unwrap()
+                .keys()
+                .any(|key| key.dbsym == dbsym)
+        {
+            return false;
+        }
+
+        log::debug!("[pager] forget_db: db = {}", db);
+        self.packs.lock().unwrap().remove(&dbsym);
+        self.bandwidth.lock().unwrap().remove(&dbsym);
+        interner.remove(db);
+
+        true
+    }
+
+    /// Persists the LRU cache ordering, so that it can be restored after a restart.
+    pub(crate) fn save_lru(&self) -> io::Result<()> {
+        log::debug!("[pager] save_lru");
+
+        match self.save_lru_inner() {
+            Err(err) => {
+                log::error!("[pager] save_lru: {}", err);
+                Err(err)
+            }
+            x => x,
+        }
+    }
+
     /// Returns the minimum available space that pager is trying to keep on the FS.
     pub(crate) fn min_available_space(&self) -> u64 {
         self.min_available_space.load(Ordering::Acquire)
@@ -261,6 +659,16 @@ impl Pager {
         self.min_available_space.store(maa, Ordering::Release)
     }
 
+    /// Returns the minimum number of free inodes that pager is trying to keep on the FS.
+    pub(crate) fn min_available_inodes(&self) -> u64 {
+        self.min_available_inodes.load(Ordering::Acquire)
+    }
+
+    /// Sets the minimum number of free inodes that pager needs to maintain on the FS.
+    pub(crate) fn set_min_available_inodes(&self, mai: u64) {
+        self.min_available_inodes.store(mai, Ordering::Release)
+    }
+
     /// Returns the maximum number of pages that pager will cache on local FS.
     pub(crate) fn max_cached_pages(&self) -> usize {
         self.max_cached_pages.load(Ordering::Acquire)
@@ -271,6 +679,112 @@ impl Pager {
         self.max_cached_pages.store(mcp, Ordering::Release)
     }
 
+    /// Returns the maximum fraction of the FS that pager will use for the cache.
+    pub(crate) fn max_cache_fraction(&self) -> f64 {
+        f64::from_bits(self.max_cache_fraction.load(Ordering::Acquire))
+    }
+
+    /// Sets the maximum fraction of the FS that pager will use for the cache. Zero disables the limit.
+    pub(crate) fn set_max_cache_fraction(&self, mcf: f64) {
+        self.max_cache_fraction
+            .store(mcf.to_bits(), Ordering::Release)
+    }
+
+    /// Returns the maximum number of pages requested from LFSC at once.
+    pub(crate) fn max_pages_per_request(&self) -> usize {
+        self.max_pages_per_request.load(Ordering::Acquire)
+    }
+
+    /// Sets the maximum number of pages requested from LFSC at once. Zero disables the limit.
+    pub(crate) fn set_max_pages_per_request(&self, mpr: usize) {
+        self.max_pages_per_request.store(mpr, Ordering::Release)
+    }
+
+    /// Returns the number of pages kept in memory in front of the on-disk cache.
+    pub(crate) fn mem_cache_pages(&self) -> usize {
+        self.mem_cache
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map_or(0, |cache| cache.cap())
+    }
+
+    /// Sets the number of pages kept in memory, `0` disables the memory cache.
+    /// The currently cached pages are dropped.
+    pub(crate) fn set_mem_cache_pages(&self, pages: usize) {
+        *self.mem_cache.lock().unwrap() = RawLRU::new(pages).ok();
+    }
+
+    /// Returns true if the newly cached pages are compressed.
+    pub(crate) fn cache_compression(&self) -> bool {
+        self.compression.load(Ordering::Acquire)
+    }
+
+    /// Enables or disables compression of the newly cached pages. The already
+    /// cached pages are left as they are.
+    pub(crate) fn set_cache_compression(&self, enabled: bool) -> io::Result<()> {
+        if enabled && cfg!(target_os = "emscripten") {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "cache compression is not supported in browsers",
+            ));
+        }
+
+        self.compression.store(enabled, Ordering::Release);
+
+        Ok(())
+    }
+
+    /// Returns a snapshot of the cache statistics.
+    pub(crate) fn stats(&self) -> PagerStatsSnapshot {
+        self.stats.snapshot()
+    }
+
+    /// Resets all the cache statistics counters to zero.
+    pub(crate) fn reset_stats(&self) {
+        self.stats.reset()
+    }
+
+    /// Returns the number of bytes of page data downloaded from LFSC for the `db`.
+    pub(crate) fn bandwidth(&self, db: &str) -> u64 {
+        let dbsym = self.interner.lock().unwrap().get_or_intern(db);
+        self.bandwidth
+            .lock()
+            .unwrap()
+            .get(&dbsym)
+            .map_or(0, |bw| bw.downloaded)
+    }
+
+    /// Resets the number of bytes downloaded for the `db` to zero.
+    pub(crate) fn reset_bandwidth(&self, db: &str) {
+        let dbsym = self.interner.lock().unwrap().get_or_intern(db);
+        if let Some(bw) = self.bandwidth.lock().unwrap().get_mut(&dbsym) {
+            bw.downloaded = 0;
+        }
+    }
+
+    /// Returns the bandwidth limit of the `db`, if any.
+    pub(crate) fn bandwidth_limit(&self, db: &str) -> Option<BandwidthLimit> {
+        let dbsym = self.interner.lock().unwrap().get_or_intern(db);
+        self.bandwidth
+            .lock()
+            .unwrap()
+            .get(&dbsym)
+            .and_then(|bw| bw.limit)
+    }
+
+    /// Limits the bandwidth used to fetch the `db` pages from LFSC. Once the limit
+    /// is used up, remote fetches fail with [io::ErrorKind::WouldBlock] until it's
+    /// replenished over the limit's window.
+    pub(crate) fn set_bandwidth_limit(&self, db: &str, limit: Option<BandwidthLimit>) {
+        let dbsym = self.interner.lock().unwrap().get_or_intern(db);
+        let mut bandwidth = self.bandwidth.lock().unwrap();
+        let bw = bandwidth.entry(dbsym).or_default();
+        bw.limit = limit;
+        bw.tokens = limit.map_or(0.0, |limit| limit.bytes as f64);
+        bw.refilled_at = time::Instant::now();
+    }
+
     fn get_page_inner(
         &self,
         db: &str,
@@ -284,7 +798,7 @@ impl Pager {
             _ => (),
         };
 
-        self.get_page_remote(db, pos, pgno, prefetch)
+        self.get_page_remote(db, pos, pgno, prefetch, None)
     }
 
     #[allow(clippy::too_many_arguments)]
@@ -297,9 +811,13 @@ impl Pager {
         offset: u64,
         local_only: bool,
         prefetch: Option<&[ltx::PageNum]>,
+        cancel: Option<&AtomicBool>,
     ) -> io::Result<PageSource> {
         match self.get_page_slice_local(db, pos, pgno, buf, offset) {
-            Ok(_) => return Ok(PageSource::Local),
+            Ok(_) => {
+                metrics().record_local_hit();
+                return Ok(PageSource::Local);
+            }
             Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
             _ => (),
         };
@@ -311,7 +829,7 @@ impl Pager {
             ));
         }
 
-        let page = self.get_page_remote(db, pos, pgno, prefetch)?;
+        let page = self.get_page_remote(db, pos, pgno, prefetch, cancel)?;
         let offset = offset as usize;
         buf.copy_from_slice(&page.as_ref()[offset..offset + buf.len()]);
 
@@ -324,12 +842,18 @@ impl Pager {
         _pos: Option<ltx::Pos>,
         pgno: ltx::PageNum,
     ) -> io::Result<Page> {
-        let mut file = fs::File::open(self.pages_path(db).join(PathBuf::from(pgno)))?;
-        let mut buf = Vec::new();
-        file.read_to_end(&mut buf)?;
+        let buf = match self.get_mem_page(db, pgno, |data| data.to_vec()) {
+            Some(buf) => buf,
+            None => {
+                let buf = self.read_page_data(db, pgno)?;
+                self.put_mem_page(db, pgno, &buf);
+
+                buf
+            }
+        };
 
-        // Mark the page as recently accessed
-        self.lru.lock().unwrap().get(&self.cache_key(db, pgno));
+        metrics().record_local_hit();
+        self.mark_accessed(db, pgno);
 
         Ok(Page::new(pgno, buf))
     }
@@ -342,21 +866,118 @@ impl Pager {
         buf: &mut [u8],
         offset: u64,
     ) -> io::Result<()> {
-        let mut file = fs::File::open(self.pages_path(db).join(PathBuf::from(pgno)))?;
-        file.read_exact_at(buf, offset)?;
+        let mut copy_slice =
+            |data: &[u8]| match data.get(offset as usize..offset as usize + buf.len()) {
+                Some(slice) => {
+                    buf.copy_from_slice(slice);
+                    Ok(())
+                }
+                None => Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+            };
+
+        if let Some(ret) = self.get_mem_page(db, pgno, &mut copy_slice) {
+            ret?;
+        } else if self.mem_cache.lock().unwrap().is_some() {
+            // Read the whole page, so that it can be cached in memory
+            let data = self.read_page_data(db, pgno)?;
+            self.put_mem_page(db, pgno, &data);
+            copy_slice(&data)?;
+        } else {
+            match self.backend {
+                PagerBackend::Files => {
+                    let mut file = open_nofollow(
+                        fs::OpenOptions::new().read(true),
+                        &self.pages_path(db).join(PathBuf::from(pgno)),
+                    )?;
+                    if is_page_size(file.metadata()?.len()) {
+                        file.read_exact_at(buf, offset)?;
+                    } else {
+                        // Compressed pages can't be read at an offset
+                        let mut data = Vec::new();
+                        file.read_to_end(&mut data)?;
+                        copy_slice(&decompress_page(&data)?)?;
+                    }
+                }
+                PagerBackend::Packed => self.pack(db)?.read_at(pgno, buf, offset)?,
+            };
+        }
 
-        // Mark the page as recently accessed
-        self.lru.lock().unwrap().get(&self.cache_key(db, pgno));
+        self.mark_accessed(db, pgno);
 
         Ok(())
     }
 
+    // Reads the whole page from the local FS.
+    fn read_page_data(&self, db: &str, pgno: ltx::PageNum) -> io::Result<Vec<u8>> {
+        match self.backend {
+            PagerBackend::Files => {
+                let mut file = open_nofollow(
+                    fs::OpenOptions::new().read(true),
+                    &self.pages_path(db).join(PathBuf::from(pgno)),
+                )?;
+                let mut buf = Vec::new();
+                file.read_to_end(&mut buf)?;
+                if !is_page_size(buf.len() as u64) {
+                    buf = decompress_page(&buf)?;
+                }
+
+                Ok(buf)
+            }
+            PagerBackend::Packed => self.pack(db)?.read(pgno),
+        }
+    }
+
+    // Calls `f` with the page data if the page is cached in memory.
+    fn get_mem_page<T>(
+        &self,
+        db: &str,
+        pgno: ltx::PageNum,
+        f: impl FnOnce(&[u8]) -> T,
+    ) -> Option<T> {
+        let key = self.cache_key(db, pgno);
+        let ret = f(self.mem_cache.lock().unwrap().as_mut()?.get(&key)?);
+        self.stats.mem_hits.fetch_add(1, Ordering::Relaxed);
+
+        Some(ret)
+    }
+
+    fn put_mem_page(&self, db: &str, pgno: ltx::PageNum, data: &[u8]) {
+        let key = self.cache_key(db, pgno);
+        if let Some(cache) = self.mem_cache.lock().unwrap().as_mut() {
+            cache.put(key, data.to_vec());
+        }
+    }
+
+    // Removes the page from both the LRU and the memory cache.
+    fn forget_page(&self, db: &str, pgno: ltx::PageNum) {
+        self.untrack_page(db, pgno);
+        let key = self.cache_key(db, pgno);
+        if let Some(cache) = self.mem_cache.lock().unwrap().as_mut() {
+            cache.remove(&key);
+        }
+    }
+
+    fn mark_accessed(&self, db: &str, pgno: ltx::PageNum) {
+        // Mark the page as recently accessed and account for the first access
+        // of a prefetched page
+        let mut lru = self.lru.lock().unwrap();
+        let key = self.cache_key(db, pgno);
+        if let Some(prefetched) = lru.get_mut(&key) {
+            if *prefetched {
+                *prefetched = false;
+                self.stats.prefetch_hits.fetch_add(1, Ordering::Relaxed);
+            }
+            self.track_lru_page(key);
+        }
+    }
+
     fn get_page_remote(
         &self,
         db: &str,
         pos: Option<ltx::Pos>,
         pgno: ltx::PageNum,
         prefetch: Option<&[ltx::PageNum]>,
+        cancel: Option<&AtomicBool>,
     ) -> io::Result<Page> {
         let pos = if let Some(pos) = pos {
             pos
@@ -364,97 +985,353 @@ impl Pager {
             return Err(io::ErrorKind::NotFound.into());
         };
 
+        // Suppress duplicated requests for the same page. The first caller
+        // does the actual fetch, the rest wait for its result.
+        let key = InflightKey {
+            dbsym: self.interner.lock().unwrap().get_or_intern(db),
+            txid: pos.txid.into_inner(),
+            checksum: pos.post_apply_checksum.into_inner(),
+            pgno,
+        };
+        let (fetch, leader) = {
+            let mut inflight = self.inflight.lock().unwrap();
+            if let Some(fetch) = inflight.get(&key) {
+                (Arc::clone(fetch), false)
+            } else {
+                let fetch = Arc::new(InflightFetch::new());
+                inflight.insert(key, Arc::clone(&fetch));
+                (fetch, true)
+            }
+        };
+
+        if !leader {
+            log::trace!(
+                "[pager] get_page_remote: db = {}, pos = {}, pgno = {}, waiting for in-flight request",
+                db,
+                pos,
+                pgno
+            );
+            return match fetch.wait() {
+                // The request has been cancelled by another connection, not this one
+                Err(err)
+                    if err.kind() == io::ErrorKind::Interrupted
+                        && !cancel.map_or(false, |cancel| cancel.load(Ordering::Acquire)) =>
+                {
+                    self.get_page_remote(db, Some(pos), pgno, prefetch, cancel)
+                }
+                ret => ret.map(|data| Page::new(pgno, data)),
+            };
+        }
+
+        let guard = InflightGuard::new(&self.inflight, key, fetch);
+        let ret = self.fetch_page_remote(db, pos, pgno, prefetch, cancel);
+        guard.complete(&ret);
+
+        ret
+    }
+
+    fn fetch_page_remote(
+        &self,
+        db: &str,
+        pos: ltx::Pos,
+        pgno: ltx::PageNum,
+        prefetch: Option<&[ltx::PageNum]>,
+        cancel: Option<&AtomicBool>,
+    ) -> io::Result<Page> {
+        let dbsym = self.interner.lock().unwrap().get_or_intern(db);
+        if let Some(bw) = self.bandwidth.lock().unwrap().get_mut(&dbsym) {
+            if !bw.available() {
+                return Err(io::Error::new(
+                    io::ErrorKind::WouldBlock,
+                    "bandwidth limit exceeded",
+                ));
+            }
+        }
+
         let mut pages = vec![pgno];
         if let Some(pgnos) = prefetch {
             pages.extend(pgnos);
         }
-        let pages = match self.client.get_pages(db, pos, &pages) {
-            Ok(pages) => pages,
-            Err(lfsc::Error::PosMismatch(x)) => {
-                log::warn!("get_page_remote: db = {}, pgno = {}, pos mismatch error, requested = {}, got = {}",
-                    db, pgno, pos, x);
-                // LFSC no longer have the requested pos. At this point we may try to recover
-                // from this ourselves, or tell the user to retry the transaction. The only
-                // safe situation when we can recover is when this is the very first read
-                // of a TX. But, in 99.9% the very first read will hit the cache (page 1),
-                // so just return a custom error code to the user. The client code can retry
-                // the transaction automatically after that.
-                return Err(io::Error::new(
-                    io::ErrorKind::Other,
-                    CodeError::new(LITEVFS_IOERR_POS_MISMATCH),
-                ));
+
+        // Pages kept from before a resync are revalidated, the rest are downloaded in one batch
+        let mut revalidated_page = None;
+        if self.client.conditional_fetches() {
+            let revalidated = self
+                .revalidate_pages(db, pos, &pages, pgno, cancel)
+                .map_err(|err| fetch_error(db, pos, pgno, err))?;
+            pages.retain(|pgno| !revalidated.iter().any(|page| page.number() == *pgno));
+            revalidated_page = revalidated.into_iter().find(|page| page.number() == pgno);
+            if pages.is_empty() {
+                return revalidated_page.ok_or(io::ErrorKind::NotFound.into());
             }
-            Err(x) => return Err(x.into()),
-        };
+        }
 
-        let mut requested_page: Option<Page> = None;
-        for page in pages {
+        // Pages are cached as they arrive, instead of after the whole batch
+        let downloaded = AtomicU64::new(0);
+        let fetched = AtomicUsize::new(0);
+        let requested_page = Mutex::new(revalidated_page);
+        let ret = self.get_pages_remote(db, pos, &pages, cancel, &|page| {
             log::trace!(
-                "[pager] get_page_remote: pos = {}, pgno = {}, got = {}",
+                "[pager] fetch_page_remote: pos = {}, pgno = {}, got = {}",
                 pos,
                 pgno,
                 page.number(),
             );
+            downloaded.fetch_add(page.as_ref().len() as u64, Ordering::Relaxed);
+            fetched.fetch_add(1, Ordering::Relaxed);
+
             let page_ref = PageRef {
                 data: page.as_ref(),
                 number: page.number(),
             };
-            self.put_page_inner(db, page_ref)?;
+            self.put_page_inner(db, page_ref, page.number() != pgno)?;
+            if let (true, Some(etag)) = (self.client.conditional_fetches(), page.etag()) {
+                self.put_etag(db, page.number(), etag)?;
+            }
 
             if page.number() == pgno {
-                requested_page = Some(Page::new(page.number(), page.into_inner()))
+                *requested_page.lock().unwrap() = Some(Page::new(page.number(), page.into_inner()));
             }
+
+            Ok(())
+        });
+
+        let downloaded = downloaded.into_inner();
+        self.stats
+            .bytes_downloaded
+            .fetch_add(downloaded, Ordering::Relaxed);
+        self.bandwidth
+            .lock()
+            .unwrap()
+            .entry(dbsym)
+            .or_default()
+            .consume(downloaded);
+
+        if let Err(err) = ret {
+            return Err(fetch_error(db, pos, pgno, err));
         }
 
-        requested_page.ok_or(io::ErrorKind::NotFound.into())
+        metrics().record_remote_fetch(fetched.into_inner());
+
+        requested_page
+            .into_inner()
+            .unwrap()
+            .ok_or(io::ErrorKind::NotFound.into())
     }
 
-    fn put_page_inner(&self, db: &str, page: PageRef) -> io::Result<()> {
-        let tmp_name = self.tmp_path(db).join(PathBuf::from(page.number()));
-        let final_name = self.pages_path(db).join(PathBuf::from(page.number()));
+    // Requests the pages from LFSC, splitting large batches into a few parallel requests
+    // of at most `max_pages_per_request` pages. The pages are passed to `on_page` as they arrive.
+    fn get_pages_remote(
+        &self,
+        db: &str,
+        pos: ltx::Pos,
+        pgnos: &[ltx::PageNum],
+        cancel: Option<&AtomicBool>,
+        on_page: &(dyn Fn(lfsc::Page) -> io::Result<()> + Sync),
+    ) -> Result<(), lfsc::Error> {
+        let max_pages = match self.max_pages_per_request() {
+            0 => usize::MAX,
+            n => n,
+        };
 
-        self.reclaim_space()?;
+        if self.fetch_parallelism <= 1 || pgnos.len() < MIN_PARALLEL_FETCH_PAGES {
+            for chunk in pgnos.chunks(max_pages) {
+                self.client
+                    .stream_pages(db, pos, chunk, cancel, &mut |page| on_page(page))?;
+            }
 
-        let mut file = fs::File::create(&tmp_name)?;
-        file.write_all(page.as_ref())?;
-        fs::rename(tmp_name, final_name)?;
+            return Ok(());
+        }
 
-        self.lru
-            .lock()
-            .unwrap()
-            .put(self.cache_key(db, page.number()), ());
+        let chunk_size = ((pgnos.len() + self.fetch_parallelism - 1) / self.fetch_parallelism)
+            .max(MIN_PARALLEL_FETCH_PAGES / 2)
+            .min(max_pages);
+        let chunks = pgnos.chunks(chunk_size).collect::<Vec<_>>();
+
+        // No more than `fetch_parallelism` requests are in flight at once
+        for batch in chunks.chunks(self.fetch_parallelism) {
+            thread::scope(|s| {
+                let requests = batch
+                    .iter()
+                    .map(|&chunk| {
+                        s.spawn(move || {
+                            self.client
+                                .stream_pages(db, pos, chunk, cancel, &mut |page| on_page(page))
+                        })
+                    })
+                    .collect::<Vec<_>>();
+
+                for request in requests {
+                    request.join().expect("get_pages thread panicked")?;
+                }
+
+                Ok::<_, lfsc::Error>(())
+            })?;
+        }
 
         Ok(())
     }
 
-    fn del_page_inner(&self, db: &str, pgno: ltx::PageNum) -> io::Result<bool> {
-        let name = self.pages_path(db).join(PathBuf::from(pgno));
-        let removed = remove_file(name)?;
-
-        self.lru.lock().unwrap().remove(&self.cache_key(db, pgno));
+    fn put_page_inner(&self, db: &str, page: PageRef, prefetched: bool) -> io::Result<()> {
+        self.store_page(db, page)?;
+        self.track_page(db, page.number(), prefetched);
 
-        Ok(removed)
+        Ok(())
     }
 
-    fn truncate_inner(&self, db: &str, pgno: ltx::PageNum) -> io::Result<()> {
-        let fname: ffi::OsString = PathBuf::from(pgno).into();
-
+    // Writes the page to the cache without making it evictable.
+    fn store_page(&self, db: &str, page: PageRef) -> io::Result<()> {
+        self.reclaim_space()?;
+        // The new data doesn't necessarily match the ETag of the old one
+        if self.client.conditional_fetches() {
+            self.remove_stale(db, page.number())?;
+        }
+
+        match self.backend {
+            PagerBackend::Files => {
+                let tmp_name = self.tmp_path(db).join(PathBuf::from(page.number()));
+                let final_name = self.pages_path(db).join(PathBuf::from(page.number()));
+
+                // Renaming over a symlink replaces the link itself, not its target
+                let mut file = open_nofollow(
+                    fs::OpenOptions::new()
+                        .write(true)
+                        .create(true)
+                        .truncate(true),
+                    &tmp_name,
+                )?;
+                match self.compress_page(page.as_ref())? {
+                    Some(data) => file.write_all(&data)?,
+                    None => file.write_all(page.as_ref())?,
+                };
+                if self.durability == CacheDurability::Safe {
+                    (self.fsync)(&file)?;
+                }
+                fs::rename(tmp_name, final_name)?;
+                // Make the rename itself durable. Directories can't be opened on Windows.
+                #[cfg(unix)]
+                if self.durability == CacheDurability::Safe {
+                    (self.fsync)(&fs::File::open(self.pages_path(db))?)?;
+                }
+            }
+            PagerBackend::Packed => {
+                let pack = self.pack(db)?;
+                let removed = match self.durability {
+                    CacheDurability::Safe => {
+                        pack.write_synced(page.number(), page.as_ref(), self.fsync)?
+                    }
+                    CacheDurability::Fast => pack.write(page.number(), page.as_ref())?,
+                };
+                // Page size change drops all the previously cached pages
+                for pgno in removed {
+                    self.forget_page(db, pgno);
+                }
+            }
+        };
+
+        self.put_mem_page(db, page.number(), page.as_ref());
+        self.page_size_hint
+            .store(page.as_ref().len() as u64, Ordering::Relaxed);
+
+        Ok(())
+    }
+
+    // Removes a cached page from the LRU cache, so that it can't be evicted.
+    fn untrack_page(&self, db: &str, pgno: ltx::PageNum) {
+        let mut lru = self.lru.lock().unwrap();
+        let key = self.cache_key(db, pgno);
+        lru.remove(&key);
+        self.untrack_lru_page(key);
+    }
+
+    // Puts a cached page into the LRU cache, so that it can be evicted.
+    fn track_page(&self, db: &str, pgno: ltx::PageNum, prefetched: bool) {
+        // Interned with the LRU cache locked, so that `forget_db` can't drop the name
+        // before the page is tracked
+        let mut lru = self.lru.lock().unwrap();
+        let key = self.cache_key(db, pgno);
+        lru.put(key, prefetched);
+        self.track_lru_page(key);
+        drop(lru);
+
+        // Another thread may be saving it already, don't wait for it
+        let save_due = matches!(
+            self.lru_saved_at.try_lock(),
+            Ok(saved_at) if saved_at.elapsed() >= LRU_SAVE_PERIOD
+        );
+        if save_due {
+            _ = self.save_lru();
+        }
+    }
+
+    // Returns the compressed page data, or `None` if the page is to be stored as is.
+    fn compress_page(&self, data: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        if !self.cache_compression() {
+            return Ok(None);
+        }
+
+        let compressed = compress_page(data)?;
+        if let Some(ref compressed) = compressed {
+            self.stats.compressed_pages.fetch_add(1, Ordering::Relaxed);
+            self.stats
+                .uncompressed_bytes
+                .fetch_add(data.len() as u64, Ordering::Relaxed);
+            self.stats
+                .compressed_bytes
+                .fetch_add(compressed.len() as u64, Ordering::Relaxed);
+        }
+
+        Ok(compressed)
+    }
+
+    fn del_page_inner(&self, db: &str, pgno: ltx::PageNum) -> io::Result<bool> {
+        let removed = self.remove_page_data(db, pgno)?;
+        if self.client.conditional_fetches() {
+            self.remove_stale(db, pgno)?;
+        }
+
+        self.forget_page(db, pgno);
+
+        Ok(removed)
+    }
+
+    fn truncate_inner(&self, db: &str, pgno: ltx::PageNum) -> io::Result<()> {
+        if self.backend == PagerBackend::Packed {
+            for rpgno in self.pack(db)?.truncate(pgno)? {
+                self.forget_page(db, rpgno);
+            }
+
+            return Ok(());
+        }
+
         for entry in fs::read_dir(self.pages_path(db))? {
             let entry = entry?;
-            if !entry.file_type()?.is_file() || entry.file_name() <= fname {
+            if !entry.file_type()?.is_file() {
                 continue;
             }
 
-            remove_file(entry.path())?;
-
             let rpgno = ltx::PageNum::try_from(Path::new(&entry.file_name()))?;
-            self.lru.lock().unwrap().remove(&self.cache_key(db, rpgno));
+            if rpgno <= pgno {
+                continue;
+            }
+
+            remove_file(entry.path())?;
+            self.forget_page(db, rpgno);
         }
 
         Ok(())
     }
 
     fn clear_inner(&self, db: &str) -> io::Result<Vec<ltx::PageNum>> {
+        if self.backend == PagerBackend::Packed {
+            let pgnos = self.pack(db)?.clear()?;
+            for &pgno in &pgnos {
+                self.forget_page(db, pgno);
+            }
+
+            return Ok(pgnos);
+        }
+
         let mut pgnos = Vec::new();
 
         for entry in fs::read_dir(self.pages_path(db))? {
@@ -466,7 +1343,7 @@ impl Pager {
             remove_file(entry.path())?;
 
             let rpgno = ltx::PageNum::try_from(Path::new(&entry.file_name()))?;
-            self.lru.lock().unwrap().remove(&self.cache_key(db, rpgno));
+            self.forget_page(db, rpgno);
 
             pgnos.push(rpgno);
         }
@@ -475,9 +1352,457 @@ impl Pager {
     }
 
     fn has_page_inner(&self, db: &str, pgno: ltx::PageNum) -> io::Result<bool> {
-        let page_name = self.pages_path(db).join(PathBuf::from(pgno));
+        match self.backend {
+            PagerBackend::Files => self.pages_path(db).join(PathBuf::from(pgno)).try_exists(),
+            PagerBackend::Packed => Ok(self.pack(db)?.contains(pgno)),
+        }
+    }
+
+    fn cached_pages_inner(&self, db: &str) -> io::Result<Vec<ltx::PageNum>> {
+        if self.backend == PagerBackend::Packed {
+            return Ok(self.pack(db)?.pages());
+        }
+
+        let mut pgnos = Vec::new();
+        for entry in fs::read_dir(self.pages_path(db))? {
+            let entry = entry?;
+            if entry.file_type()?.is_file() {
+                pgnos.push(ltx::PageNum::try_from(Path::new(&entry.file_name()))?);
+            }
+        }
+
+        Ok(pgnos)
+    }
+
+    fn page_count_inner(&self, db: &str) -> io::Result<usize> {
+        if self.backend == PagerBackend::Packed {
+            return Ok(self.pack(db)?.pages().len());
+        }
+
+        let mut count = 0;
+        for entry in fs::read_dir(self.pages_path(db))? {
+            if entry?.file_type()?.is_file() {
+                count += 1;
+            }
+        }
+
+        Ok(count)
+    }
+
+    // Removes the page data from the local FS, without touching the LRU cache.
+    fn remove_page_data(&self, db: &str, pgno: ltx::PageNum) -> io::Result<bool> {
+        match self.backend {
+            PagerBackend::Files => remove_file(self.pages_path(db).join(PathBuf::from(pgno))),
+            PagerBackend::Packed => self.pack(db)?.remove(pgno),
+        }
+    }
+
+    fn put_etag(&self, db: &str, pgno: ltx::PageNum, etag: &str) -> io::Result<()> {
+        fs::write(self.etags_path(db).join(PathBuf::from(pgno)), etag)
+    }
+
+    // Removes the ETag and the stale copy of the page, if any.
+    fn remove_stale(&self, db: &str, pgno: ltx::PageNum) -> io::Result<()> {
+        remove_file(self.stale_path(db).join(PathBuf::from(pgno)))?;
+        remove_file(self.etags_path(db).join(PathBuf::from(pgno)))?;
+        let key = self.cache_key(db, pgno);
+        self.stale.lock().unwrap().remove(&key);
+
+        Ok(())
+    }
+
+    // Removes one of the stale copies, which are the first to go when the cache is over
+    // its limits. Returns `false` if there are none.
+    fn remove_stale_copy(&self) -> io::Result<bool> {
+        let Some(key) = self.stale.lock().unwrap().iter().next().copied() else {
+            return Ok(false);
+        };
+
+        let db = self
+            .interner
+            .lock()
+            .unwrap()
+            .resolve(key.dbsym)
+            .map(|db| db.to_string());
+        match db {
+            Some(db) => {
+                log::trace!(
+                    "[pager] remove_stale_copy: db = {}, pgno = {}",
+                    db,
+                    key.pgno
+                );
+                self.remove_stale(&db, key.pgno)?;
+            }
+            None => {
+                self.stale.lock().unwrap().remove(&key);
+            }
+        }
+
+        Ok(true)
+    }
+
+    fn has_stale(&self, db: &str, pgno: ltx::PageNum) -> bool {
+        let key = self.cache_key(db, pgno);
+        self.stale.lock().unwrap().contains(&key)
+    }
+
+    // Returns the ETag and the data of the stale copy of the page, if any.
+    fn stale_page(&self, db: &str, pgno: ltx::PageNum) -> io::Result<Option<(String, Vec<u8>)>> {
+        let read = |path: PathBuf| match fs::read(path) {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+            Ok(data) => Ok(Some(data)),
+        };
+
+        let Some(etag) = read(self.etags_path(db).join(PathBuf::from(pgno)))? else {
+            return Ok(None);
+        };
+        let Some(mut data) = read(self.stale_path(db).join(PathBuf::from(pgno)))? else {
+            return Ok(None);
+        };
+        // Page files are moved as they are, compressed or not
+        if !is_page_size(data.len() as u64) {
+            data = decompress_page(&data)?;
+        }
+        let etag =
+            String::from_utf8(etag).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        Ok(Some((etag, data)))
+    }
+
+    fn keep_stale_inner(&self, db: &str) -> io::Result<Vec<ltx::PageNum>> {
+        // Copies left from a previous resync have been revalidated or are outdated
+        for entry in fs::read_dir(self.stale_path(db))? {
+            let entry = entry?;
+            remove_file(entry.path())?;
+            if let Ok(pgno) = ltx::PageNum::try_from(Path::new(&entry.file_name())) {
+                let key = self.cache_key(db, pgno);
+                self.stale.lock().unwrap().remove(&key);
+            }
+        }
+
+        let mut kept = Vec::new();
+        for pgno in self.cached_pages_inner(db)? {
+            let etag_path = self.etags_path(db).join(PathBuf::from(pgno));
+            if !etag_path.try_exists()? {
+                continue;
+            }
+
+            let stale_path = self.stale_path(db).join(PathBuf::from(pgno));
+            match self.backend {
+                // The page leaves the cache, so it isn't stored twice
+                PagerBackend::Files => {
+                    fs::rename(self.pages_path(db).join(PathBuf::from(pgno)), stale_path)?;
+                    self.forget_page(db, pgno);
+                }
+                // Pages can't be moved out of the pack, the copy replaces the cleared one
+                PagerBackend::Packed => fs::write(stale_path, self.read_page_data(db, pgno)?)?,
+            }
+            let key = self.cache_key(db, pgno);
+            self.stale.lock().unwrap().insert(key);
+            kept.push(pgno);
+        }
+
+        Ok(kept)
+    }
 
-        page_name.try_exists()
+    // Revalidates the pages out of `pgnos` that have stale copies, sending up to
+    // `fetch_parallelism` conditional requests at once, as LFSC only takes an ETag
+    // per request. Returns the revalidated or downloaded pages. All but `pgno` are
+    // cached as prefetched.
+    fn revalidate_pages(
+        &self,
+        db: &str,
+        pos: ltx::Pos,
+        pgnos: &[ltx::PageNum],
+        pgno: ltx::PageNum,
+        cancel: Option<&AtomicBool>,
+    ) -> Result<Vec<Page>, lfsc::Error> {
+        let stale = pgnos
+            .iter()
+            .copied()
+            .filter(|&pgno| self.has_stale(db, pgno))
+            .collect::<Vec<_>>();
+
+        let mut pages = Vec::with_capacity(stale.len());
+        for batch in stale.chunks(self.fetch_parallelism.max(1)) {
+            if let [rpgno] = batch {
+                pages.extend(self.revalidate_page(db, pos, *rpgno, *rpgno != pgno, cancel)?);
+                continue;
+            }
+
+            thread::scope(|s| {
+                let requests = batch
+                    .iter()
+                    .map(|&rpgno| {
+                        s.spawn(move || self.revalidate_page(db, pos, rpgno, rpgno != pgno, cancel))
+                    })
+                    .collect::<Vec<_>>();
+
+                for request in requests {
+                    pages.extend(request.join().expect("revalidate_page thread panicked")?);
+                }
+
+                Ok::<_, lfsc::Error>(())
+            })?;
+        }
+
+        Ok(pages)
+    }
+
+    // Returns the stale copy of the page if LFSC confirms that it hasn't changed, or the new
+    // version of the page otherwise. Returns `None` if there is no stale copy of the page.
+    fn revalidate_page(
+        &self,
+        db: &str,
+        pos: ltx::Pos,
+        pgno: ltx::PageNum,
+        prefetched: bool,
+        cancel: Option<&AtomicBool>,
+    ) -> Result<Option<Page>, lfsc::Error> {
+        let Some((etag, data)) = self.stale_page(db, pgno)? else {
+            return Ok(None);
+        };
+
+        let (data, etag) = match self
+            .client
+            .get_page_if_none_match(db, pos, pgno, &etag, cancel)?
+        {
+            None => {
+                log::debug!(
+                    "[pager] revalidate_page: db = {}, pos = {}, pgno = {}, not modified",
+                    db,
+                    pos,
+                    pgno
+                );
+                self.stats.revalidated_pages.fetch_add(1, Ordering::Relaxed);
+
+                (data, Some(etag))
+            }
+            Some(page) => {
+                let downloaded = page.as_ref().len() as u64;
+                self.stats
+                    .bytes_downloaded
+                    .fetch_add(downloaded, Ordering::Relaxed);
+                let dbsym = self.interner.lock().unwrap().get_or_intern(db);
+                self.bandwidth
+                    .lock()
+                    .unwrap()
+                    .entry(dbsym)
+                    .or_default()
+                    .consume(downloaded);
+
+                let etag = page.etag().map(String::from);
+                (page.into_inner(), etag)
+            }
+        };
+
+        self.put_page_inner(db, PageRef::new(pgno, &data), prefetched)?;
+        if let Some(etag) = etag {
+            self.put_etag(db, pgno, &etag)?;
+        }
+        metrics().record_remote_fetch(1);
+
+        Ok(Some(Page::new(pgno, data)))
+    }
+
+    // Returns the pack file of the `db`, opening it on first access.
+    fn pack(&self, db: &str) -> io::Result<Arc<PackFile>> {
+        let dbsym = self.interner.lock().unwrap().get_or_intern(db);
+        let mut packs = self.packs.lock().unwrap();
+        if let Some(pack) = packs.get(&dbsym) {
+            return Ok(Arc::clone(pack));
+        }
+
+        let pack = Arc::new(PackFile::open(self.db_path(db))?);
+        packs.insert(dbsym, Arc::clone(&pack));
+
+        Ok(pack)
+    }
+
+    fn save_lru_inner(&self) -> io::Result<()> {
+        let mut saved_at = self.lru_saved_at.lock().unwrap();
+
+        let keys = self.lru_order();
+        let pages = {
+            let interner = self.interner.lock().unwrap();
+            keys.into_iter()
+                .filter_map(|key| Some((interner.resolve(key.dbsym)?.to_string(), key.pgno)))
+                .collect::<Vec<_>>()
+        };
+
+        let tmp_name = self.root.join("lru.tmp");
+        let mut file = io::BufWriter::new(fs::File::create(&tmp_name)?);
+        for (db, pgno) in pages {
+            writeln!(file, "{}\t{}", db, PathBuf::from(pgno).display())?;
+        }
+        file.flush()?;
+        drop(file);
+        fs::rename(tmp_name, self.lru_path())?;
+
+        *saved_at = time::Instant::now();
+
+        Ok(())
+    }
+
+    // Rebuilds the LRU cache from the pages cached on disk. The pages are ordered
+    // according to the persisted list, pages missing from the list are considered
+    // the coldest ones and list entries without a page on disk are ignored.
+    fn restore_lru(&self) -> io::Result<()> {
+        let mut cached = HashSet::new();
+        let entries = match fs::read_dir(&self.root) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err),
+        };
+        for entry in entries {
+            let entry = entry?;
+            if !entry.file_type()?.is_dir() {
+                continue;
+            }
+            let db = if let Some(db) = entry.file_name().to_str() {
+                db.to_string()
+            } else {
+                continue;
+            };
+
+            // Stale copies left from before the restart count towards the cache limits
+            match fs::read_dir(self.stale_path(&db)) {
+                Ok(pages) => {
+                    for page in pages {
+                        let page = page?;
+                        if let Ok(pgno) = ltx::PageNum::try_from(Path::new(&page.file_name())) {
+                            let key = self.cache_key(&db, pgno);
+                            self.stale.lock().unwrap().insert(key);
+                        }
+                    }
+                }
+                Err(err) if err.kind() == io::ErrorKind::NotFound => (),
+                Err(err) => return Err(err),
+            }
+
+            if self.backend == PagerBackend::Packed {
+                if self.db_path(&db).join(PackFile::INDEX_NAME).try_exists()? {
+                    cached.extend(
+                        self.pack(&db)?
+                            .pages()
+                            .into_iter()
+                            .map(|pgno| (db.clone(), pgno)),
+                    );
+                }
+                continue;
+            }
+
+            let pages = match fs::read_dir(self.pages_path(&db)) {
+                Ok(pages) => pages,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(err),
+            };
+            for page in pages {
+                let page = page?;
+                if !page.file_type()?.is_file() {
+                    continue;
+                }
+
+                if let Ok(pgno) = ltx::PageNum::try_from(Path::new(&page.file_name())) {
+                    cached.insert((db.clone(), pgno));
+                }
+            }
+        }
+
+        let order = match fs::read_to_string(self.lru_path()) {
+            Ok(order) => order,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(err) => return Err(err),
+        };
+        let mut ordered = Vec::new();
+        for line in order.lines() {
+            let key = line.rsplit_once('\t').and_then(|(db, pgno)| {
+                Some((
+                    db.to_string(),
+                    ltx::PageNum::try_from(Path::new(pgno)).ok()?,
+                ))
+            });
+
+            if let Some(key) = key.and_then(|key| cached.take(&key)) {
+                ordered.push(key);
+            }
+        }
+
+        let mut lru = self.lru.lock().unwrap();
+        for (db, pgno) in cached.into_iter().chain(ordered) {
+            // Promote the page to the protected segment right away, colder pages
+            // are demoted to the probationary segment as the hotter ones are added.
+            let key = self.cache_key(&db, pgno);
+            lru.put(key, false);
+            _ = lru.get(&key);
+            self.track_lru_page(key);
+        }
+
+        Ok(())
+    }
+
+    // Records a page put into or accessed in the LRU cache, called with the LRU cache locked.
+    fn track_lru_page(&self, key: PageCacheKey) {
+        let tick = self.lru_clock.fetch_add(1, Ordering::Relaxed);
+        self.lru_pages
+            .lock()
+            .unwrap()
+            .entry(key.dbsym)
+            .or_default()
+            .insert(key.pgno, tick);
+    }
+
+    // Records a page removed from the LRU cache, called with the LRU cache locked.
+    fn untrack_lru_page(&self, key: PageCacheKey) {
+        let mut lru_pages = self.lru_pages.lock().unwrap();
+        if let Some(pgnos) = lru_pages.get_mut(&key.dbsym) {
+            pgnos.remove(&key.pgno);
+            if pgnos.is_empty() {
+                lru_pages.remove(&key.dbsym);
+            }
+        }
+    }
+
+    // Checks if the LRU cache still tracks any page of the database, dropping the
+    // pages the cache has dropped on its own from the index along the way. Called
+    // with the LRU cache locked.
+    fn has_lru_pages(&self, lru: &SegmentedCache<PageCacheKey, bool>, dbsym: Symbol) -> bool {
+        let mut lru_pages = self.lru_pages.lock().unwrap();
+        let Some(pgnos) = lru_pages.get_mut(&dbsym) else {
+            return false;
+        };
+        pgnos.retain(|&pgno, _| lru.contains(&PageCacheKey { dbsym, pgno }));
+        if !pgnos.is_empty() {
+            return true;
+        }
+        lru_pages.remove(&dbsym);
+
+        false
+    }
+
+    // Returns the LRU cache keys from the least to the most recently used. Unlike the
+    // cache itself, which can only be walked by draining it, this doesn't block the
+    // lookups for long.
+    fn lru_order(&self) -> Vec<PageCacheKey> {
+        let mut keys = self
+            .lru_pages
+            .lock()
+            .unwrap()
+            .iter()
+            .flat_map(|(&dbsym, pgnos)| {
+                pgnos
+                    .iter()
+                    .map(move |(&pgno, &tick)| (tick, PageCacheKey { dbsym, pgno }))
+            })
+            .collect::<Vec<_>>();
+        keys.sort_unstable_by_key(|&(tick, _)| tick);
+
+        keys.into_iter().map(|(_, key)| key).collect()
+    }
+
+    fn lru_path(&self) -> PathBuf {
+        self.root.join("lru")
     }
 
     fn pages_path(&self, db: &str) -> PathBuf {
@@ -488,6 +1813,14 @@ impl Pager {
         self.db_path(db).join("tmp")
     }
 
+    fn etags_path(&self, db: &str) -> PathBuf {
+        self.db_path(db).join("etags")
+    }
+
+    fn stale_path(&self, db: &str) -> PathBuf {
+        self.db_path(db).join("stale")
+    }
+
     fn cache_key(&self, db: &str, pgno: ltx::PageNum) -> PageCacheKey {
         PageCacheKey {
             dbsym: self.interner.lock().unwrap().get_or_intern(db),
@@ -495,27 +1828,80 @@ impl Pager {
         }
     }
 
+    /// Returns the number of cached pages, including the stale copies kept for
+    /// revalidation, and the space available on the cache FS.
+    pub(crate) fn cache_usage(&self) -> io::Result<CacheUsage> {
+        let pages = self.lru.lock().unwrap().len() + self.stale.lock().unwrap().len();
+        let stats = statvfs(&self.root)?;
+        metrics().set_cache_usage(pages, stats.available_space);
+
+        Ok(CacheUsage {
+            pages,
+            available_space: stats.available_space,
+            total_space: stats.total_space,
+            available_inodes: stats.available_inodes,
+        })
+    }
+
+    /// Evicts the least recently used pages until at least `min_space` is available
+    /// on the cache FS and the cache and free inode limits are satisfied. Returns the number of
+    /// evicted pages.
+    pub(crate) fn reclaim(&self, min_space: u64) -> io::Result<usize> {
+        let evicted = self.reclaim_space_to(min_space.max(self.min_available_space()))?;
+        log::info!(
+            "[pager] reclaim: min_space = {}, evicted = {}",
+            ByteSize::b(min_space).to_string_as(true),
+            evicted
+        );
+
+        Ok(evicted)
+    }
+
     fn reclaim_space(&self) -> io::Result<()> {
-        let max_pages = self.max_cached_pages();
-        let min_space = self.min_available_space();
+        self.reclaim_space_to(self.min_available_space())?;
+
+        Ok(())
+    }
 
+    fn reclaim_space_to(&self, min_space: u64) -> io::Result<usize> {
+        let mut evicted = 0;
         loop {
-            let pages = self.lru.lock().unwrap().len();
-            let space = statvfs(&self.root)?.available_space;
+            let pages = self.lru.lock().unwrap().len() + self.stale.lock().unwrap().len();
+            let stats = statvfs(&self.root)?;
+            let space = stats.available_space;
+            let inodes = stats.available_inodes;
+            let min_inodes = self.min_available_inodes();
+            metrics().set_cache_usage(pages, space);
+
+            let max_pages = effective_max_pages(
+                self.max_cached_pages(),
+                self.max_cache_fraction(),
+                stats.total_space,
+                self.page_size_hint.load(Ordering::Relaxed),
+            );
 
             log::trace!(
-                "[pager] reclaim_space: pages = {}, max_pages = {}, space = {}, min_space = {}",
+                "[pager] reclaim_space: pages = {}, max_pages = {}, space = {}, min_space = {}, inodes = {}, min_inodes = {}",
                 pages,
                 max_pages,
                 ByteSize::b(space).to_string_as(true),
                 ByteSize::b(min_space).to_string_as(true),
+                inodes,
+                min_inodes,
             );
 
-            if pages == 0 || space >= min_space && (pages <= max_pages || max_pages == 0) {
-                return Ok(());
+            if pages == 0
+                || space >= min_space
+                    && inodes >= min_inodes
+                    && (pages <= max_pages || max_pages == 0)
+            {
+                return Ok(evicted);
             }
 
-            self.remove_lru_page()?;
+            if !self.remove_stale_copy()? {
+                self.remove_lru_page()?;
+            }
+            evicted += 1;
         }
     }
 
@@ -523,28 +1909,46 @@ impl Pager {
         let cache_key = {
             let mut lru = self.lru.lock().unwrap();
 
-            if let Some((cache_key, _)) = lru.remove_lru_from_probationary() {
+            let cache_key = if let Some((cache_key, _)) = lru.remove_lru_from_probationary() {
                 cache_key
             } else if let Some((cache_key, _)) = lru.remove_lru_from_protected() {
                 cache_key
             } else {
                 return Ok(());
-            }
+            };
+            self.untrack_lru_page(cache_key);
+
+            cache_key
         };
 
-        if let Some(db) = self.interner.lock().unwrap().resolve(cache_key.dbsym) {
+        let db = self
+            .interner
+            .lock()
+            .unwrap()
+            .resolve(cache_key.dbsym)
+            .map(|db| db.to_string());
+        if let Some(db) = db {
             log::trace!(
                 "[pager] remove_lru_page: db = {}, pgno = {}",
                 db,
                 cache_key.pgno
             );
-            remove_file(self.pages_path(db).join(PathBuf::from(cache_key.pgno)))?;
+            self.remove_page_data(&db, cache_key.pgno)?;
+        }
+        if let Some(cache) = self.mem_cache.lock().unwrap().as_mut() {
+            cache.remove(&cache_key);
         }
 
         Ok(())
     }
 }
 
+impl Drop for Pager {
+    fn drop(&mut self) {
+        _ = self.save_lru();
+    }
+}
+
 /// A struct that owns a single database page.
 pub(crate) struct Page {
     data: Vec<u8>,
@@ -599,12 +2003,377 @@ impl<'a> AsRef<[u8]> for PageRef<'a> {
     }
 }
 
-#[derive(PartialEq, Eq, Hash)]
+/// Cache effectiveness counters maintained by [Pager]. Local hits and remote fetches
+/// are counted by the process-wide [Metrics](crate::metrics::Metrics) instead, only
+/// their values as of the last reset are kept here.
+#[derive(Default)]
+struct PagerStats {
+    local_hits_base: AtomicU64,
+    mem_hits: AtomicU64,
+    remote_fetches_base: AtomicU64,
+    prefetch_hits: AtomicU64,
+    revalidated_pages: AtomicU64,
+    bytes_downloaded: AtomicU64,
+    compressed_pages: AtomicU64,
+    uncompressed_bytes: AtomicU64,
+    compressed_bytes: AtomicU64,
+}
+
+impl PagerStats {
+    fn snapshot(&self) -> PagerStatsSnapshot {
+        PagerStatsSnapshot {
+            local_hits: metrics()
+                .local_page_hits()
+                .saturating_sub(self.local_hits_base.load(Ordering::Relaxed)),
+            mem_hits: self.mem_hits.load(Ordering::Relaxed),
+            remote_fetches: metrics()
+                .remote_page_fetches()
+                .saturating_sub(self.remote_fetches_base.load(Ordering::Relaxed)),
+            prefetch_hits: self.prefetch_hits.load(Ordering::Relaxed),
+            revalidated_pages: self.revalidated_pages.load(Ordering::Relaxed),
+            bytes_downloaded: self.bytes_downloaded.load(Ordering::Relaxed),
+            compressed_pages: self.compressed_pages.load(Ordering::Relaxed),
+            compression_ratio: match self.compressed_bytes.load(Ordering::Relaxed) {
+                0 => 0.0,
+                compressed => {
+                    self.uncompressed_bytes.load(Ordering::Relaxed) as f64 / compressed as f64
+                }
+            },
+        }
+    }
+
+    fn reset(&self) {
+        self.local_hits_base
+            .store(metrics().local_page_hits(), Ordering::Relaxed);
+        self.mem_hits.store(0, Ordering::Relaxed);
+        self.remote_fetches_base
+            .store(metrics().remote_page_fetches(), Ordering::Relaxed);
+        self.prefetch_hits.store(0, Ordering::Relaxed);
+        self.revalidated_pages.store(0, Ordering::Relaxed);
+        self.bytes_downloaded.store(0, Ordering::Relaxed);
+        self.compressed_pages.store(0, Ordering::Relaxed);
+        self.uncompressed_bytes.store(0, Ordering::Relaxed);
+        self.compressed_bytes.store(0, Ordering::Relaxed);
+    }
+}
+
+/// Disk usage of the [Pager] cache.
+#[derive(Debug, serde::Serialize)]
+pub(crate) struct CacheUsage {
+    pub(crate) pages: usize,
+    pub(crate) available_space: u64,
+    pub(crate) total_space: u64,
+    pub(crate) available_inodes: u64,
+}
+
+/// A point-in-time copy of [Pager] cache statistics.
+#[derive(Debug, serde::Serialize)]
+pub(crate) struct PagerStatsSnapshot {
+    // Counted across all the pagers of the process
+    pub(crate) local_hits: u64,
+    pub(crate) mem_hits: u64,
+    // Counted across all the pagers of the process
+    pub(crate) remote_fetches: u64,
+    pub(crate) prefetch_hits: u64,
+    pub(crate) revalidated_pages: u64,
+    pub(crate) bytes_downloaded: u64,
+    pub(crate) compressed_pages: u64,
+    // Size of the compressed pages before compression divided by their size after it
+    pub(crate) compression_ratio: f64,
+}
+
+/// Number of bytes that may be downloaded per `window`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct BandwidthLimit {
+    pub(crate) bytes: u64,
+    pub(crate) window: time::Duration,
+}
+
+// Bandwidth used by a database, limited with a token bucket that holds up to
+// `limit.bytes` tokens and is refilled at `limit.bytes` per `limit.window`.
+// The limit is soft: a fetch is allowed while there are any tokens left, so
+// the bucket can go negative by up to one fetch.
+struct Bandwidth {
+    downloaded: u64,
+    limit: Option<BandwidthLimit>,
+    tokens: f64,
+    refilled_at: time::Instant,
+}
+
+impl Default for Bandwidth {
+    fn default() -> Self {
+        Bandwidth {
+            downloaded: 0,
+            limit: None,
+            tokens: 0.0,
+            refilled_at: time::Instant::now(),
+        }
+    }
+}
+
+impl Bandwidth {
+    fn refill(&mut self) {
+        if let Some(limit) = self.limit {
+            let now = time::Instant::now();
+            let elapsed = now.duration_since(self.refilled_at).as_secs_f64();
+            let rate = limit.bytes as f64 / limit.window.as_secs_f64().max(f64::EPSILON);
+            self.tokens = (self.tokens + elapsed * rate).min(limit.bytes as f64);
+            self.refilled_at = now;
+        }
+    }
+
+    fn available(&mut self) -> bool {
+        self.refill();
+        self.limit.is_none() || self.tokens > 0.0
+    }
+
+    fn consume(&mut self, bytes: u64) {
+        self.downloaded += bytes;
+        if self.limit.is_some() {
+            self.refill();
+            self.tokens -= bytes as f64;
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
 struct PageCacheKey {
-    dbsym: DefaultSymbol,
+    dbsym: Symbol,
+    pgno: ltx::PageNum,
+}
+
+#[derive(PartialEq, Eq, Hash)]
+struct InflightKey {
+    dbsym: Symbol,
+    txid: u64,
+    checksum: u64,
     pgno: ltx::PageNum,
 }
 
+/// A remote page request shared between all the callers asking for the same page.
+struct InflightFetch {
+    result: Mutex<Option<io::Result<Vec<u8>>>>,
+    cvar: Condvar,
+}
+
+impl InflightFetch {
+    fn new() -> InflightFetch {
+        InflightFetch {
+            result: Mutex::new(None),
+            cvar: Condvar::new(),
+        }
+    }
+
+    /// Stores the result of the fetch and wakes up all the waiters.
+    fn complete(&self, result: &io::Result<Page>) {
+        let result = match result {
+            Ok(page) => Ok(page.as_ref().to_vec()),
+            Err(err) => Err(clone_error(err)),
+        };
+
+        *self.result.lock().unwrap() = Some(result);
+        self.cvar.notify_all();
+    }
+
+    /// Blocks until the fetch is completed and returns a copy of its result.
+    fn wait(&self) -> io::Result<Vec<u8>> {
+        let mut result = self.result.lock().unwrap();
+        while result.is_none() {
+            result = self.cvar.wait(result).unwrap();
+        }
+
+        match result.as_ref().unwrap() {
+            Ok(data) => Ok(data.clone()),
+            Err(err) => Err(clone_error(err)),
+        }
+    }
+}
+
+// Completes the fetch of the leader and removes it from the in-flight ones, even if
+// the fetch panics, so that the waiters don't wait forever.
+struct InflightGuard<'a> {
+    inflight: &'a Mutex<HashMap<InflightKey, Arc<InflightFetch>>>,
+    key: Option<InflightKey>,
+    fetch: Arc<InflightFetch>,
+}
+
+impl<'a> InflightGuard<'a> {
+    fn new(
+        inflight: &'a Mutex<HashMap<InflightKey, Arc<InflightFetch>>>,
+        key: InflightKey,
+        fetch: Arc<InflightFetch>,
+    ) -> InflightGuard<'a> {
+        InflightGuard {
+            inflight,
+            key: Some(key),
+            fetch,
+        }
+    }
+
+    fn complete(mut self, result: &io::Result<Page>) {
+        if let Some(key) = self.key.take() {
+            self.inflight.lock().unwrap().remove(&key);
+            self.fetch.complete(result);
+        }
+    }
+}
+
+impl Drop for InflightGuard<'_> {
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            if let Ok(mut inflight) = self.inflight.lock() {
+                inflight.remove(&key);
+            }
+            self.fetch.complete(&Err(io::Error::new(
+                io::ErrorKind::Other,
+                "page fetch panicked",
+            )));
+        }
+    }
+}
+
+// io::Error is not cloneable, so reconstruct it preserving the kind and
+// SQLite error code, if any.
+fn clone_error(err: &io::Error) -> io::Error {
+    if let Some(code) = err.get_ref().and_then(|e| e.downcast_ref::<CodeError>()) {
+        io::Error::new(err.kind(), *code)
+    } else {
+        io::Error::new(err.kind(), err.to_string())
+    }
+}
+
+// Page files of exactly one page are stored as is, compressed ones never have a valid
+// page size, so that the two can be told apart regardless of the current setting.
+fn is_page_size(len: u64) -> bool {
+    len.is_power_of_two() && (512..=65536).contains(&len)
+}
+
+// Returns `None` if compression doesn't save any space.
+#[cfg(not(target_os = "emscripten"))]
+fn compress_page(data: &[u8]) -> io::Result<Option<Vec<u8>>> {
+    let mut enc = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::fast());
+    enc.write_all(data)?;
+    let mut compressed = enc.finish()?;
+    if compressed.len() >= data.len() {
+        return Ok(None);
+    }
+    // The decoder ignores anything past the end of the stream
+    if is_page_size(compressed.len() as u64) {
+        compressed.push(0);
+    }
+
+    Ok(Some(compressed))
+}
+
+#[cfg(target_os = "emscripten")]
+fn compress_page(_data: &[u8]) -> io::Result<Option<Vec<u8>>> {
+    Ok(None)
+}
+
+#[cfg(not(target_os = "emscripten"))]
+fn decompress_page(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut page = Vec::new();
+    flate2::read::ZlibDecoder::new(data)
+        .read_to_end(&mut page)
+        .map_err(|err| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid compressed page: {}", err),
+            )
+        })?;
+
+    Ok(page)
+}
+
+#[cfg(target_os = "emscripten")]
+fn decompress_page(_data: &[u8]) -> io::Result<Vec<u8>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "compressed pages are not supported in browsers",
+    ))
+}
+
+/// Opens `path` without following a symlink in its last component, so that a symlink
+/// planted in the cache directory can't redirect reads or writes outside of it.
+pub(crate) fn open_nofollow(opts: &mut fs::OpenOptions, path: &Path) -> io::Result<fs::File> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+
+        opts.custom_flags(libc::O_NOFOLLOW);
+    }
+    #[cfg(not(unix))]
+    if fs::symlink_metadata(path).map_or(false, |m| m.file_type().is_symlink()) {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "refusing to follow a symlink",
+        ));
+    }
+
+    opts.open(path)
+}
+
+// Converts an error of fetching the page to the error returned to SQLite.
+fn fetch_error(db: &str, pos: ltx::Pos, pgno: ltx::PageNum, err: lfsc::Error) -> io::Error {
+    match err {
+        lfsc::Error::PosMismatch(x) => {
+            log::warn!("[pager] fetch_page_remote: db = {}, pgno = {}, pos mismatch error, requested = {}, got = {}",
+                db, pgno, pos, x);
+            // LFSC no longer have the requested pos. At this point we may try to recover
+            // from this ourselves, or tell the user to retry the transaction. The only
+            // safe situation when we can recover is when this is the very first read
+            // of a TX. But, in 99.9% the very first read will hit the cache (page 1),
+            // so just return a custom error code to the user. The client code can retry
+            // the transaction automatically after that.
+            pos_mismatch_error()
+        }
+        x => x.into(),
+    }
+}
+
+// Tells SQLite, and the application, that the transaction has to be retried.
+pub(crate) fn pos_mismatch_error() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Other,
+        CodeError::with_message(LITEVFS_IOERR_POS_MISMATCH, POS_MISMATCH_MESSAGE),
+    )
+}
+
+/// Removes the files in `dir` that haven't been modified for `min_age`. Returns the
+/// number of removed files.
+pub(crate) fn remove_old_files(dir: &Path, min_age: time::Duration) -> io::Result<usize> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(err) => return Err(err),
+    };
+
+    let mut removed = 0;
+    for entry in entries {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if !metadata.is_file() {
+            continue;
+        }
+        // Files modified in the future, e.g. after a clock change, are kept until they age
+        let age = metadata.modified()?.elapsed().unwrap_or_default();
+        if age < min_age {
+            continue;
+        }
+
+        log::info!(
+            "[pager] remove_old_files: path = {}, age = {}",
+            entry.path().display(),
+            humantime::format_duration(time::Duration::from_secs(age.as_secs()))
+        );
+        if remove_file(entry.path())? {
+            removed += 1;
+        }
+    }
+
+    Ok(removed)
+}
+
 fn remove_file<P: AsRef<Path>>(file: P) -> io::Result<bool> {
     match fs::remove_file(file) {
         Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(false),
@@ -613,8 +2382,27 @@ fn remove_file<P: AsRef<Path>>(file: P) -> io::Result<bool> {
     }
 }
 
+// Returns the maximum number of cached pages as the most restrictive of the absolute
+// `max_pages` and the `fraction` of the FS `total_space`. Zero means no limit.
+fn effective_max_pages(max_pages: usize, fraction: f64, total_space: u64, page_size: u64) -> usize {
+    if fraction <= 0.0 || page_size == 0 {
+        return max_pages;
+    }
+
+    // Keep at least one page, as zero disables the limit
+    let fraction_pages = ((total_space as f64 * fraction) as u64 / page_size).max(1) as usize;
+    if max_pages == 0 {
+        fraction_pages
+    } else {
+        max_pages.min(fraction_pages)
+    }
+}
+
 struct FsStats {
     available_space: u64,
+    total_space: u64,
+    // u64::MAX if the FS doesn't limit the number of inodes
+    available_inodes: u64,
 }
 
 #[cfg(unix)]
@@ -623,7 +2411,7 @@ fn statvfs<P>(path: P) -> io::Result<FsStats>
 where
     P: AsRef<Path>,
 {
-    use std::{mem, os::unix::prelude::OsStrExt};
+    use std::{ffi, mem, os::unix::prelude::OsStrExt};
 
     let cstr = match ffi::CString::new(path.as_ref().as_os_str().as_bytes()) {
         Ok(cstr) => cstr,
@@ -642,6 +2430,13 @@ where
         } else {
             Ok(FsStats {
                 available_space: stat.f_frsize as u64 * stat.f_bavail as u64,
+                total_space: stat.f_frsize as u64 * stat.f_blocks as u64,
+                // Some filesystems, e.g. btrfs, allocate inodes dynamically and report zero
+                available_inodes: if stat.f_files == 0 {
+                    u64::MAX
+                } else {
+                    stat.f_favail as u64
+                },
             })
         }
     }
@@ -665,7 +2460,7 @@ where
         .encode_wide()
         .chain(Some(0))
         .collect();
-    let available_space = unsafe {
+    let (available_space, total_space) = unsafe {
         if GetVolumePathNameW(
             path_utf8.as_ptr(),
             root_path.as_mut_ptr(),
@@ -691,20 +2486,650 @@ where
         }
 
         let bytes_per_cluster = sectors_per_cluster as u64 * bytes_per_sector as u64;
-        bytes_per_cluster * number_of_free_clusters as u64
+        (
+            bytes_per_cluster * number_of_free_clusters as u64,
+            bytes_per_cluster * total_number_of_clusters as u64,
+        )
     };
 
-    Ok(FsStats { available_space })
+    Ok(FsStats {
+        available_space,
+        total_space,
+        // NTFS doesn't have a fixed number of inodes
+        available_inodes: u64::MAX,
+    })
 }
 
 #[cfg(test)]
 mod tests {
-    use std::env::temp_dir;
+    use super::{
+        CacheDurability, InflightFetch, InflightGuard, InflightKey, Page, PageRef, Pager,
+        PagerBackend,
+    };
+    use crate::{builder::EnvSettings, interner::Interner, lfsc, testutil::TempDir};
+    use litetx as ltx;
+    use std::{
+        collections::HashMap,
+        env::temp_dir,
+        fs, io,
+        path::PathBuf,
+        sync::{Arc, Mutex},
+        thread,
+    };
+
+    #[test]
+    fn pos_mismatch_error() {
+        let err = super::pos_mismatch_error();
+        let code = err
+            .get_ref()
+            .and_then(|inner| inner.downcast_ref::<sqlite_vfs::CodeError>())
+            .expect("code error");
+
+        assert_eq!(crate::LITEVFS_IOERR_POS_MISMATCH, code.code());
+        assert!(!code.message().unwrap_or_default().is_empty());
+        assert!(err.to_string().contains("retried"));
+    }
 
     #[test]
     fn statvfs() {
         let stats = super::statvfs(temp_dir()).expect("statvfs");
 
         assert!(stats.available_space > 0);
+        assert!(stats.total_space >= stats.available_space);
+        assert!(stats.available_inodes > 0);
+    }
+
+    #[test]
+    fn effective_max_pages() {
+        use super::effective_max_pages;
+
+        // 20% of 100 pages of 4k
+        let total = 100 * 4096;
+        assert_eq!(0, effective_max_pages(0, 0.0, total, 4096));
+        assert_eq!(50, effective_max_pages(50, 0.0, total, 4096));
+        assert_eq!(20, effective_max_pages(0, 0.2, total, 4096));
+        assert_eq!(10, effective_max_pages(10, 0.2, total, 4096));
+        assert_eq!(20, effective_max_pages(50, 0.2, total, 4096));
+        assert_eq!(1, effective_max_pages(0, 0.0001, total, 4096));
+        // Nothing is cached yet, so the page size is unknown
+        assert_eq!(50, effective_max_pages(50, 0.2, total, 0));
+    }
+
+    #[test]
+    fn inflight_fetch_shares_result() {
+        let fetch = Arc::new(InflightFetch::new());
+
+        let waiters = (0..4)
+            .map(|_| {
+                let fetch = Arc::clone(&fetch);
+                thread::spawn(move || fetch.wait())
+            })
+            .collect::<Vec<_>>();
+
+        fetch.complete(&Ok(Page::new(ltx::PageNum::ONE, vec![1, 2, 3])));
+
+        for waiter in waiters {
+            assert_eq!(vec![1, 2, 3], waiter.join().unwrap().expect("page data"));
+        }
+    }
+
+    #[test]
+    fn inflight_fetch_shares_error() {
+        let fetch = InflightFetch::new();
+
+        fetch.complete(&Err(io::ErrorKind::NotFound.into()));
+
+        assert_eq!(
+            io::ErrorKind::NotFound,
+            fetch.wait().expect_err("error").kind()
+        );
+    }
+
+    #[test]
+    fn inflight_fetch_panics() {
+        let dbsym = Interner::new().get_or_intern("db");
+        let key = || InflightKey {
+            dbsym,
+            txid: 1,
+            checksum: 1,
+            pgno: ltx::PageNum::ONE,
+        };
+        let inflight = Mutex::new(HashMap::new());
+        let fetch = Arc::new(InflightFetch::new());
+        inflight.lock().unwrap().insert(key(), Arc::clone(&fetch));
+
+        let ret = thread::scope(|s| {
+            s.spawn(|| {
+                let _guard = InflightGuard::new(&inflight, key(), Arc::clone(&fetch));
+                panic!("fetch failed");
+            })
+            .join()
+        });
+        assert!(ret.is_err());
+
+        // The waiters get an error and the next caller starts a new fetch
+        assert!(inflight.lock().unwrap().is_empty());
+        assert_eq!(
+            io::ErrorKind::Other,
+            fetch.wait().expect_err("error").kind()
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinked_pages() {
+        let root = TempDir::new("symlink");
+        let outside = temp_dir().join(format!("litevfs-symlink-target-{}", std::process::id()));
+        let client = Arc::new(lfsc::Client::builder().build());
+        let pager = Pager::new(&root, client, PagerBackend::Files, &EnvSettings::default());
+        pager.set_mem_cache_pages(0);
+        pager.prepare_db("db").expect("prepare_db");
+        let pgno = |n| ltx::PageNum::new(n).unwrap();
+
+        fs::write(&outside, [7; 512]).expect("write");
+        let page_path = root.join("db").join("pages").join(PathBuf::from(pgno(1)));
+        std::os::unix::fs::symlink(&outside, &page_path).expect("symlink");
+        pager
+            .get_page("db", None, pgno(1), None)
+            .expect_err("page read through a symlink");
+
+        let tmp_path = root.join("db").join("tmp").join(PathBuf::from(pgno(2)));
+        std::os::unix::fs::symlink(&outside, tmp_path).expect("symlink");
+        pager
+            .put_page("db", PageRef::new(pgno(2), &[0; 512]))
+            .expect_err("page written through a symlink");
+        assert_eq!(vec![7; 512], fs::read(&outside).expect("read"));
+
+        fs::remove_file(outside).expect("cleanup");
+    }
+
+    #[test]
+    fn safe_durability() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        static FSYNCS: AtomicUsize = AtomicUsize::new(0);
+        fn fsync(_: &fs::File) -> io::Result<()> {
+            FSYNCS.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+
+        let root = TempDir::new("durability");
+        let client = Arc::new(lfsc::Client::builder().build());
+        let mut pager = Pager::new(&root, client, PagerBackend::Files, &EnvSettings::default());
+        pager.fsync = fsync;
+        pager.prepare_db("db").expect("prepare_db");
+        let pgno = |n| ltx::PageNum::new(n).unwrap();
+
+        pager.durability = CacheDurability::Fast;
+        pager
+            .put_page("db", PageRef::new(pgno(1), &[1; 512]))
+            .expect("put_page");
+        assert_eq!(0, FSYNCS.load(Ordering::SeqCst));
+
+        // The page file and, where supported, the pages directory
+        pager.durability = CacheDurability::Safe;
+        pager
+            .put_page("db", PageRef::new(pgno(2), &[2; 512]))
+            .expect("put_page");
+        let expected = if cfg!(unix) { 2 } else { 1 };
+        assert_eq!(expected, FSYNCS.load(Ordering::SeqCst));
+        drop(pager);
+        fs::remove_dir_all(&root).expect("cleanup");
+
+        // The pack data and its index
+        let mut pager = Pager::new(
+            &root,
+            Arc::new(lfsc::Client::builder().build()),
+            PagerBackend::Packed,
+            &EnvSettings::default(),
+        );
+        pager.fsync = fsync;
+        pager.durability = CacheDurability::Safe;
+        pager.prepare_db("db").expect("prepare_db");
+        pager
+            .put_page("db", PageRef::new(pgno(1), &[1; 512]))
+            .expect("put_page");
+        assert_eq!(expected + 2, FSYNCS.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn bandwidth_limit() {
+        use super::BandwidthLimit;
+
+        let root = TempDir::new("bandwidth");
+        let client = Arc::new(lfsc::Client::builder().build());
+        let pager = Pager::new(&root, client, PagerBackend::Files, &EnvSettings::default());
+        let dbsym = pager.interner.lock().unwrap().get_or_intern("db");
+        let consume = |bytes| {
+            pager
+                .bandwidth
+                .lock()
+                .unwrap()
+                .entry(dbsym)
+                .or_default()
+                .consume(bytes)
+        };
+        let available = || {
+            pager
+                .bandwidth
+                .lock()
+                .unwrap()
+                .get_mut(&dbsym)
+                .map_or(true, |bw| bw.available())
+        };
+
+        // Downloads are accounted for without a limit
+        consume(4096);
+        assert!(available());
+        assert_eq!(4096, pager.bandwidth("db"));
+        pager.reset_bandwidth("db");
+        assert_eq!(0, pager.bandwidth("db"));
+
+        let limit = BandwidthLimit {
+            bytes: 8192,
+            window: std::time::Duration::from_millis(200),
+        };
+        pager.set_bandwidth_limit("db", Some(limit));
+        assert_eq!(Some(limit), pager.bandwidth_limit("db"));
+        consume(4096);
+        assert!(available());
+        consume(8192);
+        assert!(!available());
+        assert_eq!(12288, pager.bandwidth("db"));
+
+        // The bucket is refilled over the window
+        thread::sleep(std::time::Duration::from_millis(250));
+        assert!(available());
+
+        pager.set_bandwidth_limit("db", None);
+        consume(1 << 20);
+        assert!(available());
+    }
+
+    #[test]
+    #[cfg(not(target_os = "emscripten"))]
+    fn max_pages_per_request() {
+        use crate::testutil::{mock_lfsc, serve_pages};
+        use std::sync::Mutex;
+
+        let (client, server) = mock_lfsc(Some(3), serve_pages(512));
+        let client = Arc::new(client);
+
+        let root = TempDir::new("max-pages");
+        let mut pager = Pager::new(&root, client, PagerBackend::Files, &EnvSettings::default());
+        pager.fetch_parallelism = 1;
+        pager.set_max_pages_per_request(10);
+        let pos = ltx::Pos {
+            txid: ltx::TXID::ONE,
+            post_apply_checksum: ltx::Checksum::new(0x8000_0000_0000_0001),
+        };
+        let pgnos = (1..=25)
+            .map(|n| ltx::PageNum::new(n).unwrap())
+            .collect::<Vec<_>>();
+
+        let fetched = Mutex::new(Vec::new());
+        pager
+            .get_pages_remote("db", pos, &pgnos, None, &|page| {
+                fetched.lock().unwrap().push(page.number());
+                Ok(())
+            })
+            .expect("get_pages_remote");
+        assert_eq!(pgnos, fetched.into_inner().unwrap());
+        assert_eq!(3, server.join().unwrap().len());
+    }
+
+    #[test]
+    #[cfg(not(target_os = "emscripten"))]
+    fn conditional_fetches() {
+        use crate::testutil::{serve, Response};
+
+        // Page 1 hasn't changed, page 2 has
+        let (host, server) = serve(Some(2), |request| {
+            if request.header("if-none-match") == Some("\"v1\"") {
+                Response::new(304)
+            } else {
+                Response::pages([(2, &[9; 512][..])]).header("ETag", "\"v3\"")
+            }
+        });
+        let client = Arc::new(
+            lfsc::Client::builder()
+                .host(&host.parse().unwrap())
+                .conditional_fetches(true)
+                .retries(0)
+                .build(),
+        );
+
+        let root = TempDir::new("conditional");
+        let pager = Pager::new(&root, client, PagerBackend::Files, &EnvSettings::default());
+        pager.set_mem_cache_pages(0);
+        pager.prepare_db("db").expect("prepare_db");
+        let pgno = |n| ltx::PageNum::new(n).unwrap();
+        let pos = ltx::Pos {
+            txid: ltx::TXID::ONE,
+            post_apply_checksum: ltx::Checksum::new(0x8000_0000_0000_0001),
+        };
+        for (n, fill, etag) in [(1, 7, "\"v1\""), (2, 8, "\"v2\"")] {
+            pager
+                .put_page("db", PageRef::new(pgno(n), &[fill; 512]))
+                .expect("put_page");
+            pager.put_etag("db", pgno(n), etag).expect("put_etag");
+        }
+
+        // The whole database has changed, the pages are moved out of the cache but
+        // still count towards its size
+        assert_eq!(2, pager.keep_stale("db").expect("keep_stale").len());
+        assert!(!pager.has_page("db", pgno(1)).expect("has_page"));
+        assert_eq!(2, pager.cache_usage().expect("cache_usage").pages);
+        pager.clear("db").expect("clear");
+
+        // Both pages are revalidated by one fetch. Page 1 is not modified and the local
+        // copy is kept, page 2 is and the new version replaces the local copy.
+        let page = pager
+            .get_page("db", Some(pos), pgno(1), Some(&[pgno(2)]))
+            .expect("get_page");
+        assert_eq!(&[7; 512][..], page.as_ref());
+        assert!(pager.has_page("db", pgno(1)).expect("has_page"));
+        assert_eq!(1, pager.stats().revalidated_pages);
+
+        let page = pager
+            .get_page("db", Some(pos), pgno(2), None)
+            .expect("get_page");
+        assert_eq!(&[9; 512][..], page.as_ref());
+        let etag_path = root.join("db").join("etags").join(PathBuf::from(pgno(2)));
+        assert_eq!("\"v3\"", fs::read_to_string(etag_path).expect("etag"));
+        assert!(pager
+            .stale_page("db", pgno(2))
+            .expect("stale_page")
+            .is_none());
+        assert_eq!(2, pager.cache_usage().expect("cache_usage").pages);
+
+        let mut etags = server
+            .join()
+            .unwrap()
+            .iter()
+            .map(|request| request.header("if-none-match").map(String::from))
+            .collect::<Vec<_>>();
+        etags.sort();
+        assert_eq!(
+            vec![Some("\"v1\"".to_string()), Some("\"v2\"".to_string())],
+            etags
+        );
+    }
+
+    #[test]
+    fn remove_orphans() {
+        let root = TempDir::new("orphans");
+        let tmp = root.join("db").join("tmp");
+        fs::create_dir_all(&tmp).expect("create_dir_all");
+        fs::write(tmp.join("1"), [0; 512]).expect("write");
+
+        // Fresh files may still be written to
+        let client = Arc::new(lfsc::Client::builder().build());
+        let mut pager = Pager::new(&root, client, PagerBackend::Files, &EnvSettings::default());
+        pager.prepare_db("db").expect("prepare_db");
+        assert!(tmp.join("1").exists());
+
+        pager.orphan_age = std::time::Duration::ZERO;
+        pager.prepare_db("db").expect("prepare_db");
+        assert!(!tmp.join("1").exists());
+        assert_eq!(0, pager.remove_orphans("db").expect("remove_orphans"));
+    }
+
+    #[test]
+    fn reclaim() {
+        let root = TempDir::new("reclaim");
+        let client = Arc::new(lfsc::Client::builder().build());
+        let pager = Pager::new(&root, client, PagerBackend::Files, &EnvSettings::default());
+        pager.prepare_db("db").expect("prepare_db");
+        for n in 1..=3 {
+            pager
+                .put_page("db", PageRef::new(ltx::PageNum::new(n).unwrap(), &[0; 512]))
+                .expect("put_page");
+        }
+        assert_eq!(3, pager.cache_usage().expect("cache_usage").pages);
+        let mut cached = pager.cached_pages("db").expect("cached_pages");
+        cached.sort();
+        assert_eq!(
+            (1..=3)
+                .map(|n| ltx::PageNum::new(n).unwrap())
+                .collect::<Vec<_>>(),
+            cached
+        );
+
+        // Nothing to evict with the default limits
+        assert_eq!(0, pager.reclaim(0).expect("reclaim"));
+
+        // An unreachable target evicts everything
+        assert_eq!(3, pager.reclaim(u64::MAX).expect("reclaim"));
+        assert_eq!(0, pager.cache_usage().expect("cache_usage").pages);
+        assert_eq!(0, pager.page_count("db").expect("page_count"));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn reclaim_inodes() {
+        let root = TempDir::new("reclaim-inodes");
+        let client = Arc::new(lfsc::Client::builder().build());
+        let pager = Pager::new(&root, client, PagerBackend::Files, &EnvSettings::default());
+        pager.prepare_db("db").expect("prepare_db");
+        let inodes = super::statvfs(&root).expect("statvfs").available_inodes;
+        if inodes == u64::MAX {
+            // The FS doesn't limit inodes
+            return;
+        }
+
+        for n in 1..=3 {
+            pager
+                .put_page("db", PageRef::new(ltx::PageNum::new(n).unwrap(), &[0; 512]))
+                .expect("put_page");
+        }
+
+        // Enough free inodes
+        pager.set_min_available_inodes(1);
+        assert_eq!(0, pager.reclaim(0).expect("reclaim"));
+        assert_eq!(3, pager.cache_usage().expect("cache_usage").pages);
+
+        // Free space is fine, but there are never enough free inodes
+        pager.set_min_available_inodes(u64::MAX);
+        assert_eq!(3, pager.reclaim(0).expect("reclaim"));
+        assert_eq!(0, pager.page_count("db").expect("page_count"));
+    }
+
+    #[test]
+    fn forget_db() {
+        let root = TempDir::new("forget");
+        let client = Arc::new(lfsc::Client::builder().build());
+        let pager = Pager::new(&root, client, PagerBackend::Files, &EnvSettings::default());
+        pager.prepare_db("db").expect("prepare_db");
+        pager
+            .put_page("db", PageRef::new(ltx::PageNum::ONE, &[0; 512]))
+            .expect("put_page");
+        assert_eq!(1, pager.interner.lock().unwrap().len());
+
+        // Cached pages keep the name interned
+        assert!(!pager.forget_db("db"));
+        assert_eq!(1, pager.interner.lock().unwrap().len());
+
+        pager.clear("db").expect("clear");
+        assert!(pager.forget_db("db"));
+        assert_eq!(0, pager.interner.lock().unwrap().len());
+    }
+
+    #[test]
+    fn forget_db_concurrently() {
+        use std::thread;
+
+        let root = TempDir::new("forget-concurrently");
+        let client = Arc::new(lfsc::Client::builder().build());
+        let pager = Arc::new(Pager::new(
+            &root,
+            client,
+            PagerBackend::Files,
+            &EnvSettings::default(),
+        ));
+        pager.prepare_db("db").expect("prepare_db");
+
+        let writer = thread::spawn({
+            let pager = Arc::clone(&pager);
+            move || {
+                for n in 1..=200 {
+                    pager
+                        .put_page("db", PageRef::new(ltx::PageNum::new(n).unwrap(), &[0; 512]))
+                        .expect("put_page");
+                }
+            }
+        });
+        while !writer.is_finished() {
+            pager.forget_db("db");
+        }
+        writer.join().unwrap();
+
+        // Every tracked page still refers to an interned name
+        let interner = pager.interner.lock().unwrap();
+        let keys = pager.lru_order();
+        assert!(!keys.is_empty());
+        assert!(keys.iter().all(|key| interner.resolve(key.dbsym).is_some()));
+    }
+
+    #[test]
+    fn restore_lru_order() {
+        let root = TempDir::new("lru");
+        let client = Arc::new(lfsc::Client::builder().build());
+        let pgno = |n| ltx::PageNum::new(n).unwrap();
+        let order = |pager: &Pager| {
+            pager
+                .lru_order()
+                .into_iter()
+                .map(|key| key.pgno.into_inner())
+                .collect::<Vec<_>>()
+        };
+
+        let pager = Pager::new(
+            &root,
+            Arc::clone(&client),
+            PagerBackend::Files,
+            &EnvSettings::default(),
+        );
+        pager.prepare_db("db").expect("prepare_db");
+        for n in 1..=3 {
+            pager
+                .put_page("db", PageRef::new(pgno(n), &[0; 512]))
+                .expect("put_page");
+        }
+        pager.get_page("db", None, pgno(2), None).expect("get_page");
+        assert_eq!(vec![1, 3, 2], order(&pager));
+        drop(pager);
+
+        // A page missing from the persisted list and a stale list entry
+        fs::write(
+            root.join("db").join("pages").join(PathBuf::from(pgno(4))),
+            [0; 512],
+        )
+        .expect("write page");
+        fs::remove_file(root.join("db").join("pages").join(PathBuf::from(pgno(1))))
+            .expect("remove page");
+
+        let pager = Pager::new(&root, client, PagerBackend::Files, &EnvSettings::default());
+        assert_eq!(vec![4, 3, 2], order(&pager));
+        drop(pager);
+    }
+
+    #[test]
+    fn mem_cache() {
+        let root = TempDir::new("mem-cache");
+        let client = Arc::new(lfsc::Client::builder().build());
+        let pgno = ltx::PageNum::new(2).unwrap();
+
+        let pager = Pager::new(&root, client, PagerBackend::Files, &EnvSettings::default());
+        pager.prepare_db("db").expect("prepare_db");
+        pager
+            .put_page("db", PageRef::new(pgno, &[2; 512]))
+            .expect("put_page");
+        // Start with an empty memory cache
+        pager.set_mem_cache_pages(4);
+
+        let mut buf = [0; 100];
+        pager
+            .get_page_slice("db", None, pgno, &mut buf, 0, true, None, None)
+            .expect("get_page_slice");
+        assert_eq!(0, pager.stats().mem_hits);
+
+        let page = pager.get_page("db", None, pgno, None).expect("get_page");
+        assert_eq!(&[2; 512], page.as_ref());
+        assert_eq!(1, pager.stats().mem_hits);
+
+        // Deleted pages are gone from memory too
+        assert!(pager.del_page("db", pgno).expect("del_page"));
+        assert!(pager
+            .get_page_slice("db", None, pgno, &mut buf, 0, true, None, None)
+            .is_err());
+        assert_eq!(1, pager.stats().mem_hits);
+
+        drop(pager);
+    }
+
+    #[test]
+    #[cfg(not(target_os = "emscripten"))]
+    fn cache_compression() {
+        let root = TempDir::new("compression");
+        let client = Arc::new(lfsc::Client::builder().build());
+        let pgno = |n| ltx::PageNum::new(n).unwrap();
+        let path = |n: u32| root.join("db").join("pages").join(PathBuf::from(pgno(n)));
+
+        let pager = Pager::new(&root, client, PagerBackend::Files, &EnvSettings::default());
+        pager.prepare_db("db").expect("prepare_db");
+        pager.set_mem_cache_pages(0);
+        pager
+            .put_page("db", PageRef::new(pgno(1), &[1; 4096]))
+            .expect("put_page");
+        pager
+            .set_cache_compression(true)
+            .expect("set_cache_compression");
+        pager
+            .put_page("db", PageRef::new(pgno(2), &[2; 4096]))
+            .expect("put_page");
+        // Random data doesn't compress, so it is stored as is
+        let random = (0..4096).map(|_| rand::random::<u8>()).collect::<Vec<_>>();
+        pager
+            .put_page("db", PageRef::new(pgno(3), &random))
+            .expect("put_page");
+
+        assert_eq!(4096, fs::metadata(path(1)).expect("metadata").len());
+        assert!(fs::metadata(path(2)).expect("metadata").len() < 4096);
+        assert_eq!(4096, fs::metadata(path(3)).expect("metadata").len());
+        let stats = pager.stats();
+        assert_eq!(1, stats.compressed_pages);
+        assert!(stats.compression_ratio > 1.0);
+
+        // Both kinds of pages are readable regardless of the setting
+        for enabled in [true, false] {
+            pager
+                .set_cache_compression(enabled)
+                .expect("set_cache_compression");
+            for (n, data) in [(1, vec![1; 4096]), (2, vec![2; 4096]), (3, random.clone())] {
+                let page = pager.get_page("db", None, pgno(n), None).expect("get_page");
+                assert_eq!(data, page.as_ref());
+
+                let mut buf = [0; 100];
+                pager
+                    .get_page_slice("db", None, pgno(n), &mut buf, 24, true, None, None)
+                    .expect("get_page_slice");
+                assert_eq!(&data[24..124], &buf);
+            }
+        }
+
+        drop(pager);
+    }
+
+    #[test]
+    fn is_page_size() {
+        use super::is_page_size;
+
+        assert!(is_page_size(512));
+        assert!(is_page_size(4096));
+        assert!(is_page_size(65536));
+        assert!(!is_page_size(256));
+        assert!(!is_page_size(4095));
+        assert!(!is_page_size(131072));
     }
 }
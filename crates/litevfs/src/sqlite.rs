@@ -5,12 +5,28 @@ use litetx as ltx;
 pub(crate) const HEADER_SIZE: usize = 100;
 pub(crate) const WRITE_VERSION_OFFSET: usize = 18;
 pub(crate) const READ_VERSION_OFFSET: usize = 19;
+pub(crate) const RESERVED_BYTES_OFFSET: usize = 20;
 pub(crate) const COMMIT_RANGE: ops::Range<usize> = 28..32;
 
+/// Returns the pages referenced by the given b-tree page that are likely to be
+/// read next: children of interior pages, roots of all the tables for the schema
+/// page and the first overflow pages of cells with spilled payloads.
+///
+/// `reserved` is the number of bytes reserved at the end of each page by
+/// extensions, they are excluded from the usable page size.
+///
+/// Prefetching is best-effort, so malformed cells are skipped instead of failing.
 pub(crate) fn prefetch_candidates(
     data: &[u8],
     pgno: ltx::PageNum,
+    reserved: u8,
 ) -> Option<BTreeSet<ltx::PageNum>> {
+    // SQLite requires the usable size to be at least 480 bytes
+    let usable = data.len().checked_sub(reserved as usize)?;
+    if usable < 480 {
+        return None;
+    }
+
     let bh = if pgno == ltx::PageNum::ONE {
         &data[HEADER_SIZE..]
     } else {
@@ -19,93 +35,214 @@ pub(crate) fn prefetch_candidates(
 
     let num_cells = u16::from_be_bytes(bh[3..5].try_into().unwrap());
     match bh[0] {
-        0x0d if pgno == ltx::PageNum::ONE => Some(master_table(&bh[8..], data, num_cells)),
+        0x0d if pgno == ltx::PageNum::ONE => Some(master_table(&bh[8..], data, usable, num_cells)),
         0x02 | 0x05 => {
             let rightmost_pointer = u32::from_be_bytes(bh[8..12].try_into().unwrap());
-            let mut pgnos = interior_table_or_index(&bh[12..], data, num_cells);
+            let mut pgnos =
+                interior_table_or_index(&bh[12..], data, usable, num_cells, bh[0] == 0x02);
             if let Ok(pgno) = ltx::PageNum::new(rightmost_pointer) {
                 pgnos.insert(pgno);
             }
 
             Some(pgnos)
         }
+        0x0a | 0x0d => {
+            // Don't reset the prefetch candidates of the parent page unless there is
+            // something to prefetch.
+            let pgnos = leaf_table_or_index(&bh[8..], data, usable, num_cells, bh[0] == 0x0a);
+            if pgnos.is_empty() {
+                None
+            } else {
+                Some(pgnos)
+            }
+        }
         _ => None,
     }
 }
 
 // Returns the page numbers of the roots of all tables/indices/etc.
-fn master_table(pointers: &[u8], data: &[u8], num_cells: u16) -> BTreeSet<ltx::PageNum> {
-    pointers[..num_cells as usize * 2]
-        .chunks_exact(2)
-        .map(|c| u16::from_be_bytes(c.try_into().unwrap()) as usize)
-        .filter_map(|cell| {
-            if cell >= data.len() {
-                return None;
-            }
-            let cell = &data[cell..];
-            let (length, cell) = read_varint(cell);
-            let (_rowid, cell) = read_varint(cell);
+fn master_table(
+    pointers: &[u8],
+    data: &[u8],
+    usable: usize,
+    num_cells: u16,
+) -> BTreeSet<ltx::PageNum> {
+    cell_pointers(pointers, num_cells, usable)
+        .flat_map(|cell| {
+            let Some((length, cell)) = read_varint(&data[cell..]) else {
+                return [None, None];
+            };
+            let Some((_rowid, cell)) = read_varint(cell) else {
+                return [None, None];
+            };
 
-            // Has overflow page, ignore for now.
-            if length as usize > data.len() - 35 {
-                return None;
-            }
+            let length = length as usize;
+            let max_local = table_max_local(usable);
+            let overflow = overflow_page(cell, length, usable, max_local);
+            let payload = &cell[..local_payload_size(length, usable, max_local).min(cell.len())];
 
-            let (hsize, mut header) = read_varint(cell);
-            let body = &cell[hsize as usize..];
+            [root_page(payload), overflow]
+        })
+        .flatten()
+        .collect()
+}
 
-            // skip type/name/tbl_name
-            let mut pgno_offset: usize = 0;
-            for _ in 0..3 {
-                let (typ, header2) = read_varint(header);
-                pgno_offset += type_size(typ);
+// Returns the root page number of a sqlite_schema record, if it's stored locally.
+fn root_page(payload: &[u8]) -> Option<ltx::PageNum> {
+    let (hsize, mut header) = read_varint(payload)?;
+    let body = payload.get(usize::try_from(hsize).ok()?..)?;
 
-                header = header2;
-            }
+    // skip type/name/tbl_name
+    let mut pgno_offset: usize = 0;
+    for _ in 0..3 {
+        let (typ, header2) = read_varint(header)?;
+        pgno_offset = pgno_offset.checked_add(type_size(typ)?)?;
+
+        header = header2;
+    }
+
+    let (typ, _) = read_varint(header)?;
+    let size = type_size(typ)?;
+    let pgno = body
+        .get(pgno_offset..pgno_offset.checked_add(size)?)?
+        .iter()
+        .fold(0u64, |n, &b| (n << 8) | b as u64);
+
+    ltx::PageNum::new(pgno as u32).ok()
+}
 
-            let (pgno, _) = read_varint(&body[pgno_offset..]);
+// Returns the page numbers of the pages referenced by an interior table or index page.
+fn interior_table_or_index(
+    pointers: &[u8],
+    data: &[u8],
+    usable: usize,
+    num_cells: u16,
+    index: bool,
+) -> BTreeSet<ltx::PageNum> {
+    cell_pointers(pointers, num_cells, usable)
+        .flat_map(|cell| {
+            let Some(pgno) = data.get(cell..cell + 4) else {
+                return [None, None];
+            };
 
-            ltx::PageNum::new(pgno as u32).ok()
+            let pgno = u32::from_be_bytes(pgno.try_into().unwrap());
+            let overflow = if index {
+                read_varint(&data[cell + 4..]).and_then(|(length, payload)| {
+                    overflow_page(payload, length as usize, usable, index_max_local(usable))
+                })
+            } else {
+                None
+            };
+
+            [ltx::PageNum::new(pgno).ok(), overflow]
         })
+        .flatten()
         .collect()
 }
 
-// Returns the page numbers of the pages referenced by an interior table or index page.
-fn interior_table_or_index(pointers: &[u8], data: &[u8], num_cells: u16) -> BTreeSet<ltx::PageNum> {
-    pointers[..num_cells as usize * 2]
-        .chunks_exact(2)
-        .map(|c| u16::from_be_bytes(c.try_into().unwrap()) as usize)
+// Returns the first overflow pages of the cells of a leaf table or index page.
+fn leaf_table_or_index(
+    pointers: &[u8],
+    data: &[u8],
+    usable: usize,
+    num_cells: u16,
+    index: bool,
+) -> BTreeSet<ltx::PageNum> {
+    let max_local = if index {
+        index_max_local(usable)
+    } else {
+        table_max_local(usable)
+    };
+
+    cell_pointers(pointers, num_cells, usable)
         .filter_map(|cell| {
-            if cell >= data.len() {
-                return None;
-            }
-            let cell = &data[cell..];
+            let (length, cell) = read_varint(&data[cell..])?;
+            let payload = if index { cell } else { read_varint(cell)?.1 };
 
-            let pgno = u32::from_be_bytes(cell[0..4].try_into().unwrap());
-            ltx::PageNum::new(pgno).ok()
+            overflow_page(payload, length as usize, usable, max_local)
         })
         .collect()
 }
 
-fn read_varint(data: &[u8]) -> (i64, &[u8]) {
+// Returns the offsets of the cells within the usable part of the page. Pointers that
+// don't fit in the page are ignored.
+fn cell_pointers(
+    pointers: &[u8],
+    num_cells: u16,
+    usable: usize,
+) -> impl Iterator<Item = usize> + '_ {
+    pointers
+        .get(..num_cells as usize * 2)
+        .unwrap_or_default()
+        .chunks_exact(2)
+        .map(|c| u16::from_be_bytes(c.try_into().unwrap()) as usize)
+        .filter(move |&cell| cell < usable)
+}
+
+// Returns the first overflow page number of a cell payload of the given length.
+fn overflow_page(
+    payload: &[u8],
+    length: usize,
+    usable: usize,
+    max_local: usize,
+) -> Option<ltx::PageNum> {
+    if length <= max_local {
+        return None;
+    }
+
+    let local = local_payload_size(length, usable, max_local);
+    let pgno = payload.get(local..local + 4)?;
+
+    ltx::PageNum::new(u32::from_be_bytes(pgno.try_into().unwrap())).ok()
+}
+
+// Returns the number of payload bytes stored on the b-tree page itself, the
+// rest is spilled to overflow pages. See https://www.sqlite.org/fileformat.html#b_tree_pages
+fn local_payload_size(length: usize, usable: usize, max_local: usize) -> usize {
+    if length <= max_local {
+        return length;
+    }
+
+    let min_local = (usable - 12) * 32 / 255 - 23;
+    let local = min_local + (length - min_local) % (usable - 4);
+    if local <= max_local {
+        local
+    } else {
+        min_local
+    }
+}
+
+fn table_max_local(usable: usize) -> usize {
+    usable - 35
+}
+
+fn index_max_local(usable: usize) -> usize {
+    (usable - 12) * 64 / 255 - 23
+}
+
+// Returns the varint at the start of `data` and the rest of the data, `None` if
+// the data ends before the varint does.
+fn read_varint(data: &[u8]) -> Option<(i64, &[u8])> {
     let mut n: i64 = 0;
     for (i, &b) in data.iter().enumerate() {
         if i == 8 {
             n = (n << 8) | (b as i64);
-            return (n, &data[i + 1..]);
+            return Some((n, &data[i + 1..]));
         }
 
         n = (n << 7) | ((b as i64) & 0x7f);
         if b < 0x80 {
-            return (n, &data[i + 1..]);
+            return Some((n, &data[i + 1..]));
         }
     }
 
-    unreachable!();
+    None
 }
 
-fn type_size(typ: i64) -> usize {
-    match typ {
+// Returns the size of a record value of the given serial type, `None` for the
+// types that can't appear in a valid database.
+fn type_size(typ: i64) -> Option<usize> {
+    let size = match typ {
         // NULL, 0 or 1
         0 | 8 | 9 => 0,
         // 8-bit int
@@ -123,8 +260,92 @@ fn type_size(typ: i64) -> usize {
         // float
         7 => 8,
         // internal, should not be present in valid DBs
-        10 | 11 => unreachable!(),
+        10 | 11 => return None,
+        n if n < 0 => return None,
         n if n % 2 == 0 => ((n - 12) / 2) as usize,
         n => ((n - 13) / 2) as usize,
+    };
+
+    Some(size)
+}
+
+#[cfg(test)]
+mod tests {
+    use litetx as ltx;
+
+    #[test]
+    fn prefetch_candidates_overflow() {
+        let mut page = vec![0; 4096];
+
+        // Table leaf with a single cell at offset 100
+        page[0] = 0x0d;
+        page[3..5].copy_from_slice(&1u16.to_be_bytes());
+        page[8..10].copy_from_slice(&100u16.to_be_bytes());
+
+        // Payload length 5000 (varint), rowid 1. With 4096 bytes pages 908 bytes
+        // are stored locally, followed by the first overflow page number.
+        page[100..103].copy_from_slice(&[0xa7, 0x08, 0x01]);
+        page[1011..1015].copy_from_slice(&42u32.to_be_bytes());
+
+        let pgnos = super::prefetch_candidates(&page, ltx::PageNum::new(2).unwrap(), 0).unwrap();
+        assert_eq!(
+            vec![ltx::PageNum::new(42).unwrap()],
+            pgnos.into_iter().collect::<Vec<_>>()
+        );
+
+        // No overflow, nothing to prefetch
+        page[100..103].copy_from_slice(&[0x0a, 0x01, 0x00]);
+        assert!(super::prefetch_candidates(&page, ltx::PageNum::new(2).unwrap(), 0).is_none());
+    }
+
+    #[test]
+    fn prefetch_candidates_reserved_bytes() {
+        let mut page = vec![0; 4096];
+
+        page[0] = 0x0d;
+        page[3..5].copy_from_slice(&1u16.to_be_bytes());
+        page[8..10].copy_from_slice(&100u16.to_be_bytes());
+
+        // Payload length 5000 (varint), rowid 1. With 32 reserved bytes the usable
+        // size is 4064, so 940 bytes are stored locally.
+        page[100..103].copy_from_slice(&[0xa7, 0x08, 0x01]);
+        page[1043..1047].copy_from_slice(&42u32.to_be_bytes());
+
+        let pgnos = super::prefetch_candidates(&page, ltx::PageNum::new(2).unwrap(), 32).unwrap();
+        assert_eq!(
+            vec![ltx::PageNum::new(42).unwrap()],
+            pgnos.into_iter().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn prefetch_candidates_malformed() {
+        use rand::Rng;
+
+        let mut rng = rand::thread_rng();
+        for _ in 0..2000 {
+            let mut page = vec![0; [512, 1024, 4096][rng.gen_range(0..3)]];
+            rng.fill(&mut page[..]);
+
+            // Make sure all the page types are covered, not only the invalid ones
+            let pgno = ltx::PageNum::new(rng.gen_range(1..=2)).unwrap();
+            let offset = if pgno == ltx::PageNum::ONE {
+                super::HEADER_SIZE
+            } else {
+                0
+            };
+            page[offset] = [0x02, 0x05, 0x0a, 0x0d][rng.gen_range(0..4)];
+
+            super::prefetch_candidates(&page, pgno, rng.gen());
+        }
+    }
+
+    #[test]
+    fn read_varint_truncated() {
+        assert_eq!(None, super::read_varint(&[]));
+        assert_eq!(None, super::read_varint(&[0xff; 3]));
+        assert_eq!(Some((-1, &[][..])), super::read_varint(&[0xff; 9]));
+        assert_eq!(None, super::type_size(10));
+        assert_eq!(None, super::type_size(-1));
     }
 }
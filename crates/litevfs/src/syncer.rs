@@ -20,6 +20,11 @@ impl From<lfsc::Changes> for Option<Changes> {
     }
 }
 
+/// Default period of the background sync, can be overridden with `LITEVFS_SYNC_PERIOD`.
+pub(crate) const DEFAULT_SYNC_PERIOD: std::time::Duration = std::time::Duration::from_secs(1);
+/// Default fraction of the sync period the background sync is randomly shifted by.
+pub(crate) const DEFAULT_SYNC_JITTER: f64 = 0.1;
+
 #[cfg(not(target_os = "emscripten"))]
 pub(crate) use native::Syncer;
 
@@ -28,30 +33,49 @@ pub(crate) use emscripten::Syncer;
 
 #[cfg(not(target_os = "emscripten"))]
 mod native {
-    use crate::{lfsc, PositionsLogger};
+    use crate::{
+        interner::{Interner, Symbol},
+        lfsc,
+        metrics::metrics,
+        PositionsLogger, Worker,
+    };
     use litetx as ltx;
     use std::{
         collections::HashMap,
         io,
         sync::{Arc, Condvar, Mutex},
-        thread, time,
+        time,
     };
-    use string_interner::{DefaultSymbol, StringInterner};
+
+    enum Message {
+        // The sync periods have changed, recalculate the next sync.
+        Notify,
+        Shutdown,
+    }
 
     pub(crate) struct Syncer {
         client: Arc<lfsc::Client>,
-        notifier: crossbeam_channel::Sender<()>,
+        notifier: crossbeam_channel::Sender<Message>,
+        // The background sync, `None` once the syncer is shut down.
+        worker: Mutex<Option<Worker>>,
         period: time::Duration,
+        jitter: f64,
 
-        interner: Mutex<StringInterner>,
-        dbs: Mutex<HashMap<DefaultSymbol, Db>>,
+        interner: Mutex<Interner>,
+        dbs: Mutex<HashMap<Symbol, Db>>,
         cvar: Condvar,
+        // Notified when a sync request completes, successfully or not.
+        sync_cvar: Condvar,
     }
 
     struct Db {
         position: Option<ltx::Pos>,
         changes: Option<super::Changes>,
         conns: u32,
+        // Number of sync requests in flight for the database.
+        syncing: u32,
+        // The error of the last completed sync request, `None` if it succeeded.
+        sync_error: Option<String>,
 
         last_sync: time::SystemTime,
         period: time::Duration,
@@ -80,32 +104,44 @@ mod native {
     }
 
     impl Syncer {
-        pub(crate) fn new(client: Arc<lfsc::Client>, period: time::Duration) -> Arc<Syncer> {
+        pub(crate) fn new(
+            client: Arc<lfsc::Client>,
+            period: time::Duration,
+            jitter: f64,
+        ) -> Arc<Syncer> {
             let (tx, rx) = crossbeam_channel::unbounded();
             let syncer = Arc::new(Syncer {
                 client,
                 notifier: tx,
+                worker: Mutex::new(None),
                 period,
-                interner: Mutex::new(StringInterner::new()),
+                jitter: jitter.clamp(0.0, 0.9),
+                interner: Mutex::new(Interner::new()),
                 dbs: Mutex::new(HashMap::new()),
                 cvar: Condvar::new(),
+                sync_cvar: Condvar::new(),
             });
 
-            thread::spawn({
+            *syncer.worker.lock().unwrap() = Some(Worker::spawn({
                 let syncer = Arc::clone(&syncer);
 
                 move || syncer.run(rx)
-            });
+            }));
 
             syncer
         }
 
-        fn sym(&self, db: &str) -> DefaultSymbol {
-            self.interner.lock().unwrap().get_or_intern(db)
+        // Names are only interned while the database is open, so lookups of closed
+        // databases don't intern them again.
+        fn sym(&self, db: &str) -> Option<Symbol> {
+            self.interner.lock().unwrap().get(db)
         }
 
+        // The name is interned and forgotten with both locks held, so that every
+        // tracked database always resolves to its name.
         pub(crate) fn open_conn(&self, db: &str, pos: Option<ltx::Pos>) {
-            let sym = self.sym(db);
+            let mut interner = self.interner.lock().unwrap();
+            let sym = interner.get_or_intern(db);
 
             self.dbs
                 .lock()
@@ -116,15 +152,19 @@ mod native {
                     position: pos,
                     changes: None,
                     conns: 1,
+                    syncing: 0,
+                    sync_error: None,
                     last_sync: time::SystemTime::now(),
                     period: self.period,
                 });
+            drop(interner);
 
             self.notify();
         }
 
         pub(crate) fn close_conn(&self, db: &str) {
-            let sym = self.sym(db);
+            let mut interner = self.interner.lock().unwrap();
+            let sym = interner.get_or_intern(db);
 
             let mut dbs = self.dbs.lock().unwrap();
             let remove = {
@@ -137,13 +177,16 @@ mod native {
 
             if remove {
                 dbs.remove(&sym);
+                interner.remove(db);
             }
+            drop(dbs);
+            drop(interner);
 
             self.notify();
         }
 
         pub(crate) fn needs_sync(&self, db: &str, pos: Option<ltx::Pos>) -> bool {
-            let sym = self.sym(db);
+            let sym = self.sym(db).unwrap();
 
             let dbs = self.dbs.lock().unwrap();
             let db = dbs.get(&sym).unwrap();
@@ -159,7 +202,7 @@ mod native {
             db: &str,
             _pos: Option<ltx::Pos>,
         ) -> io::Result<(Option<ltx::Pos>, Option<super::Changes>)> {
-            let sym = self.sym(db);
+            let sym = self.sym(db).unwrap();
 
             let mut dbs = self.dbs.lock().unwrap();
             while dbs.get(&sym).unwrap().needs_sync(&time::SystemTime::now()) {
@@ -173,7 +216,7 @@ mod native {
         }
 
         pub(crate) fn put_changes(&self, db: &str, prev_changes: super::Changes) {
-            let sym = self.sym(db);
+            let sym = self.sym(db).unwrap();
 
             let mut dbs = self.dbs.lock().unwrap();
             let db = dbs.get_mut(&sym).unwrap();
@@ -184,7 +227,7 @@ mod native {
         pub(crate) fn set_pos(&self, db: &str, pos: Option<ltx::Pos>) {
             let pos = if let Some(pos) = pos { pos } else { return };
 
-            let sym = self.sym(db);
+            let sym = self.sym(db).unwrap();
 
             let mut dbs = self.dbs.lock().unwrap();
             let db = dbs.get_mut(&sym).unwrap();
@@ -196,17 +239,30 @@ mod native {
             db.position = Some(pos);
             db.last_sync = time::SystemTime::now();
             db.changes.take();
+
+            self.cvar.notify_all();
         }
 
         pub(crate) fn sync_one(&self, db: &str, deep: bool) -> io::Result<()> {
-            let sym = self.sym(db);
+            let sym = self.sym(db).unwrap();
             if !deep {
                 return self.sync(&[sym]);
             }
 
-            let pos = self.dbs.lock().unwrap().get(&sym).unwrap().position;
+            let pos = {
+                let mut dbs = self.dbs.lock().unwrap();
+                let db = dbs.get_mut(&sym).unwrap();
+                db.syncing += 1;
+                db.position
+            };
 
-            let changes = self.client.sync_db(db, pos)?;
+            let changes = match self.client.sync_db(db, pos) {
+                Ok(changes) => changes,
+                Err(err) => {
+                    self.sync_done(&[sym], Some(err.to_string()));
+                    return Err(err.into());
+                }
+            };
 
             self.dbs.lock().unwrap().entry(sym).and_modify(|db| {
                 let local_txid = db.position.map(|p| p.txid.into_inner()).unwrap_or(0);
@@ -218,36 +274,105 @@ mod native {
                     db.last_sync = time::SystemTime::now();
                 }
             });
+            self.cvar.notify_all();
+            // Only now, so that the waiters see the changes
+            self.sync_done(&[sym], None);
 
             Ok(())
         }
 
+        /// Returns true if a sync of the `db` with LFSC is in progress.
+        pub(crate) fn is_syncing(&self, db: &str) -> bool {
+            let Some(sym) = self.sym(db) else {
+                return false;
+            };
+
+            self.dbs
+                .lock()
+                .unwrap()
+                .get(&sym)
+                .map_or(false, |db| db.syncing > 0)
+        }
+
+        /// Waits for the in-progress syncs of the `db` to complete, returning the result
+        /// of the last one. Returns `None` right away if there are none.
+        pub(crate) fn wait_for_sync(&self, db: &str) -> Option<io::Result<()>> {
+            let sym = self.sym(db)?;
+            let syncing =
+                |dbs: &HashMap<Symbol, Db>| dbs.get(&sym).map_or(false, |db| db.syncing > 0);
+
+            let mut dbs = self.dbs.lock().unwrap();
+            if !syncing(&dbs) {
+                return None;
+            }
+            while syncing(&dbs) {
+                dbs = self.sync_cvar.wait(dbs).unwrap();
+            }
+
+            match dbs.get(&sym).and_then(|db| db.sync_error.clone()) {
+                Some(err) => Some(Err(io::Error::new(io::ErrorKind::Other, err))),
+                None => Some(Ok(())),
+            }
+        }
+
+        /// Waits until the known LFSC position of the `db` reaches `txid`, for at most
+        /// `timeout`. Returns the position, or `None` if it hasn't been reached in time.
+        pub(crate) fn wait_for_txid(
+            &self,
+            db: &str,
+            txid: ltx::TXID,
+            timeout: time::Duration,
+        ) -> Option<ltx::Pos> {
+            let sym = self.sym(db)?;
+            let deadline = time::Instant::now() + timeout;
+
+            let mut dbs = self.dbs.lock().unwrap();
+            loop {
+                if let Some(pos) = dbs.get(&sym)?.position.filter(|pos| pos.txid >= txid) {
+                    return Some(pos);
+                }
+
+                let now = time::Instant::now();
+                if now >= deadline {
+                    return None;
+                }
+                dbs = self.cvar.wait_timeout(dbs, deadline - now).unwrap().0;
+            }
+        }
+
+        /// Returns the time of the last successful sync of the `db`.
+        pub(crate) fn last_sync(&self, db: &str) -> Option<time::SystemTime> {
+            let sym = self.sym(db)?;
+
+            self.dbs.lock().unwrap().get(&sym).map(|db| db.last_sync)
+        }
+
         pub(crate) fn sync_period(&self, db: &str) -> time::Duration {
-            let sym = self.sym(db);
+            let sym = self.sym(db).unwrap();
 
             self.dbs.lock().unwrap().get(&sym).unwrap().period
         }
 
         pub(crate) fn set_sync_period(&self, db: &str, period: time::Duration) {
-            let sym = self.sym(db);
+            let sym = self.sym(db).unwrap();
 
             self.dbs.lock().unwrap().get_mut(&sym).unwrap().period = period;
 
             self.notify();
         }
 
-        fn sync(&self, db_syms: &[DefaultSymbol]) -> io::Result<()> {
+        fn sync(&self, db_syms: &[Symbol]) -> io::Result<()> {
             let old_positions = {
                 let interner = self.interner.lock().unwrap();
-                let dbs = self.dbs.lock().unwrap();
+                let mut dbs = self.dbs.lock().unwrap();
 
                 db_syms
                     .iter()
                     .filter_map(|&k| {
-                        Some((
-                            interner.resolve(k).unwrap().to_owned(),
-                            dbs.get(&k)?.position,
-                        ))
+                        let db = dbs.get_mut(&k)?;
+                        db.syncing += 1;
+
+                        Some((interner.resolve(k).unwrap().to_owned(), db.position))
                     })
                     .collect()
             };
@@ -256,7 +381,13 @@ mod native {
                 "[syncer] sync: positions = {}",
                 PositionsLogger(&old_positions)
             );
-            let mut changes = self.client.sync(&old_positions)?;
+            let mut changes = match self.client.sync(&old_positions) {
+                Ok(changes) => changes,
+                Err(err) => {
+                    self.sync_done(db_syms, Some(err.to_string()));
+                    return Err(err.into());
+                }
+            };
 
             let interner = self.interner.lock().unwrap();
             let mut dbs = self.dbs.lock().unwrap();
@@ -280,17 +411,64 @@ mod native {
                 db.position = new_pos;
                 db.last_sync = now;
             }
+            drop(dbs);
+            drop(interner);
 
             self.cvar.notify_all();
+            // Only now, so that the waiters see the changes
+            self.sync_done(db_syms, None);
+
+            Ok(())
+        }
+
+        fn sync_done(&self, db_syms: &[Symbol], error: Option<String>) {
+            let mut dbs = self.dbs.lock().unwrap();
+            for k in db_syms {
+                // The database might have been closed and reopened in the meantime
+                if let Some(db) = dbs.get_mut(k) {
+                    db.syncing = db.syncing.saturating_sub(1);
+                    db.sync_error = error.clone();
+                }
+            }
+
+            self.sync_cvar.notify_all();
+        }
+
+        /// Stops the background sync, waiting for at most `timeout` for the sync in
+        /// progress, if any, to finish. Databases can still be synced on demand.
+        pub(crate) fn shutdown(&self, timeout: time::Duration) -> io::Result<()> {
+            let Some(worker) = self.worker.lock().unwrap().take() else {
+                return Ok(());
+            };
+
+            _ = self.notifier.send(Message::Shutdown);
+            if !worker.join(timeout) {
+                log::warn!("[syncer] shutdown: background sync didn't stop in time");
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "background sync didn't stop in time",
+                ));
+            }
 
             Ok(())
         }
 
         fn notify(&self) {
-            self.notifier.send(()).unwrap();
+            // The background sync is gone after a shutdown, there is nobody to notify
+            _ = self.notifier.send(Message::Notify);
         }
 
-        fn run(&self, rx: crossbeam_channel::Receiver<()>) {
+        fn jittered(&self, d: time::Duration) -> time::Duration {
+            use rand::Rng;
+
+            if self.jitter == 0.0 {
+                return d;
+            }
+
+            d.mul_f64(1.0 + rand::thread_rng().gen_range(-self.jitter..=self.jitter))
+        }
+
+        fn run(&self, rx: crossbeam_channel::Receiver<Message>) {
             use crossbeam_channel::{after, never, select};
 
             loop {
@@ -310,8 +488,10 @@ mod native {
                 } else {
                     time::Duration::ZERO
                 };
-                let next_sync =
-                    min_sync_period.map(|p| p.checked_sub(since_last_sync).unwrap_or_default());
+                // Spread the syncs of different instances in time, so that they don't
+                // hit LFSC all at once
+                let next_sync = min_sync_period
+                    .map(|p| self.jittered(p.checked_sub(since_last_sync).unwrap_or_default()));
 
                 let waiter = if let Some(next_sync) = next_sync {
                     log::debug!("[syncer]: next sync in {}ms", next_sync.as_millis());
@@ -321,7 +501,10 @@ mod native {
                 };
 
                 select! {
-                recv(rx) -> _ => (),
+                recv(rx) -> msg => match msg {
+                    Ok(Message::Notify) => (),
+                    Ok(Message::Shutdown) | Err(_) => return,
+                },
                 recv(waiter) -> _ => (),
                 };
 
@@ -336,6 +519,7 @@ mod native {
                 if !dbs.is_empty() {
                     if let Err(err) = self.sync(&dbs) {
                         log::warn!("[syncer] run: sync failed: {}", err);
+                        metrics().record_error("background_sync", None, &err);
                     }
                 }
             }
@@ -357,6 +541,111 @@ mod native {
             }
         }
     }
+
+    #[cfg(test)]
+    mod tests {
+        use super::Syncer;
+        use crate::lfsc;
+        use litetx as ltx;
+        use std::{sync::Arc, thread, time};
+
+        #[test]
+        fn forget_closed() {
+            let client = Arc::new(lfsc::Client::builder().build());
+            let syncer = Syncer::new(client, time::Duration::ZERO, 0.0);
+            syncer.open_conn("db1", None);
+            syncer.open_conn("db1", None);
+            syncer.open_conn("db2", None);
+            assert_eq!(2, syncer.interner.lock().unwrap().len());
+
+            syncer.close_conn("db1");
+            assert_eq!(2, syncer.interner.lock().unwrap().len());
+            syncer.close_conn("db1");
+            syncer.close_conn("db2");
+            assert_eq!(0, syncer.interner.lock().unwrap().len());
+            assert!(syncer.dbs.lock().unwrap().is_empty());
+        }
+
+        #[test]
+        fn wait_for_sync() {
+            let client = Arc::new(lfsc::Client::builder().build());
+            let syncer = Syncer::new(client, time::Duration::ZERO, 0.0);
+            syncer.open_conn("db", None);
+            assert!(!syncer.is_syncing("db"));
+            assert!(syncer.wait_for_sync("db").is_none());
+
+            let sym = syncer.sym("db").unwrap();
+            for error in [None, Some("offline".to_string())] {
+                syncer.dbs.lock().unwrap().get_mut(&sym).unwrap().syncing += 1;
+                assert!(syncer.is_syncing("db"));
+
+                let done = thread::spawn({
+                    let syncer = Arc::clone(&syncer);
+                    let error = error.clone();
+
+                    move || {
+                        thread::sleep(time::Duration::from_millis(50));
+                        syncer.sync_done(&[sym], error);
+                    }
+                });
+                // The waiter gets the result of the sync it has waited for
+                let ret = syncer.wait_for_sync("db").expect("sync in progress");
+                assert_eq!(error.is_some(), ret.is_err());
+                assert!(!syncer.is_syncing("db"));
+                done.join().unwrap();
+            }
+        }
+
+        #[test]
+        fn wait_for_txid() {
+            let client = Arc::new(lfsc::Client::builder().build());
+            let syncer = Syncer::new(client, time::Duration::ZERO, 0.0);
+            let pos = |txid| ltx::Pos {
+                txid: ltx::TXID::new(txid).unwrap(),
+                post_apply_checksum: ltx::Checksum::new(0x8000_0000_0000_0000 | txid),
+            };
+            syncer.open_conn("db", Some(pos(1)));
+
+            let short = time::Duration::from_millis(10);
+            assert_eq!(Some(pos(1)), syncer.wait_for_txid("db", pos(1).txid, short));
+            assert_eq!(None, syncer.wait_for_txid("db", pos(2).txid, short));
+            assert_eq!(None, syncer.wait_for_txid("other", pos(1).txid, short));
+
+            let advance = thread::spawn({
+                let syncer = Arc::clone(&syncer);
+
+                move || {
+                    thread::sleep(time::Duration::from_millis(50));
+                    syncer.set_pos("db", Some(pos(3)));
+                }
+            });
+            assert_eq!(
+                Some(pos(3)),
+                syncer.wait_for_txid("db", pos(2).txid, time::Duration::from_secs(10))
+            );
+            advance.join().unwrap();
+        }
+
+        #[test]
+        fn shutdown() {
+            let client = Arc::new(lfsc::Client::builder().build());
+            let syncer = Syncer::new(client, time::Duration::ZERO, 0.0);
+            syncer.open_conn("db", None);
+
+            syncer
+                .shutdown(time::Duration::from_secs(10))
+                .expect("shutdown");
+            assert!(syncer.worker.lock().unwrap().is_none());
+            // The background sync holds a reference until it exits
+            assert_eq!(1, Arc::strong_count(&syncer));
+
+            // Nobody listens for the notifications anymore
+            syncer.set_sync_period("db", time::Duration::from_secs(1));
+            syncer
+                .shutdown(time::Duration::ZERO)
+                .expect("shutdown again");
+        }
+    }
 }
 
 #[cfg(target_os = "emscripten")]
@@ -405,7 +694,11 @@ mod emscripten {
     }
 
     impl Syncer {
-        pub(crate) fn new(client: Arc<lfsc::Client>, period: time::Duration) -> Arc<Syncer> {
+        pub(crate) fn new(
+            client: Arc<lfsc::Client>,
+            period: time::Duration,
+            _jitter: f64,
+        ) -> Arc<Syncer> {
             Arc::new(Syncer {
                 client,
                 period,
@@ -466,6 +759,28 @@ mod emscripten {
             Ok(())
         }
 
+        pub(crate) fn is_syncing(&self, _db: &str) -> bool {
+            false
+        }
+
+        pub(crate) fn wait_for_sync(&self, _db: &str) -> Option<io::Result<()>> {
+            None
+        }
+
+        pub(crate) fn wait_for_txid(
+            &self,
+            _db: &str,
+            _txid: ltx::TXID,
+            _timeout: time::Duration,
+        ) -> Option<ltx::Pos> {
+            None
+        }
+
+        /// Returns the time of the last successful sync of the `db`.
+        pub(crate) fn last_sync(&self, db: &str) -> Option<time::SystemTime> {
+            self.dbs.lock().unwrap().get(db).map(|db| db.last_sync)
+        }
+
         pub(crate) fn sync_period(&self, db: &str) -> time::Duration {
             self.dbs.lock().unwrap().get(db).unwrap().period
         }
@@ -473,5 +788,9 @@ mod emscripten {
         pub(crate) fn set_sync_period(&self, db: &str, period: time::Duration) {
             self.dbs.lock().unwrap().get_mut(db).unwrap().period = period;
         }
+
+        pub(crate) fn shutdown(&self, _timeout: time::Duration) -> io::Result<()> {
+            Ok(())
+        }
     }
 }
@@ -0,0 +1,305 @@
+//! Fixtures shared by the unit tests.
+
+use crate::lfsc;
+use std::{
+    collections::HashMap,
+    env, fs,
+    io::{BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+    ops::Deref,
+    path::{Path, PathBuf},
+    thread,
+};
+
+/// A directory under the system temp dir, unique to the test and the process,
+/// which is removed once dropped, even if the test fails.
+pub(crate) struct TempDir(PathBuf);
+
+impl TempDir {
+    /// Returns `litevfs-<name>-<pid>` in the temp dir, removing whatever a previous
+    /// run left there. The directory itself isn't created.
+    pub(crate) fn new(name: &str) -> TempDir {
+        let path = env::temp_dir().join(format!("litevfs-{}-{}", name, std::process::id()));
+        fs::remove_dir_all(&path).ok();
+
+        TempDir(path)
+    }
+}
+
+impl Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TempDir {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        fs::remove_dir_all(&self.0).ok();
+    }
+}
+
+/// An HTTP request received by the server started with [serve].
+pub(crate) struct Request {
+    /// The request line, e.g. `GET /db/page?... HTTP/1.1`.
+    pub(crate) line: String,
+    /// Header values by lowercase header names.
+    pub(crate) headers: HashMap<String, String>,
+    pub(crate) body: Vec<u8>,
+    /// Index of the connection the request came on, in the order of accepting.
+    pub(crate) conn: usize,
+}
+
+impl Request {
+    pub(crate) fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).map(String::as_str)
+    }
+
+    /// Returns the value of the query parameter of the request URL.
+    pub(crate) fn query(&self, name: &str) -> Option<String> {
+        let target = self.line.split(' ').nth(1)?;
+        let url = if target.starts_with('/') {
+            url::Url::parse(&format!("http://localhost{}", target)).ok()?
+        } else {
+            url::Url::parse(target).ok()?
+        };
+
+        url.query_pairs()
+            .find(|(n, _)| n == name)
+            .map(|(_, value)| value.into_owned())
+    }
+
+    /// Returns the page numbers requested from the `/db/page` endpoint.
+    pub(crate) fn pgnos(&self) -> Vec<u32> {
+        self.query("pgno")
+            .map(|pgnos| {
+                pgnos
+                    .split(',')
+                    .map(|pgno| pgno.parse().expect("pgno"))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+}
+
+/// An HTTP response returned by the handler of the server started with [serve].
+pub(crate) struct Response {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+    keep_alive: bool,
+}
+
+impl Response {
+    /// Returns an empty response with the `status`, closing the connection.
+    pub(crate) fn new(status: u16) -> Response {
+        Response {
+            status,
+            headers: Vec::new(),
+            body: Vec::new(),
+            keep_alive: false,
+        }
+    }
+
+    /// Returns a response with the JSON `body`.
+    pub(crate) fn json(body: &str) -> Response {
+        Response::new(200)
+            .header("Content-Type", "application/json")
+            .body(body.as_bytes().to_vec())
+    }
+
+    /// Returns a response with the `pages` in the binary format of the `/db/page` endpoint.
+    pub(crate) fn pages<'a>(pages: impl IntoIterator<Item = (u32, &'a [u8])>) -> Response {
+        let mut body = Vec::new();
+        for (pgno, data) in pages {
+            body.extend_from_slice(&pgno.to_be_bytes());
+            body.extend_from_slice(&(data.len() as u32).to_be_bytes());
+            body.extend_from_slice(data);
+        }
+
+        Response::new(200)
+            .header("Content-Type", lfsc::PAGES_CONTENT_TYPE)
+            .body(body)
+    }
+
+    pub(crate) fn header(mut self, name: &str, value: &str) -> Response {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub(crate) fn body(mut self, body: Vec<u8>) -> Response {
+        self.body = body;
+        self
+    }
+
+    /// Keeps the connection open for the following requests.
+    pub(crate) fn keep_alive(mut self) -> Response {
+        self.keep_alive = true;
+        self
+    }
+
+    fn write_to(&self, conn: &mut TcpStream) {
+        let reason = match self.status {
+            200 => "OK",
+            304 => "Not Modified",
+            404 => "Not Found",
+            _ => "Status",
+        };
+        let mut head = format!("HTTP/1.1 {} {}\r\n", self.status, reason);
+        for (name, value) in &self.headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        head.push_str(&format!("Content-Length: {}\r\n", self.body.len()));
+        if !self.keep_alive {
+            head.push_str("Connection: close\r\n");
+        }
+        head.push_str("\r\n");
+
+        conn.write_all(head.as_bytes()).expect("write");
+        conn.write_all(&self.body).expect("write");
+    }
+}
+
+/// Starts a plain HTTP server on a local port, answering the requests one at a time with
+/// the responses returned by the `handler`. `CONNECT` requests are accepted right away,
+/// so the server can act as a proxy too. Returns the server URL and a handle returning the
+/// received requests once `requests` of them have been answered. Without a limit the server
+/// runs until the test process exits.
+pub(crate) fn serve(
+    requests: Option<usize>,
+    mut handler: impl FnMut(&Request) -> Response + Send + 'static,
+) -> (String, thread::JoinHandle<Vec<Request>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+    let host = format!("http://{}", listener.local_addr().expect("local addr"));
+
+    let server = thread::spawn(move || {
+        let mut received = Vec::new();
+        let mut answered = 0;
+        for (n, conn) in listener.incoming().enumerate() {
+            let mut conn = conn.expect("accept");
+            let mut reader = BufReader::new(conn.try_clone().expect("clone"));
+            while let Some(request) = read_request(&mut reader, n) {
+                let keep_alive = if request.line.starts_with("CONNECT") {
+                    conn.write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+                        .expect("write");
+                    true
+                } else {
+                    let response = handler(&request);
+                    response.write_to(&mut conn);
+                    answered += 1;
+                    response.keep_alive
+                };
+                received.push(request);
+
+                if requests.map_or(false, |requests| answered >= requests) {
+                    return received;
+                }
+                if !keep_alive {
+                    break;
+                }
+            }
+        }
+
+        received
+    });
+
+    (host, server)
+}
+
+/// Returns a client of the LFSC at `host` that doesn't retry failed requests.
+pub(crate) fn lfsc_client(host: &str) -> lfsc::Client {
+    lfsc::Client::builder()
+        .host(&host.parse().unwrap())
+        .retries(0)
+        .build()
+}
+
+/// Starts a mock LFSC with [serve], returning a client of it from [lfsc_client] and the
+/// handle returning the received requests.
+pub(crate) fn mock_lfsc(
+    requests: Option<usize>,
+    handler: impl FnMut(&Request) -> Response + Send + 'static,
+) -> (lfsc::Client, thread::JoinHandle<Vec<Request>>) {
+    let (host, server) = serve(requests, handler);
+
+    (lfsc_client(&host), server)
+}
+
+/// Returns a mock LFSC handler serving every requested page of `page_size` bytes,
+/// filled with its page number.
+pub(crate) fn serve_pages(page_size: usize) -> impl FnMut(&Request) -> Response + Send + 'static {
+    move |request| {
+        let pages = request
+            .pgnos()
+            .into_iter()
+            .map(|pgno| (pgno, vec![pgno as u8; page_size]))
+            .collect::<Vec<_>>();
+        Response::pages(pages.iter().map(|(pgno, data)| (*pgno, &data[..])))
+    }
+}
+
+// Reads the next request from the connection, returns `None` once the client has closed it.
+fn read_request(reader: &mut impl BufRead, conn: usize) -> Option<Request> {
+    let mut line = String::new();
+    if reader.read_line(&mut line).ok()? == 0 {
+        return None;
+    }
+
+    let mut headers = HashMap::new();
+    loop {
+        let mut header = String::new();
+        reader.read_line(&mut header).expect("read");
+        match header.trim_end().split_once(':') {
+            Some((name, value)) => {
+                headers.insert(name.to_lowercase(), value.trim().to_string());
+            }
+            None => break,
+        }
+    }
+
+    let body = if headers.get("transfer-encoding").map(String::as_str) == Some("chunked") {
+        read_chunked(reader)
+    } else {
+        let len = headers
+            .get("content-length")
+            .map_or(0, |len| len.parse().expect("content length"));
+        let mut body = vec![0; len];
+        reader.read_exact(&mut body).expect("read body");
+        body
+    };
+
+    Some(Request {
+        line: line.trim_end().to_string(),
+        headers,
+        body,
+        conn,
+    })
+}
+
+// Reads a body sent with `Transfer-Encoding: chunked`.
+fn read_chunked(reader: &mut impl BufRead) -> Vec<u8> {
+    let mut body = Vec::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).expect("read chunk size");
+        let size = line.trim_end().split(';').next().unwrap_or_default();
+        let size = usize::from_str_radix(size, 16).expect("chunk size");
+
+        let start = body.len();
+        body.resize(start + size, 0);
+        reader.read_exact(&mut body[start..]).expect("read chunk");
+        line.clear();
+        reader.read_line(&mut line).expect("read chunk end");
+
+        if size == 0 {
+            return body;
+        }
+    }
+}
@@ -1,37 +1,241 @@
 use crate::{
-    database::{Database, DatabaseManager, MAX_MAX_PREFETCH_PAGES},
-    leaser::Leaser,
+    builder::EnvSettings,
+    database::{
+        is_pos_mismatch, Background, CacheTask, CombineWrites, Database, DatabaseManager,
+        FlushStatus, DEFAULT_COMBINE_MAX_BYTES, MAX_MAX_PREFETCH_PAGES,
+    },
+    leaser::{LeaseInfo, Leaser},
     lfsc,
     locks::{ConnLock, VfsLock},
-    pager::{PageSource, Pager},
-    syncer::Syncer,
+    metrics::metrics,
+    pager::{open_nofollow, BandwidthLimit, PageSource, Pager, PagerBackend},
+    syncer::{Syncer, DEFAULT_SYNC_JITTER},
+    wal::LiteWalIndex,
 };
 use bytesize::ByteSize;
 use humantime::{format_duration, parse_duration};
+use litetx as ltx;
 use rand::Rng;
 use read_write_at::{ReadAtMut, WriteAtMut};
 use sqlite_vfs::{LockKind, OpenAccess, OpenKind, OpenOptions, Vfs};
 use std::{
+    collections::HashMap,
     fs, io,
     path::{Path, PathBuf},
     process,
     sync::{
-        atomic::{AtomicU64, Ordering},
-        Arc, Mutex, RwLock,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex, RwLock, Weak,
     },
-    thread, time,
+    time,
 };
 
 const DEFAULT_MAX_REQS_PER_QUERY: usize = 64;
 const MAX_MAX_REQS_PER_QUERY: usize = 1024;
+const DEFAULT_LOCK_TIMEOUT: time::Duration = time::Duration::from_secs(1);
+const DEFAULT_WAIT_FOR_POS_TIMEOUT: time::Duration = time::Duration::from_secs(10);
+// IDs of database connections, telling apart the connections sharing a database.
+static NEXT_CONN_ID: AtomicU64 = AtomicU64::new(0);
+// Pragmas supported by database connections. Settings have no side effects when read
+// without a value, so their current values are shown by `litevfs_help`.
+const PRAGMAS: &[(&str, bool)] = &[
+    ("litevfs_help", false),
+    ("litevfs_min_available_space", true),
+    ("litevfs_min_available_inodes", true),
+    ("litevfs_max_cached_pages", true),
+    ("litevfs_max_cache_fraction", true),
+    ("litevfs_mem_cache_pages", true),
+    ("litevfs_cache_compression", true),
+    ("litevfs_max_reqs_per_query", true),
+    ("litevfs_max_pages_per_request", true),
+    ("litevfs_offline_reads", true),
+    ("litevfs_max_prefetch_pages", true),
+    ("litevfs_prefetch_enabled", true),
+    ("litevfs_max_tx_bytes", true),
+    ("litevfs_combine_writes", true),
+    ("litevfs_eager_refetch", true),
+    ("litevfs_lock_timeout", true),
+    ("litevfs_cache_sync_period", true),
+    ("litevfs_last_sync", true),
+    ("litevfs_syncing", true),
+    ("litevfs_wait_for_pos", false),
+    ("litevfs_pos", true),
+    ("litevfs_db_size", true),
+    ("litevfs_dirty_pages", true),
+    ("litevfs_lease_status", true),
+    ("litevfs_lfsc_instance", true),
+    ("litevfs_reset_lfsc_instance", false),
+    ("litevfs_cache_stats", true),
+    ("litevfs_prefetch_stats", true),
+    ("litevfs_lfsc_latency", true),
+    ("litevfs_query_stats", true),
+    ("litevfs_bandwidth", true),
+    ("litevfs_bandwidth_limit", true),
+    ("litevfs_reclaim", false),
+    ("litevfs_recent_errors", false),
+    ("litevfs_cache_db_progress", true),
+    ("litevfs_acquire_lease", false),
+    ("litevfs_release_lease", false),
+    ("litevfs_leases", true),
+    ("litevfs_force_release_lease", false),
+    ("litevfs_cache_db", false),
+    ("litevfs_cache_db_async", false),
+    ("litevfs_drop_cache", false),
+    ("litevfs_verify_cache", false),
+    ("litevfs_sync_now", false),
+    ("litevfs_flush", false),
+    ("litevfs_check", false),
+    ("litevfs_import_file", false),
+    ("litevfs_pin_pos", true),
+    ("litevfs_unpin", false),
+];
+// Pragmas changing the database, its cache or its lease, which connections opened
+// at a position can't use.
+const MUTATING_PRAGMAS: &[&str] = &[
+    "litevfs_acquire_lease",
+    "litevfs_release_lease",
+    "litevfs_force_release_lease",
+    "litevfs_cache_db",
+    "litevfs_cache_db_async",
+    "litevfs_drop_cache",
+    "litevfs_sync_now",
+    "litevfs_wait_for_pos",
+    "litevfs_flush",
+    "litevfs_import_file",
+    // Downloads every checked page, the whole database without a sample size
+    "litevfs_verify_cache",
+];
+// Settings shared by the connections of a database, which connections opened at
+// a position can read, but not change.
+const SHARED_SETTINGS: &[&str] = &[
+    "litevfs_max_tx_bytes",
+    "litevfs_combine_writes",
+    "litevfs_eager_refetch",
+];
+// How long dropping the VFS waits for the background threads to stop.
+const DEFAULT_SHUTDOWN_TIMEOUT: time::Duration = time::Duration::from_secs(5);
+// Number of names tried when creating a new temporary database.
+const MAX_TEMP_NAME_ATTEMPTS: usize = 8;
+// Window of `litevfs_bandwidth_limit` if none is given.
+const DEFAULT_BANDWIDTH_WINDOW: time::Duration = time::Duration::from_secs(60);
+// Cache directory of additional clusters, relative to the main cache directory.
+const CLUSTERS_DIR: &str = ".clusters";
 
 /// LiteVfs implements SQLite VFS ops.
 pub struct LiteVfs {
     path: PathBuf,
+    default_cluster: Cluster,
+    clusters: HashMap<String, Cluster>,
+    // Leasers of all the clusters, the default one first, for `litevfs_leases`.
+    leasers: ClusterLeasers,
+    temp_counter: AtomicU64,
+    // Random part of temporary file names, so that VFSes sharing the cache directory
+    // don't generate the same names even if their processes get the same PID.
+    temp_salt: u32,
+    temp_in_memory: bool,
+    conn_cancels: ConnCancels,
+}
+
+// Leasers by the cluster name, `None` being the default cluster.
+type ClusterLeasers = Arc<Vec<(Option<String>, Arc<Leaser>)>>;
+
+// Cancellation flags of the open database connections. Shutting the VFS down sets them,
+// so that the reads still retrying LFSC requests give up instead of blocking SQLite.
+type ConnCancels = Arc<Mutex<Vec<Weak<AtomicBool>>>>;
+
+// Databases of a single LFSC cluster along with their cache.
+struct Cluster {
     pager: Arc<Pager>,
+    leaser: Arc<Leaser>,
     syncer: Arc<Syncer>,
-    database_manager: Mutex<DatabaseManager>,
-    temp_counter: AtomicU64,
+    database_manager: DatabaseManager,
+}
+
+impl Cluster {
+    fn new<P: AsRef<Path>>(
+        path: P,
+        client: lfsc::Client,
+        pager_backend: PagerBackend,
+        read_only: bool,
+        lease_duration: time::Duration,
+        lease_refresh_fraction: f64,
+        lease_idle_timeout: Option<time::Duration>,
+        settings: &EnvSettings,
+    ) -> Cluster {
+        let client = Arc::new(client);
+        let pager = Arc::new(Pager::new(
+            &path,
+            Arc::clone(&client),
+            pager_backend,
+            settings,
+        ));
+        let leaser = Leaser::new(
+            Arc::clone(&client),
+            lease_duration,
+            lease_refresh_fraction,
+            lease_idle_timeout,
+            read_only,
+        );
+        let syncer = Syncer::new(
+            Arc::clone(&client),
+            settings.sync_period,
+            DEFAULT_SYNC_JITTER,
+        );
+
+        Cluster {
+            pager: Arc::clone(&pager),
+            leaser: Arc::clone(&leaser),
+            syncer: Arc::clone(&syncer),
+            database_manager: DatabaseManager::new(
+                pager, client, leaser, syncer, read_only, settings,
+            ),
+        }
+    }
+}
+
+/// Shuts down a [LiteVfs] that has been handed over to SQLite, see [LiteVfs::shutdown_handle].
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    // Pager, leaser, syncer and background threads of every cluster.
+    clusters: Vec<(Arc<Pager>, Arc<Leaser>, Arc<Syncer>, Arc<Background>)>,
+    conn_cancels: ConnCancels,
+}
+
+impl ShutdownHandle {
+    /// Same as [LiteVfs::shutdown].
+    pub fn shutdown(&self, timeout: time::Duration) -> io::Result<()> {
+        log::info!("[vfs] shutdown");
+
+        for cancel in self.conn_cancels.lock().unwrap().iter() {
+            if let Some(cancel) = cancel.upgrade() {
+                cancel.store(true, Ordering::Release);
+            }
+        }
+
+        let deadline = time::Instant::now() + timeout;
+        let timeout = || deadline.saturating_duration_since(time::Instant::now());
+
+        // Keep going on errors, returning the first one
+        let mut ret = Ok(());
+        for (pager, leaser, syncer, background) in &self.clusters {
+            // The uploads need the leases, so stop them before releasing the leases
+            ret = ret
+                .and(syncer.shutdown(timeout()))
+                .and(background.shutdown(timeout()))
+                .and(leaser.shutdown(timeout()))
+                .and(pager.save_lru());
+        }
+
+        ret
+    }
+}
+
+impl Drop for LiteVfs {
+    fn drop(&mut self) {
+        if let Err(err) = self.shutdown(DEFAULT_SHUTDOWN_TIMEOUT) {
+            log::warn!("[vfs] drop: {}", err);
+        }
+    }
 }
 
 impl Vfs for LiteVfs {
@@ -42,7 +246,11 @@ impl Vfs for LiteVfs {
 
         if !matches!(
             opts.kind,
-            OpenKind::MainDb | OpenKind::TempDb | OpenKind::MainJournal | OpenKind::TempJournal
+            OpenKind::MainDb
+                | OpenKind::TempDb
+                | OpenKind::MainJournal
+                | OpenKind::TempJournal
+                | OpenKind::Wal
         ) {
             log::error!(
                 "[vfs] open: db = {}, opts = {:?}: unsupported open kind",
@@ -55,7 +263,7 @@ impl Vfs for LiteVfs {
             ));
         };
 
-        let (dbname, kind) = self.database_name_kind(db);
+        let (cluster, dbname, pos, kind) = self.database_name_kind(db);
         if kind != opts.kind && (opts.kind != OpenKind::TempJournal && kind != OpenKind::TempDb) {
             return Err(io::Error::new(
                 io::ErrorKind::InvalidInput,
@@ -64,37 +272,26 @@ impl Vfs for LiteVfs {
         };
 
         let res = match kind {
-            OpenKind::MainDb => self
+            OpenKind::MainDb => self.open_main_db(cluster, dbname, pos, opts.access),
+            OpenKind::TempDb if self.temp_in_memory => {
+                Ok(LiteHandle::new(LiteMemTempDbHandle::new(db)))
+            }
+            OpenKind::TempDb => Ok(LiteHandle::new(self.open_temp_db(db, opts.access)?)),
+
+            OpenKind::MainJournal => cluster
                 .database_manager
-                .lock()
-                .unwrap()
                 .get_database(dbname, opts.access)
-                .map(|database| {
-                    let (conn_lock, pos) = {
-                        let database = database.read().unwrap();
-
-                        (database.conn_lock(), database.pos)
-                    };
-                    self.syncer.open_conn(dbname, pos);
-
-                    LiteHandle::new(LiteDatabaseHandle::new(
-                        Arc::clone(&self.pager),
-                        Arc::clone(&self.syncer),
-                        database,
-                        conn_lock,
-                    ))
+                .and_then(|database| {
+                    if database.read().unwrap().journal_in_memory {
+                        Ok(LiteHandle::new(LiteMemJournalHandle::new(database)))
+                    } else {
+                        Ok(LiteHandle::new(LiteJournalHandle::new(database)?))
+                    }
                 }),
-            OpenKind::TempDb => Ok(LiteHandle::new(LiteTempDbHandle::new(
-                self.path.join(db),
-                opts.access,
-            )?)),
-
-            OpenKind::MainJournal => self
+            OpenKind::Wal => cluster
                 .database_manager
-                .lock()
-                .unwrap()
-                .get_database(dbname, opts.access)
-                .and_then(|database| Ok(LiteHandle::new(LiteJournalHandle::new(database)?))),
+                .get_database(dbname, OpenAccess::Read)
+                .map(|database| LiteHandle::new(LiteWalHandle::new(database))),
             _ => unreachable!(),
         };
 
@@ -108,18 +305,24 @@ impl Vfs for LiteVfs {
     fn delete(&self, db: &str) -> io::Result<()> {
         log::debug!("[vfs] delete: db = {}", db);
 
-        let (dbname, kind) = self.database_name_kind(db);
+        let (cluster, dbname, _, kind) = self.database_name_kind(db);
         match kind {
             OpenKind::MainDb => (),
             OpenKind::MainJournal => {
-                let database = self
+                let database = cluster
                     .database_manager
-                    .lock()
-                    .unwrap()
                     .get_database(dbname.as_ref(), OpenAccess::Write)?;
                 database.write().unwrap().commit_journal()?;
-                fs::remove_file(&database.read().unwrap().journal_path)?;
+                Database::upload_in_background(&database);
+                let mut database = database.write().unwrap();
+                if !database.journal_in_memory {
+                    fs::remove_file(&database.journal_path)?;
+                } else if database.mem_journal.take().is_none() {
+                    return Err(io::ErrorKind::NotFound.into());
+                }
             }
+            // The WAL is never written to, see `LiteWalHandle`, so there is nothing to
+            // delete, same as for the other files
             _ => (),
         };
 
@@ -129,31 +332,32 @@ impl Vfs for LiteVfs {
     fn exists(&self, db: &str) -> io::Result<bool> {
         log::debug!("[vfs] exists: db = {}", db);
 
-        let (dbname, kind) = self.database_name_kind(db);
+        let (cluster, dbname, _, kind) = self.database_name_kind(db);
         match kind {
-            OpenKind::MainDb => self
-                .database_manager
-                .lock()
-                .unwrap()
-                .database_exists(dbname),
+            OpenKind::MainDb => cluster.database_manager.database_exists(dbname),
             OpenKind::MainJournal => {
-                let database = self
+                let database = cluster
                     .database_manager
-                    .lock()
-                    .unwrap()
                     .get_database(dbname.as_ref(), OpenAccess::Read)?;
                 let database = database.read().unwrap();
 
-                Ok(database.journal_path.exists())
+                if database.journal_in_memory {
+                    Ok(database.mem_journal.is_some())
+                } else {
+                    Ok(database.journal_path.exists())
+                }
             }
+            // The WAL is always empty, so it's reported missing without looking up the
+            // database, same as the other files
             _ => Ok(false),
         }
     }
 
     fn temporary_name(&self) -> String {
         format!(
-            "sfvetil-{:x}_{:x}.db",
+            "sfvetil-{:x}_{:08x}_{:x}.db",
             process::id(),
+            self.temp_salt,
             self.temp_counter.fetch_add(1, Ordering::AcqRel)
         )
     }
@@ -165,39 +369,223 @@ impl Vfs for LiteVfs {
     fn sleep(&self, duration: time::Duration) -> time::Duration {
         log::debug!("[vfs] sleep: duration: {:?}", duration);
 
-        // TODO: This will block JS runtime. Should be call back to JS here???
         let now = time::Instant::now();
-        thread::sleep(duration);
+        sleep(duration);
         now.elapsed()
     }
 }
 
+#[cfg(not(target_os = "emscripten"))]
+fn sleep(duration: time::Duration) {
+    std::thread::sleep(duration)
+}
+
+// Blocking the thread would freeze the JS runtime, so yield to the event loop instead.
+// This requires the module to be built with ASYNCIFY.
+#[cfg(target_os = "emscripten")]
+fn sleep(duration: time::Duration) {
+    let ms = (duration.as_micros() + 999) / 1000;
+    unsafe { emscripten_sys::emscripten_sleep(ms.min(u32::MAX as u128) as u32) }
+}
+
 impl LiteVfs {
-    pub(crate) fn new<P: AsRef<Path>>(path: P, client: lfsc::Client) -> Self {
-        let client = Arc::new(client);
-        let pager = Arc::new(Pager::new(&path, Arc::clone(&client)));
-        let leaser = Leaser::new(Arc::clone(&client), time::Duration::from_secs(1));
-        let syncer = Syncer::new(Arc::clone(&client), time::Duration::from_secs(1));
+    /// Stops the background sync, uploads, caching and lease refresh, releases the leases
+    /// held by this process and saves the cache state, so that a restarted instance can
+    /// take over right away instead of waiting for the leases to expire. Waits for at most
+    /// `timeout` for the background threads to stop. Transactions that haven't been
+    /// uploaded yet are uploaded once the database is opened again.
+    ///
+    /// Databases can still be used afterwards, but they are only synced on demand and
+    /// write leases can't be acquired anymore. Called on drop with a default timeout.
+    pub fn shutdown(&self, timeout: time::Duration) -> io::Result<()> {
+        self.shutdown_handle().shutdown(timeout)
+    }
+
+    /// Returns a handle to shut the VFS down with once it has been registered with SQLite,
+    /// which takes the ownership of the VFS and never drops it.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        let clusters = std::iter::once(&self.default_cluster)
+            .chain(self.clusters.values())
+            .map(|cluster| {
+                (
+                    Arc::clone(&cluster.pager),
+                    Arc::clone(&cluster.leaser),
+                    Arc::clone(&cluster.syncer),
+                    cluster.database_manager.background(),
+                )
+            })
+            .collect();
+
+        ShutdownHandle {
+            clusters,
+            conn_cancels: Arc::clone(&self.conn_cancels),
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn new<P: AsRef<Path>>(
+        path: P,
+        client: lfsc::Client,
+        pager_backend: PagerBackend,
+        read_only: bool,
+        lease_duration: time::Duration,
+        lease_refresh_fraction: f64,
+        lease_idle_timeout: Option<time::Duration>,
+        settings: EnvSettings,
+        temp_in_memory: bool,
+        clusters: HashMap<String, lfsc::Client>,
+    ) -> Self {
+        let default_cluster = Cluster::new(
+            &path,
+            client,
+            pager_backend,
+            read_only,
+            lease_duration,
+            lease_refresh_fraction,
+            lease_idle_timeout,
+            &settings,
+        );
+        // Additional clusters are cached separately, so that databases with the same
+        // name in different clusters don't clash.
+        let clusters = clusters
+            .into_iter()
+            .map(|(name, client)| {
+                let cluster = Cluster::new(
+                    path.as_ref().join(CLUSTERS_DIR).join(&name),
+                    client,
+                    pager_backend,
+                    read_only,
+                    lease_duration,
+                    lease_refresh_fraction,
+                    lease_idle_timeout,
+                    &settings,
+                );
+                (name, cluster)
+            })
+            .collect::<HashMap<_, _>>();
+        let mut leasers = clusters
+            .iter()
+            .map(|(name, cluster)| (Some(name.clone()), Arc::clone(&cluster.leaser)))
+            .collect::<Vec<_>>();
+        leasers.push((None, Arc::clone(&default_cluster.leaser)));
+        leasers.sort_by(|a, b| a.0.cmp(&b.0));
 
         LiteVfs {
             path: path.as_ref().to_path_buf(),
-            pager: Arc::clone(&pager),
-            syncer: Arc::clone(&syncer),
-            database_manager: Mutex::new(DatabaseManager::new(pager, client, leaser, syncer)),
+            default_cluster,
+            clusters,
+            leasers: Arc::new(leasers),
             temp_counter: AtomicU64::new(0),
+            temp_salt: rand::thread_rng().gen(),
+            temp_in_memory,
+            conn_cancels: Arc::default(),
+        }
+    }
+
+    fn open_temp_db(&self, db: &str, access: OpenAccess) -> io::Result<LiteTempDbHandle> {
+        let mut path = path_within(&self.path, &self.path.join(db))?;
+        let mut attempts = 1;
+        loop {
+            match LiteTempDbHandle::new(&path, access) {
+                // The file must be new, so pick another name instead of failing
+                Err(err)
+                    if err.kind() == io::ErrorKind::AlreadyExists
+                        && access == OpenAccess::CreateNew
+                        && attempts < MAX_TEMP_NAME_ATTEMPTS =>
+                {
+                    log::warn!(
+                        "[vfs] open_temp_db: path = {}, attempt = {}: {}",
+                        path.display(),
+                        attempts,
+                        err
+                    );
+                    path = path_within(&self.path, &self.path.join(self.temporary_name()))?;
+                    attempts += 1;
+                }
+                x => return x,
+            }
+        }
+    }
+
+    fn open_main_db(
+        &self,
+        cluster: &Cluster,
+        dbname: &str,
+        pos: Option<&str>,
+        access: OpenAccess,
+    ) -> io::Result<LiteHandle> {
+        let pinned_pos = pos.map(parse_pos).transpose()?;
+        // PermissionDenied makes SQLite retry opening the database as read-only
+        if pinned_pos.is_some() && access != OpenAccess::Read {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "database opened at a position is read-only",
+            ));
+        }
+
+        let database = cluster.database_manager.get_database(dbname, access)?;
+        let (conn_lock, pos) = {
+            let database = database.read().unwrap();
+
+            (database.conn_lock(), database.pos)
+        };
+        cluster.syncer.open_conn(dbname, pos);
+
+        let mut handle = LiteDatabaseHandle::new(
+            Arc::clone(&cluster.pager),
+            Arc::clone(&cluster.syncer),
+            Arc::clone(&self.leasers),
+            database,
+            conn_lock,
+        );
+        {
+            let mut cancels = self.conn_cancels.lock().unwrap();
+            cancels.retain(|cancel| cancel.strong_count() > 0);
+            cancels.push(Arc::downgrade(&handle.cancel));
+        }
+        if let Some(pos) = pinned_pos {
+            handle.pinned_pos = Some(pos);
+            handle.opened_at_pos = true;
         }
+
+        Ok(LiteHandle::new(handle))
     }
 
-    fn database_name_kind<'a>(&self, db: &'a str) -> (&'a str, OpenKind) {
-        if let Some(db) = db.strip_suffix("-journal") {
+    // Splits the name into the cluster, the database name, the position the database
+    // is opened at, if any (`dbname@txid/checksum`), and the kind of the file.
+    fn database_name_kind<'a>(
+        &self,
+        db: &'a str,
+    ) -> (&Cluster, &'a str, Option<&'a str>, OpenKind) {
+        let (cluster, db) = self.database_cluster(db);
+
+        let (db, kind) = if let Some(db) = db.strip_suffix("-journal") {
             (db, OpenKind::MainJournal)
         } else if let Some(db) = db.strip_suffix("-wal") {
-            (db.trim_end_matches("-wal"), OpenKind::Wal)
+            (db, OpenKind::Wal)
         } else if db.starts_with("sfvetil-") {
-            (db, OpenKind::TempDb)
+            return (cluster, db, None, OpenKind::TempDb);
         } else {
             (db, OpenKind::MainDb)
+        };
+
+        // Names may contain `@` too, only a valid position is split off
+        match db.rsplit_once('@') {
+            Some((db, pos)) if parse_pos(pos).is_ok() => (cluster, db, Some(pos), kind),
+            _ => (cluster, db, None, kind),
+        }
+    }
+
+    // Routes `cluster:dbname` names to the named cluster. Names without a known
+    // cluster prefix belong to the default one.
+    fn database_cluster<'a>(&self, db: &'a str) -> (&Cluster, &'a str) {
+        if let Some((name, dbname)) = db.split_once(':') {
+            if let Some(cluster) = self.clusters.get(name) {
+                return (cluster, dbname);
+            }
         }
+
+        (&self.default_cluster, db)
     }
 }
 
@@ -227,6 +615,13 @@ pub trait DatabaseHandle: Sync {
         None
     }
 
+    fn wal_index(&self, _readonly: bool) -> io::Result<LiteWalIndex> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "WAL index is not supported",
+        ))
+    }
+
     fn handle_type(&self) -> &'static str;
     fn handle_name(&self) -> &str;
 }
@@ -247,7 +642,7 @@ impl LiteHandle {
 }
 
 impl sqlite_vfs::DatabaseHandle for LiteHandle {
-    type WalIndex = sqlite_vfs::WalDisabled;
+    type WalIndex = LiteWalIndex;
 
     fn size(&self) -> io::Result<u64> {
         match self.inner.size() {
@@ -377,8 +772,21 @@ impl sqlite_vfs::DatabaseHandle for LiteHandle {
         }
     }
 
-    fn wal_index(&self, _readonly: bool) -> io::Result<Self::WalIndex> {
-        Ok(sqlite_vfs::WalDisabled)
+    fn wal_index(&self, readonly: bool) -> io::Result<Self::WalIndex> {
+        match self.inner.wal_index(readonly) {
+            Err(err) => {
+                log::error!(
+                    "[handle] wal_index: type = {}, name = {}, readonly = {}: {}",
+                    self.inner.handle_type(),
+                    self.inner.handle_name(),
+                    readonly,
+                    err,
+                );
+
+                Err(err)
+            }
+            x => x,
+        }
     }
 }
 
@@ -388,15 +796,40 @@ struct LiteDatabaseHandle {
     database: Arc<RwLock<Database>>,
     lock: ConnLock,
     name: String,
+    // Leasers of all the clusters, the database belongs to one of them.
+    leasers: ClusterLeasers,
+    // Identifies the connection as the owner of the lease it acquires, which is
+    // released when the connection is closed.
+    conn_id: u64,
+    // Background caching started by this connection, cancelled when the connection is closed.
+    cache_task: Option<Arc<CacheTask>>,
+    // Set when a read transaction starts, cleared after its first read.
+    first_read: bool,
+    // Position this connection reads the database at, regardless of syncs.
+    pinned_pos: Option<ltx::Pos>,
+    // Set when the database is opened at a position, which can't be changed then.
+    opened_at_pos: bool,
+    // Position the current read transaction of a WAL database has started at, set by
+    // the WAL index, so that syncs by other connections don't change the data under it.
+    wal_read_pos: Arc<Mutex<Option<ltx::Pos>>>,
+    // Set when the connection is closed or the VFS is shut down, so that the reads
+    // waiting on LFSC give up.
+    cancel: Arc<AtomicBool>,
 
     cur_pages_per_query: usize,
     max_pages_per_query: usize,
+    // Set when the current query has run out of `max_pages_per_query`.
+    over_budget: bool,
+    // Serve reads from the cache only, without ever contacting LFSC.
+    offline_reads: bool,
+    lock_timeout: time::Duration,
 }
 
 impl LiteDatabaseHandle {
     pub(crate) fn new(
         pager: Arc<Pager>,
         syncer: Arc<Syncer>,
+        leasers: ClusterLeasers,
         database: Arc<RwLock<Database>>,
         lock: ConnLock,
     ) -> Self {
@@ -407,12 +840,44 @@ impl LiteDatabaseHandle {
             database,
             lock,
             name,
+            leasers,
+            conn_id: NEXT_CONN_ID.fetch_add(1, Ordering::Relaxed),
+            cache_task: None,
+            first_read: false,
+            pinned_pos: None,
+            opened_at_pos: false,
+            wal_read_pos: Arc::default(),
+            cancel: Arc::new(AtomicBool::new(false)),
 
             cur_pages_per_query: 0,
             max_pages_per_query: DEFAULT_MAX_REQS_PER_QUERY,
+            over_budget: false,
+            offline_reads: false,
+            lock_timeout: DEFAULT_LOCK_TIMEOUT,
         }
     }
 
+    // Returns the position the database is read at, if it's not the current one.
+    fn read_pos(&self) -> Option<ltx::Pos> {
+        self.pinned_pos.or_else(|| {
+            let pos = (*self.wal_read_pos.lock().unwrap())?;
+            (self.database.read().unwrap().pos != Some(pos)).then_some(pos)
+        })
+    }
+
+    // Reads the page from the local cache at the last known position, failing if
+    // it's not cached instead of fetching it from LFSC.
+    fn read_offline(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        let db = self.database.read().unwrap();
+        let ret = match self.read_pos() {
+            Some(pos) => db.read_at_pos(buf, offset, true, pos, None),
+            None => db.read_at(buf, offset, true, None),
+        };
+
+        ret.map(|_| ())
+            .map_err(|err| offline_read_error(err, offset))
+    }
+
     fn acquire_exclusive(&mut self) -> io::Result<()> {
         if self.lock.state() != LockKind::None {
             return Err(io::Error::new(
@@ -421,10 +886,26 @@ impl LiteDatabaseHandle {
             ));
         }
 
-        let now = time::Instant::now();
-        let timeout = time::Duration::from_secs(1);
-        let check_timeout = move || -> io::Result<()> {
-            if now.elapsed() > timeout {
+        let timeout = self.lock_timeout;
+        let deadline = time::Instant::now() + timeout;
+        let remaining = || deadline.saturating_duration_since(time::Instant::now());
+
+        loop {
+            if self.lock.acquire_timeout(LockKind::Shared, remaining()) {
+                if self.lock.acquire(LockKind::Reserved) {
+                    // Now that we have a reserved lock there can only be readers.
+                    // So wait here until all of them finish.
+                    if self.lock.acquire_timeout(LockKind::Exclusive, remaining()) {
+                        return Ok(());
+                    }
+                }
+
+                // Return back to none if we can't progress from shared
+                self.lock.acquire(LockKind::None);
+            }
+
+            // Wait for the current writer, if any, to finish
+            if remaining().is_zero() || !self.lock.wait_no_writer(remaining()) {
                 return Err(io::Error::new(
                     io::ErrorKind::WouldBlock,
                     format!(
@@ -432,35 +913,90 @@ impl LiteDatabaseHandle {
                         format_duration(timeout)
                     ),
                 ));
-            };
+            }
+        }
+    }
 
-            thread::sleep(time::Duration::from_millis(1));
+    fn release_exclusive(&mut self) {
+        self.lock.acquire(LockKind::None);
+    }
 
-            Ok(())
-        };
+    // Syncs the database with LFSC if it has moved on, before the connection starts
+    // reading. Databases in WAL mode are synced at the start of a read transaction by the
+    // WAL index. Pinned connections don't care about the latest changes.
+    fn sync_if_needed(&mut self) {
+        if self.lock.state() != LockKind::None
+            || self.pinned_pos.is_some()
+            || self.database.read().unwrap().is_wal()
+            || !self.database.read().unwrap().needs_sync()
+        {
+            return;
+        }
 
-        loop {
-            if self.lock.acquire(LockKind::Shared) {
-                if self.lock.acquire(LockKind::Reserved) {
-                    // Now that we have a reserved lock there can only be readers.
-                    // So loop here until all of them finish.
-                    while !self.lock.acquire(LockKind::Exclusive) {
-                        check_timeout()?;
-                    }
+        // This is a bit complicated. We need to initiate the sync even for read transactions,
+        // so there may be concurrent transactions executing at the time we enter `sync()`.
+        // So wait for them to finish first, otherwise they might see inconsistent state.
+        if let Err(err) = self.acquire_exclusive() {
+            log::warn!(
+                "[database] sync: db = {}, timeout waiting for active connections, skipping sync: {}",
+                self.name, err
+            );
+            metrics().record_error("sync", Some(&self.name), &err);
 
-                    return Ok(());
-                } else {
-                    // Return back to none if we can't progress from shared
-                    self.lock.acquire(LockKind::None);
-                }
-            }
+            return;
+        }
 
-            check_timeout()?;
+        // There are no readers, try and sync. If we fail, let SQLite take the read lock, we may still be
+        // able to read the data. The important part here is that `sync()` doesn't fetch any data, so
+        // the cache stays consistent.
+        {
+            let mut db = self.database.write().unwrap();
+            // Held transactions are discarded once somebody else's write shows up,
+            // so don't keep them waiting for the write-combining window any longer.
+            db.close_combine_window();
+            if let Err(err) = db.sync(false, false) {
+                log::warn!("[database] sync: db = {}: {}", self.name, err);
+                metrics().record_error("sync", Some(&self.name), &err);
+            }
         }
+
+        self.release_exclusive();
+        Database::upload_in_background(&self.database);
+        Database::refetch_in_background(&self.database);
     }
 
-    fn release_exclusive(&mut self) {
-        self.lock.acquire(LockKind::None);
+    // Moves the database to the latest LFSC position after the first read of a transaction
+    // failed with a position mismatch. Nothing has been read yet, but the transaction has
+    // started, so the shared lock is upgraded to wait for the other connections instead of
+    // being dropped, and downgraded back once the database is synced.
+    fn resync_after_pos_mismatch(&mut self) -> io::Result<()> {
+        if self.lock.state() != LockKind::Shared {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "connection is not holding a shared lock",
+            ));
+        }
+
+        let ret = if self.lock.acquire(LockKind::Reserved)
+            && self
+                .lock
+                .acquire_timeout(LockKind::Exclusive, self.lock_timeout)
+        {
+            self.database.write().unwrap().resync_after_pos_mismatch()
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                format!(
+                    "waiting for more than {} to acquire exclusive lock",
+                    format_duration(self.lock_timeout)
+                ),
+            ))
+        };
+
+        // Downgrading from a write lock always succeeds
+        self.lock.acquire(LockKind::Shared);
+
+        ret
     }
 
     fn acquire_lease_and_sync(&mut self) -> io::Result<()> {
@@ -468,7 +1004,7 @@ impl LiteDatabaseHandle {
 
         {
             let mut db = self.database.write().unwrap();
-            if let Err(err) = db.acquire_lease() {
+            if let Err(err) = db.acquire_lease(self.conn_id) {
                 drop(db);
                 self.release_exclusive();
                 return Err(err);
@@ -482,10 +1018,40 @@ impl LiteDatabaseHandle {
         };
 
         self.release_exclusive();
+        // The forced sync has closed the write-combining window
+        Database::upload_in_background(&self.database);
 
         Ok(())
     }
 
+    fn release_lease(&mut self) -> io::Result<()> {
+        self.database.read().unwrap().release_lease()
+    }
+
+    // Releases the lease acquired by this connection, unless it has been released
+    // or reacquired by some other connection since then.
+    fn release_owned_lease(&mut self) {
+        match self
+            .database
+            .read()
+            .unwrap()
+            .release_owned_lease(self.conn_id)
+        {
+            Ok(Some(lease)) => {
+                log::info!(
+                    "[database] released lease on close: db = {}, lease = {}",
+                    self.name,
+                    lease
+                );
+            }
+            Ok(None) => (),
+            Err(err) => {
+                log::warn!("[database] release_lease: db = {}: {}", self.name, err);
+                metrics().record_error("release_lease", Some(&self.name), &err);
+            }
+        }
+    }
+
     fn cache_db(&mut self) -> io::Result<()> {
         self.acquire_exclusive()?;
 
@@ -495,95 +1061,345 @@ impl LiteDatabaseHandle {
 
         ret
     }
-}
 
-impl Drop for LiteDatabaseHandle {
-    fn drop(&mut self) {
-        self.syncer.close_conn(&self.name)
-    }
-}
+    // Returns all the supported pragmas along with the current values of the settings.
+    fn help(&mut self) -> String {
+        PRAGMAS
+            .iter()
+            .map(|&(name, setting)| {
+                if !setting {
+                    return name.to_string();
+                }
 
-impl DatabaseHandle for LiteDatabaseHandle {
-    fn size(&self) -> io::Result<u64> {
-        self.database.read().unwrap().size()
+                match self.pragma(name, None) {
+                    Some(Ok(Some(val))) => format!("{} = {}", name, val),
+                    Some(Err(err)) => format!("{} = <error: {}>", name, err),
+                    _ => name.to_string(),
+                }
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
     }
 
-    fn read_exact_at(&mut self, buf: &mut [u8], offset: u64) -> io::Result<()> {
-        let local_only =
-            self.max_pages_per_query > 0 && self.cur_pages_per_query >= self.max_pages_per_query;
-        if let PageSource::Remote = self
-            .database
-            .read()
-            .unwrap()
-            .read_at(buf, offset, local_only)?
-        {
-            self.cur_pages_per_query += 1;
+    fn ensure_unpinned(&self) -> io::Result<()> {
+        if self.pinned_pos.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "connection is pinned to a position",
+            ));
         }
 
         Ok(())
     }
 
-    fn write_all_at(&mut self, buf: &[u8], offset: u64) -> io::Result<()> {
-        self.database.write().unwrap().write_at(buf, offset)
-    }
+    fn ensure_not_opened_at_pos(&self) -> io::Result<()> {
+        if self.opened_at_pos {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "database is opened at a position",
+            ));
+        }
 
-    fn set_len(&mut self, size: u64) -> io::Result<()> {
-        self.database.write().unwrap().truncate(size)
+        Ok(())
     }
 
-    fn lock(&mut self, lock: LockKind) -> bool {
-        // This connection will read data soon, check if we need to sync with LFSC.
-        if self.lock.state() == LockKind::None
-            && lock == LockKind::Shared
-            && self.database.read().unwrap().needs_sync()
-        {
-            // This is a bit complicated. We need to initiate the sync even for read transactions,
-            // so there may be concurrent transactions executing at the time we enter `sync()`.
-            // So wait for them to finish first, otherwise they might see inconsistent state.
-            if let Err(err) = self.acquire_exclusive() {
-                log::warn!(
-                    "[database] sync: db = {}, timeout waiting for active connections, skipping sync: {}",
-                    self.name, err
-                );
+    fn pin_pos(&mut self, val: &str) -> io::Result<()> {
+        self.ensure_not_opened_at_pos()?;
+        if !matches!(self.lock.state(), LockKind::None | LockKind::Shared) {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "can't pin position in a write transaction",
+            ));
+        }
 
-                return self.lock.acquire(lock);
-            }
+        let pos = if val == "current" {
+            self.database.read().unwrap().pos.ok_or(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "database position unknown",
+            ))?
+        } else {
+            parse_pos(val)?
+        };
 
-            // There are no readers, try and sync. If we fail, let SQLite take the read lock, we may still be
-            // able to read the data. The important part here is that `sync()` doesn't fetch any data, so
-            // the cache stays consistent.
-            if let Err(err) = self.database.write().unwrap().sync(false, false) {
-                log::warn!("[database] sync: db = {}: {}", self.name, err);
-            }
+        log::info!("[database] pin_pos: db = {}, pos = {}", self.name, pos);
+        self.database.read().unwrap().pin_pos(pos);
+        self.pinned_pos = Some(pos);
+
+        Ok(())
+    }
+
+    // Waits for the database position known from LFSC to reach the given position or
+    // TXID, optionally followed by a timeout, e.g. `2a 5s`. The next read transaction
+    // sees the changes up to the returned position.
+    fn wait_for_pos(&self, val: &str) -> io::Result<String> {
+        let (target, timeout) = match val.trim().split_once(' ') {
+            Some((target, timeout)) => (target, parse_duration_or_secs(timeout.trim())?),
+            None => (val.trim(), DEFAULT_WAIT_FOR_POS_TIMEOUT),
+        };
+        let txid = if target.contains('/') {
+            parse_pos(target)?.txid
+        } else {
+            u64::from_str_radix(target, 16)
+                .ok()
+                .and_then(|txid| ltx::TXID::new(txid).ok())
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("invalid TXID: {}", target),
+                    )
+                })?
+        };
 
-            self.release_exclusive();
+        match self.syncer.wait_for_txid(&self.name, txid, timeout) {
+            Some(pos) => Ok(pos.to_string()),
+            None => Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("timed out waiting for TXID {}", txid),
+            )),
         }
+    }
 
-        if lock == LockKind::None {
-            self.cur_pages_per_query = 0
+    fn set_bandwidth_limit(&self, val: &str) -> io::Result<()> {
+        let (bytes, window) = match val.trim().split_once(' ') {
+            Some((bytes, window)) => (bytes, parse_duration_or_secs(window.trim())?),
+            None => (val.trim(), DEFAULT_BANDWIDTH_WINDOW),
+        };
+        let bytes = bytes
+            .parse::<ByteSize>()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?
+            .as_u64();
+        if window.is_zero() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "window can't be zero",
+            ));
         }
 
-        self.lock.acquire(lock)
+        let limit = if bytes > 0 {
+            Some(BandwidthLimit { bytes, window })
+        } else {
+            None
+        };
+        self.pager.set_bandwidth_limit(&self.name, limit);
+
+        Ok(())
     }
 
-    fn reserved(&mut self) -> bool {
-        self.lock.reserved()
+    fn set_combine_writes(&self, val: &str) -> io::Result<()> {
+        let (window, max_bytes) = match val.trim().split_once(' ') {
+            Some((window, max_bytes)) => (
+                window,
+                max_bytes
+                    .trim()
+                    .parse::<ByteSize>()
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?
+                    .as_u64(),
+            ),
+            None => (val.trim(), DEFAULT_COMBINE_MAX_BYTES),
+        };
+        let window = parse_duration_or_secs(window)?;
+
+        let combine = if window.is_zero() {
+            None
+        } else {
+            Some(CombineWrites { window, max_bytes })
+        };
+        self.database.write().unwrap().set_combine_writes(combine);
+        Database::upload_in_background(&self.database);
+
+        Ok(())
     }
 
-    fn current_lock(&self) -> LockKind {
-        self.lock.state()
+    fn import_file(&mut self, path: &str) -> io::Result<usize> {
+        self.acquire_exclusive()?;
+
+        let ret = self.database.write().unwrap().import_file(Path::new(path));
+
+        self.release_exclusive();
+
+        ret
     }
 
-    fn pragma(
-        &mut self,
-        pragma: &str,
-        val: Option<&str>,
-    ) -> Option<Result<Option<String>, io::Error>> {
-        match (pragma, val) {
-            ("journal_mode", Some(val)) if val.to_uppercase() == "WAL" => {
-                Some(Err(io::Error::new(
-                    io::ErrorKind::InvalidInput,
-                    "WAL is not supported by LiteVFS",
+    fn sync_now(&mut self) -> io::Result<Option<ltx::Pos>> {
+        self.acquire_exclusive()?;
+
+        let ret = {
+            let mut db = self.database.write().unwrap();
+            db.sync(true, false).map(|()| db.pos)
+        };
+
+        self.release_exclusive();
+        // The forced sync has closed the write-combining window
+        Database::upload_in_background(&self.database);
+        Database::refetch_in_background(&self.database);
+
+        ret
+    }
+
+    fn flush_status(&self) -> io::Result<FlushStatus> {
+        // Combined writes are uploaded right away, so that the database gets synced
+        self.database.write().unwrap().close_combine_window();
+        Database::upload_in_background(&self.database);
+
+        Database::flush_status(&self.database)
+    }
+
+    fn drop_cache(&mut self) -> io::Result<usize> {
+        self.acquire_exclusive()?;
+
+        let ret = self.database.write().unwrap().drop_cache();
+
+        self.release_exclusive();
+
+        ret
+    }
+}
+
+impl Drop for LiteDatabaseHandle {
+    fn drop(&mut self) {
+        self.cancel.store(true, Ordering::Release);
+        if let Some(task) = self.cache_task.take() {
+            task.cancel();
+        }
+        self.release_owned_lease();
+        self.syncer.close_conn(&self.name)
+    }
+}
+
+impl DatabaseHandle for LiteDatabaseHandle {
+    fn size(&self) -> io::Result<u64> {
+        match self.read_pos() {
+            Some(pos) => self
+                .database
+                .read()
+                .unwrap()
+                .size_at_pos(pos, self.offline_reads)
+                .map_err(|err| offline_read_error(err, 0)),
+            None => self.database.read().unwrap().size(),
+        }
+    }
+
+    fn read_exact_at(&mut self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        let first_read = std::mem::take(&mut self.first_read);
+        if self.offline_reads {
+            return self.read_offline(buf, offset);
+        }
+
+        let local_only =
+            self.max_pages_per_query > 0 && self.cur_pages_per_query >= self.max_pages_per_query;
+        if local_only && !self.over_budget {
+            self.over_budget = true;
+            metrics().record_query_over_budget();
+        }
+
+        if let Some(pos) = self.read_pos() {
+            let source = self.database.read().unwrap().read_at_pos(
+                buf,
+                offset,
+                local_only,
+                pos,
+                Some(&*self.cancel),
+            )?;
+            if let PageSource::Remote = source {
+                self.cur_pages_per_query += 1;
+            }
+
+            return Ok(());
+        }
+
+        let ret =
+            self.database
+                .read()
+                .unwrap()
+                .read_at(buf, offset, local_only, Some(&*self.cancel));
+        let source = match ret {
+            // Retry only once, if LFSC moves on again, let the client retry the transaction
+            Err(err) if first_read && is_pos_mismatch(&err) => {
+                if let Err(sync_err) = self.resync_after_pos_mismatch() {
+                    log::warn!(
+                        "[database] read_exact_at: db = {}, offset = {}: {}",
+                        self.name,
+                        offset,
+                        sync_err
+                    );
+                    metrics().record_error("resync", Some(&self.name), &sync_err);
+                    return Err(err);
+                }
+
+                self.database.read().unwrap().read_at(
+                    buf,
+                    offset,
+                    local_only,
+                    Some(&*self.cancel),
+                )?
+            }
+            ret => ret?,
+        };
+
+        if let PageSource::Remote = source {
+            self.cur_pages_per_query += 1;
+        }
+
+        Ok(())
+    }
+
+    fn write_all_at(&mut self, buf: &[u8], offset: u64) -> io::Result<()> {
+        self.ensure_unpinned()?;
+        self.database.write().unwrap().write_at(buf, offset)
+    }
+
+    fn set_len(&mut self, size: u64) -> io::Result<()> {
+        self.ensure_unpinned()?;
+        self.database.write().unwrap().truncate(size)
+    }
+
+    fn lock(&mut self, lock: LockKind) -> bool {
+        // This connection will read data soon, check if we need to sync with LFSC.
+        if lock == LockKind::Shared {
+            self.sync_if_needed();
+        }
+
+        if lock == LockKind::None {
+            self.cur_pages_per_query = 0;
+            self.over_budget = false;
+        }
+
+        let first_read = self.lock.state() == LockKind::None && lock == LockKind::Shared;
+        let acquired = self.lock.acquire(lock);
+        if acquired && first_read {
+            self.first_read = true;
+            metrics().record_query();
+        }
+
+        acquired
+    }
+
+    fn reserved(&mut self) -> bool {
+        self.lock.reserved()
+    }
+
+    fn current_lock(&self) -> LockKind {
+        self.lock.state()
+    }
+
+    fn pragma(
+        &mut self,
+        pragma: &str,
+        val: Option<&str>,
+    ) -> Option<Result<Option<String>, io::Error>> {
+        if MUTATING_PRAGMAS.contains(&pragma)
+            || (val.is_some() && SHARED_SETTINGS.contains(&pragma))
+        {
+            if let Err(e) = self.ensure_not_opened_at_pos() {
+                return Some(Err(e));
+            }
+        }
+
+        match (pragma, val) {
+            ("journal_mode", Some(val)) if val.to_uppercase() == "WAL" => {
+                Some(Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "WAL is not supported by LiteVFS",
                 )))
             }
             ("litevfs_min_available_space", None) => Some(Ok(Some(
@@ -596,6 +1412,16 @@ impl DatabaseHandle for LiteDatabaseHandle {
                 }
                 Err(e) => Some(Err(io::Error::new(io::ErrorKind::InvalidInput, e))),
             },
+            ("litevfs_min_available_inodes", None) => {
+                Some(Ok(Some(self.pager.min_available_inodes().to_string())))
+            }
+            ("litevfs_min_available_inodes", Some(val)) => match val.parse::<u64>() {
+                Ok(val) => {
+                    self.pager.set_min_available_inodes(val);
+                    Some(Ok(None))
+                }
+                Err(e) => Some(Err(io::Error::new(io::ErrorKind::InvalidInput, e))),
+            },
 
             ("litevfs_max_cached_pages", None) => {
                 Some(Ok(Some(self.pager.max_cached_pages().to_string())))
@@ -608,6 +1434,44 @@ impl DatabaseHandle for LiteDatabaseHandle {
                 Err(e) => Some(Err(io::Error::new(io::ErrorKind::InvalidInput, e))),
             },
 
+            ("litevfs_mem_cache_pages", None) => {
+                Some(Ok(Some(self.pager.mem_cache_pages().to_string())))
+            }
+            ("litevfs_mem_cache_pages", Some(val)) => match val.parse::<usize>() {
+                Ok(val) => {
+                    self.pager.set_mem_cache_pages(val);
+                    Some(Ok(None))
+                }
+                Err(e) => Some(Err(io::Error::new(io::ErrorKind::InvalidInput, e))),
+            },
+
+            ("litevfs_cache_compression", None) => {
+                Some(Ok(Some(self.pager.cache_compression().to_string())))
+            }
+            ("litevfs_cache_compression", Some(val)) => match val {
+                "true" | "1" => Some(self.pager.set_cache_compression(true).map(|_| None)),
+                "false" | "0" => Some(self.pager.set_cache_compression(false).map(|_| None)),
+                _ => Some(Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "expected true or false",
+                ))),
+            },
+
+            ("litevfs_max_cache_fraction", None) => {
+                Some(Ok(Some(self.pager.max_cache_fraction().to_string())))
+            }
+            ("litevfs_max_cache_fraction", Some(val)) => match val.parse::<f64>() {
+                Ok(val) if (0.0..=1.0).contains(&val) => {
+                    self.pager.set_max_cache_fraction(val);
+                    Some(Ok(None))
+                }
+                Ok(_) => Some(Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "fraction must be between 0 and 1",
+                ))),
+                Err(e) => Some(Err(io::Error::new(io::ErrorKind::InvalidInput, e))),
+            },
+
             ("litevfs_max_reqs_per_query", None) => {
                 Some(Ok(Some(self.max_pages_per_query.to_string())))
             }
@@ -623,32 +1487,104 @@ impl DatabaseHandle for LiteDatabaseHandle {
                 Err(e) => Some(Err(io::Error::new(io::ErrorKind::InvalidInput, e))),
             },
 
+            ("litevfs_max_pages_per_request", None) => {
+                Some(Ok(Some(self.pager.max_pages_per_request().to_string())))
+            }
+            ("litevfs_max_pages_per_request", Some(val)) => match val.parse::<usize>() {
+                Ok(val) => {
+                    self.pager.set_max_pages_per_request(val);
+                    Some(Ok(None))
+                }
+                Err(e) => Some(Err(io::Error::new(io::ErrorKind::InvalidInput, e))),
+            },
+
+            ("litevfs_offline_reads", None) => Some(Ok(Some(self.offline_reads.to_string()))),
+            ("litevfs_offline_reads", Some(val)) => match val {
+                "true" | "1" => {
+                    self.offline_reads = true;
+                    Some(Ok(None))
+                }
+                "false" | "0" => {
+                    self.offline_reads = false;
+                    Some(Ok(None))
+                }
+                _ => Some(Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "expected true or false",
+                ))),
+            },
+
             ("litevfs_cache_sync_period", None) => Some(Ok(Some(
                 format_duration(self.syncer.sync_period(&self.name)).to_string(),
             ))),
-            ("litevfs_cache_sync_period", Some(val)) => {
-                let val = if val
-                    .chars()
-                    .last()
-                    .map(|c| c.is_ascii_digit())
-                    .unwrap_or_default()
-                {
-                    val.parse()
-                        .map(time::Duration::from_secs)
-                        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
-                } else {
-                    parse_duration(val).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
-                };
+            ("litevfs_cache_sync_period", Some(val)) => match parse_duration_or_secs(val) {
+                Ok(val) => {
+                    self.syncer.set_sync_period(&self.name, val);
+                    Some(Ok(None))
+                }
+                Err(e) => Some(Err(e)),
+            },
 
-                match val {
-                    Ok(val) => {
-                        self.syncer.set_sync_period(&self.name, val);
-                        Some(Ok(None))
-                    }
-                    Err(e) => Some(Err(e)),
+            ("litevfs_syncing", None) => {
+                Some(Ok(Some(self.syncer.is_syncing(&self.name).to_string())))
+            }
+            ("litevfs_last_sync", None) => {
+                let since = self.syncer.last_sync(&self.name).map(|ls| {
+                    let since = ls.elapsed().unwrap_or_default();
+                    format_duration(time::Duration::from_millis(since.as_millis() as u64))
+                        .to_string()
+                });
+                let pending = self.database.read().unwrap().needs_sync();
+
+                Some(Ok(Some(
+                    serde_json::json!({
+                        "since_last_sync": since,
+                        "pending": pending,
+                    })
+                    .to_string(),
+                )))
+            }
+            ("litevfs_last_sync", Some(_)) => Some(Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "litevfs_last_sync is read-only",
+            ))),
+
+            ("litevfs_lock_timeout", None) => {
+                Some(Ok(Some(format_duration(self.lock_timeout).to_string())))
+            }
+            // Zero means a single attempt to take the lock, as with `busy_timeout`
+            ("litevfs_lock_timeout", Some(val)) => match parse_duration_or_secs(val) {
+                Ok(val) => {
+                    self.lock_timeout = val;
+                    Some(Ok(None))
                 }
+                Err(e) => Some(Err(e)),
+            },
+
+            // SQLite applies the busy timeout itself once the VFS passes on the pragma, the
+            // exclusive lock waits just as long. Zero means a single attempt to take it.
+            // `sqlite3_busy_timeout()` doesn't reach the VFS: SQLITE_FCNTL_BUSYHANDLER
+            // only passes the handler, not the timeout, so it can't be followed.
+            ("busy_timeout", Some(val)) => {
+                if let Ok(val) = val.trim().parse::<i64>() {
+                    self.lock_timeout = time::Duration::from_millis(val.max(0) as u64);
+                }
+
+                None
             }
 
+            ("litevfs_pos", None) => Some(Ok(Some(
+                if let Some(pos) = self.database.read().unwrap().pos {
+                    pos.to_string()
+                } else {
+                    "<none>".to_string()
+                },
+            ))),
+            ("litevfs_pos", Some(_)) => Some(Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "litevfs_pos is read-only",
+            ))),
+
             ("litevfs_max_prefetch_pages", None) => Some(Ok(Some(
                 self.database.read().unwrap().prefetch_limit.to_string(),
             ))),
@@ -664,93 +1600,583 @@ impl DatabaseHandle for LiteDatabaseHandle {
                 Err(e) => Some(Err(io::Error::new(io::ErrorKind::InvalidInput, e))),
             },
 
+            ("litevfs_prefetch_enabled", None) => Some(Ok(Some(
+                self.database.read().unwrap().prefetch_enabled().to_string(),
+            ))),
+            ("litevfs_prefetch_enabled", Some(val)) => match val {
+                "true" | "1" => {
+                    self.database.write().unwrap().set_prefetch_enabled(true);
+                    Some(Ok(None))
+                }
+                "false" | "0" => {
+                    self.database.write().unwrap().set_prefetch_enabled(false);
+                    Some(Ok(None))
+                }
+                _ => Some(Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "expected true or false",
+                ))),
+            },
+
+            ("litevfs_max_tx_bytes", None) => {
+                Some(Ok(Some(match self.database.read().unwrap().max_tx_bytes {
+                    Some(max) => ByteSize::b(max).to_string_as(true),
+                    None => "0".to_string(),
+                })))
+            }
+            ("litevfs_max_tx_bytes", Some(val)) => match val.parse::<ByteSize>() {
+                Ok(val) => {
+                    self.database.write().unwrap().max_tx_bytes =
+                        Some(val.as_u64()).filter(|&max| max > 0);
+                    Some(Ok(None))
+                }
+                Err(e) => Some(Err(io::Error::new(io::ErrorKind::InvalidInput, e))),
+            },
+
+            ("litevfs_combine_writes", None) => Some(Ok(Some(
+                match self.database.read().unwrap().combine_writes() {
+                    Some(combine) => format!(
+                        "{} {}",
+                        format_duration(combine.window),
+                        ByteSize::b(combine.max_bytes).to_string_as(true)
+                    ),
+                    None => "0".to_string(),
+                },
+            ))),
+            ("litevfs_combine_writes", Some(val)) => match self.set_combine_writes(val) {
+                Ok(()) => Some(Ok(None)),
+                Err(e) => Some(Err(e)),
+            },
+
+            ("litevfs_eager_refetch", None) => Some(Ok(Some(
+                self.database.read().unwrap().eager_refetch().to_string(),
+            ))),
+            ("litevfs_eager_refetch", Some(val)) => match val {
+                "true" | "1" if cfg!(target_os = "emscripten") => Some(Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "eager refetch is not supported",
+                ))),
+                "true" | "1" => {
+                    self.database.write().unwrap().set_eager_refetch(true);
+                    Some(Ok(None))
+                }
+                "false" | "0" => {
+                    self.database.write().unwrap().set_eager_refetch(false);
+                    Some(Ok(None))
+                }
+                _ => Some(Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "expected true or false",
+                ))),
+            },
+
+            ("litevfs_db_size", None) => {
+                match self
+                    .database
+                    .read()
+                    .unwrap()
+                    .size_stats()
+                    .and_then(|stats| serde_json::to_string(&stats).map_err(|e| e.into()))
+                {
+                    Ok(stats) => Some(Ok(Some(stats))),
+                    Err(e) => Some(Err(e)),
+                }
+            }
+
+            ("litevfs_dirty_pages", None) => Some(Ok(Some(
+                self.database.read().unwrap().dirty_page_count().to_string(),
+            ))),
+            ("litevfs_dirty_pages", Some("list")) => {
+                let pages = self.database.read().unwrap().dirty_page_numbers();
+                let pages = pages
+                    .iter()
+                    .map(|pgno| pgno.into_inner())
+                    .collect::<Vec<_>>();
+                match serde_json::to_string(&serde_json::json!({
+                    "count": pages.len(),
+                    "pages": pages,
+                })) {
+                    Ok(pages) => Some(Ok(Some(pages))),
+                    Err(e) => Some(Err(e.into())),
+                }
+            }
+            ("litevfs_dirty_pages", Some(_)) => Some(Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "only 'list' is supported",
+            ))),
+
+            ("litevfs_prefetch_stats", None) => {
+                match serde_json::to_string(&self.database.read().unwrap().prefetch_stats()) {
+                    Ok(stats) => Some(Ok(Some(stats))),
+                    Err(e) => Some(Err(e.into())),
+                }
+            }
+            ("litevfs_prefetch_stats", Some("reset")) => {
+                self.database.read().unwrap().reset_prefetch_stats();
+                Some(Ok(None))
+            }
+            ("litevfs_prefetch_stats", Some(_)) => Some(Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "only 'reset' is supported",
+            ))),
+
+            ("litevfs_lfsc_latency", None) => {
+                match serde_json::to_string(&metrics().lfsc_latency()) {
+                    Ok(latency) => Some(Ok(Some(latency))),
+                    Err(e) => Some(Err(e.into())),
+                }
+            }
+            ("litevfs_lfsc_latency", Some("reset")) => {
+                metrics().reset_lfsc_latency();
+                Some(Ok(None))
+            }
+            ("litevfs_lfsc_latency", Some(_)) => Some(Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "only 'reset' is supported",
+            ))),
+
+            ("litevfs_query_stats", None) => {
+                match serde_json::to_string(&metrics().query_stats()) {
+                    Ok(stats) => Some(Ok(Some(stats))),
+                    Err(e) => Some(Err(e.into())),
+                }
+            }
+            ("litevfs_query_stats", Some("reset")) => {
+                metrics().reset_query_stats();
+                Some(Ok(None))
+            }
+            ("litevfs_query_stats", Some(_)) => Some(Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "only 'reset' is supported",
+            ))),
+
+            ("litevfs_lfsc_instance", None) => Some(Ok(Some(
+                self.database
+                    .read()
+                    .unwrap()
+                    .lfsc_instance()
+                    .unwrap_or_else(|| "<none>".to_string()),
+            ))),
+            ("litevfs_reset_lfsc_instance", None) => {
+                self.database.read().unwrap().reset_lfsc_instance();
+                Some(Ok(None))
+            }
+
+            ("litevfs_lease_status", None) => match self.database.read().unwrap().lease_info() {
+                Ok(Some(lease)) => match serde_json::to_string(&lease) {
+                    Ok(lease) => Some(Ok(Some(lease))),
+                    Err(e) => Some(Err(e.into())),
+                },
+                Ok(None) => Some(Ok(Some("<none>".to_string()))),
+                Err(e) => Some(Err(e)),
+            },
+
             ("litevfs_acquire_lease", None) => match self.acquire_lease_and_sync() {
                 Ok(()) => Some(Ok(None)),
                 Err(e) => Some(Err(e)),
             },
-            ("litevfs_release_lease", None) => {
-                match self.database.read().unwrap().release_lease() {
+            ("litevfs_release_lease", None) => match self.release_lease() {
+                Ok(()) => Some(Ok(None)),
+                Err(e) => Some(Err(e)),
+            },
+            ("litevfs_leases", None) => {
+                match serde_json::to_string(&cluster_leases(&self.leasers)) {
+                    Ok(leases) => Some(Ok(Some(leases))),
+                    Err(e) => Some(Err(e.into())),
+                }
+            }
+            // Requires the lease ID, so that a lease isn't released by accident
+            ("litevfs_force_release_lease", None) => Some(Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "lease ID is required",
+            ))),
+            ("litevfs_force_release_lease", Some(id)) => {
+                match Database::force_release_lease(&self.database, id) {
                     Ok(()) => Some(Ok(None)),
                     Err(e) => Some(Err(e)),
                 }
             }
 
-            ("litevfs_cache_db", None) => match self.cache_db() {
-                Ok(()) => Some(Ok(None)),
-                Err(e) => Some(Err(e)),
-            },
-            _ => None,
+            ("litevfs_cache_db", None) => match self.cache_db() {
+                Ok(()) => Some(Ok(None)),
+                Err(e) => Some(Err(e)),
+            },
+            ("litevfs_cache_db_async", None) => {
+                match Database::cache_in_background(&self.database) {
+                    Ok(task) => {
+                        self.cache_task = Some(task);
+                        Some(Ok(None))
+                    }
+                    Err(e) => Some(Err(e)),
+                }
+            }
+            ("litevfs_cache_db_progress", None) => {
+                match self.database.read().unwrap().cache_progress() {
+                    Some(progress) => match serde_json::to_string(&progress) {
+                        Ok(progress) => Some(Ok(Some(progress))),
+                        Err(e) => Some(Err(e.into())),
+                    },
+                    None => Some(Ok(Some("<none>".to_string()))),
+                }
+            }
+            ("litevfs_check", None) => match self.database.read().unwrap().check() {
+                Ok(report) => match serde_json::to_string(&report) {
+                    Ok(report) => Some(Ok(Some(report))),
+                    Err(e) => Some(Err(e.into())),
+                },
+                Err(e) => Some(Err(e)),
+            },
+            ("litevfs_flush", None) => match self.flush_status() {
+                Ok(status) => match serde_json::to_string(&status) {
+                    Ok(status) => Some(Ok(Some(status))),
+                    Err(e) => Some(Err(e.into())),
+                },
+                Err(e) => Some(Err(e)),
+            },
+            ("litevfs_wait_for_pos", Some(_)) if cfg!(target_os = "emscripten") => {
+                Some(Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "waiting for a position is not supported",
+                )))
+            }
+            ("litevfs_wait_for_pos", Some(val)) => Some(self.wait_for_pos(val).map(Some)),
+
+            ("litevfs_pin_pos", None) => Some(Ok(Some(
+                self.pinned_pos
+                    .map_or_else(|| "<none>".to_string(), |pos| pos.to_string()),
+            ))),
+            ("litevfs_pin_pos", Some(val)) => match self.pin_pos(val) {
+                Ok(()) => Some(Ok(None)),
+                Err(e) => Some(Err(e)),
+            },
+            // Catches up with the changes made while pinned right away, unless the
+            // connection is in a transaction, which syncs once it's finished.
+            ("litevfs_unpin", None) => match self.ensure_not_opened_at_pos() {
+                Ok(()) => {
+                    self.pinned_pos = None;
+                    self.sync_if_needed();
+                    Some(Ok(None))
+                }
+                Err(e) => Some(Err(e)),
+            },
+
+            ("litevfs_import_file", Some(path)) => match self.import_file(path) {
+                Ok(pages) => Some(Ok(Some(pages.to_string()))),
+                Err(e) => Some(Err(e)),
+            },
+            ("litevfs_sync_now", None) => match self.sync_now() {
+                Ok(pos) => Some(Ok(Some(
+                    pos.map_or_else(|| "<none>".to_string(), |pos| pos.to_string()),
+                ))),
+                Err(e) => Some(Err(e)),
+            },
+
+            ("litevfs_drop_cache", None) => match self.drop_cache() {
+                Ok(pages) => Some(Ok(Some(pages.to_string()))),
+                Err(e) => Some(Err(e)),
+            },
+
+            ("litevfs_verify_cache", val) => {
+                let sample = match val.map(str::parse::<usize>) {
+                    None => None,
+                    Some(Ok(n)) => Some(n),
+                    Some(Err(e)) => {
+                        return Some(Err(io::Error::new(io::ErrorKind::InvalidInput, e)))
+                    }
+                };
+                match Database::verify_cache(&self.database, sample) {
+                    Ok(mismatches) => Some(Ok(Some(mismatches.to_string()))),
+                    Err(e) => Some(Err(e)),
+                }
+            }
+
+            ("litevfs_recent_errors", val) => {
+                let n = match val.map(str::parse::<usize>) {
+                    None => usize::MAX,
+                    Some(Ok(n)) => n,
+                    Some(Err(e)) => {
+                        return Some(Err(io::Error::new(io::ErrorKind::InvalidInput, e)))
+                    }
+                };
+                match serde_json::to_string(&metrics().recent_errors(n)) {
+                    Ok(errors) => Some(Ok(Some(errors))),
+                    Err(e) => Some(Err(e.into())),
+                }
+            }
+
+            ("litevfs_bandwidth", None) => {
+                Some(Ok(Some(self.pager.bandwidth(&self.name).to_string())))
+            }
+            ("litevfs_bandwidth", Some("reset")) => {
+                self.pager.reset_bandwidth(&self.name);
+                Some(Ok(None))
+            }
+            ("litevfs_bandwidth", Some(_)) => Some(Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "only 'reset' is supported",
+            ))),
+            ("litevfs_bandwidth_limit", None) => {
+                Some(Ok(Some(match self.pager.bandwidth_limit(&self.name) {
+                    Some(limit) => format!(
+                        "{} {}",
+                        ByteSize::b(limit.bytes).to_string_as(true),
+                        format_duration(limit.window)
+                    ),
+                    None => "0".to_string(),
+                })))
+            }
+            ("litevfs_bandwidth_limit", Some(val)) => match self.set_bandwidth_limit(val) {
+                Ok(()) => Some(Ok(None)),
+                Err(e) => Some(Err(e)),
+            },
+
+            ("litevfs_cache_stats", None) => match serde_json::to_string(&self.pager.stats()) {
+                Ok(stats) => Some(Ok(Some(stats))),
+                Err(e) => Some(Err(e.into())),
+            },
+            ("litevfs_cache_stats", Some("reset")) => {
+                self.pager.reset_stats();
+                Some(Ok(None))
+            }
+            ("litevfs_cache_stats", Some(_)) => Some(Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "only 'reset' is supported",
+            ))),
+
+            ("litevfs_reclaim", val) => {
+                let evicted = match val.map(|val| val.parse::<ByteSize>()) {
+                    None => None,
+                    Some(Ok(min_space)) => match self.pager.reclaim(min_space.as_u64()) {
+                        Ok(evicted) => Some(evicted),
+                        Err(err) => return Some(Err(err)),
+                    },
+                    Some(Err(e)) => {
+                        return Some(Err(io::Error::new(io::ErrorKind::InvalidInput, e)))
+                    }
+                };
+
+                match self.pager.cache_usage() {
+                    Ok(usage) => Some(Ok(Some(
+                        serde_json::json!({
+                            "evicted": evicted,
+                            "pages": usage.pages,
+                            "available_space": ByteSize::b(usage.available_space).to_string_as(true),
+                            "total_space": ByteSize::b(usage.total_space).to_string_as(true),
+                            "available_inodes": usage.available_inodes,
+                        })
+                        .to_string(),
+                    ))),
+                    Err(err) => Some(Err(err)),
+                }
+            }
+
+            ("litevfs_help", None) => Some(Ok(Some(self.help()))),
+
+            // Catch typos instead of letting SQLite silently ignore them
+            (pragma, _) if PRAGMAS.iter().any(|&(name, _)| name == pragma) => Some(Err(
+                io::Error::new(io::ErrorKind::InvalidInput, "unsupported pragma value"),
+            )),
+            (pragma, _) if pragma.starts_with("litevfs_") => Some(Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "unknown pragma {}, supported pragmas: {}",
+                    pragma,
+                    PRAGMAS
+                        .iter()
+                        .map(|&(name, _)| name)
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ),
+            ))),
+            _ => None,
+        }
+    }
+
+    fn wal_index(&self, _readonly: bool) -> io::Result<LiteWalIndex> {
+        Ok(LiteWalIndex::new(
+            Arc::clone(&self.database),
+            Arc::clone(&self.wal_read_pos),
+        ))
+    }
+
+    fn handle_type(&self) -> &'static str {
+        "database"
+    }
+    fn handle_name(&self) -> &str {
+        &self.name
+    }
+}
+
+struct LiteJournalHandle {
+    journal: fs::File,
+    database: Arc<RwLock<Database>>,
+    name: String,
+}
+
+impl LiteJournalHandle {
+    pub(crate) fn new(database: Arc<RwLock<Database>>) -> io::Result<Self> {
+        let (journal, name) = {
+            let db = database.read().unwrap();
+            let journal = fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&db.journal_path)?;
+            let name = db.name.clone();
+            (journal, name)
+        };
+
+        Ok(LiteJournalHandle {
+            journal,
+            database,
+            name,
+        })
+    }
+}
+
+impl DatabaseHandle for LiteJournalHandle {
+    fn size(&self) -> io::Result<u64> {
+        self.journal.metadata().map(|m| m.len())
+    }
+
+    fn read_exact_at(&mut self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        self.journal.read_exact_at(buf, offset)
+    }
+
+    fn write_all_at(&mut self, buf: &[u8], offset: u64) -> io::Result<()> {
+        self.database
+            .write()
+            .unwrap()
+            .before_journal_write(buf, offset)?;
+        Database::upload_in_background(&self.database);
+
+        self.journal.write_all_at(buf, offset)
+    }
+
+    fn set_len(&mut self, size: u64) -> io::Result<()> {
+        self.database.write().unwrap().commit_journal()?;
+        Database::upload_in_background(&self.database);
+        self.journal.set_len(size)
+    }
+
+    fn handle_type(&self) -> &'static str {
+        "journal"
+    }
+    fn handle_name(&self) -> &str {
+        &self.name
+    }
+}
+
+/// The same as [LiteJournalHandle], but the journal is kept in memory by the [Database],
+/// so that it outlives the handle, like a file would.
+struct LiteMemJournalHandle {
+    database: Arc<RwLock<Database>>,
+    name: String,
+}
+
+impl LiteMemJournalHandle {
+    pub(crate) fn new(database: Arc<RwLock<Database>>) -> Self {
+        let name = {
+            let mut db = database.write().unwrap();
+            db.mem_journal.get_or_insert_with(Vec::new);
+            db.name.clone()
+        };
+
+        LiteMemJournalHandle { database, name }
+    }
+}
+
+impl DatabaseHandle for LiteMemJournalHandle {
+    fn size(&self) -> io::Result<u64> {
+        Ok(self
+            .database
+            .read()
+            .unwrap()
+            .mem_journal
+            .as_ref()
+            .map_or(0, |journal| journal.len() as u64))
+    }
+
+    fn read_exact_at(&mut self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        let db = self.database.read().unwrap();
+        read_mem(db.mem_journal.as_deref().unwrap_or_default(), buf, offset)
+    }
+
+    fn write_all_at(&mut self, buf: &[u8], offset: u64) -> io::Result<()> {
+        {
+            let mut db = self.database.write().unwrap();
+            db.before_journal_write(buf, offset)?;
+            write_mem(db.mem_journal.get_or_insert_with(Vec::new), buf, offset);
+        }
+        Database::upload_in_background(&self.database);
+
+        Ok(())
+    }
+
+    fn set_len(&mut self, size: u64) -> io::Result<()> {
+        {
+            let mut db = self.database.write().unwrap();
+            db.commit_journal()?;
+            let journal = db.mem_journal.get_or_insert_with(Vec::new);
+            journal.resize(size as usize, 0);
+            journal.shrink_to_fit();
         }
+        Database::upload_in_background(&self.database);
+
+        Ok(())
     }
 
     fn handle_type(&self) -> &'static str {
-        "database"
+        "journal"
     }
     fn handle_name(&self) -> &str {
         &self.name
     }
 }
 
-struct LiteJournalHandle {
-    journal: fs::File,
-    database: Arc<RwLock<Database>>,
+/// LiteVFS only reads WAL databases, so their WAL is always empty.
+struct LiteWalHandle {
     name: String,
 }
 
-impl LiteJournalHandle {
-    pub(crate) fn new(database: Arc<RwLock<Database>>) -> io::Result<Self> {
-        let (journal, name) = {
-            let db = database.read().unwrap();
-            let journal = fs::OpenOptions::new()
-                .read(true)
-                .write(true)
-                .create(true)
-                .truncate(false)
-                .open(&db.journal_path)?;
-            let name = db.name.clone();
-            (journal, name)
-        };
+impl LiteWalHandle {
+    pub(crate) fn new(database: Arc<RwLock<Database>>) -> Self {
+        let name = database.read().unwrap().name.clone();
 
-        Ok(LiteJournalHandle {
-            journal,
-            database,
-            name,
-        })
+        LiteWalHandle { name }
     }
 }
 
-impl DatabaseHandle for LiteJournalHandle {
+impl DatabaseHandle for LiteWalHandle {
     fn size(&self) -> io::Result<u64> {
-        self.journal.metadata().map(|m| m.len())
+        Ok(0)
     }
 
-    fn read_exact_at(&mut self, buf: &mut [u8], offset: u64) -> io::Result<()> {
-        self.journal.read_exact_at(buf, offset)
+    fn read_exact_at(&mut self, _buf: &mut [u8], _offset: u64) -> io::Result<()> {
+        Err(io::ErrorKind::UnexpectedEof.into())
     }
 
-    fn write_all_at(&mut self, buf: &[u8], offset: u64) -> io::Result<()> {
-        const JOURNAL_HDR_SIZE: usize = 28;
-
-        {
-            let mut db = self.database.write().unwrap();
-            if offset == 0 && buf.len() >= JOURNAL_HDR_SIZE && db.page_size().is_err() {
-                db.page_size = Some(Database::parse_page_size_journal(buf)?);
-            };
-            if offset == 0 && buf.len() == JOURNAL_HDR_SIZE && buf.iter().all(|&b| b == 0) {
-                db.commit_journal()?;
-            };
-        }
-
-        self.journal.write_all_at(buf, offset)
+    fn write_all_at(&mut self, _buf: &[u8], _offset: u64) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "writing to DB in WAL mode is unsupported",
+        ))
     }
 
     fn set_len(&mut self, size: u64) -> io::Result<()> {
-        self.database.write().unwrap().commit_journal()?;
-        self.journal.set_len(size)
+        if size > 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "writing to DB in WAL mode is unsupported",
+            ));
+        }
+
+        Ok(())
     }
 
     fn handle_type(&self) -> &'static str {
-        "journal"
+        "wal"
     }
     fn handle_name(&self) -> &str {
         &self.name
@@ -778,7 +2204,7 @@ impl LiteTempDbHandle {
         };
 
         let name = path.as_ref().to_string_lossy().to_string();
-        let file = o.open(path)?;
+        let file = open_nofollow(&mut o, path.as_ref())?;
         let vfs_lock = VfsLock::new();
         let lock = vfs_lock.conn_lock();
         Ok(LiteTempDbHandle { name, file, lock })
@@ -821,3 +2247,1201 @@ impl DatabaseHandle for LiteTempDbHandle {
         &self.name
     }
 }
+
+fn read_mem(data: &[u8], buf: &mut [u8], offset: u64) -> io::Result<()> {
+    let start = (offset as usize).min(data.len());
+    let end = (offset as usize + buf.len()).min(data.len());
+    let n = end - start;
+
+    buf[..n].copy_from_slice(&data[start..end]);
+    if n < buf.len() {
+        // SQLite expects the rest of the buffer to be zeroed on short reads
+        buf[n..].fill(0);
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+
+    Ok(())
+}
+
+fn write_mem(data: &mut Vec<u8>, buf: &[u8], offset: u64) {
+    let end = offset as usize + buf.len();
+    if end > data.len() {
+        data.resize(end, 0);
+    }
+    data[offset as usize..end].copy_from_slice(buf);
+}
+
+/// In-memory counterpart of [LiteTempDbHandle].
+struct LiteMemTempDbHandle {
+    name: String,
+    data: Vec<u8>,
+    lock: ConnLock,
+}
+
+impl LiteMemTempDbHandle {
+    pub(crate) fn new(name: &str) -> Self {
+        let vfs_lock = VfsLock::new();
+        let lock = vfs_lock.conn_lock();
+        LiteMemTempDbHandle {
+            name: name.to_string(),
+            data: Vec::new(),
+            lock,
+        }
+    }
+}
+
+impl DatabaseHandle for LiteMemTempDbHandle {
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.data.len() as u64)
+    }
+
+    fn read_exact_at(&mut self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        read_mem(&self.data, buf, offset)
+    }
+
+    fn write_all_at(&mut self, buf: &[u8], offset: u64) -> io::Result<()> {
+        write_mem(&mut self.data, buf, offset);
+
+        Ok(())
+    }
+
+    fn set_len(&mut self, size: u64) -> io::Result<()> {
+        self.data.resize(size as usize, 0);
+        self.data.shrink_to_fit();
+
+        Ok(())
+    }
+
+    fn lock(&mut self, lock: LockKind) -> bool {
+        self.lock.acquire(lock)
+    }
+
+    fn reserved(&mut self) -> bool {
+        self.lock.reserved()
+    }
+
+    fn current_lock(&self) -> LockKind {
+        self.lock.state()
+    }
+
+    fn handle_type(&self) -> &'static str {
+        "tempdb"
+    }
+    fn handle_name(&self) -> &str {
+        &self.name
+    }
+}
+
+// Parses a position in the `txid/checksum` format, both in hex.
+// Explains why a page couldn't be read from the cache only.
+fn offline_read_error(err: io::Error, offset: u64) -> io::Error {
+    if err.kind() != io::ErrorKind::WouldBlock {
+        return err;
+    }
+
+    io::Error::new(
+        io::ErrorKind::Other,
+        format!(
+            "page at offset {} is not cached and offline reads are enabled",
+            offset
+        ),
+    )
+}
+
+// Returns the leases held by this process in all the clusters.
+fn cluster_leases(leasers: &[(Option<String>, Arc<Leaser>)]) -> Vec<LeaseInfo> {
+    leasers
+        .iter()
+        .flat_map(|(cluster, leaser)| {
+            leaser.leases().into_iter().map(|info| LeaseInfo {
+                cluster: cluster.clone(),
+                ..info
+            })
+        })
+        .collect()
+}
+
+fn parse_pos(val: &str) -> io::Result<ltx::Pos> {
+    let invalid = || {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid position: {}", val),
+        )
+    };
+
+    let (txid, checksum) = val.split_once('/').ok_or_else(invalid)?;
+    let txid = u64::from_str_radix(txid, 16).map_err(|_| invalid())?;
+    let checksum = u64::from_str_radix(checksum, 16).map_err(|_| invalid())?;
+
+    Ok(ltx::Pos {
+        txid: ltx::TXID::new(txid).map_err(|_| invalid())?,
+        post_apply_checksum: ltx::Checksum::new(checksum),
+    })
+}
+
+// Resolves `path` and makes sure it doesn't point outside of `root`, e.g. via
+// a symlink or `..` components.
+fn path_within(root: &Path, path: &Path) -> io::Result<PathBuf> {
+    let root = root.canonicalize()?;
+    let resolved = match path.canonicalize() {
+        Ok(resolved) => resolved,
+        // The file doesn't exist yet, resolve the directory it's going to be created
+        // in. A dangling symlink would create the file wherever it points to.
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            if fs::symlink_metadata(path).map_or(false, |m| m.file_type().is_symlink()) {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "path is a symlink",
+                ));
+            }
+            match (path.parent(), path.file_name()) {
+                (Some(parent), Some(name)) => parent.canonicalize()?.join(name),
+                _ => return Err(err),
+            }
+        }
+        Err(err) => return Err(err),
+    };
+
+    if !resolved.starts_with(&root) {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "path is outside of the cache directory",
+        ));
+    }
+
+    Ok(resolved)
+}
+
+// Parses a duration either as a number of seconds or as a human readable duration.
+pub(crate) fn parse_duration_or_secs(val: &str) -> io::Result<time::Duration> {
+    if val
+        .chars()
+        .last()
+        .map(|c| c.is_ascii_digit())
+        .unwrap_or_default()
+    {
+        val.parse()
+            .map(time::Duration::from_secs)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+    } else {
+        parse_duration(val).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DatabaseHandle, LiteMemTempDbHandle, LiteTempDbHandle, LiteVfs, PRAGMAS};
+    use crate::{
+        builder::EnvSettings,
+        leaser::{DEFAULT_LEASE_DURATION, DEFAULT_LEASE_REFRESH_FRACTION},
+        lfsc,
+        pager::PagerBackend,
+        syncer::{Syncer, DEFAULT_SYNC_JITTER},
+        testutil::TempDir,
+    };
+    use litetx as ltx;
+    use sqlite_vfs::{OpenAccess, OpenKind, Vfs};
+    use std::{
+        collections::{BTreeMap, HashMap},
+        env::temp_dir,
+        fs, io,
+        path::Path,
+        ptr,
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    #[test]
+    fn parse_pos() {
+        let pos = ltx::Pos {
+            txid: ltx::TXID::new(0x2a).unwrap(),
+            post_apply_checksum: ltx::Checksum::new(0x8000_0000_dead_beef),
+        };
+        assert_eq!(pos, super::parse_pos(&pos.to_string()).expect("parse"));
+
+        for val in ["", "2a", "2a/", "zz/1", "0/8000000000000001"] {
+            assert_eq!(
+                io::ErrorKind::InvalidInput,
+                super::parse_pos(val).expect_err(val).kind()
+            );
+        }
+    }
+
+    #[cfg(not(target_os = "emscripten"))]
+    #[test]
+    fn pragmas_listed() {
+        let path = TempDir::new("pragmas-listed");
+        cache_db(&path, tx_pos(1), &[&page1(2), &[2; 4096]]);
+        let vfs = new_vfs(&path, "http://127.0.0.1:1");
+        let mut handle = vfs
+            .open_main_db(&vfs.default_cluster, "db", None, OpenAccess::Read)
+            .expect("open")
+            .inner;
+
+        // The settings listed by `litevfs_help` can all be read
+        for &(name, _) in PRAGMAS.iter().filter(|&&(_, setting)| setting) {
+            match handle.pragma(name, None) {
+                None => panic!("{} isn't handled", name),
+                Some(Err(err)) if err.to_string() == "unsupported pragma value" => {
+                    panic!("{} can't be read", name)
+                }
+                Some(_) => (),
+            }
+        }
+
+        // Typos are reported along with the supported pragmas
+        let err = handle
+            .pragma("litevfs_max_cached_page", None)
+            .expect("pragma")
+            .expect_err("typo");
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+        assert!(err.to_string().contains("litevfs_max_cached_pages"));
+        assert!(handle.pragma("cache_size", None).is_none());
+    }
+
+    #[test]
+    fn database_name_kind() {
+        let path = TempDir::new("clusters");
+        let vfs = LiteVfs::new(
+            &path,
+            lfsc::Client::builder().build(),
+            PagerBackend::default(),
+            false,
+            DEFAULT_LEASE_DURATION,
+            DEFAULT_LEASE_REFRESH_FRACTION,
+            None,
+            EnvSettings::default(),
+            false,
+            HashMap::from([("other".to_string(), lfsc::Client::builder().build())]),
+        );
+        let other = &vfs.clusters["other"];
+
+        for (name, cluster, dbname, pos, kind) in [
+            ("db", &vfs.default_cluster, "db", None, OpenKind::MainDb),
+            (
+                "db-journal",
+                &vfs.default_cluster,
+                "db",
+                None,
+                OpenKind::MainJournal,
+            ),
+            ("other:db", other, "db", None, OpenKind::MainDb),
+            ("other:db-journal", other, "db", None, OpenKind::MainJournal),
+            ("other:db-wal", other, "db", None, OpenKind::Wal),
+            (
+                "db-wal-wal",
+                &vfs.default_cluster,
+                "db-wal",
+                None,
+                OpenKind::Wal,
+            ),
+            (
+                "unknown:db",
+                &vfs.default_cluster,
+                "unknown:db",
+                None,
+                OpenKind::MainDb,
+            ),
+            (
+                "db@2a/ff",
+                &vfs.default_cluster,
+                "db",
+                Some("2a/ff"),
+                OpenKind::MainDb,
+            ),
+            (
+                "other:db@2a/ff-journal",
+                other,
+                "db",
+                Some("2a/ff"),
+                OpenKind::MainJournal,
+            ),
+            (
+                "user@host",
+                &vfs.default_cluster,
+                "user@host",
+                None,
+                OpenKind::MainDb,
+            ),
+            (
+                "user@host@2a/ff",
+                &vfs.default_cluster,
+                "user@host",
+                Some("2a/ff"),
+                OpenKind::MainDb,
+            ),
+        ] {
+            let (c, n, p, k) = vfs.database_name_kind(name);
+            assert!(ptr::eq(cluster, c), "wrong cluster for {}", name);
+            assert_eq!((dbname, pos, kind), (n, p, k), "{}", name);
+        }
+    }
+
+    #[test]
+    fn wal_files() {
+        let path = TempDir::new("wal-files");
+        let vfs = LiteVfs::new(
+            &path,
+            lfsc::Client::builder().build(),
+            PagerBackend::default(),
+            false,
+            DEFAULT_LEASE_DURATION,
+            DEFAULT_LEASE_REFRESH_FRACTION,
+            None,
+            EnvSettings::default(),
+            false,
+            HashMap::new(),
+        );
+
+        // Neither touches the database, which doesn't exist here
+        for name in ["db-wal", "db@2a/ff-wal"] {
+            assert!(!vfs.exists(name).expect("exists"), "{}", name);
+            vfs.delete(name).expect("delete");
+            assert!(!vfs.exists(name).expect("exists"), "{}", name);
+        }
+    }
+
+    #[cfg(not(target_os = "emscripten"))]
+    #[test]
+    fn shutdown() {
+        let path = TempDir::new("shutdown");
+        fs::create_dir_all(&path).expect("create dir");
+        let vfs = LiteVfs::new(
+            &path,
+            lfsc::Client::builder().build(),
+            PagerBackend::default(),
+            false,
+            DEFAULT_LEASE_DURATION,
+            DEFAULT_LEASE_REFRESH_FRACTION,
+            None,
+            EnvSettings::default(),
+            false,
+            HashMap::new(),
+        );
+
+        let handle = vfs.shutdown_handle();
+        handle.shutdown(Duration::from_secs(10)).expect("shutdown");
+        vfs.shutdown(Duration::ZERO).expect("shutdown again");
+
+        // Nobody would refresh a new lease
+        assert!(vfs.default_cluster.leaser.acquire_lease("db").is_err());
+        // Nor start new uploads
+        assert!(vfs
+            .default_cluster
+            .database_manager
+            .background()
+            .is_stopped());
+    }
+
+    #[cfg(not(target_os = "emscripten"))]
+    #[test]
+    fn shutdown_cancels_reads() {
+        use crate::testutil::{serve, Response};
+        use std::{thread, time::Instant};
+
+        let (host, _) = serve(None, |_| Response::new(429).header("Retry-After", "2"));
+        let path = TempDir::new("shutdown-cancels-reads");
+        // Page 2 isn't cached, so reading it waits for LFSC to stop rate limiting
+        cache_db(&path, tx_pos(1), &[&page1(2)]);
+        let vfs = LiteVfs::new(
+            &path,
+            lfsc::Client::builder().host(&host.parse().unwrap()).build(),
+            PagerBackend::default(),
+            false,
+            DEFAULT_LEASE_DURATION,
+            DEFAULT_LEASE_REFRESH_FRACTION,
+            None,
+            EnvSettings::default(),
+            false,
+            HashMap::new(),
+        );
+        let mut handle = vfs
+            .open_main_db(&vfs.default_cluster, "db", None, OpenAccess::Read)
+            .expect("open")
+            .inner;
+
+        let shutdown = vfs.shutdown_handle();
+        let started = Instant::now();
+        let shutdown = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(200));
+            shutdown.shutdown(Duration::from_secs(5))
+        });
+
+        // The read gives up instead of waiting out the retries
+        let mut buf = vec![0; 4096];
+        let err = handle.read_exact_at(&mut buf, 4096).expect_err("read");
+        assert_eq!(io::ErrorKind::Interrupted, err.kind());
+        assert!(started.elapsed() < Duration::from_secs(2));
+        shutdown.join().unwrap().expect("shutdown");
+    }
+
+    #[test]
+    fn temporary_names() {
+        let path = TempDir::new("temp-names");
+        let new_vfs = || {
+            LiteVfs::new(
+                &path,
+                lfsc::Client::builder().build(),
+                PagerBackend::default(),
+                false,
+                DEFAULT_LEASE_DURATION,
+                DEFAULT_LEASE_REFRESH_FRACTION,
+                None,
+                EnvSettings::default(),
+                false,
+                HashMap::new(),
+            )
+        };
+        let (vfs1, vfs2) = (new_vfs(), new_vfs());
+        fs::create_dir_all(&path).expect("create dir");
+
+        // Same PID and counters, but different salts
+        let names1 = (0..16).map(|_| vfs1.temporary_name()).collect::<Vec<_>>();
+        let names2 = (0..16).map(|_| vfs2.temporary_name()).collect::<Vec<_>>();
+        assert!(names1.iter().all(|name| !names2.contains(name)));
+
+        // A taken name is replaced when the file must be new
+        let taken = vfs1.temporary_name();
+        fs::write(path.join(&taken), []).expect("write");
+        let file = vfs2
+            .open_temp_db(&taken, OpenAccess::CreateNew)
+            .expect("open");
+        assert!(!file.name.ends_with(&taken));
+        let file = vfs2.open_temp_db(&taken, OpenAccess::Create).expect("open");
+        assert!(file.name.ends_with(&taken));
+    }
+
+    #[cfg(not(target_os = "emscripten"))]
+    #[test]
+    fn cluster_leases() {
+        use crate::{
+            leaser::Leaser,
+            testutil::{lfsc_client, serve, Response},
+        };
+
+        let (host, _) = serve(None, |request| {
+            Response::json(&format!(
+                r#"{{"id":"{}","expires_at":"2100-01-01T00:00:00Z"}}"#,
+                request.query("db").expect("db")
+            ))
+        });
+        let leaser = || {
+            Leaser::new(
+                Arc::new(lfsc_client(&host)),
+                DEFAULT_LEASE_DURATION,
+                DEFAULT_LEASE_REFRESH_FRACTION,
+                None,
+                false,
+            )
+        };
+        let leasers = vec![(None, leaser()), (Some("other".to_string()), leaser())];
+        leasers[0].1.acquire_lease("db1").expect("acquire");
+        leasers[1].1.acquire_lease("db2").expect("acquire");
+
+        // The leases of the other clusters are listed too
+        let leases = serde_json::to_value(super::cluster_leases(&leasers)).expect("json");
+        assert_eq!(
+            serde_json::json!([
+                {"db": "db1", "id": "db1", "expires_at": "2100-01-01T00:00:00Z"},
+                {"cluster": "other", "db": "db2", "id": "db2", "expires_at": "2100-01-01T00:00:00Z"},
+            ]),
+            leases
+        );
+    }
+
+    #[cfg(not(target_os = "emscripten"))]
+    #[test]
+    fn lease_released_on_close() {
+        use crate::testutil::{serve, Response};
+
+        let released = Arc::new(Mutex::new(Vec::new()));
+        let (host, _) = serve(None, {
+            let released = Arc::clone(&released);
+
+            move |request| {
+                if request.line.starts_with("DELETE") {
+                    released.lock().unwrap().push(request.query("id"));
+                    Response::new(200)
+                } else if request.line.contains("/sync") {
+                    Response::json(r#"{"changes":{}}"#)
+                } else {
+                    Response::json(r#"{"id":"lease1","expires_at":"2100-01-01T00:00:00Z"}"#)
+                }
+            }
+        });
+        let path = TempDir::new("lease-released-on-close");
+        cache_db(&path, tx_pos(1), &[&page1(2), &[2; 4096]]);
+        let vfs = new_vfs(&path, &host);
+        let open = || {
+            vfs.open_main_db(&vfs.default_cluster, "db", None, OpenAccess::Write)
+                .expect("open")
+                .inner
+        };
+        let (mut conn1, conn2) = (open(), open());
+        conn1
+            .pragma("litevfs_acquire_lease", None)
+            .expect("pragma")
+            .expect("litevfs_acquire_lease");
+
+        // Only the connection that has acquired the lease releases it
+        drop(conn2);
+        assert!(released.lock().unwrap().is_empty());
+        drop(conn1);
+        assert_eq!(vec![Some("lease1".to_string())], *released.lock().unwrap());
+    }
+
+    #[cfg(not(target_os = "emscripten"))]
+    #[test]
+    fn sync_now() {
+        let (host, lfsc) = serve_lfsc();
+        let count = |conn: &rusqlite::Connection| {
+            conn.query_row("select count(*) from t", [], |row| row.get::<_, i64>(0))
+                .expect("select")
+        };
+        let (path1, path2) = (TempDir::new("sync-now-1"), TempDir::new("sync-now-2"));
+
+        let conn1 = open_sqlite("litevfs-sync-now-1", &path1, &host);
+        conn1
+            .execute_batch(
+                "pragma litevfs_acquire_lease; create table t (x); insert into t values (1);",
+            )
+            .expect("create");
+        let conn2 = open_sqlite("litevfs-sync-now-2", &path2, &host);
+        assert_eq!(1, count(&conn2));
+
+        // The write on the first VFS is seen by the second one once it has synced
+        conn1
+            .execute("insert into t values (2)", [])
+            .expect("insert");
+        let pos: String = conn2
+            .query_row("pragma litevfs_sync_now", [], |row| row.get(0))
+            .expect("litevfs_sync_now");
+        assert_eq!(lfsc.lock().unwrap().pos.expect("pos").to_string(), pos);
+        assert_eq!(2, count(&conn2));
+    }
+
+    #[cfg(not(target_os = "emscripten"))]
+    #[test]
+    fn unpin_syncs() {
+        let (host, _lfsc) = serve_lfsc();
+        let count = |conn: &rusqlite::Connection| {
+            conn.query_row("select count(*) from t", [], |row| row.get::<_, i64>(0))
+                .expect("select")
+        };
+        let pending = |conn: &rusqlite::Connection| {
+            let last_sync: String = conn
+                .query_row("pragma litevfs_last_sync", [], |row| row.get(0))
+                .expect("litevfs_last_sync");
+            serde_json::from_str::<serde_json::Value>(&last_sync).expect("json")["pending"]
+                .as_bool()
+                .expect("pending")
+        };
+        let (path1, path2) = (TempDir::new("unpin-1"), TempDir::new("unpin-2"));
+
+        let conn1 = open_sqlite("litevfs-unpin-1", &path1, &host);
+        conn1
+            .execute_batch(
+                "pragma litevfs_acquire_lease; create table t (x); insert into t values (1);",
+            )
+            .expect("create");
+        let conn2 = open_sqlite("litevfs-unpin-2", &path2, &host);
+        assert_eq!(1, count(&conn2));
+        conn2
+            .execute_batch("pragma litevfs_pin_pos = current")
+            .expect("litevfs_pin_pos");
+
+        // The pinned connection doesn't see the write, even once the VFS knows about it
+        conn1
+            .execute("insert into t values (2)", [])
+            .expect("insert");
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(10);
+        while !pending(&conn2) {
+            assert!(std::time::Instant::now() < deadline, "never synced");
+            std::thread::sleep(std::time::Duration::from_millis(50));
+        }
+        assert_eq!(1, count(&conn2));
+
+        // Unpinning catches up right away
+        conn2
+            .execute_batch("pragma litevfs_unpin")
+            .expect("litevfs_unpin");
+        assert!(!pending(&conn2));
+        assert_eq!(2, count(&conn2));
+    }
+
+    #[cfg(not(target_os = "emscripten"))]
+    #[test]
+    fn vacuum() {
+        let (host, lfsc) = serve_lfsc();
+        let path = TempDir::new("vacuum");
+        let conn = open_sqlite("litevfs-vacuum", &path, &host);
+        let page_count = || {
+            conn.query_row("pragma page_count", [], |row| row.get::<_, u32>(0))
+                .expect("page_count")
+        };
+
+        conn.execute_batch(
+            "pragma litevfs_acquire_lease;
+             create table t (x);
+             with recursive n(i) as (select 1 union all select i + 1 from n where i < 200)
+             insert into t select zeroblob(1000) from n;
+             delete from t where rowid > 10;",
+        )
+        .expect("fill");
+        let filled = page_count();
+        assert_eq!(Some(&filled), lfsc.lock().unwrap().pages.keys().last());
+
+        conn.execute_batch("vacuum").expect("vacuum");
+
+        // The removed pages are gone from LFSC, and the checksum the VFS has computed
+        // without them matches the one of the remaining pages.
+        let vacuumed = page_count();
+        assert!(vacuumed < filled);
+        let lfsc = lfsc.lock().unwrap();
+        assert_eq!(Some(&vacuumed), lfsc.pages.keys().last());
+        assert_eq!(vacuumed as usize, lfsc.pages.len());
+        let pos: ltx::Pos =
+            serde_json::from_slice(&fs::read(path.join("db").join("pos")).expect("read pos"))
+                .expect("pos");
+        assert_eq!(lfsc.pos, Some(pos));
+    }
+
+    // Returns the position after the transaction `txid`.
+    #[cfg(not(target_os = "emscripten"))]
+    fn tx_pos(txid: u64) -> ltx::Pos {
+        ltx::Pos {
+            txid: ltx::TXID::new(txid).unwrap(),
+            post_apply_checksum: ltx::Checksum::new(0x8000_0000_0000_0000 | txid),
+        }
+    }
+
+    // Returns page 1 of a database of `commit` pages.
+    #[cfg(not(target_os = "emscripten"))]
+    fn page1(commit: u32) -> Vec<u8> {
+        let mut page1 = vec![0; 4096];
+        page1[16..18].copy_from_slice(&4096u16.to_be_bytes());
+        page1[28..32].copy_from_slice(&commit.to_be_bytes());
+
+        page1
+    }
+
+    // Caches the `pages` of the database `db` as of `pos`, as if they've been read before.
+    #[cfg(not(target_os = "emscripten"))]
+    fn cache_db(path: &Path, pos: ltx::Pos, pages: &[&[u8]]) {
+        use crate::pager::{PageRef, Pager};
+
+        let pager = Pager::new(
+            path,
+            Arc::new(lfsc::Client::builder().build()),
+            PagerBackend::default(),
+            &EnvSettings::default(),
+        );
+        pager.prepare_db("db").expect("prepare_db");
+        for (n, page) in pages.iter().enumerate() {
+            let pgno = ltx::PageNum::new(n as u32 + 1).unwrap();
+            pager
+                .put_page("db", PageRef::new(pgno, page))
+                .expect("put_page");
+        }
+        fs::write(
+            pager.db_path("db").join("pos"),
+            serde_json::to_vec(&pos).expect("json"),
+        )
+        .expect("write pos");
+    }
+
+    // Returns a VFS caching the databases in `path`, with LFSC at `host`.
+    #[cfg(not(target_os = "emscripten"))]
+    fn new_vfs(path: &Path, host: &str) -> LiteVfs {
+        use crate::testutil::lfsc_client;
+
+        LiteVfs::new(
+            path,
+            lfsc_client(host),
+            PagerBackend::default(),
+            false,
+            DEFAULT_LEASE_DURATION,
+            DEFAULT_LEASE_REFRESH_FRACTION,
+            None,
+            EnvSettings::default(),
+            false,
+            HashMap::new(),
+        )
+    }
+
+    // Registers a VFS caching the databases in `path` under `vfs_name`, and opens
+    // the database `db` with it.
+    #[cfg(not(target_os = "emscripten"))]
+    fn open_sqlite(vfs_name: &str, path: &Path, host: &str) -> rusqlite::Connection {
+        use rusqlite::{Connection, OpenFlags};
+
+        sqlite_vfs::LinkedExtension::build()
+            .register(vfs_name, new_vfs(path, host), false)
+            .expect("register");
+        Connection::open_with_flags_and_vfs(
+            "db",
+            OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+            vfs_name,
+        )
+        .expect("open")
+    }
+
+    // The database `db` of the LFSC started by `serve_lfsc`.
+    #[cfg(not(target_os = "emscripten"))]
+    #[derive(Default)]
+    struct LfscDb {
+        pos: Option<ltx::Pos>,
+        pages: BTreeMap<u32, Vec<u8>>,
+    }
+
+    // Starts an LFSC with the single database `db`, applying the uploaded transactions
+    // to its pages kept in memory. Returns the LFSC URL and the database.
+    #[cfg(not(target_os = "emscripten"))]
+    fn serve_lfsc() -> (String, Arc<Mutex<LfscDb>>) {
+        use crate::testutil::{serve, Response};
+        use litetx::PageChecksum;
+        use std::io::BufReader;
+
+        let lfsc = Arc::new(Mutex::new(LfscDb::default()));
+        let (host, _) = serve(None, {
+            let lfsc = Arc::clone(&lfsc);
+
+            move |request| {
+                let LfscDb { pos, pages } = &mut *lfsc.lock().unwrap();
+                let pos_json = match pos {
+                    Some(pos) => serde_json::to_value(*pos).expect("json"),
+                    None => serde_json::json!({
+                        "txid": "0000000000000000",
+                        "postApplyChecksum": "0000000000000000",
+                    }),
+                };
+                let changes = |known: bool| {
+                    if known {
+                        serde_json::json!({ "pos": pos_json })
+                    } else {
+                        serde_json::json!({ "pos": pos_json, "all": true })
+                    }
+                };
+
+                let target = request.line.split(' ').nth(1).unwrap_or_default();
+                match target.split('?').next().unwrap_or_default() {
+                    "/pos" if pos.is_some() => {
+                        Response::json(&serde_json::json!({ "db": pos_json }).to_string())
+                    }
+                    "/pos" => Response::json("{}"),
+                    "/sync" => {
+                        let body: serde_json::Value =
+                            serde_json::from_slice(&request.body).expect("sync body");
+                        let changes = body["positions"]
+                            .as_object()
+                            .expect("positions")
+                            .iter()
+                            .map(|(db, known)| (db.clone(), changes(*known == pos_json)))
+                            .collect::<serde_json::Map<_, _>>();
+                        Response::json(&serde_json::json!({ "changes": changes }).to_string())
+                    }
+                    "/db/sync" => Response::json(
+                        &changes(request.query("pos") == pos.map(|pos| pos.to_string()))
+                            .to_string(),
+                    ),
+                    "/db/page" => Response::pages(
+                        request
+                            .pgnos()
+                            .into_iter()
+                            .filter_map(|pgno| pages.get(&pgno).map(|data| (pgno, &data[..]))),
+                    ),
+                    "/db/tx" => {
+                        let (mut dec, header) =
+                            ltx::Decoder::new(BufReader::new(&request.body[..])).expect("decoder");
+                        let mut data = vec![0; header.page_size.into_inner() as usize];
+                        while let Some(pgno) = dec.decode_page(&mut data).expect("decode_page") {
+                            pages.insert(pgno.into_inner(), data.clone());
+                        }
+                        pages.retain(|&pgno, _| pgno <= header.commit.into_inner());
+
+                        let checksum = pages.iter().fold(0, |checksum, (&pgno, data)| {
+                            checksum
+                                ^ data
+                                    .page_checksum(ltx::PageNum::new(pgno).unwrap())
+                                    .into_inner()
+                        });
+                        *pos = Some(ltx::Pos {
+                            txid: header.max_txid,
+                            post_apply_checksum: ltx::Checksum::new(checksum),
+                        });
+                        Response::new(200)
+                    }
+                    "/lease" if request.line.starts_with("DELETE") => Response::new(200),
+                    "/lease" => {
+                        Response::json(r#"{"id":"lease1","expires_at":"2100-01-01T00:00:00Z"}"#)
+                    }
+                    _ => Response::new(404),
+                }
+            }
+        });
+
+        (host, lfsc)
+    }
+
+    #[cfg(not(target_os = "emscripten"))]
+    #[test]
+    fn offline_reads() {
+        use crate::syncer::Changes;
+        use std::collections::BTreeSet;
+
+        // Pages 1 and 2 of the 3-page database are cached
+        let path = TempDir::new("offline-reads");
+        let page1 = page1(3);
+        cache_db(&path, tx_pos(1), &[&page1, &[2; 4096]]);
+
+        // Nothing listens there
+        let vfs = new_vfs(&path, "http://127.0.0.1:1");
+        let mut handle = vfs
+            .open_main_db(&vfs.default_cluster, "db", None, OpenAccess::Read)
+            .expect("open")
+            .inner;
+        for (pragma, val) in [
+            ("litevfs_offline_reads", "true"),
+            ("litevfs_pin_pos", "current"),
+        ] {
+            handle
+                .pragma(pragma, Some(val))
+                .expect("pragma")
+                .expect(pragma);
+        }
+
+        // Somebody else changes pages 1 and 3 while LFSC is still reachable
+        let database = vfs
+            .default_cluster
+            .database_manager
+            .get_database("db", OpenAccess::Read)
+            .expect("database");
+        let pgnos = [1, 3].map(|n| ltx::PageNum::new(n).unwrap());
+        vfs.default_cluster.syncer.set_pos("db", Some(tx_pos(2)));
+        vfs.default_cluster
+            .syncer
+            .put_changes("db", Changes::Pages(BTreeSet::from(pgnos)));
+        database.write().unwrap().sync(false, false).expect("sync");
+
+        // The pinned position is still readable from the cache
+        let mut buf = vec![0; 4096];
+        assert_eq!(3 * 4096, handle.size().expect("size"));
+        handle.read_exact_at(&mut buf, 0).expect("read page 1");
+        assert_eq!(page1, buf);
+        handle.read_exact_at(&mut buf, 4096).expect("read page 2");
+        assert_eq!(vec![2; 4096], buf);
+
+        // Missing pages fail right away instead of trying LFSC
+        let err = handle
+            .read_exact_at(&mut buf, 2 * 4096)
+            .expect_err("read page 3");
+        assert!(err.to_string().contains("not cached"), "{}", err);
+    }
+
+    #[cfg(not(target_os = "emscripten"))]
+    #[test]
+    fn open_at_pos() {
+        use crate::testutil::{serve, Response};
+
+        // LFSC serves the database as of the first transaction, with page 2 filled with 2s,
+        // while the cache holds the second one, with page 2 filled with 3s
+        let (host, server) = serve(Some(2), |request| {
+            assert_eq!(Some(tx_pos(1).to_string()), request.query("pos"));
+            let pages = request
+                .pgnos()
+                .into_iter()
+                .map(|pgno| (pgno, if pgno == 1 { page1(2) } else { vec![2; 4096] }))
+                .collect::<Vec<_>>();
+            Response::pages(pages.iter().map(|(pgno, data)| (*pgno, &data[..])))
+        });
+        let path = TempDir::new("open-at-pos");
+        cache_db(&path, tx_pos(2), &[&page1(2), &[3; 4096]]);
+        let vfs = new_vfs(&path, &host);
+        let at_pos = tx_pos(1).to_string();
+
+        // PermissionDenied makes SQLite retry opening the database as read-only
+        let err = vfs
+            .open_main_db(
+                &vfs.default_cluster,
+                "db",
+                Some(at_pos.as_str()),
+                OpenAccess::Write,
+            )
+            .err()
+            .expect("open for writing");
+        assert_eq!(io::ErrorKind::PermissionDenied, err.kind());
+
+        let mut handle = vfs
+            .open_main_db(
+                &vfs.default_cluster,
+                "db",
+                Some(at_pos.as_str()),
+                OpenAccess::Read,
+            )
+            .expect("open")
+            .inner;
+        let mut buf = vec![0; 4096];
+        assert_eq!(2 * 4096, handle.size().expect("size"));
+        handle.read_exact_at(&mut buf, 4096).expect("read");
+        assert_eq!(vec![2; 4096], buf);
+
+        // Neither the database nor the state shared with the other connections changes
+        assert_eq!(
+            io::ErrorKind::PermissionDenied,
+            handle.write_all_at(&buf, 4096).expect_err("write").kind()
+        );
+        for (pragma, val) in [
+            ("litevfs_acquire_lease", None),
+            ("litevfs_release_lease", None),
+            ("litevfs_force_release_lease", Some("lease")),
+            ("litevfs_import_file", Some("db.sqlite")),
+            ("litevfs_sync_now", None),
+            ("litevfs_wait_for_pos", Some("2")),
+            ("litevfs_flush", None),
+            ("litevfs_drop_cache", None),
+            ("litevfs_verify_cache", None),
+            ("litevfs_combine_writes", Some("1s")),
+            ("litevfs_unpin", None),
+        ] {
+            let err = handle
+                .pragma(pragma, val)
+                .expect("pragma")
+                .expect_err(pragma);
+            assert_eq!(io::ErrorKind::PermissionDenied, err.kind(), "{}", pragma);
+        }
+        // Shared settings can still be read
+        handle
+            .pragma("litevfs_combine_writes", None)
+            .expect("pragma")
+            .expect("litevfs_combine_writes");
+
+        // Other connections read the current position from the cache
+        let mut current = vfs
+            .open_main_db(&vfs.default_cluster, "db", None, OpenAccess::Read)
+            .expect("open")
+            .inner;
+        current.read_exact_at(&mut buf, 4096).expect("read");
+        assert_eq!(vec![3; 4096], buf);
+
+        assert_eq!(
+            vec![vec![1], vec![2]],
+            server
+                .join()
+                .unwrap()
+                .iter()
+                .map(|r| r.pgnos())
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[cfg(not(target_os = "emscripten"))]
+    #[test]
+    fn wal_mode() {
+        use crate::syncer::Changes;
+        use sqlite_vfs::wip::{WalIndex, WalIndexLock};
+        use std::collections::BTreeSet;
+
+        // The read and write versions of 2 mean WAL mode
+        let path = TempDir::new("wal-mode");
+        let mut page1 = page1(2);
+        page1[18..20].copy_from_slice(&[2, 2]);
+        cache_db(&path, tx_pos(1), &[&page1, &[2; 4096]]);
+        let vfs = new_vfs(&path, "http://127.0.0.1:1");
+
+        // PermissionDenied makes SQLite retry opening the database as read-only
+        let err = vfs
+            .open_main_db(&vfs.default_cluster, "db", None, OpenAccess::Write)
+            .err()
+            .expect("open for writing");
+        assert_eq!(io::ErrorKind::PermissionDenied, err.kind());
+
+        let open = || {
+            vfs.open_main_db(&vfs.default_cluster, "db", None, OpenAccess::Read)
+                .expect("open")
+                .inner
+        };
+        let (mut conn1, conn2) = (open(), open());
+        let mut index1 = conn1.wal_index(true).expect("wal_index");
+        let mut index2 = conn2.wal_index(true).expect("wal_index");
+
+        // The index is shared by the connections
+        let mut region = index1.map(0).expect("map");
+        region[0] = 1;
+        index1.push(0, &region).expect("push");
+        assert_eq!(1, index2.map(0).expect("map")[0]);
+
+        // The database moves on while the first connection is reading
+        assert!(index1.lock(3..4, WalIndexLock::Shared).expect("lock"));
+        let database = vfs
+            .default_cluster
+            .database_manager
+            .get_database("db", OpenAccess::Read)
+            .expect("database");
+        vfs.default_cluster.syncer.set_pos("db", Some(tx_pos(2)));
+        vfs.default_cluster.syncer.put_changes(
+            "db",
+            Changes::Pages(BTreeSet::from([ltx::PageNum::new(2).unwrap()])),
+        );
+
+        // The second one syncs and resets the index, while the first one keeps reading
+        // the database as of its own transaction
+        assert!(index2.lock(4..5, WalIndexLock::Shared).expect("lock"));
+        assert_eq!(Some(tx_pos(2)), database.read().unwrap().pos);
+        assert_eq!(0, index2.map(0).expect("map")[0]);
+        assert_eq!(8192, conn1.size().expect("size"));
+        let mut buf = vec![0; 16];
+        conn1.read_exact_at(&mut buf, 24).expect("read");
+        assert_eq!(page1[24..40], buf[..]);
+    }
+
+    #[cfg(not(target_os = "emscripten"))]
+    #[test]
+    fn busy_timeout() {
+        let path = TempDir::new("busy-timeout");
+        cache_db(&path, tx_pos(1), &[&page1(2), &[2; 4096]]);
+        let vfs = new_vfs(&path, "http://127.0.0.1:1");
+        let open = || {
+            vfs.open_main_db(&vfs.default_cluster, "db", None, OpenAccess::Read)
+                .expect("open")
+                .inner
+        };
+        let (mut conn1, mut conn2) = (open(), open());
+
+        // SQLite still applies the pragma itself
+        assert!(conn1.pragma("busy_timeout", Some("2500")).is_none());
+        assert_eq!(
+            Some("2s 500ms".to_string()),
+            conn1
+                .pragma("litevfs_lock_timeout", None)
+                .expect("pragma")
+                .expect("litevfs_lock_timeout")
+        );
+
+        // Without a timeout the lock is taken at the first attempt or never, whichever
+        // pragma sets it
+        assert!(conn2.lock(sqlite_vfs::LockKind::Shared));
+        for (pragma, zero) in [("busy_timeout", "0"), ("litevfs_lock_timeout", "0s")] {
+            conn1
+                .pragma("litevfs_lock_timeout", Some("1h"))
+                .expect("pragma")
+                .expect("litevfs_lock_timeout");
+            if let Some(ret) = conn1.pragma(pragma, Some(zero)) {
+                ret.expect(pragma);
+            }
+            assert_eq!(
+                Some("0s".to_string()),
+                conn1
+                    .pragma("litevfs_lock_timeout", None)
+                    .expect("pragma")
+                    .expect("litevfs_lock_timeout")
+            );
+
+            let started = std::time::Instant::now();
+            let err = conn1
+                .pragma("litevfs_drop_cache", None)
+                .expect("pragma")
+                .expect_err("drop_cache");
+            assert_eq!(io::ErrorKind::WouldBlock, err.kind());
+            assert!(started.elapsed() < super::DEFAULT_LOCK_TIMEOUT);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn path_within() {
+        let root = TempDir::new("path-within");
+        let outside = TempDir::new("path-outside");
+        fs::create_dir_all(&root).expect("create root");
+        fs::create_dir_all(&outside).expect("create outside");
+
+        let resolved = super::path_within(&root, &root.join("temp1")).expect("new file");
+        assert!(resolved.ends_with("temp1"));
+
+        std::os::unix::fs::symlink(outside.join("temp2"), root.join("temp2")).expect("symlink");
+        std::os::unix::fs::symlink(&outside, root.join("dir")).expect("symlink");
+        for path in [
+            root.join("temp2"),
+            root.join("dir").join("temp3"),
+            root.join("..").join("temp4"),
+        ] {
+            assert_eq!(
+                io::ErrorKind::PermissionDenied,
+                super::path_within(&root, &path)
+                    .expect_err("path outside of root")
+                    .kind()
+            );
+        }
+
+        // Dangling symlinks aren't followed on open either
+        assert!(LiteTempDbHandle::new(root.join("temp2"), OpenAccess::Create).is_err());
+        assert!(!outside.join("temp2").exists());
+    }
+
+    #[test]
+    fn parse_duration_or_secs() {
+        use super::parse_duration_or_secs;
+
+        assert_eq!(
+            Duration::from_secs(5),
+            parse_duration_or_secs("5").expect("parse")
+        );
+        let period = parse_duration_or_secs("250ms").expect("parse");
+        assert_eq!(Duration::from_millis(250), period);
+        assert_eq!(
+            io::ErrorKind::InvalidInput,
+            parse_duration_or_secs("invalid")
+                .expect_err("invalid")
+                .kind()
+        );
+
+        // Newly opened databases inherit the default, which can be overridden per database
+        let client = Arc::new(lfsc::Client::builder().build());
+        let syncer = Syncer::new(client, period, DEFAULT_SYNC_JITTER);
+        syncer.open_conn("db1", None);
+        assert_eq!(period, syncer.sync_period("db1"));
+
+        syncer.set_sync_period("db1", Duration::from_secs(10));
+        syncer.open_conn("db2", None);
+        assert_eq!(Duration::from_secs(10), syncer.sync_period("db1"));
+        assert_eq!(period, syncer.sync_period("db2"));
+    }
+
+    #[test]
+    fn mem_temp_db_handle() {
+        let path = temp_dir().join(format!("litevfs-tempdb-{}", std::process::id()));
+        let file = LiteTempDbHandle::new(&path, OpenAccess::Create).expect("open");
+        let mem = LiteMemTempDbHandle::new("tempdb");
+
+        for mut handle in [
+            Box::new(file) as Box<dyn DatabaseHandle>,
+            Box::new(mem) as Box<dyn DatabaseHandle>,
+        ] {
+            let mut buf = [0xff; 8];
+            assert_eq!(0, handle.size().expect("size"));
+            assert_eq!(
+                io::ErrorKind::UnexpectedEof,
+                handle.read_exact_at(&mut buf, 0).expect_err("read").kind()
+            );
+
+            handle.write_all_at(&[1; 4], 4).expect("write");
+            assert_eq!(8, handle.size().expect("size"));
+            handle.read_exact_at(&mut buf, 0).expect("read");
+            assert_eq!([0, 0, 0, 0, 1, 1, 1, 1], buf);
+            assert_eq!(
+                io::ErrorKind::UnexpectedEof,
+                handle.read_exact_at(&mut buf, 4).expect_err("read").kind()
+            );
+
+            handle.set_len(6).expect("truncate");
+            assert_eq!(6, handle.size().expect("size"));
+            handle.read_exact_at(&mut buf[..6], 0).expect("read");
+            assert_eq!([0, 0, 0, 0, 1, 1], buf[..6]);
+
+            handle.set_len(8).expect("extend");
+            handle.read_exact_at(&mut buf, 0).expect("read");
+            assert_eq!([0, 0, 0, 0, 1, 1, 0, 0], buf);
+        }
+
+        fs::remove_file(path).expect("cleanup");
+    }
+}
@@ -0,0 +1,437 @@
+use crate::database::Database;
+use litetx as ltx;
+use sqlite_vfs::wip::{WalIndex, WalIndexLock};
+use std::{
+    collections::HashMap,
+    io,
+    ops::Range,
+    sync::{Arc, Mutex, RwLock},
+};
+
+const WAL_INDEX_REGION_SIZE: usize = 32768;
+// SQLITE_SHM_NLOCK
+const WAL_NUM_LOCKS: usize = 8;
+// WAL_WRITE_LOCK
+const WAL_WRITE_LOCK: usize = 0;
+// WAL_CKPT_LOCK
+const WAL_CKPT_LOCK: usize = 1;
+// WAL_READ_LOCK(0)
+const WAL_READ_LOCK_START: u8 = 3;
+
+type Region = [u8; WAL_INDEX_REGION_SIZE];
+
+/// WAL index (shared memory) of a single database, shared between all the
+/// connections to it.
+///
+/// LiteVFS only supports reading WAL databases. All the pages are always
+/// fetched from the database itself, so the WAL is always empty and the index
+/// is only used by SQLite to coordinate readers.
+///
+/// Starting a read transaction may sync the [Database] while the state is locked,
+/// so the state is always locked before the database lock, never the other way
+/// around. The read transactions that are already running keep reading the
+/// database as of the position they have started at.
+pub(crate) struct WalIndexState {
+    regions: HashMap<u32, Box<Region>>,
+    locks: [SlotLock; WAL_NUM_LOCKS],
+}
+
+#[derive(Default, Clone, Copy)]
+struct SlotLock {
+    readers: usize,
+    writer: bool,
+}
+
+impl WalIndexState {
+    pub(crate) fn new() -> Arc<Mutex<WalIndexState>> {
+        Arc::new(Mutex::new(WalIndexState {
+            regions: HashMap::new(),
+            locks: [SlotLock::default(); WAL_NUM_LOCKS],
+        }))
+    }
+
+    fn region(&mut self, region: u32) -> &mut Region {
+        self.regions
+            .entry(region)
+            .or_insert_with(|| Box::new([0; WAL_INDEX_REGION_SIZE]))
+    }
+
+    // Whether somebody is writing to or checkpointing the WAL. Neither ever happens to
+    // the empty WAL of a read-only database, but SQLite may still take the locks.
+    fn is_writing(&self) -> bool {
+        [WAL_WRITE_LOCK, WAL_CKPT_LOCK]
+            .iter()
+            .any(|&slot| self.locks[slot].readers > 0 || self.locks[slot].writer)
+    }
+
+    // Zeroes the WAL index header, forcing SQLite to rebuild the index and drop
+    // its page cache on the next read transaction.
+    fn invalidate(&mut self) {
+        self.regions.remove(&0);
+    }
+}
+
+/// A per-connection view of the [WalIndexState].
+pub(crate) struct LiteWalIndex {
+    state: Arc<Mutex<WalIndexState>>,
+    database: Arc<RwLock<Database>>,
+    locks: [WalIndexLock; WAL_NUM_LOCKS],
+    // Position the connection's read transaction has started at, shared with its
+    // database handle, which reads the database as of it.
+    read_pos: Arc<Mutex<Option<ltx::Pos>>>,
+}
+
+impl LiteWalIndex {
+    pub(crate) fn new(
+        database: Arc<RwLock<Database>>,
+        read_pos: Arc<Mutex<Option<ltx::Pos>>>,
+    ) -> LiteWalIndex {
+        let state = database.read().unwrap().wal_index();
+
+        LiteWalIndex {
+            state,
+            database,
+            locks: [WalIndexLock::None; WAL_NUM_LOCKS],
+            read_pos,
+        }
+    }
+
+    // Syncs the database with LFSC at the start of a read transaction. The other read
+    // transactions keep reading at their own positions, so only writers and checkpoints
+    // hold the sync off. The state stays locked, so that no other connection starts
+    // reading meanwhile, and the database is locked only after it.
+    fn maybe_sync(&self, state: &mut WalIndexState) {
+        if state.is_writing() || !self.database.read().unwrap().needs_sync() {
+            return;
+        }
+
+        let mut database = self.database.write().unwrap();
+        let prev_pos = database.pos;
+        if let Err(err) = database.sync(false, false) {
+            log::warn!("[wal] sync: db = {}: {}", database.name, err);
+            return;
+        }
+
+        if database.pos != prev_pos {
+            state.invalidate();
+        }
+    }
+
+    fn can_transition(&self, state: &WalIndexState, slot: usize, to: WalIndexLock) -> bool {
+        let from = self.locks[slot];
+        let lock = state.locks[slot];
+
+        match to {
+            WalIndexLock::None => true,
+            WalIndexLock::Shared => !lock.writer || from == WalIndexLock::Exclusive,
+            WalIndexLock::Exclusive => {
+                let other_readers = if from == WalIndexLock::Shared {
+                    lock.readers - 1
+                } else {
+                    lock.readers
+                };
+
+                other_readers == 0 && (!lock.writer || from == WalIndexLock::Exclusive)
+            }
+        }
+    }
+
+    fn transition(&mut self, state: &mut WalIndexState, slot: usize, to: WalIndexLock) {
+        let lock = &mut state.locks[slot];
+
+        match self.locks[slot] {
+            WalIndexLock::None => (),
+            WalIndexLock::Shared => lock.readers -= 1,
+            WalIndexLock::Exclusive => lock.writer = false,
+        };
+        match to {
+            WalIndexLock::None => (),
+            WalIndexLock::Shared => lock.readers += 1,
+            WalIndexLock::Exclusive => lock.writer = true,
+        };
+
+        self.locks[slot] = to;
+    }
+}
+
+impl WalIndex for LiteWalIndex {
+    fn map(&mut self, region: u32) -> io::Result<Region> {
+        Ok(*self.state.lock().unwrap().region(region))
+    }
+
+    fn lock(&mut self, locks: Range<u8>, lock: WalIndexLock) -> io::Result<bool> {
+        if locks.end as usize > WAL_NUM_LOCKS {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid WAL index lock",
+            ));
+        }
+
+        let state = Arc::clone(&self.state);
+        let mut state = state.lock().unwrap();
+
+        // A connection taking its first read lock is starting a read transaction
+        let starting = lock == WalIndexLock::Shared
+            && locks.start >= WAL_READ_LOCK_START
+            && self.locks.iter().all(|&l| l == WalIndexLock::None);
+        if starting {
+            self.maybe_sync(&mut state);
+        }
+
+        let slots = locks.start as usize..locks.end as usize;
+        if !slots
+            .clone()
+            .all(|slot| self.can_transition(&state, slot, lock))
+        {
+            return Ok(false);
+        }
+
+        for slot in slots {
+            self.transition(&mut state, slot, lock);
+        }
+
+        if starting {
+            let database = self.database.read().unwrap();
+            if let Some(pos) = database.pos {
+                database.pin_pos(pos);
+            }
+            *self.read_pos.lock().unwrap() = database.pos;
+        } else if self.locks.iter().all(|&l| l == WalIndexLock::None) {
+            self.read_pos.lock().unwrap().take();
+        }
+
+        Ok(true)
+    }
+
+    fn delete(self) -> io::Result<()> {
+        self.state.lock().unwrap().regions.clear();
+
+        Ok(())
+    }
+
+    fn pull(&mut self, region: u32, data: &mut Region) -> io::Result<()> {
+        data.copy_from_slice(&self.state.lock().unwrap().region(region)[..]);
+
+        Ok(())
+    }
+
+    fn push(&mut self, region: u32, data: &Region) -> io::Result<()> {
+        self.state
+            .lock()
+            .unwrap()
+            .region(region)
+            .copy_from_slice(&data[..]);
+
+        Ok(())
+    }
+}
+
+impl Drop for LiteWalIndex {
+    fn drop(&mut self) {
+        let state = Arc::clone(&self.state);
+        let mut state = state.lock().unwrap();
+
+        for slot in 0..WAL_NUM_LOCKS {
+            self.transition(&mut state, slot, WalIndexLock::None);
+        }
+        self.read_pos.lock().unwrap().take();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LiteWalIndex;
+    use crate::{
+        builder::EnvSettings,
+        database::{Database, DatabaseManager},
+        leaser::{Leaser, DEFAULT_LEASE_DURATION, DEFAULT_LEASE_REFRESH_FRACTION},
+        lfsc,
+        pager::{PageRef, Pager, PagerBackend},
+        syncer::{Changes, Syncer, DEFAULT_SYNC_JITTER, DEFAULT_SYNC_PERIOD},
+        testutil::TempDir,
+    };
+    use litetx as ltx;
+    use sqlite_vfs::{
+        wip::{WalIndex, WalIndexLock},
+        OpenAccess,
+    };
+    use std::{
+        collections::BTreeSet,
+        fs, io,
+        sync::{Arc, Mutex, RwLock},
+    };
+
+    fn tx_pos(txid: u64) -> ltx::Pos {
+        ltx::Pos {
+            txid: ltx::TXID::new(txid).unwrap(),
+            post_apply_checksum: ltx::Checksum::new(0x8000_0000_0000_0000 | txid),
+        }
+    }
+
+    // Opens a database of a single page cached at `tx_pos(1)`, returning the syncer
+    // that tells it about the new positions.
+    fn open_database(root: &TempDir) -> (Arc<RwLock<Database>>, Arc<Syncer>) {
+        let client = Arc::new(lfsc::Client::builder().build());
+        let pager = Arc::new(Pager::new(
+            root,
+            Arc::clone(&client),
+            PagerBackend::Files,
+            &EnvSettings::default(),
+        ));
+        let mut page1 = vec![0; 4096];
+        page1[16..18].copy_from_slice(&4096u16.to_be_bytes());
+        page1[28..32].copy_from_slice(&1u32.to_be_bytes());
+        pager.prepare_db("db").expect("prepare_db");
+        pager
+            .put_page("db", PageRef::new(ltx::PageNum::ONE, &page1))
+            .expect("put_page");
+        fs::write(
+            pager.db_path("db").join("pos"),
+            serde_json::to_vec(&tx_pos(1)).expect("json"),
+        )
+        .expect("write pos");
+
+        let leaser = Leaser::new(
+            Arc::clone(&client),
+            DEFAULT_LEASE_DURATION,
+            DEFAULT_LEASE_REFRESH_FRACTION,
+            None,
+            true,
+        );
+        let syncer = Syncer::new(
+            Arc::clone(&client),
+            DEFAULT_SYNC_PERIOD,
+            DEFAULT_SYNC_JITTER,
+        );
+        let manager = DatabaseManager::new(
+            pager,
+            client,
+            leaser,
+            Arc::clone(&syncer),
+            true,
+            &EnvSettings::default(),
+        );
+        let database = manager
+            .get_database("db", OpenAccess::Read)
+            .expect("get_database");
+        syncer.open_conn("db", Some(tx_pos(1)));
+
+        (database, syncer)
+    }
+
+    // Tells the database that LFSC has moved on to `tx_pos(txid)`.
+    fn move_on(syncer: &Syncer, txid: u64) {
+        syncer.set_pos("db", Some(tx_pos(txid)));
+        syncer.put_changes(
+            "db",
+            Changes::Pages(BTreeSet::from([ltx::PageNum::new(2).unwrap()])),
+        );
+    }
+
+    fn index(database: &Arc<RwLock<Database>>) -> (LiteWalIndex, Arc<Mutex<Option<ltx::Pos>>>) {
+        let read_pos = Arc::default();
+        let index = LiteWalIndex::new(Arc::clone(database), Arc::clone(&read_pos));
+
+        (index, read_pos)
+    }
+
+    #[test]
+    fn shared_regions() {
+        let root = TempDir::new("wal-regions");
+        let (database, _syncer) = open_database(&root);
+        let (mut index1, _) = index(&database);
+        let (mut index2, _) = index(&database);
+
+        // Regions start zeroed and are shared by the connections
+        let mut region = index1.map(1).expect("map");
+        assert!(region.iter().all(|&b| b == 0));
+        region[0] = 1;
+        index1.push(1, &region).expect("push");
+        let mut pulled = [0; super::WAL_INDEX_REGION_SIZE];
+        index2.pull(1, &mut pulled).expect("pull");
+        assert_eq!(1, pulled[0]);
+
+        // Deleting the index drops all of them
+        index1.delete().expect("delete");
+        assert_eq!(0, index2.map(1).expect("map")[0]);
+    }
+
+    #[test]
+    fn lock_mapping() {
+        let root = TempDir::new("wal-locks");
+        let (database, _syncer) = open_database(&root);
+        let (mut index1, _) = index(&database);
+        let (mut index2, _) = index(&database);
+
+        assert_eq!(
+            io::ErrorKind::InvalidInput,
+            index1
+                .lock(0..9, WalIndexLock::Shared)
+                .expect_err("lock")
+                .kind()
+        );
+
+        // Readers share the slot, a writer needs it for itself
+        assert!(index1.lock(3..4, WalIndexLock::Shared).expect("lock"));
+        assert!(index2.lock(3..4, WalIndexLock::Shared).expect("lock"));
+        assert!(!index1.lock(3..4, WalIndexLock::Exclusive).expect("lock"));
+        assert!(index2.lock(3..4, WalIndexLock::None).expect("unlock"));
+        assert!(index1.lock(3..4, WalIndexLock::Exclusive).expect("lock"));
+        assert!(!index2.lock(3..4, WalIndexLock::Shared).expect("lock"));
+        assert!(!index2.lock(3..4, WalIndexLock::Exclusive).expect("lock"));
+
+        // A range is locked as a whole or not at all
+        assert!(!index2.lock(2..4, WalIndexLock::Shared).expect("lock"));
+        assert!(index2.lock(2..3, WalIndexLock::Shared).expect("lock"));
+
+        // Dropping the connection releases its locks
+        drop(index1);
+        assert!(index2.lock(3..4, WalIndexLock::Exclusive).expect("lock"));
+    }
+
+    #[cfg(not(target_os = "emscripten"))]
+    #[test]
+    fn sync_at_read_start() {
+        let root = TempDir::new("wal-sync");
+        let (database, syncer) = open_database(&root);
+        let (mut index1, read_pos1) = index(&database);
+        let (mut index2, read_pos2) = index(&database);
+        let (mut writer, _) = index(&database);
+        let mut region = index1.map(0).expect("map");
+        region[0] = 1;
+        index1.push(0, &region).expect("push");
+
+        // Running read transactions don't hold the sync off, they keep their position
+        assert!(index1.lock(3..4, WalIndexLock::Shared).expect("lock"));
+        assert_eq!(Some(tx_pos(1)), *read_pos1.lock().unwrap());
+        move_on(&syncer, 2);
+        assert!(index2.lock(4..5, WalIndexLock::Shared).expect("lock"));
+        assert_eq!(Some(tx_pos(2)), database.read().unwrap().pos);
+        assert_eq!(Some(tx_pos(1)), *read_pos1.lock().unwrap());
+        assert_eq!(Some(tx_pos(2)), *read_pos2.lock().unwrap());
+        // The header is zeroed, so that SQLite drops its page cache
+        assert_eq!(0, index1.map(0).expect("map")[0]);
+        assert!(index2.lock(4..5, WalIndexLock::None).expect("unlock"));
+        assert_eq!(None, *read_pos2.lock().unwrap());
+
+        // Writers and checkpoints do
+        for slot in [0, 1] {
+            assert!(writer
+                .lock(slot..slot + 1, WalIndexLock::Exclusive)
+                .expect("lock"));
+            move_on(&syncer, slot as u64 + 3);
+            assert!(index2.lock(4..5, WalIndexLock::Shared).expect("lock"));
+            assert_eq!(Some(tx_pos(2)), database.read().unwrap().pos);
+            assert!(index2.lock(4..5, WalIndexLock::None).expect("unlock"));
+            assert!(writer
+                .lock(slot..slot + 1, WalIndexLock::None)
+                .expect("unlock"));
+        }
+
+        // Only the first read lock of a transaction syncs
+        assert!(index1.lock(5..6, WalIndexLock::Shared).expect("lock"));
+        assert_eq!(Some(tx_pos(2)), database.read().unwrap().pos);
+        assert!(index2.lock(4..5, WalIndexLock::Shared).expect("lock"));
+        assert_eq!(Some(tx_pos(4)), database.read().unwrap().pos);
+    }
+}
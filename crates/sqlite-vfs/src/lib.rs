@@ -992,7 +992,8 @@ mod io {
                 .get_ref()
                 .and_then(|inner| inner.downcast_ref::<CodeError>())
             {
-                return code.0 as c_int;
+                // Keep the error around, so that its message can be retrieved
+                return state.set_last_error(code.code() as c_int, err);
             } else {
                 return state.set_last_error(ffi::SQLITE_IOERR_READ, err);
             }
@@ -2019,13 +2020,38 @@ impl From<std::ffi::NulError> for RegisterError {
     }
 }
 
-#[derive(Debug)]
-pub struct CodeError(i32);
+#[derive(Debug, Clone, Copy)]
+pub struct CodeError {
+    code: i32,
+    message: Option<&'static str>,
+}
 
 impl CodeError {
     /// Construct a new error with the specific SQLite error code.
     pub fn new(code: i32) -> CodeError {
-        CodeError(code)
+        CodeError {
+            code,
+            message: None,
+        }
+    }
+
+    /// Construct a new error with the specific SQLite error code and a message
+    /// describing it.
+    pub fn with_message(code: i32, message: &'static str) -> CodeError {
+        CodeError {
+            code,
+            message: Some(message),
+        }
+    }
+
+    /// Returns the SQLite error code.
+    pub fn code(&self) -> i32 {
+        self.code
+    }
+
+    /// Returns the message describing the error, if any.
+    pub fn message(&self) -> Option<&'static str> {
+        self.message
     }
 }
 
@@ -2033,7 +2059,10 @@ impl std::error::Error for CodeError {}
 
 impl std::fmt::Display for CodeError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "SQLite error {}", self.0)
+        match self.message {
+            Some(message) => write!(f, "{} (SQLite error {})", message, self.code),
+            None => write!(f, "SQLite error {}", self.code),
+        }
     }
 }
 
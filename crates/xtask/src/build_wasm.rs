@@ -55,7 +55,7 @@ pub fn build_wasm(version: &str) -> Result<(), DynError> {
             "sqlite3_wasm_extra_init.c={}/wasm32-unknown-emscripten/release/liblitevfs.a",
             metadata.target_directory
         ),
-        "emcc.flags=-s EXTRA_EXPORTED_RUNTIME_METHODS=['ENV'] -s FETCH",
+        "emcc.flags=-s EXTRA_EXPORTED_RUNTIME_METHODS=['ENV'] -s FETCH -s ASYNCIFY",
         "release"
     )
     .run()?;